@@ -0,0 +1,108 @@
+"""
+SavedModel front-end: load a (Keras-exported) SavedModel, compile its training graph with TGE and write a SavedModel of the compiled graph.
+
+    from savedmodel import compile_saved_model
+    compile_saved_model("model_dir", "out_dir", devices, lambda tge: tge.selective_replication())
+
+The training graph is the MetaGraph saved with `tags` (by default the `train` tag that keras.experimental.export_saved_model uses).
+Its signature must contain the train op under the output key `train_op` (or the collection `train_op` must be set).
+"""
+
+import tensorflow as tf
+from tge import TGE, original_name, replica_name, replica_format
+
+
+def load_training_graph(path, tags=("train",), signature_key=None):
+    """
+    returns (graph_def, signature, variables). variables is a list of (name, dtype, shape, value) of all global variables,
+    where name is the name of the variable op (without ':0'). signature is None if the MetaGraph has no signatures.
+    """
+    graph = tf.Graph()
+    with graph.as_default(), tf.Session(graph=graph) as sess:
+        meta = tf.saved_model.loader.load(sess, list(tags), path)
+        signature = None
+        if meta.signature_def:
+            signature = meta.signature_def[signature_key or next(iter(meta.signature_def))]
+
+        variables = []
+        for var in tf.global_variables():
+            variables.append((var.op.name, var.dtype, var.shape.as_list(), sess.run(var)))
+
+        graph_def = graph.as_graph_def(add_shapes=True)
+
+    return graph_def, signature, variables
+
+
+def _sinks(graph_def, signature):
+    if signature is not None and "train_op" in signature.outputs:
+        return [signature.outputs["train_op"].name.split(':')[0]]
+    sinks = [node.name for node in graph_def.node if node.op in ("NoOp", "AssignAddVariableOp") and node.name.endswith("train_op")]
+    assert sinks, "cannot find the train op. Export it in the signature under the key `train_op`"
+    return sinks
+
+
+def compile_saved_model(path, out_path, devices, set_strategy, tags=("train",), signature_key=None, configure=None):
+    """
+    set_strategy: called with the TGE object to set the strategy, e.g. `lambda tge: tge.set_strategy(...)`.
+    configure: (optional) called with the TGE object before compiling to set topology, options, etc.
+    The compiled graph keeps the original Placeholders (split in graph), so the signature inputs stay valid.
+    Signature outputs, including `train_op`, are mapped to the first replica of each output tensor.
+    The trained values are loaded by feeding the initializers of the variables, so no nodes are added to the compiled graph.
+    """
+    graph_def, signature, variables = load_training_graph(path, tags, signature_key)
+
+    tge = TGE(graph_def, devices, sinks=_sinks(graph_def, signature))
+    tge.set_placeholder_mode('split')
+    if configure is not None:
+        configure(tge)
+    set_strategy(tge)
+    tge.compile()
+    compiled = tge.get_result()
+
+    fmt = replica_format(compiled)
+    replica_names = {} # original name => replica names in the order of replica index
+    for node in compiled.node:
+        original = original_name(node.name, fmt)
+        if original is not None:
+            replica_names.setdefault(original[0], []).append((original[1], node.name))
+    replica_names = { name: [x for _, x in sorted(replicas)] for name, replicas in replica_names.items() }
+    compiled_names = set(node.name for node in compiled.node)
+
+    graph = tf.Graph()
+    with graph.as_default():
+        tf.import_graph_def(compiled, name="")
+        with tf.Session(graph=graph) as sess:
+            sess.run(graph.get_operation_by_name(tge.init_op))
+
+            # copy the trained values into every replica of each variable by feeding the value input of its initializer
+            for name, dtype, shape, value in variables:
+                # the initializer `<variable>/Assign` is replicated along with the variable
+                initializer = name + '/Assign'
+                if name in replica_names:
+                    initializers = [replica_name(initializer, i, fmt) for i in range(len(replica_names[name]))]
+                else:
+                    initializers = [initializer]
+                for initializer in initializers:
+                    assert initializer in compiled_names, "cannot find the initializer {} of {}".format(initializer, name)
+                    assign = graph.get_operation_by_name(initializer)
+                    sess.run(assign, { assign.inputs[1]: value })
+
+            def compiled_tensor(info):
+                name, index = info.name.split(':')
+                if name in replica_names:
+                    name = replica_names[name][0]
+                return graph.get_tensor_by_name(name + ':' + index)
+
+            builder = tf.saved_model.builder.SavedModelBuilder(out_path)
+            signature_def_map = {}
+            if signature is not None:
+                build_tensor_info = tf.saved_model.utils.build_tensor_info
+                signature_def_map[signature_key or tf.saved_model.signature_constants.DEFAULT_SERVING_SIGNATURE_DEF_KEY] = tf.saved_model.signature_def_utils.build_signature_def(
+                    inputs={ k: build_tensor_info(graph.get_tensor_by_name(v.name)) for k, v in signature.inputs.items() },
+                    outputs={ k: build_tensor_info(compiled_tensor(v)) for k, v in signature.outputs.items() },
+                    method_name=signature.method_name
+                )
+            builder.add_meta_graph_and_variables(sess, list(tags), signature_def_map=signature_def_map, main_op=None)
+            builder.save()
+
+    return tge
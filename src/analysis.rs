@@ -0,0 +1,376 @@
+use oh_my_rust::*;
+use std::convert::TryInto;
+use crate::graph::*;
+use crate::misc::Target;
+#[cfg(feature = "search")]
+use crate::misc::Profiler;
+#[cfg(feature = "search")]
+use crate::simulator::{Simulator, SimpleSimulator, GRPC_LATENCY};
+#[cfg(feature = "search")]
+use crate::proto::node_def::NodeDef;
+
+/// resident bytes of variables (including optimizer slots, which are variables as well) on each device under the current forms.
+/// Must be called after editing. This alone frequently exceeds the memory of small GPUs, in which case full replication is infeasible.
+pub fn variable_memory(graph: &mut Graph, ndev: usize) -> Vec<u64> {
+    let mut result = vec![0; ndev];
+
+    for node in graph.nodes.iter_mut() {
+        if !node.is_variable() {
+            continue
+        }
+
+        let size = variable_size(node);
+        let replica_size = if node.form.is_part() {
+            size / node.form.ndev() as u64
+        } else {
+            size
+        };
+
+        for device_id in node.form.devices.iter() {
+            result[*device_id] += replica_size
+        }
+    }
+
+    result
+}
+
+/// bytes of the value of a variable, also for resource variables whose output is only a handle
+pub fn variable_size(node: &mut Node) -> u64 {
+    if node.raw_node.op == "VarHandleOp" { // the output is a resource handle, the actual shape is in the attr
+        let dims: Option<Vec<u64>> = node.raw_node.attr["shape"].get_shape().dim.iter().map(|x| x.size.try_into().ok()).collect();
+        let element_size = dtype_size(node.raw_node.attr["dtype"].get_field_type()).unwrap_or(4);
+        #[allow(clippy::unnecessary_fold)]
+        dims.map(|x| x.iter().fold(1, |x, y| x * y) * element_size).unwrap_or(0)
+    } else {
+        node.get_output(0).get_size()
+    }
+}
+
+/// the one-time cost of getting a plan running, reported separately from the step time
+#[cfg(feature = "search")]
+#[derive(Debug, Clone, Default)]
+pub struct StartupCost {
+    pub broadcast_time: u64, // simulated time of the broadcast op that copies replica 0 of each variable to the other replicas
+    pub constant_bytes: Vec<u64>, // bytes of constants embedded in the graph partition of each device
+    pub constant_time: u64 // time to ship the constants of devices on remote tasks, assuming workers receive in parallel
+}
+
+/// all transfers, no computation
+#[cfg(feature = "search")]
+struct TransferOnly;
+
+#[cfg(feature = "search")]
+impl Profiler for TransferOnly {
+    fn profile(&self, _node: &NodeDef, _device_id: usize) -> Option<u64> {
+        Some(0)
+    }
+}
+
+/// estimate the startup cost of a compiled target. Must be called before dangling nodes are removed since the broadcast op is not reachable from the sinks.
+#[cfg(feature = "search")]
+pub fn startup_cost(target: &Target) -> StartupCost {
+    let n = target.ndev();
+    let mut result = StartupCost { constant_bytes: vec![0; n], ..Default::default() };
+
+    // simulate the ancestors of the broadcast op alone
+    let node_dict: std::collections::HashMap<&str, &NodeDef> = target.pb.node.iter().map(|x| (&x.name[..], x)).collect();
+    if node_dict.contains_key(Graph::BROADCAST_OP) {
+        let mut keep = std::collections::BTreeSet::new();
+        let mut queue = vec![Graph::BROADCAST_OP];
+        while let Some(name) = queue.pop() {
+            if keep.insert(name) {
+                queue.extend(node_dict[name].input.iter().map(|x| {
+                    let x = x.trim_start_matches('^');
+                    &x[..x.find(':').unwrap_or(x.len())]
+                }))
+            }
+        }
+        let mut pb = crate::proto::graph::GraphDef::new();
+        for node in target.pb.node.iter().filter(|x| keep.contains(&x.name[..])) {
+            pb.node.push(node.clone())
+        }
+        let subgraph = Target::new(pb, target.devices.clone(), target.links.clone(), target.paths.clone(), target.sinks.clone(), target.nccls.clone());
+        result.broadcast_time = SimpleSimulator.evaluate::<std::fs::File>(&TransferOnly, subgraph, None, &mut vec![0; n]);
+    }
+
+    let device_dict: std::collections::HashMap<&str, usize> = target.devices.iter().enumerate().map(|(i, x)| (&x[..], i)).collect();
+    for node in target.pb.node.iter().filter(|x| x.op == "Const") {
+        let device_id = match device_dict.get(&node.device[..]) {
+            Some(x) => *x,
+            None => continue
+        };
+        let tensor = node.attr["value"].get_tensor();
+        result.constant_bytes[device_id] += if tensor.tensor_content.is_empty() {
+            tensor.get_tensor_shape().dim.iter().map(|d| d.size.max(0) as u64).product::<u64>() * dtype_size(tensor.dtype).unwrap_or(4)
+        } else {
+            tensor.tensor_content.len() as u64
+        }
+    }
+
+    let master = &target.devices[0][..target.devices[0].rfind('/').unwrap()];
+    result.constant_time = (0..n).filter(|d| !target.devices[*d].starts_with(master)).map(|d| {
+        let bandwidth = target.paths[d].iter().map(|link| target.links[*link]).min().unwrap_or(u64::MAX);
+        result.constant_bytes[d] / bandwidth + GRPC_LATENCY
+    }).max().unwrap_or(0);
+
+    result
+}
+
+/// the predicted step time and peak memory of each device
+#[cfg(feature = "search")]
+#[derive(Debug, Clone, Default)]
+pub struct Estimate {
+    pub time: u64,
+    pub memory: Vec<u64>,
+    pub traffic: u64 // bytes sent between devices
+}
+
+/// predict the step of an edited graph directly from the forms and the transfer plan, without emitting the replicas and the conversions.
+/// It follows the model of `SimpleSimulator` (one compute queue per device, links are occupied by one transfer at a time, a transfer takes
+/// size / bottleneck bandwidth + GRPC_LATENCY) but schedules the original nodes in topological order (see `Graph::topological_order`), so it
+/// is cheaper and coarser. Aggregations that the editor already emitted are modeled as a ring all-reduce when the devices match, and as a
+/// sum on the first destination device otherwise.
+#[cfg(feature = "search")]
+pub fn estimate(graph: &mut Graph, plan: &crate::plan::TransferPlan, target: &Target, profiler: &impl Profiler) -> Estimate {
+    let n = target.ndev();
+    let conversions: std::collections::BTreeMap<(usize, usize, &Form), Conversion> = plan.transfers.iter().map(|x| ((x.node, x.index, &x.to), x.conversion)).collect();
+    let derived: std::collections::BTreeMap<(usize, usize, &Form), &Form> = plan.transfers.iter() // conversions from a full form reused by `plan::reuse_aggregations`
+        .filter(|x| x.from != graph.nodes[x.node].form).map(|x| ((x.node, x.index, &x.to), &x.from)).collect();
+
+    let mut gpu_available = vec![0; n];
+    let mut link_available = vec![0; target.links.len()];
+    let mut finish: Vec<Vec<u64>> = graph.nodes.iter().map(|x| vec![0; x.form.ndev()]).collect();
+    let mut last_use: Vec<Vec<u64>> = finish.clone();
+    let mut aggregated: std::collections::BTreeMap<(usize, usize, Form), u64> = std::collections::BTreeMap::new(); // when the aggregated value is ready on the first destination device
+    let mut buffers: Vec<(usize, u64, u64, u64)> = vec![]; // device, size, alloc, free
+    let traffic = std::cell::Cell::new(0);
+
+    let transfer = |link_available: &mut Vec<u64>, from: usize, to: usize, size: u64, at: u64| -> u64 {
+        let path = &target.paths[from * n + to];
+        if from == to || path.is_empty() {
+            return at
+        }
+        traffic.set(traffic.get() + size);
+        let est = path.iter().fold(at, |max, link| std::cmp::max(max, link_available[*link]));
+        let bandwidth = path.iter().fold(u64::MAX, |min, link| std::cmp::min(min, target.links[*link])).max(1); // links of unknown bandwidth may be 0
+        let eft = est + size / bandwidth + GRPC_LATENCY;
+        for link in path.iter() {
+            link_available[*link] = eft
+        }
+        eft
+    };
+
+    for node_id in graph.topological_order() {
+        let devices = graph.nodes[node_id].form.devices.clone();
+        let form_code = graph.nodes[node_id].form.code();
+        for (replica, device_id) in devices.iter().copied().enumerate() {
+            let mut ready = 0;
+            let mut inputs: Vec<(usize, u64, u64)> = vec![]; // input node, bytes on this device, arrival
+            for (i, (input_id, index, _)) in graph.nodes[node_id].inputs.clone().into_iter().enumerate() {
+                let to = graph.nodes[node_id].input_form(i);
+                let from = graph.nodes[input_id].form.clone();
+                let size = graph.nodes[input_id].get_output(index).get_size();
+                let nfrom = if from.is_part() { from.ndev() as u64 } else { 1 };
+                let conversion = conversions.get(&(input_id, index, &to)).copied();
+                let anchor = derived.get(&(input_id, index, &to));
+
+                let (arrival, bytes, sources): (u64, u64, Vec<usize>) = match conversion {
+                    Some(conversion) if anchor.is_some() => { // the parts are gathered on the first device of the reused form, then copied or split from there
+                        let anchor = anchor.unwrap();
+                        let key = (input_id, index, (*anchor).clone());
+                        let root = anchor.devices[0];
+                        let ready_at = *aggregated.entry(key).or_insert_with(|| {
+                            (0..from.ndev()).map(|j| transfer(&mut link_available, from.devices[j], root, size / nfrom, finish[input_id][j])).max().unwrap_or(0)
+                        });
+                        let bytes = if conversion == Conversion::Split { size / to.ndev() as u64 } else { size };
+                        let source = if conversion == Conversion::Split { root } else { anchor.devices.iter().copied().find(|x| *x == device_id).unwrap_or(root) };
+                        (transfer(&mut link_available, source, device_id, bytes, ready_at), bytes, (0..from.ndev()).collect())
+                    }
+                    Some(Conversion::Identity) => {
+                        (transfer(&mut link_available, from.devices[replica], device_id, size / nfrom, finish[input_id][replica]), size / nfrom, vec![replica])
+                    }
+                    Some(Conversion::Broadcast) => {
+                        let source = from.devices.iter().position(|x| *x == device_id).unwrap_or(0);
+                        (transfer(&mut link_available, from.devices[source], device_id, size, finish[input_id][source]), size, vec![source])
+                    }
+                    Some(Conversion::Split | Conversion::SparseSplit) => {
+                        let part = size / to.ndev() as u64;
+                        (transfer(&mut link_available, from.devices[0], device_id, part, finish[input_id][0]), part, vec![0])
+                    }
+                    Some(Conversion::AllGather) => {
+                        let arrival = (0..from.ndev()).map(|j| transfer(&mut link_available, from.devices[j], device_id, size / nfrom, finish[input_id][j])).max().unwrap_or(0);
+                        (arrival, size, (0..from.ndev()).collect())
+                    }
+                    Some(Conversion::Resplit) => {
+                        let part = size / to.ndev() as u64;
+                        let arrival = (0..from.ndev()).map(|j| transfer(&mut link_available, from.devices[j], device_id, part / nfrom, finish[input_id][j])).max().unwrap_or(0);
+                        (arrival, part, (0..from.ndev()).collect())
+                    }
+                    Some(Conversion::ReduceScatter) => { // this replica sums its slice of every source
+                        let part = graph.nodes[input_id].get_output(index).reduced_size() / to.ndev() as u64;
+                        let arrival = (0..from.ndev()).map(|j| transfer(&mut link_available, from.devices[j], device_id, part, finish[input_id][j])).max().unwrap_or(0);
+                        (arrival, size / to.ndev() as u64, (0..from.ndev()).collect())
+                    }
+                    Some(Conversion::AllToAll) => { // with L pieces in total, this replica gets the pieces [replica * L / m, (replica + 1) * L / m)
+                        let l = (from.ndev() / crate::plan::gcd(from.ndev(), to.ndev()) * to.ndev()) as u64;
+                        let (per_source, per_destination) = (l / from.ndev() as u64, l / to.ndev() as u64);
+                        let mut pieces = std::collections::BTreeMap::new();
+                        for g in replica as u64 * per_destination..(replica as u64 + 1) * per_destination {
+                            *pieces.entry((g / per_source) as usize).or_insert(0) += 1;
+                        }
+                        let arrival = pieces.iter().map(|(&j, &count)| transfer(&mut link_available, from.devices[j], device_id, count * size / l, finish[input_id][j])).max().unwrap_or(0);
+                        (arrival, size / to.ndev() as u64, pieces.keys().copied().collect())
+                    }
+                    _ if from.ndev() <= 1 || from.is_full() => { // provided by the editor without changing the replicas
+                        (transfer(&mut link_available, from.devices[0], device_id, size, finish[input_id][0]), size, vec![0])
+                    }
+                    _ => { // Concat, Sum and the aggregations of the editor. Sums cross links in the dtype of `Tensor::reduction_dtype`
+                        let size = if matches!(conversion, Some(Conversion::Concat | Conversion::SparseConcat)) { size } else { graph.nodes[input_id].get_output(index).reduced_size() };
+                        let all_reduce = conversion.is_none() && from.devices == to.devices;
+                        let root = to.devices[0];
+                        let key = (input_id, index, to.clone());
+                        let ready_at = match aggregated.get(&key) {
+                            Some(x) => *x,
+                            None => {
+                                let x = if all_reduce {
+                                    let order = match graph.options.get("ring_order").map(|x| &x[..]) {
+                                        Some("bandwidth") => ring_order(target, &from.devices),
+                                        _ => (0..from.ndev()).collect()
+                                    };
+                                    let bandwidth = (0..from.ndev()).map(|j| {
+                                        let path = &target.paths[from.devices[order[(j + 1) % from.ndev()]] * n + from.devices[order[j]]];
+                                        path.iter().map(|link| target.links[*link]).min().unwrap_or(u64::MAX)
+                                    }).min().unwrap_or(u64::MAX);
+                                    let start = finish[input_id].iter().copied().max().unwrap_or(0);
+                                    traffic.set(traffic.get() + 2 * (from.ndev() as u64 - 1) * size); // each device sends 2(n-1) chunks of size/n
+                                    start + 2 * (from.ndev() as u64 - 1) * (size / from.ndev() as u64) / bandwidth + GRPC_LATENCY
+                                } else {
+                                    (0..from.ndev()).map(|j| transfer(&mut link_available, from.devices[j], root, size / nfrom, finish[input_id][j])).max().unwrap_or(0)
+                                };
+                                aggregated.insert(key, x);
+                                x
+                            }
+                        };
+                        let arrival = if all_reduce { ready_at } else { transfer(&mut link_available, root, device_id, size, ready_at) };
+                        (arrival, size, (0..from.ndev()).collect())
+                    }
+                };
+
+                ready = std::cmp::max(ready, arrival);
+                inputs.push((input_id, bytes, arrival));
+                for source in sources {
+                    last_use[input_id][source] = std::cmp::max(last_use[input_id][source], arrival)
+                }
+            }
+            for control in graph.nodes[node_id].controls.clone() {
+                ready = std::cmp::max(ready, finish[control].iter().copied().max().unwrap_or(0))
+            }
+
+            let mut probe = NodeDef::new();
+            probe.name = graph.nodes[node_id].raw_node.name.clone();
+            probe.op = graph.nodes[node_id].raw_node.op.clone();
+            probe.attr.insert("_tge_origin".into(), crate::proto::attr_value::AttrValue::new().apply(|x| x.set_s(probe.name.as_bytes().to_vec())));
+            probe.attr.insert("_tge_form".into(), crate::proto::attr_value::AttrValue::new().apply(|x| x.set_s(form_code.as_bytes().to_vec())));
+            let start = std::cmp::max(ready, gpu_available[device_id]);
+            let eft = start + profiler.profile(&probe, device_id).unwrap_or(0);
+            gpu_available[device_id] = eft;
+            finish[node_id][replica] = eft;
+            last_use[node_id][replica] = eft;
+
+            for (input_id, bytes, arrival) in inputs {
+                if !graph.nodes[input_id].form.devices.contains(&device_id) { // a received copy lives until the consumer finishes
+                    buffers.push((device_id, bytes, arrival, eft))
+                }
+            }
+        }
+    }
+
+    // outputs live from their production to the last transfer or use. Variables are counted separately as they persist
+    for node_id in 0..graph.nodes.len() {
+        if graph.nodes[node_id].is_variable() {
+            continue
+        }
+        let form = graph.nodes[node_id].form.clone();
+        let nparts = if form.is_part() { form.ndev() as u64 } else { 1 };
+        let size: u64 = (0..graph.nodes[node_id].outputs.len()).map(|i| graph.nodes[node_id].get_output(i).get_size()).sum::<u64>() / nparts;
+        for (replica, device_id) in form.devices.iter().enumerate() {
+            buffers.push((*device_id, size, finish[node_id][replica], last_use[node_id][replica]))
+        }
+    }
+
+    let mut memory = variable_memory(graph, n);
+    for (device_id, peak) in memory.iter_mut().enumerate() {
+        let mut events: Vec<(u64, i64)> = buffers.iter().filter(|x| x.0 == device_id).flat_map(|(_, size, alloc, free)| vec![(*alloc, *size as i64), (*free + 1, -(*size as i64))]).collect();
+        events.sort_unstable();
+        let (mut current, mut max) = (0i64, 0i64);
+        for (_, delta) in events {
+            current += delta;
+            max = std::cmp::max(max, current)
+        }
+        *peak += max as u64
+    }
+
+    Estimate { time: gpu_available.iter().copied().max().unwrap_or(0), memory, traffic: traffic.get() }
+}
+
+/// an input whose `_tge_input_sizes` annotation disagrees with the size observed at runtime
+#[derive(Debug, Clone)]
+pub struct SizeMismatch {
+    pub node: String,
+    pub input: String,
+    pub annotated: u64,
+    pub actual: u64
+}
+
+/// parse a runtime shape dump into the bytes of each tensor. Each line is `<node>:<output> <dtype> [<dim>,...]`, where dtype is the numeric
+/// value of DataType, e.g. `dense/MatMul/replica_0:0 1 [32,128]`. Tensors of variable-length dtypes are skipped.
+pub fn parse_shape_dump(dump: &str) -> Result<std::collections::BTreeMap<String, u64>, String> {
+    let mut sizes = std::collections::BTreeMap::new();
+    for (lineno, line) in dump.lines().enumerate().filter(|(_, x)| !x.trim().is_empty()) {
+        let err = || format!("line {}: malformed shape record `{}`", lineno + 1, line);
+        let mut segs = line.split_ascii_whitespace();
+        let (name, dtype, shape) = match (segs.next(), segs.next(), segs.next(), segs.next()) {
+            (Some(name), Some(dtype), Some(shape), None) => (name, dtype, shape),
+            _ => return Err(err())
+        };
+        let dtype = dtype.parse().ok().and_then(<crate::proto::types::DataType as protobuf::ProtobufEnum>::from_i32).ok_or_else(err)?;
+        let dims = shape.strip_prefix('[').and_then(|x| x.strip_suffix(']')).ok_or_else(err)?;
+        let nelem = dims.split(',').filter(|x| !x.is_empty()).map(|x| x.trim().parse::<u64>().map_err(|_| err())).product::<Result<u64, _>>()?;
+        match dtype_size(dtype) {
+            Some(size) => { sizes.insert(name.to_string(), nelem * size); }
+            None => debug!("skip {} of variable-length type {:?}", name, dtype)
+        }
+    }
+    Ok(sizes)
+}
+
+/// compare the `_tge_input_sizes` written by the compiler with the sizes observed at runtime (see `parse_shape_dump`). Inputs that are
+/// annotated but not in the dump are ignored. An input is flagged if the relative error exceeds `tolerance`. Sorted by absolute error, largest first.
+pub fn check_input_sizes(target: &Target, actual: &std::collections::BTreeMap<String, u64>, tolerance: f64) -> Vec<SizeMismatch> {
+    let mut mismatches = vec![];
+    let mut nchecked = 0;
+    for node in target.pb.node.iter() {
+        let sizes = match node.attr.get("_tge_input_sizes") {
+            Some(x) => &x.get_list().i,
+            None => continue
+        };
+        for (input, annotated) in node.input.iter().zip(sizes.iter()) {
+            let parsed = crate::naming::Input::parse(input);
+            if parsed.control || *annotated <= 0 { // 0 means not annotated
+                continue
+            }
+            let actual = match actual.get(&format!("{}:{}", parsed.name, parsed.index)) {
+                Some(x) => *x,
+                None => continue
+            };
+            nchecked += 1;
+            let annotated = *annotated as u64;
+            if (annotated as f64 - actual as f64).abs() > tolerance * actual.max(1) as f64 {
+                mismatches.push(SizeMismatch { node: node.name.clone(), input: input.clone(), annotated, actual })
+            }
+        }
+    }
+    mismatches.sort_by_key(|x| std::cmp::Reverse((x.annotated as i64 - x.actual as i64).abs()));
+    info!("checked {} annotated input sizes, {} mismatches", nchecked, mismatches.len());
+    mismatches
+}
@@ -0,0 +1,116 @@
+use oh_my_rust::*;
+use std::collections::BTreeMap;
+use crate::misc::Target;
+
+/// the latency of a message in the time unit of the link bandwidths, the same as `simulator::GRPC_LATENCY`
+const LATENCY: u64 = 12;
+
+/// per-tensor choice among the AddN (0), collective (1), ring (2) and NCCL (3) aggregation methods, set by the option `backend_selection`.
+/// `thresholds` sums tensors below `backend_small_bytes` (64KB by default) with AddN, uses NCCL within a machine, and across machines the
+/// collective ops (if `collective_backend` is `collective`) below `backend_large_bytes` (4MB by default) and the ring otherwise.
+/// `cost` picks the method with the lowest `estimate_time` on the devices and links of the target.
+/// It also decides how Full tensors are replicated to devices without a replica, see `select_broadcast`.
+#[derive(Debug, Clone)]
+pub enum BackendSelection {
+    Thresholds { small: u64, large: u64, fanout: usize },
+    Cost
+}
+
+impl BackendSelection {
+    pub fn from_options(options: &BTreeMap<String, String>) -> Option<Self> {
+        let get = |name: &str, default: u64| options.get(name).map(|x| x.parse().unwrap_or_else(|_| panic!("option {} should be a number", name))).unwrap_or(default);
+        match options.get("backend_selection").map(|x| &x[..]) {
+            None | Some("none") => None,
+            Some("thresholds") => Some(BackendSelection::Thresholds {
+                small: get("backend_small_bytes", 1 << 16),
+                large: get("backend_large_bytes", 1 << 22),
+                fanout: get("backend_broadcast_fanout", 4) as _
+            }),
+            Some("cost") => Some(BackendSelection::Cost),
+            Some(x) => panic!("unknown backend_selection {}, expecting thresholds, cost or none", x)
+        }
+    }
+
+    /// the method for summing a tensor of `size` bytes whose replicas are on `devices`. Only the methods 0 to 3 of the strategy are replaced;
+    /// the others (sync, hierarchical, the trees and the CPU methods) are deliberate choices and are kept.
+    pub fn select(&self, method: u8, size: u64, devices: &[usize], target: &Target, options: &BTreeMap<String, String>) -> u8 {
+        if method > 3 {
+            return method
+        }
+        let single_machine = target.machines().iter().any(|machine| devices.iter().all(|d| machine.contains(d)));
+        let collective = options.get("collective_backend").map(|x| x == "collective").unwrap_or(false);
+        match self {
+            BackendSelection::Thresholds { small, .. } if size < *small => 0,
+            BackendSelection::Thresholds { .. } if single_machine => 3,
+            BackendSelection::Thresholds { large, .. } if collective && size < *large => 1,
+            BackendSelection::Thresholds { .. } => 2,
+            BackendSelection::Cost => [0, 1, 2, 3].iter().copied()
+                .filter(|m| match m { 1 => collective, 3 => single_machine, _ => true })
+                .min_by_key(|m| estimate_time(*m, size, devices, target)).unwrap()
+        }
+    }
+
+    /// whether to replicate a Full tensor of `size` bytes from `source` to the devices without a replica (`destinations`) with a
+    /// NcclBroadcast instead of letting each of them read the source. NCCL only runs within a machine and not with the collective backend.
+    /// `thresholds` broadcasts tensors of at least `backend_small_bytes` to at least `backend_broadcast_fanout` (4 by default) devices,
+    /// `cost` compares `estimate_broadcast_time`.
+    pub fn select_broadcast(&self, size: u64, source: usize, destinations: &[usize], target: &Target, options: &BTreeMap<String, String>) -> bool {
+        let single_machine = target.machines().iter().any(|machine| machine.contains(&source) && destinations.iter().all(|d| machine.contains(d)));
+        let collective = options.get("collective_backend").map(|x| x == "collective").unwrap_or(false);
+        if !single_machine || collective || destinations.is_empty() {
+            return false
+        }
+        match self {
+            BackendSelection::Thresholds { small, fanout, .. } => size >= *small && destinations.len() >= *fanout,
+            BackendSelection::Cost => estimate_broadcast_time(true, size, source, destinations, target) < estimate_broadcast_time(false, size, source, destinations, target)
+        }
+    }
+}
+
+/// a rough time of summing `size` bytes replicated on `devices` with `method` and leaving the sum on all of them. AddN sends every replica
+/// to the first device and the sum back, through the links of the first device. The ring runs 2(n-1) rounds of graph ops, each sending a
+/// 1/n chunk over the slowest hop of the ring. The collective ops and NCCL run the same rounds inside one kernel; NCCL uses the profiled
+/// model of the device set if the target has one.
+pub fn estimate_time(method: u8, size: u64, devices: &[usize], target: &Target) -> u64 {
+    let n = devices.len() as u64;
+    let ndev = target.ndev();
+    let bandwidth = |from: usize, to: usize| target.paths[from * ndev + to].iter().map(|link| target.links[*link]).min().unwrap_or(u64::MAX);
+    if n < 2 {
+        return 0
+    }
+
+    let root = devices[0];
+    let ring = (0..devices.len()).map(|i| bandwidth(devices[i], devices[(i + 1) % devices.len()])).min().unwrap_or(u64::MAX);
+    let rounds = 2 * (n - 1) * (size / n / ring);
+    match method {
+        0 => devices.iter().filter(|d| **d != root).map(|d| size / bandwidth(*d, root) + size / bandwidth(root, *d)).sum::<u64>() + 2 * LATENCY,
+        2 => rounds + 2 * (n - 1) * LATENCY,
+        3 => {
+            let mut names: Vec<&str> = devices.iter().map(|d| &target.devices[*d][..]).collect();
+            names.sort_unstable();
+            names.dedup();
+            match target.nccls.get(&names.join(",")) {
+                Some(model) => {
+                    let kb = (size >> 10) as f64;
+                    (model[0] * kb + model[1]).max(model[2] * kb + model[3]) as u64
+                }
+                None => rounds + LATENCY
+            }
+        }
+        _ => rounds + LATENCY
+    }
+}
+
+/// a rough time of copying `size` bytes from `source` to `destinations`. Without NCCL every destination reads the source, and the reads
+/// share the copy engine of the source. NCCL pipelines the tensor along a chain of the devices, so it takes the slowest hop of the chain
+/// plus a latency per device.
+pub fn estimate_broadcast_time(nccl: bool, size: u64, source: usize, destinations: &[usize], target: &Target) -> u64 {
+    let ndev = target.ndev();
+    let bandwidth = |from: usize, to: usize| target.paths[from * ndev + to].iter().map(|link| target.links[*link]).min().unwrap_or(u64::MAX);
+    if nccl {
+        let chain = std::iter::once(&source).chain(destinations).zip(destinations).map(|(a, b)| bandwidth(*a, *b)).min().unwrap_or(u64::MAX);
+        size / chain + destinations.len() as u64 * LATENCY
+    } else {
+        destinations.iter().map(|d| size / bandwidth(source, *d)).sum::<u64>() + LATENCY
+    }
+}
@@ -0,0 +1,48 @@
+use oh_my_rust::*;
+use crate::graph::Graph;
+use crate::misc::Target;
+use crate::editor::Strategy;
+use crate::budget::Budget;
+
+#[derive(Debug, Clone)]
+pub enum Failure {
+    OverBudget(String),
+    Unroutable(String),
+    NoKernel(String)
+}
+
+impl Failure {
+    /// the status code returned through FFI. 0 means success
+    pub fn code(&self) -> u32 {
+        match self {
+            Failure::OverBudget(_) => 1,
+            Failure::Unroutable(_) => 2,
+            Failure::NoKernel(_) => 3
+        }
+    }
+}
+
+/// compile an edited graph and check the result against the kernels, the topology and the budget
+pub fn compile_checked(graph: &mut Graph, target: &mut Target) -> Result<(), Failure> {
+    crate::kernels::check_placement(graph, target).map_err(Failure::NoKernel)?;
+    graph.compile(target);
+    graph.route_transfers(target).map_err(Failure::Unroutable)?;
+    Budget::from_options(&graph.options).check(target).map_err(Failure::OverBudget)
+}
+
+/// compile the same graph against many (target, strategy) pairs, reusing the parsed graph and op registry.
+/// `on_done` is called with the index of each job as soon as it finishes, so callers can stream results out.
+/// Jobs run one after another since a Graph is not thread-safe.
+pub fn compile_batch(graph: &mut Graph, jobs: &mut [(&mut Target, Strategy)], mut on_done: impl FnMut(usize, &mut Target, Result<(), Failure>)) {
+    task!("compiling {} jobs...", jobs.len());
+    for (i, (target, strategy)) in jobs.iter_mut().enumerate() {
+        crate::editor::reset(graph);
+        let strategy = strategy.iter().map(|(name, s)| (&name[..], s.clone())).collect();
+        crate::editor::edit(graph, target, &strategy);
+        let result = compile_checked(graph, target);
+        if let Err(e) = &result {
+            warn!("job {}: {:?}", i, e)
+        }
+        on_done(i, target, result)
+    }
+}
@@ -0,0 +1,68 @@
+use oh_my_rust::*;
+use std::collections::BTreeMap;
+use crate::misc::Target;
+
+/// limits on the size of the compiled graph, set by the options `max_nodes`, `max_aux_nodes_per_tensor` and `max_control_edges`.
+/// Huge graphs are slow to import and may exceed what the TF runtime handles gracefully.
+#[derive(Debug, Default, Clone)]
+pub struct Budget {
+    pub max_nodes: Option<usize>,
+    pub max_aux_nodes_per_tensor: Option<usize>,
+    pub max_control_edges: Option<usize>
+}
+
+impl Budget {
+    pub fn from_options(options: &BTreeMap<String, String>) -> Self {
+        let get = |name: &str| options.get(name).map(|x| x.parse().unwrap_or_else(|_| panic!("option {} should be a number", name)));
+        Budget {
+            max_nodes: get("max_nodes"),
+            max_aux_nodes_per_tensor: get("max_aux_nodes_per_tensor"),
+            max_control_edges: get("max_control_edges")
+        }
+    }
+
+    /// pick the aggregation method for a tensor replicated `n` times: the requested one if it fits in the per-tensor budget, otherwise AddN (method 0)
+    pub fn fit_method(&self, method: u8, n: usize, name: &str) -> u8 {
+        match self.max_aux_nodes_per_tensor {
+            Some(max) if estimate_aux_nodes(method, n) > max => {
+                warn!("aggregating {} with method {} needs about {} aux nodes, exceeding the budget of {}. Fall back to AddN", name, method, estimate_aux_nodes(method, n), max);
+                0
+            }
+            _ => method
+        }
+    }
+
+    pub fn check(&self, target: &Target) -> Result<(), String> {
+        let nodes = target.pb.node.len();
+        if let Some(max) = self.max_nodes {
+            if nodes > max {
+                return Err(format!("the compiled graph has {} nodes, exceeding the budget of {}", nodes, max))
+            }
+        }
+
+        let control_edges = target.pb.node.iter().map(|x| x.input.iter().filter(|i| i.starts_with('^')).count()).sum::<usize>();
+        if let Some(max) = self.max_control_edges {
+            if control_edges > max {
+                return Err(format!("the compiled graph has {} control edges, exceeding the budget of {}", control_edges, max))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// number of aux nodes generated by each aggregation method for a tensor on `n` devices. See `editor::edit` for the methods.
+pub fn estimate_aux_nodes(method: u8, n: usize) -> usize {
+    match method {
+        1 => 2 * n, // local AddN and CollectiveReduce
+        2 => 8 * n + 2 * n * (n - 1), // shape, flatten, split, concat, reshape, plus n-1 rounds of add and identity
+        3 => n, // NcclAllReduce
+        4 => 2 * n + 4, // local step reads and accumulator applies, plus accumulator, take and scaling
+        5 => 10 * n, // AddN, NcclAllReduce and Identity per device, plus the ring across machines which is usually much shorter
+        6 => 2 * n, // AddN and Identity per device
+        7 => 13 * n, // local AddN, shape, flatten, split, concat and reshape per device, plus AddN and Identity per device in each tree
+        8 => 3 * n + 1, // AddN and Identity per host CPU, the total across hosts, and Identity per device
+        9 => 10 * n, // AddN per machine and Identity per device, plus the ring across the host CPUs which is usually much shorter
+        _ => 1 // AddN
+    }
+}
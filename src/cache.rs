@@ -0,0 +1,84 @@
+use oh_my_rust::*;
+use protobuf::Message;
+use std::collections::{BTreeMap, HashMap};
+use crate::proto::graph::GraphDef;
+use crate::proto::node_def::NodeDef;
+use crate::spill::{SpillFile, Spilled};
+
+/// NodeDefs emitted by previous compilations of the same graph, content-addressed by the node (or tensor) and the forms involved,
+/// so a strategy search that changes the forms of a few nodes only regenerates the affected replicas and conversions.
+/// Enabled by the option `subgraph_cache`. Entries are dropped whenever the options or the devices change, since they affect what is emitted.
+/// With the option `spill_dir`, the NodeDefs are kept in a scratch file instead of memory (see `spill`).
+#[derive(Debug, Default)]
+pub struct SubgraphCache {
+    options: BTreeMap<String, String>,
+    devices: Box<[String]>,
+    replicas: HashMap<String, Stored>, // see `Node::cache_key`
+    transfers: HashMap<String, (Box<[String]>, Stored)>, // see `plan::emit_transfers`
+    spill: Option<SpillFile>,
+    pub hits: usize,
+    pub misses: usize
+}
+
+#[derive(Debug)]
+enum Stored {
+    Memory(Vec<NodeDef>),
+    Spilled(Spilled)
+}
+
+impl SubgraphCache {
+    /// drop all entries if the options or devices differ from those of the cached compilations
+    pub fn validate(&mut self, options: &BTreeMap<String, String>, devices: &[String]) {
+        if &self.options != options || &*self.devices != devices {
+            if !self.replicas.is_empty() || !self.transfers.is_empty() {
+                info!("options or devices changed, dropping {} cached subgraphs", self.replicas.len() + self.transfers.len());
+            }
+            self.replicas.clear();
+            self.transfers.clear();
+            self.spill = options.get("spill_dir").map(|dir| SpillFile::create(dir).unwrap_or_else(|e| panic!("cannot create a scratch file in {}: {}", dir, e)));
+            self.options = options.clone();
+            self.devices = devices.into()
+        }
+    }
+
+    pub fn replicas(&mut self, key: &str) -> Option<Vec<NodeDef>> {
+        let stored = self.replicas.get(key)?;
+        Some(load(&mut self.spill, stored))
+    }
+
+    pub fn insert_replicas(&mut self, key: String, nodes: Vec<NodeDef>) {
+        let stored = store(&mut self.spill, nodes);
+        self.replicas.insert(key, stored);
+    }
+
+    /// the names of a conversion in the destination form and the NodeDefs that produce them
+    pub fn transfer(&mut self, key: &str) -> Option<(Box<[String]>, Vec<NodeDef>)> {
+        let (names, stored) = self.transfers.get(key)?;
+        Some((names.clone(), load(&mut self.spill, stored)))
+    }
+
+    pub fn insert_transfer(&mut self, key: String, names: Box<[String]>, nodes: Vec<NodeDef>) {
+        let stored = store(&mut self.spill, nodes);
+        self.transfers.insert(key, (names, stored));
+    }
+}
+
+fn store(spill: &mut Option<SpillFile>, nodes: Vec<NodeDef>) -> Stored {
+    match spill {
+        Some(file) => {
+            let bytes = GraphDef::new().apply(|x| x.node = nodes.into()).write_to_bytes().unwrap();
+            Stored::Spilled(file.write(&bytes).unwrap_or_else(|e| panic!("cannot write to the scratch file: {}", e)))
+        }
+        None => Stored::Memory(nodes)
+    }
+}
+
+fn load(spill: &mut Option<SpillFile>, stored: &Stored) -> Vec<NodeDef> {
+    match stored {
+        Stored::Memory(nodes) => nodes.clone(),
+        Stored::Spilled(at) => {
+            let bytes = spill.as_mut().expect("BUG: spilled cache entry without a scratch file").read(*at).unwrap_or_else(|e| panic!("cannot read the scratch file: {}", e));
+            protobuf::parse_from_bytes::<GraphDef>(&bytes).unwrap().node.into()
+        }
+    }
+}
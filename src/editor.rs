@@ -3,22 +3,50 @@ use std::convert::TryInto;
 use crate::graph::*;
 use std::collections::{BTreeSet, BTreeMap};
 use crate::misc::Target;
+use crate::budget::Budget;
+use crate::backend::BackendSelection;
+
+/// a strategy maps node names to (devices, aggregation_method), the same as the argument of `edit`
+pub type Strategy = BTreeMap<String, (Vec<usize>, u8)>;
+
+type AllReduce = fn(&mut Tensor, &Form, &Form, &mut Target) -> Box<[String]>;
+type FusionGroups = BTreeMap<(Form, Form, usize, i32), Vec<(usize, usize)>>; // (from, to, all-reduce method, dtype) => gradients
 
 pub fn edit(graph: &mut Graph, target: &mut Target, strategy: &BTreeMap<&str, (Vec<usize>, u8)>) { // devices (the same definition of form), aggregation_method
-    let allow_split_input = graph.options.contains_key("replace_placeholder");
+    graph.replica_scheme = crate::naming::ReplicaScheme::from_options(&graph.options);
+    let allow_split_input = graph.options.contains_key("replace_placeholder") || graph.options.contains_key("placeholder_mode");
+    let budget = Budget::from_options(&graph.options);
+    let backend_selection = BackendSelection::from_options(&graph.options);
+    let local_sgd = graph.options.contains_key("local_sgd_period");
+    let async_sgd = graph.options.get("async_sgd").map(|x| x == "True").unwrap_or(false);
+    let horovod = graph.options.get("collective_backend").map(|x| x == "horovod").unwrap_or(false);
+    let fusion_bucket_size: Option<u64> = graph.options.get("fusion_bucket_size").map(|x| x.parse().expect("fusion_bucket_size should be a number"));
+    let sync_required: Option<usize> = graph.options.get("replicas_to_aggregate").map(|x| x.parse().expect("replicas_to_aggregate should be a number"));
+    let backup_required: Option<usize> = graph.options.get("backup_required").map(|x| x.parse().expect("backup_required should be a number"));
+    let reduction = Reduction::of(&graph.options);
+    let forms = mesh_forms(graph, target).apply(|x| x.extend(split_forms(graph)));
 
     // do replications as the user requested
-    for node in graph.nodes.iter_mut() {
+    for (node_id, node) in graph.nodes.iter_mut().enumerate() {
         let s = strategy.get(&node.raw_node.name[..]).cloned();
+        let s = match forms.get(&node.raw_node.name) {
+            Some(form) => Some((form.devices.clone(), s.map(|x| x.1).unwrap_or(0))),
+            None => s
+        };
 
         match &node.raw_node.op[..] {
+            _ if node.graph().initializers.contains_key(&node_id) => {} // placed later along with the variable
             // TODO: RandomUniform, NoOp
             "NoOp" => node.put_on_devices(&[0]), // ignore decision and put on device 0
-            "Placeholder" | "IteratorGetNext" if !allow_split_input => node.put_on_devices(&[0]),
-            "Assign" => { // ignore decision and put along with the variable
+            _ if node.is_input() && !allow_split_input => node.put_on_devices(&[0]),
+            _ if node.is_assign() => { // ignore decision and put along with the variable
                 let var = &node.graph().nodes[node.inputs[0].0];
                 node.put_on_devices(&var.form.devices);
             }
+            _ if node.is_stateful() => match s { // ignore replication and keep only the first device
+                Some((devices, _)) if !devices.is_empty() => node.put_on_devices(&devices[..1]),
+                _ => node.put_on_devices(&[0])
+            }
             _ => match s {
                 Some((devices, _)) => node.put_on_devices(&devices),
                 None => node.put_on_devices(&(0..target.ndev()).collect::<Vec<_>>()),
@@ -26,26 +54,39 @@ pub fn edit(graph: &mut Graph, target: &mut Target, strategy: &BTreeMap<&str, (V
         }
     }
 
+    // initializers run only once, on the first device of the variable
+    for (node_id, var_id) in graph.initializers.iter() {
+        let device = graph.nodes[*var_id].form.devices[0];
+        graph.nodes[*node_id].put_on_devices(&[device]);
+    }
+
     // only split if the whole group is replicated the same times. Otherwise go cache (default).
+    // With `async_sgd` the variables keep a single copy that every replica updates (see `async_devices`), so they do not count.
     let mut visited_groups = BTreeSet::new();
     for node in graph.nodes.iter_mut() {
-        if node.group.is_some() && !visited_groups.contains(&node.group.as_ref().map(|x| x.as_ptr() as *const _).unwrap()) {
-            visited_groups.insert(node.group.as_ref().map(|x| x.as_ptr() as *const _).unwrap());
+        if let Some(group_ptr) = node.group.as_ref().map(|x| x.as_ptr() as *const _).filter(|x| !visited_groups.contains(x)) {
+            visited_groups.insert(group_ptr);
             if graph.options.get("log_groups").map(|x| x == "True").unwrap_or(false) {
                  info!("group {}: {:?}", visited_groups.len(), node.group.as_ref().unwrap().borrow().iter().map(|x| node.graph().nodes[*x].raw_node.name.clone()).collect::<Vec<_>>());
             }
             let group = &node.group.as_ref().unwrap().borrow();
-            let n = node.form.ndev();
-            if n > 1 && group.iter().copied().all(|x| node.graph().nodes[x].form.ndev() == n) {
+            let counts: BTreeSet<usize> = group.iter().map(|x| &node.graph().nodes[*x]).filter(|x| !(async_sgd && x.is_variable())).map(|x| x.form.ndev()).collect();
+            if counts.len() == 1 && counts.iter().all(|n| *n > 1) {
                 for member in group.iter() {
                     let member = &mut node.graph().nodes[*member];
+                    if !member.is_splittable() {
+                        continue
+                    }
                     if member.inputs.is_empty() && member.is_input() {
-                        member.form.kind = FormKind::Part;
+                        if !member.get_output(0).has_flag(Tensor::IS_SPARSE) { // the rows of fed SparseTensors are split by `Tensor::sparse_split` instead
+                            member.form.kind = FormKind::Part;
+                        }
                         continue
                     }
                     for (id, index, kind) in member.inputs.iter_mut() {
                         let input = node.graph().nodes[*id].get_output(*index);
-                        if input.has_flag(Tensor::IS_FROM_INPUT) && (group.contains(id) || input.has_flag(Tensor::IS_BATCHED)) {
+                        // the members of a SparseTensor are split together, including those that do not depend on the input (see `Tensor::IS_SPARSE`)
+                        if input.has_flag(Tensor::IS_SPARSE) || (input.has_flag(Tensor::IS_FROM_INPUT) && (group.contains(id) || input.has_flag(Tensor::IS_BATCHED))) {
                             *kind = FormKind::Part;
                             member.form.kind = FormKind::Part;
                         }
@@ -55,25 +96,79 @@ pub fn edit(graph: &mut Graph, target: &mut Target, strategy: &BTreeMap<&str, (V
         }
     }
 
+    audit_multi_output_forms(graph);
+    keep_ragged_whole(graph);
+    keep_unsplit_whole(graph, &forms);
+    split_along_axes(graph, &forms);
+
+    let mut fusible: Vec<((usize, usize), Form, Form, AllReduce)> = vec![]; // small gradients whose all-reduce is deferred to the fusion below
+
     for node in graph.nodes.iter_mut() {
         match &node.raw_node.op[..] {
             n if apply_nodes_dict(n).is_some() => {
                 node.form.kind = FormKind::Full;
                 node.inputs[apply_nodes_dict(n).unwrap()].2 = FormKind::Full;
+                if let Some(devices) = async_sgd.then(|| async_devices(node)).flatten() {
+                    node.form.devices = devices;
+                    node.inputs[apply_nodes_dict(n).unwrap()].2 = FormKind::Part;
+                    continue
+                }
                 let (id, index, _) = &node.inputs[apply_nodes_dict(n).unwrap()];
                 if node.replicated().unwrap() {
                     let s = strategy.get(&node.raw_node.name[..]).cloned();
                     let grad = &mut node.graph().nodes[*id].get_output(*index);
                     if grad.node().form.is_part() { // is_part implies ndev > 1
+                        let s = match &backend_selection {
+                            Some(selection) if grad.node().form.devices == node.form.devices => {
+                                let (devices, m) = s.unwrap_or_else(|| (node.form.devices.clone(), 0));
+                                let selected = selection.select(m, grad.reduced_size(), &node.form.devices, target, &node.graph().options);
+                                if selected != m {
+                                    debug!("aggregating {} with method {} instead of {}", grad.original_name(), selected, m);
+                                }
+                                Some((devices, selected))
+                            }
+                            _ => s
+                        };
+                        let s = s.map(|(devices, m)| (devices, budget.fit_method(m, node.form.ndev(), &node.raw_node.name)));
                         let full = match s {
-                            Some((_, m @ 1..=3)) if grad.node().form.devices == node.form.devices => match m {
-                                1 => grad.all_reduce_sum_collective(&grad.node().form, &node.form, target),
-                                2 => grad.all_reduce_sum_ring(&grad.node().form, &node.form, target),
-                                3 => grad.all_reduce_sum_nccl(&grad.node().form, &node.form, target),
-                                _ => unreachable!()
-                            },
+                            _ if local_sgd && grad.node().form.devices == node.form.devices => { // each replica applies its own gradient, see `Graph::add_periodic_averaging`
+                                grad.source_names(&grad.node().form)
+                            }
+                            // the sync and backup methods keep their accumulators, and only sums can be compressed
+                            _ if grad.compressed_sum().is_some() && backup_required.is_none() && !matches!(s, Some((_, 4))) && reduction.combining() == Reduction::Sum => {
+                                grad.reduce_gradient(&grad.node().form, &node.form, target, grad.compressed_sum().unwrap())
+                            }
+                            Some((_, m @ (1..=3 | 5..=9))) if grad.node().form.devices == node.form.devices => {
+                                let all_reduce: AllReduce = match m {
+                                    _ if horovod && target.covers_all_machines(&node.form.devices) => Tensor::all_reduce_horovod,
+                                    1 => Tensor::all_reduce_sum_collective,
+                                    2 => Tensor::all_reduce_sum_ring,
+                                    3 => Tensor::all_reduce_sum_nccl,
+                                    5 => Tensor::all_reduce_hierarchical,
+                                    6 => Tensor::all_reduce_tree,
+                                    7 => Tensor::all_reduce_double_tree,
+                                    8 => Tensor::all_reduce_cpu_staged,
+                                    9 => Tensor::all_reduce_cpu_ring,
+                                    _ => unreachable!()
+                                };
+                                if fusion_bucket_size.map(|x| grad.get_size() < x).unwrap_or(false) && !grad.get_shape().is_empty() {
+                                    fusible.push(((*id, *index), grad.node().form.clone(), node.form.clone(), all_reduce));
+                                    continue
+                                }
+                                grad.reduce_gradient(&grad.node().form, &node.form, target, |t, from, to, target| {
+                                    t.reduce_with_cast(from, to, target, |t, from, to, target| t.reduce_chunked(from, to, target, all_reduce))
+                                })
+                            }
                             _ => {
-                                let x = grad.aggregate_sum(&grad.node().form, &node.form.clone().apply(|x| x.devices.truncate(1)), target);
+                                let to = node.form.clone().apply(|x| x.devices.truncate(1));
+                                let x = match backup_required {
+                                    _ if matches!(s, Some((_, 4))) => {
+                                        let required = sync_required.unwrap_or_else(|| grad.node().form.ndev());
+                                        grad.reduce_gradient(&grad.node().form, &to, target, |t, from, to, target| t.reduce_with_cast(from, to, target, |t, from, to, target| t.aggregate_sum_sync(from, to, target, required)))
+                                    }
+                                    Some(required) => grad.reduce_gradient(&grad.node().form, &to, target, |t, from, to, target| t.reduce_with_cast(from, to, target, |t, from, to, target| t.aggregate_sum_backup(from, to, target, required))),
+                                    None => grad.reduce_gradient(&grad.node().form, &to, target, |t, from, to, target| t.reduce_with_cast(from, to, target, Tensor::aggregate_sum))
+                                };
                                 if node.form.ndev() > 1 {
                                     (0..node.form.ndev()).map(|_| x[0].clone()).collect()
                                 } else {
@@ -134,21 +229,221 @@ pub fn edit(graph: &mut Graph, target: &mut Target, strategy: &BTreeMap<&str, (V
             _ => {}
         }
     }
+
+    if let Some(bucket_size) = fusion_bucket_size {
+        fuse_all_reduces(graph, target, fusible, bucket_size)
+    }
+}
+
+/// all-reduce the deferred small gradients in buckets of up to `bucket_size` bytes (option `fusion_bucket_size`), see `Tensor::reduce_fused`.
+/// Only gradients with the same forms, dtype and all-reduce method share a bucket, and buckets are filled in graph order. Gradients that
+/// are summed in another dtype (see `Tensor::reduction_dtype`, e.g. with the option `compression` = `fp16`) are casted per bucket.
+fn fuse_all_reduces(graph: &mut Graph, target: &mut Target, fusible: Vec<((usize, usize), Form, Form, AllReduce)>, bucket_size: u64) {
+    let mut groups: FusionGroups = BTreeMap::new();
+    let mut methods = BTreeMap::new();
+    for ((id, index), from, to, f) in fusible {
+        let dtype = graph.nodes[id].get_output(index).dtype().get_field_type() as i32;
+        methods.insert(f as usize, f);
+        let group = groups.entry((from, to, f as usize, dtype)).or_default();
+        if !group.contains(&(id, index)) {
+            group.push((id, index))
+        }
+    }
+
+    for ((from, to, f, _), members) in groups {
+        let f = methods[&f];
+        let mut buckets: Vec<(Vec<(usize, usize)>, u64)> = vec![];
+        for (id, index) in members {
+            let size = graph.nodes[id].get_output(index).get_size();
+            match buckets.last_mut() {
+                Some((bucket, total)) if *total + size <= bucket_size => {
+                    bucket.push((id, index));
+                    *total += size
+                }
+                _ => buckets.push((vec![(id, index)], size))
+            }
+        }
+
+        for (bucket, _) in buckets {
+            let (id, index) = bucket[0];
+            let carrier = graph.nodes[id].graph().nodes[id].get_output(index);
+            if bucket.len() == 1 {
+                let full = carrier.reduce_gradient(&from, &to, target, |t, from, to, target| {
+                    t.reduce_with_cast(from, to, target, |t, from, to, target| t.reduce_chunked(from, to, target, f))
+                });
+                carrier.forms.insert(to.clone(), full);
+                continue
+            }
+            let others: Vec<&Tensor> = bucket[1..].iter().map(|(id, index)| &*graph.nodes[*id].graph().nodes[*id].get_output(*index)).collect();
+            let reduction = Reduction::of(&graph.options);
+            carrier.reduction = reduction.combining(); // the bucket is reduced by the aux nodes of the carrier
+            let results = carrier.reduce_fused(&others, &from, &to, target, f);
+            carrier.reduction = Reduction::Sum;
+            for ((id, index), full) in bucket.into_iter().zip(results) {
+                let member = graph.nodes[id].get_output(index);
+                let full = member.finish_reduction(reduction, &from, &to, full, target);
+                member.forms.insert(to.clone(), full);
+            }
+        }
+    }
+}
+
+/// asynchronous SGD (option `async_sgd`): instead of being aggregated, the gradient of each replica is applied on its own to the shared variable.
+/// The optimizer op gets one replica per gradient replica, all on the device of the variable since TF colocates them with the variable anyway,
+/// and its gradient input is marked Part so replica i takes the gradient of replica i. Returns these devices, or None if the gradient is not
+/// split or the variable or an optimizer state has several copies (e.g. the default strategy mirrors them; `strategy::parameter_server`
+/// does not), in which case it is aggregated as usual. See `Graph::add_async_barriers` for bounding the staleness.
+fn async_devices(node: &Node) -> Option<Vec<usize>> {
+    let (grad_index, states) = optimizer_inputs(&node.raw_node.op).unwrap();
+    let grad = &node.graph().nodes[node.inputs[grad_index].0];
+    if !grad.form.is_part() || grad.form.ndev() < 2 {
+        return None
+    }
+    if states.iter().any(|i| node.graph().nodes[node.inputs[*i].0].form.ndev() != 1) {
+        warn!("{} updates a variable or optimizer state with several copies, aggregating its gradient synchronously", node.graph().describe(&node.raw_node.name));
+        return None
+    }
+    let device = node.graph().nodes[node.inputs[states[0]].0].form.devices[0];
+    Some(vec![device; grad.form.ndev()])
+}
+
+/// a node has a single form for all its outputs. For ops with several outputs of different shapes (e.g. the batch statistics of FusedBatchNorm),
+/// a Part form is only valid if every used output is split along the same leading dimension. Nodes violating this are reverted to Full.
+fn audit_multi_output_forms(graph: &mut Graph) {
+    let mut used_outputs: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
+    for node in graph.nodes.iter() {
+        for (id, index, _) in node.inputs.iter() {
+            used_outputs.entry(*id).or_default().insert(*index);
+        }
+    }
+
+    for (node_id, node) in graph.nodes.iter_mut().enumerate() {
+        if !node.form.is_part() {
+            continue
+        }
+
+        let shapes = match node.raw_node.attr.get("_output_shapes") {
+            Some(x) if x.get_list().shape.len() > 1 => &x.get_list().shape,
+            _ => continue
+        };
+        let leading_dim = |i: usize| shapes.get(i).and_then(|s| s.dim.first()).map(|d| d.size);
+        let inconsistent: Vec<_> = used_outputs.get(&node_id).map(|x| x.iter().copied().filter(|i| *i != 0 && leading_dim(*i) != leading_dim(0)).collect()).unwrap_or_default();
+        if inconsistent.is_empty() {
+            continue
+        }
+
+        warn!("{} ({}) cannot be split: outputs {:?} do not share the batch dimension with output 0. Revert to full form", node.graph().describe(&node.raw_node.name), node.raw_node.op, inconsistent);
+        node.form.kind = FormKind::Full;
+        for (_, _, kind) in node.inputs.iter_mut() {
+            *kind = FormKind::Full
+        }
+    }
+}
+
+/// RaggedTensor components (see `Tensor::IS_RAGGED`) cannot be split by rows, so nodes that produce or consume them are reverted to Full.
+/// Each reverted node is reported, since it runs on the whole batch on every device it is replicated on.
+fn keep_ragged_whole(graph: &mut Graph) {
+    for node in graph.nodes.iter_mut() {
+        if !node.form.is_part() {
+            continue
+        }
+        let produces = node.outputs.iter().any(|x| x.has_flag(Tensor::IS_RAGGED));
+        let consumes = node.inputs.iter().any(|(id, index, _)| node.graph().nodes[*id].get_output(*index).has_flag(Tensor::IS_RAGGED));
+        if !produces && !consumes {
+            continue
+        }
+
+        warn!("{} ({}) {} RaggedTensor components, which cannot be split by rows. Revert to full form", node.graph().describe(&node.raw_node.name), node.raw_node.op,
+            if produces { "produces" } else { "consumes" });
+        node.form.kind = FormKind::Full;
+        for (_, _, kind) in node.inputs.iter_mut() {
+            *kind = FormKind::Full
+        }
+    }
+}
+
+/// nodes listed in the option `unsplit_nodes` (space separated names), e.g. those that an imported XLA sharding replicates (see
+/// `TGE.import_sharding`), and nodes with a full form in `mesh_forms` or `split_forms` are reverted to Full so each of their replicas
+/// runs on the whole batch.
+fn keep_unsplit_whole(graph: &mut Graph, forms: &BTreeMap<String, Form>) {
+    let mut names: BTreeSet<&str> = graph.options.get("unsplit_nodes").map(|x| x.split_ascii_whitespace().collect()).unwrap_or_default();
+    names.extend(forms.iter().filter(|(_, form)| form.is_full()).map(|(name, _)| &name[..]));
+    if names.is_empty() {
+        return
+    }
+    for node in graph.nodes.iter_mut() {
+        if node.form.is_part() && names.contains(&node.raw_node.name[..]) {
+            node.form.kind = FormKind::Full;
+            for (_, _, kind) in node.inputs.iter_mut() {
+                *kind = FormKind::Full
+            }
+        }
+    }
+}
+
+/// nodes whose form is split along a dimension other than the batch, e.g. `part1_0_1` for the columns of a MatMul, are split that way
+/// whatever their group does. Their inputs are split along the matching dimension, or used whole (see `Node::split_input_axis`).
+fn split_along_axes(graph: &mut Graph, forms: &BTreeMap<String, Form>) {
+    for node in graph.nodes.iter_mut() {
+        let axis = match forms.get(&node.raw_node.name) {
+            Some(form) if form.is_part() && form.axis != 0 && node.is_splittable() => form.axis,
+            _ => continue
+        };
+        node.form.kind = FormKind::Part;
+        node.form.axis = axis;
+        let kinds: Vec<_> = (0..node.inputs.len()).map(|i| if node.split_input_axis(i).is_some() { FormKind::Part } else { FormKind::Full }).collect();
+        for ((_, _, kind), split) in node.inputs.iter_mut().zip(kinds) {
+            *kind = split
+        }
+    }
+}
+
+/// the option `split_forms` places nodes by the forms it lists as space separated `<node>:<form code>` rather than by the strategy, e.g.
+/// `dense/MatMul:part1_0_1` splits the columns of the node between devices 0 and 1 (see `Form::code`). Full forms and Part forms along
+/// the batch are placed like mesh forms (see `mesh_forms`), other Part forms are split by `split_along_axes`.
+fn split_forms(graph: &Graph) -> BTreeMap<String, Form> {
+    let entries = match graph.options.get("split_forms") {
+        Some(x) => x.split_ascii_whitespace(),
+        None => return BTreeMap::new()
+    };
+    entries.map(|entry| {
+        let (name, code) = entry.rsplit_once(':').unwrap_or_else(|| panic!("split_forms entries should be <node>:<form code>, found {}", entry));
+        (name.to_string(), Form::from_code(code))
+    }).collect()
+}
+
+/// the option `mesh_forms` places nodes by their forms on the mesh of the target (see `TGE.set_mesh`) rather than by the strategy. It lists
+/// space separated `<node>:<mesh form>:<slice>`, e.g. `dense/MatMul:part/full:1` puts the node on the second replica group of part/full
+/// (see `MeshForm::to_forms`), where it is split if its group is. The aggregation method still comes from the strategy.
+fn mesh_forms(graph: &Graph, target: &Target) -> BTreeMap<String, Form> {
+    let entries = match graph.options.get("mesh_forms") {
+        Some(x) => x.split_ascii_whitespace(),
+        None => return BTreeMap::new()
+    };
+    let mesh = target.mesh.as_ref().expect("mesh_forms requires a mesh, see TGE.set_mesh");
+    entries.map(|entry| {
+        let segs: Vec<&str> = entry.rsplitn(3, ':').collect();
+        assert_eq!(segs.len(), 3, "mesh_forms entries should be <node>:<mesh form>:<slice>, found {}", entry);
+        let slice: usize = segs[0].parse().expect("the slice in mesh_forms should be a number");
+        let mut forms = crate::mesh::MeshForm::from_code(segs[1]).to_forms(mesh);
+        assert!(slice < forms.len(), "{} has only {} slices on mesh {}", segs[1], forms.len(), mesh.code());
+        (segs[2].to_string(), forms.swap_remove(slice))
+    }).collect()
 }
 
 pub fn reset(graph: &mut Graph) {
     for node in graph.nodes.iter_mut() {
-        node.form = Form { kind: FormKind::Full, devices: vec![] };
+        node.form = Form { kind: FormKind::Full, devices: vec![], axis: 0 };
         for (_, _, form) in node.inputs.iter_mut() {
             *form = FormKind::Full
         }
+        for output in node.outputs.iter_mut() { // the cached conversions refer to nodes of the previous target
+            output.forms.clear()
+        }
     }
+    graph.reset_collective_state();
 }
 
 fn apply_nodes_dict(x: &str) -> Option<usize> { // get gradient input index if it is an "apply*" operation
-    match x {
-        "ApplyGradientDescent" => Some(2),
-        "ApplyAdam" => Some(9),
-        _ => None
-    }
+    optimizer_inputs(x).map(|(grad_index, _)| grad_index)
 }
@@ -0,0 +1,878 @@
+//! The C API used by tge.py. Every function takes or returns raw pointers to objects created by the `create_*` functions,
+//! which must be released by the matching `destroy_*` function.
+
+use oh_my_rust::*;
+use protobuf::{Message, parse_from_bytes};
+use std::collections::BTreeMap;
+use crate::*;
+use crate::simulator::Simulator;
+use crate::graph::Graph;
+use crate::misc::{Target, DataProfiler};
+use crate::registry::OpRegistry;
+
+#[no_mangle]
+unsafe extern fn create_graph(pb: *const u8, pb_len: u32) -> *mut Graph {
+    let pb = std::slice::from_raw_parts(pb, pb_len as usize);
+    let g: proto::graph::GraphDef = parse_from_bytes(pb).unwrap();
+
+    Box::leak(Graph::new(&g.node).apply(|x| {
+        x.set_versions(g.get_versions());
+        x.set_library(g.get_library())
+    }))
+}
+
+#[no_mangle]
+unsafe extern fn create_graph_with_registry(pb: *const u8, pb_len: u32, registry: *const OpRegistry) -> *mut Graph {
+    let pb = std::slice::from_raw_parts(pb, pb_len as usize);
+    let g: proto::graph::GraphDef = parse_from_bytes(pb).unwrap();
+
+    Box::leak(Graph::with_registry(&g.node, (*registry).clone()).apply(|x| {
+        x.set_versions(g.get_versions());
+        x.set_library(g.get_library())
+    }))
+}
+
+#[no_mangle]
+unsafe extern fn destroy_graph(graph: *mut Graph) {
+    free(graph)
+}
+
+#[no_mangle]
+unsafe extern fn create_registry() -> *mut OpRegistry {
+    leak(OpRegistry::default())
+}
+
+#[no_mangle]
+unsafe extern fn destroy_registry(registry: *mut OpRegistry) {
+    free(registry)
+}
+
+#[no_mangle]
+unsafe extern fn register_custom_op(registry: *mut OpRegistry, spec_raw: *const u8, spec_len: u32) {
+    let spec = std::str::from_utf8(std::slice::from_raw_parts(spec_raw, spec_len as usize)).unwrap();
+    for line in spec.lines().filter(|x| !x.trim().is_empty()) {
+        (*registry).register(line)
+    }
+}
+
+/// `locations_raw` has a line of `<node name>\t<file:line>` per node, see `Graph::set_source_locations`
+#[no_mangle]
+unsafe extern fn set_source_locations(graph: *mut Graph, locations_raw: *const u8, locations_len: u32) {
+    let locations = std::str::from_utf8(std::slice::from_raw_parts(locations_raw, locations_len as usize)).unwrap();
+    (*graph).set_source_locations(locations.lines().filter_map(|line| line.split_once('\t')).map(|(name, location)| (name.to_string(), location.to_string())).collect())
+}
+
+#[no_mangle]
+unsafe extern fn set_option(graph: *mut Graph, name: *const u8, name_len: u32, value: *const u8, value_len: u32) {
+    let name = std::str::from_utf8(std::slice::from_raw_parts(name, name_len as usize)).unwrap();
+    let value = std::str::from_utf8(std::slice::from_raw_parts(value, value_len as usize)).unwrap();
+    (*graph).options.insert(name.to_string(), value.to_string());
+}
+
+/// the options set so far, including those applied by profiles, as `<name>\t<value>` lines. Writes them into `dest` if its
+/// capacity is enough. Returns the required length.
+#[no_mangle]
+unsafe extern fn get_options(graph: *const Graph, dest: *mut u8, capacity: u32) -> u32 {
+    let report = (*graph).options.iter().map(|(name, value)| format!("{}\t{}", name, value)).collect::<Vec<_>>().join("\n");
+    if report.len() <= capacity as usize {
+        std::slice::from_raw_parts_mut(dest, report.len()).copy_from_slice(report.as_bytes())
+    }
+    report.len() as _
+}
+
+/// the callback receives (compiled, total, aux nodes, eta in seconds). Pass null to remove it.
+#[no_mangle]
+unsafe extern fn set_progress_callback(graph: *mut Graph, callback: Option<unsafe extern fn(u32, u32, u32, f64)>) {
+    (*graph).progress = callback.map(|f| Box::new(move |p: &graph::Progress| f(p.compiled as _, p.total as _, p.aux_nodes as _, p.eta)) as _)
+}
+
+/// write the compiled graph to `path`, see `Target::save`. Returns the number of bytes written, or 0 if it fails, in which case the reason is logged.
+#[no_mangle]
+unsafe extern fn save_target(target: *const Target, path: *const u8, path_len: u32) -> u64 {
+    let path = std::str::from_utf8(std::slice::from_raw_parts(path, path_len as usize)).unwrap();
+    (*target).save(path).unwrap_or_else(|e| {
+        warn!("cannot save the compiled graph to {}: {}", path, e);
+        0
+    })
+}
+
+/// partition the compiled graph by device (`by` = 0) or by task (1) and write the partitions with their manifest to `dir`, see
+/// `partition::export`. `incarnations_raw` has a line `<device> <incarnation>` for each device whose incarnation is known. Returns the
+/// number of partitions, or 0 if it fails, in which case the reason is logged.
+#[no_mangle]
+unsafe extern fn export_partitions(target: *const Target, by: u32, dir: *const u8, dir_len: u32, suffix: *const u8, suffix_len: u32, incarnations_raw: *const u8, incarnations_len: u32) -> u32 {
+    let dir = std::str::from_utf8(std::slice::from_raw_parts(dir, dir_len as usize)).unwrap();
+    let suffix = std::str::from_utf8(std::slice::from_raw_parts(suffix, suffix_len as usize)).unwrap();
+    let incarnations: BTreeMap<String, u64> = std::str::from_utf8(std::slice::from_raw_parts(incarnations_raw, incarnations_len as usize)).unwrap()
+        .lines().filter_map(|line| line.rsplit_once(' ')).map(|(device, x)| (device.to_string(), x.parse().expect("incarnations should be numbers"))).collect();
+    let by = match by {
+        0 => partition::PartitionBy::Device,
+        1 => partition::PartitionBy::Task,
+        _ => unreachable!()
+    };
+    partition::export(&*target, by, &incarnations, dir, suffix).map(|x| x as _).unwrap_or_else(|e| {
+        warn!("cannot export the partitions to {}: {}", dir, e);
+        0
+    })
+}
+
+/// write the feed mapping of per-replica placeholders as JSON: original name => replica names in the order of batch slices
+#[no_mangle]
+unsafe extern fn write_feed_mapping(graph: *const Graph, path: *const u8, path_len: u32) {
+    let path = std::str::from_utf8(std::slice::from_raw_parts(path, path_len as usize)).unwrap();
+    let mut file = std::fs::File::create(path).unwrap();
+    let mapping = (*graph).feed_mapping();
+    let mut json = String::from("{");
+    for (i, (name, replicas)) in mapping.iter().enumerate() {
+        json += &format!("{}\n  \"{}\": [{}]", if i == 0 { "" } else { "," }, name, replicas.iter().map(|x| format!("\"{}\"", x)).collect::<Vec<_>>().join(", "));
+    }
+    json += "\n}\n";
+    std::io::Write::write_all(&mut file, json.as_bytes()).unwrap()
+}
+
+#[no_mangle]
+unsafe extern fn get_groups(graph: *mut Graph, names_raw: *const u8, names_len: *const u8, result: *mut u32) {
+    let names = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace();
+    let result = std::slice::from_raw_parts_mut(result, (*graph).nodes.len()); // the actual length could be shorter
+    let groups = (*graph).get_groups();
+    let mut group_id = BTreeMap::new();
+    let mut id = 0;
+
+    for (name, res) in names.zip(result) {
+        if let Some(g) = &groups[name] {
+            *res = *group_id.entry(g).or_insert_with(|| { id += 1; id - 1 })
+        } else {
+            *res = id;
+            id += 1
+        }
+    }
+}
+
+/// write 1 into `result` for nodes (in the order of `names`) that should be replicated, 0 otherwise. `profiler` can be null.
+#[no_mangle]
+unsafe extern fn selective_replication(graph: *const Graph, profiler: *const DataProfiler, ndev: u32, coverage: f64, names_raw: *const u8, names_len: u32, result: *mut u32) {
+    let names = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace();
+    let result = std::slice::from_raw_parts_mut(result, (*graph).nodes.len());
+    let strategy = strategy::selective_replication(&*graph, profiler.as_ref(), ndev as usize, coverage, 0);
+    for (name, res) in names.zip(result) {
+        *res = strategy.get(name).map(|(devices, _)| (devices.len() > 1) as u32).unwrap_or(0)
+    }
+}
+
+/// for each node (in the order of `names`), write the number of replicas on each device into a row of `result` (ndev columns) according to its XLA sharding annotation.
+/// The first element of each row is 0 if the node is not split, or 1 plus the axis that it is split along. Rows of nodes without annotation are left untouched.
+#[no_mangle]
+unsafe extern fn import_sharding(graph: *const Graph, ndev: u32, names_raw: *const u8, names_len: u32, result: *mut u32) {
+    let ndev = ndev as usize;
+    let names = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace();
+    let result = std::slice::from_raw_parts_mut(result, (*graph).nodes.len() * (ndev + 1));
+    let forms = sharding::import(&*graph, ndev);
+    for (name, row) in names.zip(result.chunks_mut(ndev + 1)) {
+        if let Some(form) = forms.get(name) {
+            row[0] = if form.is_part() { form.axis as u32 + 1 } else { 0 };
+            for device_id in form.devices.iter() {
+                row[device_id + 1] += 1
+            }
+        }
+    }
+}
+
+/// parameter-server strategy with bandwidth-aware shard placement. `result` has the same layout as in `profile_strategy`
+#[no_mangle]
+unsafe extern fn ps_placement(graph: *mut Graph, target: *const Target, hot_fraction: f64, names_raw: *const u8, names_len: u32, result: *mut u32) {
+    let ndev = (*target).ndev();
+    let names = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace();
+    let result = std::slice::from_raw_parts_mut(result, (*graph).nodes.len() * (ndev + 1));
+    let strategy = strategy::ps_placement(&mut *graph, &*target, hot_fraction);
+    for (name, row) in names.zip(result.chunks_mut(ndev + 1)) {
+        let (devices, method) = &strategy[name];
+        row[0] = *method as _;
+        for device_id in devices {
+            row[device_id + 1] += 1
+        }
+    }
+}
+
+/// parameter servers on the devices in `servers` and compute replicated on the devices in `workers`, see `strategy::parameter_server`.
+/// `result` has the same layout as in `profile_strategy`
+#[no_mangle]
+unsafe extern fn parameter_server(graph: *mut Graph, ndev: u32, servers_raw: *const u32, n_servers: u32, workers_raw: *const u32, n_workers: u32, names_raw: *const u8, names_len: u32, result: *mut u32) {
+    let ndev = ndev as usize;
+    let names = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace();
+    let result = std::slice::from_raw_parts_mut(result, (*graph).nodes.len() * (ndev + 1));
+    let servers: Vec<usize> = std::slice::from_raw_parts(servers_raw, n_servers as usize).iter().map(|x| *x as usize).collect();
+    let workers: Vec<usize> = std::slice::from_raw_parts(workers_raw, n_workers as usize).iter().map(|x| *x as usize).collect();
+    assert!(servers.iter().chain(workers.iter()).all(|x| *x < ndev), "device index out of range");
+    let strategy = strategy::parameter_server(&mut *graph, &servers, &workers);
+    for (name, row) in names.zip(result.chunks_mut(ndev + 1)) {
+        let (devices, method) = &strategy[name];
+        row[0] = *method as _;
+        for device_id in devices {
+            row[device_id + 1] += 1
+        }
+    }
+}
+
+/// mirror everything on the devices in `devices_raw` and average the variables every `period` steps, see `strategy::local_sgd`.
+/// `result` has the same layout as in `profile_strategy`. Returns the period to set as the option `local_sgd_period`.
+#[no_mangle]
+unsafe extern fn local_sgd(graph: *const Graph, ndev: u32, devices_raw: *const u32, n_devices: u32, period: u32, method: u32, names_raw: *const u8, names_len: u32, result: *mut u32) -> u32 {
+    let ndev = ndev as usize;
+    let names = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace();
+    let result = std::slice::from_raw_parts_mut(result, (*graph).nodes.len() * (ndev + 1));
+    let devices: Vec<usize> = std::slice::from_raw_parts(devices_raw, n_devices as usize).iter().map(|x| *x as usize).collect();
+    assert!(devices.iter().all(|x| *x < ndev), "device index out of range");
+    let (strategy, period) = strategy::local_sgd(&*graph, &devices, period as _, method as _);
+    for (name, row) in names.zip(result.chunks_mut(ndev + 1)) {
+        let (devices, method) = &strategy[name];
+        row[0] = *method as _;
+        for device_id in devices {
+            row[device_id + 1] += 1
+        }
+    }
+    period as _
+}
+
+/// run the strategy plugin in the shared object at `path` (see `plugin`). `result` has the same layout as in `profile_strategy` and should be zeroed;
+/// rows left all-zero are nodes the plugin made no decision for. Returns 0 on success, 1 on error.
+#[cfg(feature = "plugins")]
+#[no_mangle]
+unsafe extern fn plugin_strategy(graph: *const Graph, target: *const Target, path_raw: *const u8, path_len: u32, names_raw: *const u8, names_len: u32, result: *mut u32) -> u32 {
+    use crate::plugin::StrategyPlugin;
+
+    let ndev = (*target).ndev();
+    let path = std::str::from_utf8(std::slice::from_raw_parts(path_raw, path_len as usize)).unwrap();
+    let names = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace();
+    let result = std::slice::from_raw_parts_mut(result, (*graph).nodes.len() * (ndev + 1));
+    let strategy = match plugin::DynamicPlugin::load(path).and_then(|plugin| plugin.strategy(&*graph, &*target)) {
+        Ok(x) => x,
+        Err(e) => {
+            warn!("{}", e);
+            return 1
+        }
+    };
+    for (name, row) in names.zip(result.chunks_mut(ndev + 1)) {
+        if let Some((devices, method)) = strategy.get(name) {
+            row[0] = *method as _;
+            for device_id in devices {
+                row[device_id + 1] += 1
+            }
+        }
+    }
+    0
+}
+
+/// patch a strategy with an overrides file (see `strategy::parse_overrides`). `result` has the same layout as in `profile_strategy`
+/// and should be filled with the current strategy; all-zero rows are nodes without a decision. Returns 0 on success, 1 on error (nothing is changed).
+#[no_mangle]
+unsafe extern fn apply_overrides(graph: *const Graph, ndev: u32, text: *const u8, text_len: u32, names_raw: *const u8, names_len: u32, result: *mut u32) -> u32 {
+    let ndev = ndev as usize;
+    let text = std::str::from_utf8(std::slice::from_raw_parts(text, text_len as usize)).unwrap();
+    let names: Vec<_> = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace().collect();
+    let result = std::slice::from_raw_parts_mut(result, names.len() * (ndev + 1));
+
+    let mut strategy: strategy::Strategy = names.iter().zip(result.chunks(ndev + 1)).filter(|(_, row)| row[1..].iter().any(|x| *x > 0)).map(|(name, row)| {
+        let devices = row[1..].iter().enumerate().flat_map(|(device_id, n)| std::iter::repeat(device_id).take(*n as usize)).collect();
+        (name.to_string(), (devices, row[0] as u8))
+    }).collect();
+    if let Err(e) = strategy::parse_overrides(text).and_then(|overrides| strategy::apply_overrides(&*graph, &mut strategy, &overrides, ndev)) {
+        warn!("cannot apply overrides: {}", e);
+        return 1
+    }
+
+    for (name, row) in names.iter().zip(result.chunks_mut(ndev + 1)) {
+        if let Some((devices, method)) = strategy.get(*name) {
+            row.iter_mut().for_each(|x| *x = 0);
+            row[0] = *method as _;
+            for device_id in devices {
+                row[device_id + 1] += 1
+            }
+        }
+    }
+    0
+}
+
+/// extend a plan for `m` devices to `n` devices, see `strategy::warm_start`. `result` has the same layout as in `profile_strategy` with `n` devices
+/// and should be filled with the plan for `m` devices (the columns of the other devices are 0); all-zero rows are nodes without a decision.
+#[no_mangle]
+unsafe extern fn warm_start(m: u32, n: u32, names_raw: *const u8, names_len: u32, result: *mut u32) {
+    let (m, n) = (m as usize, n as usize);
+    let names: Vec<_> = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace().collect();
+    let result = std::slice::from_raw_parts_mut(result, names.len() * (n + 1));
+
+    let strategy: strategy::Strategy = names.iter().zip(result.chunks(n + 1)).filter(|(_, row)| row[1..].iter().any(|x| *x > 0)).map(|(name, row)| {
+        let devices = row[1..].iter().enumerate().flat_map(|(device_id, n)| std::iter::repeat(device_id).take(*n as usize)).collect();
+        (name.to_string(), (devices, row[0] as u8))
+    }).collect();
+    let strategy = strategy::warm_start(&strategy, m, n);
+
+    for (name, row) in names.iter().zip(result.chunks_mut(n + 1)) {
+        if let Some((devices, method)) = strategy.get(*name) {
+            row.iter_mut().for_each(|x| *x = 0);
+            row[0] = *method as _;
+            for device_id in devices {
+                row[device_id + 1] += 1
+            }
+        }
+    }
+}
+
+/// parse a profile and apply its options to the graph. Returns null if the profile is malformed.
+#[no_mangle]
+unsafe extern fn load_profile(graph: *mut Graph, text: *const u8, text_len: u32) -> *mut profile::Profile {
+    let text = std::str::from_utf8(std::slice::from_raw_parts(text, text_len as usize)).unwrap();
+    match profile::Profile::parse(text) {
+        Ok(profile) => {
+            profile.apply_to(&mut *graph);
+            leak(profile)
+        }
+        Err(e) => {
+            warn!("invalid profile: {}", e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+unsafe extern fn destroy_profile(profile: *mut profile::Profile) {
+    free(profile)
+}
+
+/// write the strategy of the profile into `result`, one row of ndev+1 per node in the order of `names`: the aggregation method followed by the number of replicas on each device
+#[no_mangle]
+unsafe extern fn profile_strategy(profile: *const profile::Profile, graph: *const Graph, ndev: u32, names_raw: *const u8, names_len: u32, result: *mut u32) {
+    let ndev = ndev as usize;
+    let names = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace();
+    let result = std::slice::from_raw_parts_mut(result, (*graph).nodes.len() * (ndev + 1));
+    let strategy = (*profile).strategy(&*graph, ndev);
+    for (name, row) in names.zip(result.chunks_mut(ndev + 1)) {
+        let (devices, method) = &strategy[name];
+        row[0] = *method as _;
+        for device_id in devices {
+            row[device_id + 1] += 1
+        }
+    }
+}
+
+#[no_mangle]
+unsafe extern fn run_output_passes(profile: *const profile::Profile, target: *mut Target) {
+    (*profile).run_output_passes(&mut *target)
+}
+
+#[no_mangle]
+unsafe extern fn edit_graph(graph: *mut Graph, target: *mut Target, strategy_raw: *const u8, strategy_len: u32) {
+    let strategy_str = std::str::from_utf8(std::slice::from_raw_parts(strategy_raw, strategy_len as usize)).unwrap();
+    let strategy = strategy_str.lines().map(|line| {
+        let line = line.split_ascii_whitespace().collect::<Vec<_>>();
+        let name = <&str>::clone(&line[0]);
+        let method = line[1].parse::<u8>().unwrap();
+        let places = line[2..].iter().map(|x| x.parse().unwrap()).collect(); // assume sorted
+        (name, (places, method))
+    }).collect();
+    editor::edit(&mut *graph, &mut *target, &strategy)
+}
+
+/// predict the step time (returned) and the peak memory of each device (written to `memory`) of an edited graph without compiling it. See `analysis::estimate`.
+#[no_mangle]
+unsafe extern fn estimate(graph: *mut Graph, target: *const Target, profiler: *const DataProfiler, memory: *mut u64) -> u64 {
+    let plan = plan::plan_transfers(&mut *graph);
+    let result = analysis::estimate(&mut *graph, &plan, &*target, &*profiler);
+    std::slice::from_raw_parts_mut(memory, (*target).ndev()).copy_from_slice(&result.memory);
+    result.time
+}
+
+/// improve a strategy with `search::anytime_search` for `budget_ms` milliseconds. `result` has the same layout as in `profile_strategy`, holds the
+/// initial strategy (all-zero rows are left to the editor) and receives the best one. `metrics` receives the predicted time, the number of
+/// evaluated plans, the elapsed milliseconds and the predicted memory of each device. `memory_limit` is in bytes per device, 0 for no limit.
+#[no_mangle]
+unsafe extern fn anytime_search(graph: *mut Graph, target: *const Target, profiler: *const DataProfiler, budget_ms: u64, memory_limit: u64, names_raw: *const u8, names_len: u32, result: *mut u32, metrics: *mut u64) {
+    let ndev = (*target).ndev();
+    let names: Vec<_> = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace().collect();
+    let result = std::slice::from_raw_parts_mut(result, names.len() * (ndev + 1));
+    let metrics = std::slice::from_raw_parts_mut(metrics, 3 + ndev);
+
+    let initial: editor::Strategy = names.iter().zip(result.chunks(ndev + 1)).filter(|(_, row)| row[1..].iter().any(|x| *x > 0)).map(|(name, row)| {
+        let devices = row[1..].iter().enumerate().flat_map(|(device_id, n)| std::iter::repeat(device_id).take(*n as usize)).collect();
+        (name.to_string(), (devices, row[0] as u8))
+    }).collect();
+    let memory_limit = if memory_limit == 0 { None } else { Some(memory_limit) };
+    let found = search::anytime_search(&mut *graph, &*target, &*profiler, initial, std::time::Duration::from_millis(budget_ms), memory_limit, &rng::RngConfig::from_options(&(*graph).options));
+
+    for (name, row) in names.iter().zip(result.chunks_mut(ndev + 1)) {
+        if let Some((devices, method)) = found.strategy.get(*name) {
+            row.iter_mut().for_each(|x| *x = 0);
+            row[0] = *method as _;
+            for device_id in devices {
+                row[device_id + 1] += 1
+            }
+        }
+    }
+    metrics[0] = found.estimate.time;
+    metrics[1] = found.evaluated as _;
+    metrics[2] = found.elapsed.as_millis() as _;
+    metrics[3..].copy_from_slice(&found.estimate.memory);
+}
+
+/// search for the pareto front of step time, peak memory and network traffic with `search::pareto_search`. `result` has `max_plans` blocks with
+/// the layout of `profile_strategy`; the first one holds the initial strategy. The plans are written into the blocks, the fastest, the one
+/// with least memory and the one with least traffic first and then the others by time, and `metrics` receives the predicted time, traffic
+/// and memory of each device of each plan. Returns the number of plans written.
+#[no_mangle]
+unsafe extern fn pareto_search(graph: *mut Graph, target: *const Target, profiler: *const DataProfiler, budget_ms: u64, memory_limit: u64, names_raw: *const u8, names_len: u32, result: *mut u32, max_plans: u32, metrics: *mut u64) -> u32 {
+    let ndev = (*target).ndev();
+    let names: Vec<_> = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace().collect();
+    let block = names.len() * (ndev + 1);
+    let result = std::slice::from_raw_parts_mut(result, max_plans as usize * block);
+    let metrics = std::slice::from_raw_parts_mut(metrics, max_plans as usize * (2 + ndev));
+
+    let initial: editor::Strategy = names.iter().zip(result[..block].chunks(ndev + 1)).filter(|(_, row)| row[1..].iter().any(|x| *x > 0)).map(|(name, row)| {
+        let devices = row[1..].iter().enumerate().flat_map(|(device_id, n)| std::iter::repeat(device_id).take(*n as usize)).collect();
+        (name.to_string(), (devices, row[0] as u8))
+    }).collect();
+    let memory_limit = if memory_limit == 0 { None } else { Some(memory_limit) };
+    let front = search::pareto_search(&mut *graph, &*target, &*profiler, initial, std::time::Duration::from_millis(budget_ms), memory_limit, &rng::RngConfig::from_options(&(*graph).options));
+
+    let mut written: Vec<&(editor::Strategy, analysis::Estimate)> = vec![];
+    for plan in [front.fastest(), front.least_memory(), front.least_traffic()].iter().copied().chain(front.plans.iter()) {
+        if written.len() < max_plans as usize && !written.iter().any(|x| std::ptr::eq(*x, plan)) {
+            written.push(plan)
+        }
+    }
+
+    for (i, (strategy, estimate)) in written.iter().enumerate() {
+        let rows = &mut result[i * block..(i + 1) * block];
+        for (name, row) in names.iter().zip(rows.chunks_mut(ndev + 1)) {
+            row.iter_mut().for_each(|x| *x = 0);
+            if let Some((devices, method)) = strategy.get(*name) {
+                row[0] = *method as _;
+                for device_id in devices {
+                    row[device_id + 1] += 1
+                }
+            }
+        }
+        let m = &mut metrics[i * (2 + ndev)..(i + 1) * (2 + ndev)];
+        m[0] = estimate.time;
+        m[1] = estimate.traffic;
+        m[2..].copy_from_slice(&estimate.memory);
+    }
+    written.len() as _
+}
+
+/// what-if analysis of losing each device (see `search::contingency_analysis`) for the strategy in `result`, which has the same layout as in
+/// `profile_strategy`. Writes one `<device>\t<baseline time>\t<repaired time>\t<fits>\t<fallback device>\t<lost replicas>\t<orphaned nodes>` line
+/// per device into `dest` if its capacity is enough, where the fallback device is -1 if nothing is orphaned and the repaired time is -1 if
+/// no device survives. Orphaned nodes are comma-separated. Returns the required length.
+#[no_mangle]
+unsafe extern fn contingency_report(graph: *mut Graph, target: *const Target, profiler: *const DataProfiler, memory_limit: u64, names_raw: *const u8, names_len: u32, result: *const u32, dest: *mut u8, capacity: u32) -> u32 {
+    let ndev = (*target).ndev();
+    let names: Vec<_> = std::str::from_utf8(std::slice::from_raw_parts(names_raw, names_len as usize)).unwrap().split_ascii_whitespace().collect();
+    let result = std::slice::from_raw_parts(result, names.len() * (ndev + 1));
+    let strategy: editor::Strategy = names.iter().zip(result.chunks(ndev + 1)).filter(|(_, row)| row[1..].iter().any(|x| *x > 0)).map(|(name, row)| {
+        let devices = row[1..].iter().enumerate().flat_map(|(device_id, n)| std::iter::repeat(device_id).take(*n as usize)).collect();
+        (name.to_string(), (devices, row[0] as u8))
+    }).collect();
+    let memory_limit = if memory_limit == 0 { None } else { Some(memory_limit) };
+
+    let (baseline, contingencies) = search::contingency_analysis(&mut *graph, &*target, &*profiler, &strategy, memory_limit);
+    let report = contingencies.iter().map(|x| format!("{}\t{}\t{}\t{}\t{}\t{}\t{}", x.device, baseline.time,
+        x.repair.as_ref().map(|e| e.time as i64).unwrap_or(-1), x.fits, x.fallback.map(|d| d as i64).unwrap_or(-1), x.lost_replicas, x.orphaned.join(","))
+    ).collect::<Vec<_>>().join("\n");
+    if report.len() <= capacity as usize {
+        std::slice::from_raw_parts_mut(dest, report.len()).copy_from_slice(report.as_bytes())
+    }
+    report.len() as _
+}
+
+/// `result` receives the broadcast time, the time to ship constants, and the total bytes of constants
+#[no_mangle]
+unsafe extern fn startup_cost(target: *const Target, result: *mut u64) {
+    let result = std::slice::from_raw_parts_mut(result, 3);
+    let cost = analysis::startup_cost(&*target);
+    result[0] = cost.broadcast_time;
+    result[1] = cost.constant_time;
+    result[2] = cost.constant_bytes.iter().sum();
+}
+
+#[no_mangle]
+unsafe extern fn variable_memory(graph: *mut Graph, ndev: u32, result: *mut u64) {
+    let result = std::slice::from_raw_parts_mut(result, ndev as usize);
+    result.copy_from_slice(&analysis::variable_memory(&mut *graph, ndev as usize))
+}
+
+#[no_mangle]
+unsafe extern fn reset_graph(graph: *mut Graph) {
+    editor::reset(&mut *graph)
+}
+
+#[no_mangle]
+unsafe extern fn create_target(
+    devices_raw: *const u8, devices_len: u32,
+    links_raw: *const u8, links_len: u32,
+    paths_raw: *const u8, paths_len: u32,
+    sinks_raw: *const u8, sinks_len: u32,
+    nccls_raw: *const u8, nccls_len: u32
+) -> *mut Target {
+    let links_str = std::str::from_utf8(std::slice::from_raw_parts(links_raw, links_len as usize)).unwrap();
+    let links = links_str.split_ascii_whitespace().map(|x| x.parse().unwrap()).collect();
+
+    let paths_str = std::str::from_utf8(std::slice::from_raw_parts(paths_raw, paths_len as usize)).unwrap();
+    let paths = paths_str.lines().map(|x| x.split_ascii_whitespace().map(|x| x.parse().unwrap()).collect()).collect();
+
+    let devices_str = std::str::from_utf8(std::slice::from_raw_parts(devices_raw, devices_len as usize)).unwrap();
+    let devices = devices_str.split_ascii_whitespace().map(|x| x.to_owned()).collect();
+
+    let sinks_str = std::str::from_utf8(std::slice::from_raw_parts(sinks_raw, sinks_len as usize)).unwrap();
+    let sinks = sinks_str.split_ascii_whitespace().map(|x| x.to_string()).collect();
+
+    let nccls_str = std::str::from_utf8(std::slice::from_raw_parts(nccls_raw, nccls_len as usize)).unwrap();
+    let nccls = nccls_str.lines().filter(|x| !x.is_empty()).map(|line| {
+        let mut m = [0., 0., 0., 0.];
+        let line: Vec<_> = line.split_ascii_whitespace().collect();
+        for i in 0..4 {
+            m[i] = line[i+1].parse().unwrap()
+        }
+        (line[0].to_string(), m)
+    }).collect();
+
+    let target = Target::new(proto::graph::GraphDef::new(), devices, links, paths, sinks, nccls);
+    leak(target)
+}
+
+#[no_mangle]
+unsafe extern fn set_mesh(target: *mut Target, mesh_raw: *const u8, mesh_len: u32) {
+    let mesh_str = std::str::from_utf8(std::slice::from_raw_parts(mesh_raw, mesh_len as usize)).unwrap();
+    let mesh = mesh::DeviceMesh::from_code(mesh_str);
+    assert!(mesh.devices.iter().all(|x| *x < (*target).ndev()), "mesh refers to unknown devices");
+    (*target).mesh = Some(mesh)
+}
+
+/// each line describes a CPU: `name gpu:bandwidth gpu:bandwidth ...`
+#[no_mangle]
+unsafe extern fn set_cpus(target: *mut Target, cpus_raw: *const u8, cpus_len: u32) {
+    let cpus_str = std::str::from_utf8(std::slice::from_raw_parts(cpus_raw, cpus_len as usize)).unwrap();
+    (*target).cpus = cpus_str.lines().filter(|x| !x.trim().is_empty()).map(|line| {
+        let mut segs = line.split_ascii_whitespace();
+        let name = segs.next().unwrap().to_string();
+        let bandwidth = segs.map(|x| {
+            let (gpu, bandwidth) = x.split_at(x.find(':').expect("expect gpu:bandwidth"));
+            let gpu: usize = gpu.parse().unwrap();
+            assert!(gpu < (*target).ndev(), "cpu {} refers to unknown device {}", name, gpu);
+            (gpu, bandwidth[1..].parse().unwrap())
+        }).collect();
+        misc::CpuDevice { name, bandwidth }
+    }).collect()
+}
+
+#[no_mangle]
+unsafe extern fn destroy_target(target: *mut Target) {
+    free(target)
+}
+
+#[no_mangle]
+unsafe extern fn compute_size(target: *mut Target) -> u32 {
+    (*target).pb.compute_size()
+}
+
+#[no_mangle]
+unsafe extern fn read_protobuf(target: *mut Target, dest: *mut u8) {
+    let mut ptr = std::slice::from_raw_parts_mut(dest, (*target).pb.get_cached_size() as usize);
+    (*target).pb.write_to_writer(&mut ptr).unwrap()
+}
+
+/// write the names of aux variables (separated by newlines) into `dest` if its capacity is enough. Returns the required length.
+#[no_mangle]
+unsafe extern fn get_aux_variables(target: *const Target, dest: *mut u8, capacity: u32) -> u32 {
+    let names = (*target).aux_variables.iter().map(|x| &x.name[..]).collect::<Vec<_>>().join("\n");
+    if names.len() <= capacity as usize {
+        std::slice::from_raw_parts_mut(dest, names.len()).copy_from_slice(names.as_bytes())
+    }
+    names.len() as _
+}
+
+/// check the `_tge_input_sizes` of the compiled graph against a runtime shape dump (see `analysis::parse_shape_dump`).
+/// Writes one `<node>\t<input>\t<annotated>\t<actual>` line per mismatch into `dest` if its capacity is enough. Returns the required length.
+#[no_mangle]
+unsafe extern fn check_input_sizes(target: *const Target, dump_raw: *const u8, dump_len: u32, tolerance: f32, dest: *mut u8, capacity: u32) -> u32 {
+    let dump = std::str::from_utf8(std::slice::from_raw_parts(dump_raw, dump_len as usize)).unwrap();
+    let actual = analysis::parse_shape_dump(dump).unwrap_or_else(|e| panic!("{}", e));
+    let report = analysis::check_input_sizes(&*target, &actual, tolerance as _).iter()
+        .map(|x| format!("{}\t{}\t{}\t{}", x.node, x.input, x.annotated, x.actual)).collect::<Vec<_>>().join("\n");
+    if report.len() <= capacity as usize {
+        std::slice::from_raw_parts_mut(dest, report.len()).copy_from_slice(report.as_bytes())
+    }
+    report.len() as _
+}
+
+/// summarize a serialized GraphDef (see `stats::GraphStats`). Writes `GraphStats::report` into `dest` if its capacity is enough.
+/// Returns the required length.
+#[no_mangle]
+unsafe extern fn graph_stats(pb: *const u8, pb_len: u32, dest: *mut u8, capacity: u32) -> u32 {
+    let pb = std::slice::from_raw_parts(pb, pb_len as usize);
+    let g: proto::graph::GraphDef = parse_from_bytes(pb).unwrap();
+    let report = stats::GraphStats::of(&g.node).report();
+    if report.len() <= capacity as usize {
+        std::slice::from_raw_parts_mut(dest, report.len()).copy_from_slice(report.as_bytes())
+    }
+    report.len() as _
+}
+
+/// a serialized GraphDef with its tensors stripped and, if `rename` is not 0, its nodes renamed (see `repro::anonymize`). The versions
+/// and the function library are kept. Writes it into `dest` if its capacity is enough. Returns the required length.
+#[no_mangle]
+unsafe extern fn anonymize_graph(pb: *const u8, pb_len: u32, rename: u32, dest: *mut u8, capacity: u32) -> u32 {
+    let pb = std::slice::from_raw_parts(pb, pb_len as usize);
+    let mut g: proto::graph::GraphDef = parse_from_bytes(pb).unwrap();
+    let nodes = repro::anonymize(&g.node, rename != 0);
+    g.node = nodes.into();
+    let bytes = g.write_to_bytes().unwrap();
+    if bytes.len() <= capacity as usize {
+        std::slice::from_raw_parts_mut(dest, bytes.len()).copy_from_slice(&bytes)
+    }
+    bytes.len() as _
+}
+
+/// save the message and backtrace of the first panic to `path`, see `repro::install_panic_hook`
+#[no_mangle]
+unsafe extern fn set_panic_file(path: *const u8, path_len: u32) {
+    let path = std::str::from_utf8(std::slice::from_raw_parts(path, path_len as usize)).unwrap();
+    repro::install_panic_hook(path.into())
+}
+
+/// the version of the crate as a NUL-terminated string
+#[no_mangle]
+unsafe extern fn tge_version() -> *const u8 {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr()
+}
+
+/// predict the nodes of the compiled graph that soft placement would move (see `kernels::predict_soft_placement`).
+/// Writes one `<node>\t<op>\t<from>\t<to>\t<reason>` line per node into `dest` if its capacity is enough. Returns the required length.
+#[no_mangle]
+unsafe extern fn soft_placement_report(graph: *const Graph, target: *const Target, dest: *mut u8, capacity: u32) -> u32 {
+    let table = kernels::KernelTable::from_options(&(*graph).options);
+    let report = kernels::predict_soft_placement(&*target, &table).iter()
+        .map(|x| format!("{}\t{}\t{}\t{}\t{}\t{}", x.node, x.op, x.from, x.to, x.reason, (*graph).source_location(&x.owner).unwrap_or(""))).collect::<Vec<_>>().join("\n");
+    if report.len() <= capacity as usize {
+        std::slice::from_raw_parts_mut(dest, report.len()).copy_from_slice(report.as_bytes())
+    }
+    report.len() as _
+}
+
+/// returns 0 if success, 1 if the compiled graph exceeds the budget set by options, 2 if some transfers have no route,
+/// 3 if some ops without GPU kernels are placed on GPUs (see `kernels::check_placement`)
+#[no_mangle]
+unsafe extern fn compile(graph: *mut Graph, target: *mut Target) -> u32 {
+    match batch::compile_checked(&mut *graph, &mut *target) {
+        Ok(()) => 0,
+        Err(e) => {
+            warn!("{:?}", e);
+            e.code()
+        }
+    }
+}
+
+/// compile the graph for each of the `njobs` targets. `strategies_raw` has the same format as in `edit_graph`, with jobs separated by lines of `---`.
+/// `callback(job, status)` is called as each job finishes, where status has the same meaning as the return value of `compile`.
+#[no_mangle]
+unsafe extern fn compile_batch(graph: *mut Graph, targets: *const *mut Target, njobs: u32, strategies_raw: *const u8, strategies_len: u32, callback: unsafe extern fn(u32, u32)) {
+    let strategies_str = std::str::from_utf8(std::slice::from_raw_parts(strategies_raw, strategies_len as usize)).unwrap();
+    let targets = std::slice::from_raw_parts(targets, njobs as usize);
+    let mut jobs: Vec<(&mut Target, strategy::Strategy)> = strategies_str.split("---\n").zip(targets).map(|(strategy_str, target)| {
+        let strategy = strategy_str.lines().filter(|x| !x.is_empty()).map(|line| {
+            let line = line.split_ascii_whitespace().collect::<Vec<_>>();
+            let method = line[1].parse::<u8>().unwrap();
+            let places = line[2..].iter().map(|x| x.parse().unwrap()).collect();
+            (line[0].to_string(), (places, method))
+        }).collect();
+        (&mut **target, strategy)
+    }).collect();
+    assert!(jobs.len() == njobs as usize, "the number of strategies does not match the number of targets");
+    batch::compile_batch(&mut *graph, &mut jobs, |i, _, result| callback(i as _, result.err().map(|e| e.code()).unwrap_or(0)))
+}
+
+/// run the original graph and the compiled graph for one step on CPUs and compare `fetches` (separated by whitespace). Returns 0 if they match.
+#[cfg(feature = "tensorflow")]
+#[no_mangle]
+unsafe extern fn smoke_test(pb: *const u8, pb_len: u32, target: *const Target, init_raw: *const u8, init_len: u32, fetches_raw: *const u8, fetches_len: u32, batchsize: u32, tolerance: f32) -> u32 {
+    let pb = std::slice::from_raw_parts(pb, pb_len as usize);
+    let original: proto::graph::GraphDef = parse_from_bytes(pb).unwrap();
+    let init = std::str::from_utf8(std::slice::from_raw_parts(init_raw, init_len as usize)).unwrap();
+    let fetches: Vec<_> = std::str::from_utf8(std::slice::from_raw_parts(fetches_raw, fetches_len as usize)).unwrap().split_ascii_whitespace().map(|x| x.to_string()).collect();
+    let init = if init.is_empty() { None } else { Some(init) };
+
+    match runtime::smoke_test(&original, &(*target).pb, init, &fetches, batchsize as _, tolerance) {
+        Ok(()) => 0,
+        Err(e) => {
+            warn!("smoke test failed: {}", e);
+            1
+        }
+    }
+}
+
+/// run both graphs for one step and compare every float tensor. Returns 0 if they are equivalent, 1 if some tensor diverges, 2 if the graphs cannot be run.
+#[cfg(feature = "tensorflow")]
+#[no_mangle]
+unsafe extern fn check_equivalence(pb: *const u8, pb_len: u32, target: *const Target, init_raw: *const u8, init_len: u32, batchsize: u32, tolerance: f32) -> u32 {
+    let pb = std::slice::from_raw_parts(pb, pb_len as usize);
+    let original: proto::graph::GraphDef = parse_from_bytes(pb).unwrap();
+    let init = std::str::from_utf8(std::slice::from_raw_parts(init_raw, init_len as usize)).unwrap();
+    let init = if init.is_empty() { None } else { Some(init) };
+
+    match runtime::check_equivalence(&original, &(*target).pb, init, batchsize as _, tolerance) {
+        Ok(None) => 0,
+        Ok(Some(d)) => {
+            warn!("first diverging tensor: {} (compiled as {}), element {}: expect {}, got {}", d.origin, d.replica, d.element, d.expected, d.actual);
+            1
+        }
+        Err(e) => {
+            warn!("equivalence check failed to run: {}", e);
+            2
+        }
+    }
+}
+
+#[no_mangle]
+unsafe extern fn create_profiler(profile_data: *const u8, profile_len: u32) -> *mut DataProfiler {
+    let profile_str = std::str::from_utf8(std::slice::from_raw_parts(profile_data, profile_len as usize)).unwrap();
+    let mut profile_dict: BTreeMap<String, Vec<(usize, Vec<u64>)>> = BTreeMap::new();
+    for line in profile_str.lines() {
+        let line = line.split_ascii_whitespace().collect::<Vec<_>>();
+        let name = line[0].to_string();
+        let nrep = line[1].parse().unwrap();
+        let times = line[2..].iter().map(|x| x.parse().unwrap()).collect();
+        let v = profile_dict.entry(name).or_default();
+        let pos = v.binary_search_by_key(&nrep, |x| x.0).unwrap_or_else(|e| e);
+        v.insert(pos, (nrep, times))
+    };
+    leak(DataProfiler { data: profile_dict })
+}
+
+#[no_mangle]
+unsafe extern fn destroy_profiler(profiler: *mut DataProfiler) {
+    free(profiler)
+}
+
+#[no_mangle]
+unsafe extern fn heft_rank(target: *mut Target, profiler: *const DataProfiler) {
+    scheduler::heft_rank(&mut *target, &*profiler, false)
+}
+
+#[no_mangle]
+unsafe extern fn heft_control(target: *mut Target, profiler: *const DataProfiler) { // this automatically calls rank inside
+    scheduler::heft_control(&mut *target, &*profiler)
+}
+
+/// see `scheduler::smooth_transfers`. Returns the number of gated transfers
+#[no_mangle]
+unsafe extern fn smooth_transfers(target: *mut Target, profiler: *const DataProfiler, threshold: u64, max_inflight: u32) -> u32 {
+    scheduler::smooth_transfers(&mut *target, &*profiler, threshold, max_inflight as _) as _
+}
+
+/// a copy of the target without dangling nodes, for the analyses that simulate a compiled target but must leave it as is
+fn pruned_copy(target: &Target) -> Target {
+    let mut copy = Target::new(target.pb.clone(), target.devices.clone(), target.links.clone(), target.paths.clone(), target.sinks.clone(), target.nccls.clone()).apply(|x| {
+        x.mesh = target.mesh.clone();
+        x.cpus = target.cpus.clone();
+        x.aux_variables = target.aux_variables.clone()
+    });
+    polishing::remove_dangling_nodes(&mut copy);
+    copy
+}
+
+/// simulate the target with the bandwidth of each link scaled by `1 + delta` in turn (see `simulator::bandwidth_sensitivity`), after removing
+/// the dangling nodes from a copy of it. The target is neither consumed nor changed.
+/// `result` receives the perturbed step time, the derivative and the elasticity of each link. Returns the unperturbed step time.
+#[no_mangle]
+unsafe extern fn bandwidth_sensitivity(target: *const Target, profiler: *const DataProfiler, delta: f64, result: *mut f64) -> u64 {
+    let result = std::slice::from_raw_parts_mut(result, 3 * (*target).links.len());
+    let (baseline, sensitivities) = simulator::bandwidth_sensitivity(&*profiler, &pruned_copy(&*target), delta);
+    for (x, row) in sensitivities.iter().zip(result.chunks_mut(3)) {
+        row[0] = x.time as _;
+        row[1] = x.derivative;
+        row[2] = x.elasticity;
+    }
+    baseline
+}
+
+/// write the ops × devices heatmap of the compiled target (see `heatmap::Heatmap`) to `path`, as html if it ends with .html and json otherwise.
+/// `scope_depth` groups the ops by name scope; 0 keeps one row per op. The dangling nodes are removed from a copy, so the target is neither
+/// consumed nor changed.
+#[no_mangle]
+unsafe extern fn placement_heatmap(target: *const Target, profiler: *const DataProfiler, scope_depth: u32, path: *const u8, path_len: u32) {
+    let path = std::str::from_utf8(std::slice::from_raw_parts(path, path_len as usize)).unwrap();
+    let heatmap = heatmap::Heatmap::new(&pruned_copy(&*target), &*profiler, if scope_depth == 0 { None } else { Some(scope_depth as _) });
+    let mut file = std::fs::File::create(path).unwrap();
+    if path.ends_with(".html") {
+        heatmap.write_html(&mut file).unwrap()
+    } else {
+        heatmap.write_json(&mut file).unwrap()
+    }
+}
+
+#[no_mangle]
+unsafe extern fn evaluate(target: *mut Target, profiler: *const DataProfiler, trace_path: *const u8, trace_len: u32, lifetime_path: *const u8, lifetime_len: u32, memory: *mut u64) -> u64 {
+    let simulator = simulator::SimpleSimulator;
+    let tracer = if trace_len == 0 {
+        None
+    } else {
+        Some(std::str::from_utf8(std::slice::from_raw_parts(trace_path, trace_len as usize)).unwrap())
+    };
+
+    if lifetime_len == 0 {
+        return simulator.evaluate(&*profiler, *reclaim(target), tracer.map(|x| std::fs::File::create(x).unwrap()).as_mut(), std::slice::from_raw_parts_mut(memory, (*target).devices.len()))
+    }
+
+    let lifetime_path = std::str::from_utf8(std::slice::from_raw_parts(lifetime_path, lifetime_len as usize)).unwrap();
+    let devices = (*target).devices.clone();
+    let mut lifetimes = vec![];
+    let time = simulator.evaluate_with_lifetimes(&*profiler, *reclaim(target), tracer.map(|x| std::fs::File::create(x).unwrap()).as_mut(), std::slice::from_raw_parts_mut(memory, devices.len()), Some(&mut lifetimes));
+
+    let mut file = std::fs::File::create(lifetime_path).unwrap();
+    if lifetime_path.ends_with(".svg") {
+        simulator::write_lifetimes_svg(&lifetimes, &devices, &mut file).unwrap()
+    } else {
+        simulator::write_lifetimes_json(&lifetimes, &devices, &mut file).unwrap()
+    }
+
+    time
+}
+
+#[no_mangle]
+unsafe extern fn remove_collocation_hint(target: *mut Target) {
+    polishing::remove_collocation_hint(&mut *target)
+}
+
+#[no_mangle]
+unsafe extern fn remove_shape_hint(target: *mut Target) {
+    polishing::remove_shape_hint(&mut *target)
+}
+
+/// pack cross-device tensors smaller than `threshold` bytes, see `polishing::coalesce_transfers`
+#[no_mangle]
+unsafe extern fn coalesce_transfers(target: *mut Target, threshold: u64) {
+    polishing::coalesce_transfers(&mut *target, threshold)
+}
+
+#[no_mangle]
+unsafe extern fn place_input_pipelines(target: *mut Target) {
+    polishing::place_input_pipelines(&mut *target)
+}
+
+#[no_mangle]
+unsafe extern fn assign_collective_streams(target: *mut Target) {
+    polishing::assign_collective_streams(&mut *target)
+}
+
+#[no_mangle]
+unsafe extern fn insert_step_markers(target: *mut Target) {
+    polishing::insert_step_markers(&mut *target)
+}
+
+#[no_mangle]
+unsafe extern fn destruct_names(target: *mut Target) {
+    polishing::destruct_names(&mut *target)
+}
+
+#[no_mangle]
+unsafe extern fn remove_dangling_nodes(target: *mut Target) {
+    polishing::remove_dangling_nodes(&mut *target);
+}
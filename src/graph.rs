@@ -8,9 +8,11 @@ use std::iter::FromIterator;
 use crate::proto::attr_value::AttrValue_ListValue;
 use std::hint::unreachable_unchecked;
 use std::rc::Rc;
+use crate::naming::parse_input;
 use std::cell::RefCell;
 use std::hash::Hash;
 use crate::misc::Target;
+use crate::registry::{OpRegistry, ShapeFn};
 
 #[derive(Default)]
 pub struct CollectiveState {
@@ -36,20 +38,49 @@ impl CollectiveState {
     }
 }
 
+/// a snapshot of compilation, reported periodically to the progress callback
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub compiled: usize, // number of original nodes compiled so far
+    pub total: usize,
+    pub aux_nodes: usize, // nodes emitted so far that are not replicas of original nodes
+    pub elapsed: f64, // seconds
+    pub eta: f64 // seconds, extrapolated linearly from the nodes compiled so far
+}
+
+pub type ProgressCallback = Box<dyn FnMut(&Progress)>;
+
+/// a summation of the parts of a tensor between two forms, e.g. `Tensor::aggregate_sum`
+pub type Summation = fn(&mut Tensor, &Form, &Form, &mut Target) -> Box<[String]>;
+
 #[derive(Default)]
 pub struct Graph {
     pub nodes: Vec<Node>, // This vector is partial ordered: inputs are guaranteed to appear earlier than descendants
     pub options: BTreeMap<String, String>,
     pub name_dict: BTreeMap<String, usize>,
+    pub registry: OpRegistry,
+    pub initializers: BTreeMap<usize, usize>, // nodes that only serve to compute the initial value of a variable => the variable
+    pub init_assigns: BTreeSet<usize>, // the Assign nodes that initialize variables
+    pub progress: Option<ProgressCallback>, // called at most once per `progress_interval_ms` (default 1000) during compilation, and once at the end
+    pub versions: crate::proto::versions::VersionDef, // of the input graph, see `set_versions`
+    pub cache: crate::cache::SubgraphCache, // only used with the option `subgraph_cache`
+    pub spill: Option<crate::spill::SpillFile>, // holds the large attrs of `nodes` with the option `spill_dir`, see `spill_large_attrs`
+    pub library: crate::proto::function::FunctionDefLibrary, // of the input graph, see `set_library`
+    pub source_locations: BTreeMap<String, String>, // node name => the Python code that created it (`file:line`), see `set_source_locations`
+    pub replica_scheme: crate::naming::ReplicaScheme, // parsed from the option `replica_format` once by `editor::edit`
 
     collective_state: CollectiveState
 }
 
 impl Graph {
     pub fn new(nodes: &[NodeDef]) -> Box<Self> {
+        Self::with_registry(nodes, OpRegistry::default())
+    }
+
+    pub fn with_registry(nodes: &[NodeDef], registry: OpRegistry) -> Box<Self> {
         task!("building graph of {} nodes...", nodes.len());
 
-        let mut g = Box::new(Graph { nodes: Vec::with_capacity(nodes.len()), ..Default::default() });
+        let mut g = Box::new(Graph { nodes: Vec::with_capacity(nodes.len()), registry, ..Default::default() });
 
         // not always optimal, but good enough since the input is actually mostly ordered
         let mut queue: std::collections::VecDeque::<_> = nodes.iter().collect();
@@ -73,20 +104,191 @@ impl Graph {
         }
 
         g.analyze();
+        g.analyze_initializers();
 
         g
     }
 
+    /// record the `versions` of the input graph, warning about nodes that the producer version implies to differ from the assumptions.
+    /// The emitted graph carries the versions derived by `versions::emitted`.
+    pub fn set_versions(&mut self, versions: &crate::proto::versions::VersionDef) {
+        let nodes: Vec<NodeDef> = self.nodes.iter().map(|x| x.raw_node.clone()).collect();
+        crate::versions::check(versions, &nodes);
+        self.versions = versions.clone()
+    }
+
+    /// record the function library of the input graph. The emitted graph carries the functions and gradients that its nodes refer to,
+    /// merged into whatever library the target already has.
+    pub fn set_library(&mut self, library: &crate::proto::function::FunctionDefLibrary) {
+        let nodes: Vec<NodeDef> = self.nodes.iter().map(|x| x.raw_node.clone()).collect();
+        crate::library::check(library, &nodes);
+        self.library = library.clone()
+    }
+
+    /// record where the nodes of the input graph were defined in the model code (`file:line`, e.g. from the tracebacks of the Python ops),
+    /// so diagnostics and reports can point at the code users recognize. Nodes created by grappler or function inlining are found through
+    /// the `original_node_names` of their `experimental_debug_info`.
+    pub fn set_source_locations(&mut self, locations: BTreeMap<String, String>) {
+        let unknown = locations.keys().filter(|x| !self.name_dict.contains_key(*x)).count();
+        if unknown > 0 {
+            debug!("{} of {} source locations are for nodes not in the graph", unknown, locations.len())
+        }
+        self.source_locations = locations
+    }
+
+    /// the source location of a node or tensor of the input graph, see `set_source_locations`
+    pub fn source_location(&self, name: &str) -> Option<&str> {
+        let name = parse_input(name).0;
+        if let Some(x) = self.source_locations.get(name) {
+            return Some(x)
+        }
+        let node = &self.nodes[*self.name_dict.get(name)?];
+        node.raw_node.get_experimental_debug_info().original_node_names.iter().find_map(|x| self.source_locations.get(x)).map(|x| &x[..])
+    }
+
+    /// the name followed by its source location if known, for diagnostics
+    pub fn describe(&self, name: &str) -> String {
+        match self.source_location(name) {
+            Some(location) => format!("{} (at {})", name, location),
+            None => name.to_string()
+        }
+    }
+
     /// setup the replicas and links. Note that auxiliary nodes are already there by strategies.
     pub fn compile(&mut self, target: &mut Target) {
         task!("compiling graph of {} nodes...", self.nodes.len());
+        target.pb.set_versions(crate::versions::emitted(&self.versions));
+        self.add_metadata_node(target);
+        if let Some(dir) = self.options.get("spill_dir").cloned() {
+            self.spill_large_attrs(&dir);
+        }
+        let mut progress = self.progress.take();
+        let interval = std::time::Duration::from_millis(self.options.get("progress_interval_ms").map(|x| x.parse().expect("progress_interval_ms should be a number")).unwrap_or(1000));
+        let start = std::time::Instant::now();
+        let mut last_report = start;
+        let (total, base) = (self.nodes.len(), target.pb.node.len());
+
+        let mut cache = if self.options.get("subgraph_cache").map(|x| x == "True").unwrap_or(false) {
+            Some(std::mem::take(&mut self.cache).apply(|x| x.validate(&self.options, &target.devices)))
+        } else {
+            None
+        };
+        let (hits, misses) = cache.as_ref().map(|x| (x.hits, x.misses)).unwrap_or_default();
+
+        // conversions are planned for the whole graph first, so passes over the plan can see all transfers before any of them is emitted
+        let plan = crate::plan::plan_transfers(self);
+        crate::plan::emit_transfers(self, &plan, target, cache.as_mut());
+        let mut replicas = 0;
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            match &mut cache {
+                Some(cache) => {
+                    let key = node.cache_key(target);
+                    if let Some(nodes) = cache.replicas(&key) {
+                        cache.hits += 1;
+                        target.pb.node.extend(nodes)
+                    } else {
+                        cache.misses += 1;
+                        let start = target.pb.node.len();
+                        node.compile(target);
+                        cache.insert_replicas(key, target.pb.node.iter().skip(start).cloned().collect());
+                    }
+                }
+                None => node.compile(target)
+            }
+            replicas += node.form.ndev();
+
+            if let Some(callback) = &mut progress {
+                let now = std::time::Instant::now();
+                if now - last_report >= interval || i + 1 == total {
+                    last_report = now;
+                    let elapsed = (now - start).as_secs_f64();
+                    let aux_nodes = (target.pb.node.len() - base).saturating_sub(replicas);
+                    callback(&Progress { compiled: i + 1, total, aux_nodes, elapsed, eta: elapsed / (i + 1) as f64 * (total - i - 1) as f64 })
+                }
+            }
+        }
+        self.progress = progress;
+        if let Some(cache) = cache {
+            info!("subgraph cache: {} hits, {} misses", cache.hits - hits, cache.misses - misses);
+            self.cache = cache
+        }
+
+        if self.options.get("collective_control_deps").map(|x| x != "none").unwrap_or(true) {
+            self.add_control_dependencies_for_collective_nodes(target);
+        }
+        if let Some(steps) = self.options.get("accumulation_steps") {
+            self.add_gradient_accumulation(target, steps.parse().expect("accumulation_steps should be a number"));
+        }
+        let async_sync_period: usize = self.options.get("async_sync_period").map(|x| x.parse().expect("async_sync_period should be a number")).unwrap_or(0);
+        if async_sync_period > 0 && self.options.contains_key("async_sgd") {
+            self.add_async_barriers(target, async_sync_period);
+        }
+        self.add_sync_replicas(target);
+        if self.options.contains_key("backup_required") {
+            self.add_backup_targets(target);
+        }
+        if let Some(period) = self.options.get("local_sgd_period") {
+            self.add_periodic_averaging(target, period.parse().expect("local_sgd_period should be a number"));
+        }
+        target.finalize_aux_variables(); // after the passes above, which create aux variables of their own
+        self.add_init_op(target);
+        if self.options.get("broadcast_op").map(|x| x == "True").unwrap_or(false) {
+            self.add_broadcast_op(target, Self::BROADCAST_OP, &[]);
+        }
+        if self.options.get("broadcast_on_init").map(|x| x == "True").unwrap_or(false) {
+            self.add_init_broadcast(target);
+        }
+        if let Some(seconds) = self.options.get("collective_timeout") {
+            set_collective_timeouts(target, seconds.parse().expect("collective_timeout should be a number of seconds"));
+        }
+
+        let mut library = target.pb.take_library();
+        crate::library::merge(&mut library, &crate::library::required(&self.library, &target.pb.node)).unwrap_or_else(|e| panic!("{}", e));
+        crate::library::check(&library, &target.pb.node);
+        if !library.function.is_empty() || !library.gradient.is_empty() {
+            target.pb.set_library(library)
+        }
+
+        if let Some(name) = self.options.get("audit_name") {
+            add_audit_node(target, name)
+        }
+        if let Some(spill) = &mut self.spill {
+            for node in target.pb.node.iter_mut() {
+                spill.restore_attrs(node).unwrap_or_else(|e| panic!("cannot read spilled attrs of {}: {}", node.name, e))
+            }
+        }
+    }
+
+    /// move the tensor attrs of the original nodes larger than the option `spill_threshold` to a scratch file in `dir` (see `spill`), so the
+    /// replicas emitted during compilation only carry references, which `compile` resolves at the end. The nodes stay spilled, so later
+    /// compilations (e.g. during a search) reuse the file.
+    fn spill_large_attrs(&mut self, dir: &str) {
+        let threshold = self.options.get("spill_threshold").map(|x| x.parse().expect("spill_threshold should be a number of bytes")).unwrap_or(crate::spill::DEFAULT_THRESHOLD);
+        let spill = self.spill.get_or_insert_with(|| crate::spill::SpillFile::create(dir).unwrap_or_else(|e| panic!("cannot create a scratch file in {}: {}", dir, e)));
+        let (mut bytes, mut count) = (0, 0);
         for node in self.nodes.iter_mut() {
-            node.compile(target)
+            let moved = spill.spill_attrs(&mut node.raw_node, threshold).unwrap_or_else(|e| panic!("cannot spill attrs of {}: {}", node.raw_node.name, e));
+            bytes += moved;
+            count += (moved > 0) as usize;
+        }
+        if count > 0 {
+            info!("spilled {} bytes of attrs of {} nodes, the scratch file has {} bytes", bytes, count, spill.len())
         }
+    }
 
-        self.add_control_dependencies_for_collective_nodes(target)
+    /// reroute transfers between devices that have no declared path through the hub device given by the `hub_device` option.
+    /// Returns an error listing the unroutable transfers if there is no hub or the hub itself is unreachable.
+    pub fn route_transfers(&self, target: &mut Target) -> Result<(), String> {
+        let hub = self.options.get("hub_device").map(|x| x.parse().expect("hub_device should be a device index"));
+        crate::polishing::route_via_hub(target, hub)
     }
 
+    pub const INIT_OP: &'static str = "tge_init";
+    pub const USER_INIT_OP: &'static str = "init"; // the default name of `tf.global_variables_initializer` in the input graph
+    pub const BROADCAST_OP: &'static str = "tge_broadcast";
+    pub const INIT_BROADCAST_OP: &'static str = "tge_init_broadcast";
+    pub const AUDIT_NODE: &'static str = "tge_audit";
+
     /// set flags and assign groups for nodes
     /// 1. mark tensors that has batchsize dimension with hand-crafted whitelist rules
     /// 2. group the nodes so that a.) all nodes inside a group is splittable and b.) all cross-group tensors are splittable
@@ -107,7 +309,8 @@ impl Graph {
         // mark batch splittability
         for node in self.nodes.iter_mut() {
             match &node.raw_node.op[..] {
-                "Placeholder" | "IteratorGetNext" | "Conv2D" | "MaxPool" | "Conv2DBackpropInput" | "BiasAdd" => {
+                _ if node.is_input() => node.get_output(0).set_flag(Tensor::IS_BATCHED),
+                "Conv2D" | "MaxPool" | "Conv2DBackpropInput" | "BiasAdd" => {
                     node.get_output(0).set_flag(Tensor::IS_BATCHED);
                 },
                 "Cast" | "ZerosLike" |"GreaterEqual" | "Neg" | "Log1p" | "Exp" | "Slice" |
@@ -124,12 +327,93 @@ impl Graph {
                         break
                     }
                 },
+                // a SparseTensor that comes from the input is batched by its rows. Its members are marked where they are consumed together, so
+                // triples produced by sparse ops (e.g. the indices and values of SparseReorder with the shape it passes through) are found as well.
+                op if !sparse_inputs(op).is_empty() => {
+                    let mut batched = false;
+                    for positions in sparse_inputs(op) {
+                        let triple = positions.map(|i| (node.inputs[i].0, node.inputs[i].1));
+                        if !triple[..2].iter().any(|(id, index)| node.graph().nodes[*id].get_output(*index).has_flag(Tensor::IS_FROM_INPUT)) {
+                            continue
+                        }
+                        for (role, (id, index)) in triple.iter().enumerate() {
+                            let member = node.graph().nodes[*id].get_output(*index);
+                            member.set_flag(Tensor::IS_SPARSE);
+                            member.unset_flag(Tensor::IS_BATCHED);
+                            member.sparse_triple = Some((triple, role));
+                        }
+                        batched = true;
+                    }
+                    let dense_rows = match op {
+                        "SparseTensorDenseMatMul" => !node.raw_node.attr.get("adjoint_a").map(|x| x.get_b()).unwrap_or(false),
+                        "SparseTensorDenseAdd" | "SparseToDense" => true,
+                        _ => false
+                    };
+                    if batched && dense_rows {
+                        node.get_output(0).set_flag(Tensor::IS_BATCHED);
+                    }
+                },
+                // the flat values and row splits of a RaggedTensor are not batched even if the RaggedTensor is: splitting them along the first
+                // dimension would cut rows in the middle and leave the splits unshifted. They are kept whole, see `editor::keep_ragged_whole`.
+                // The dense tensor of RaggedTensorToTensor has rows again.
+                op if !ragged_inputs(&node.raw_node).is_empty() => {
+                    let mut from_input = false;
+                    for i in ragged_inputs(&node.raw_node) {
+                        let (id, index, _) = node.inputs[i];
+                        let component = node.graph().nodes[id].get_output(index);
+                        from_input |= component.has_flag(Tensor::IS_FROM_INPUT);
+                        component.set_flag(Tensor::IS_RAGGED);
+                        component.unset_flag(Tensor::IS_BATCHED);
+                    }
+                    if from_input && op == "RaggedTensorToTensor" {
+                        node.get_output(0).set_flag(Tensor::IS_BATCHED);
+                    }
+                },
+                "Shape" | "ShapeN" => for (i, (id, index, _)) in node.inputs.clone().into_iter().enumerate() {
+                    if node.graph().nodes[id].get_output(index).has_flag(Tensor::IS_BATCHED) {
+                        node.get_output(i).set_flag(Tensor::IS_SHAPE);
+                    }
+                },
+                // the gradients of broadcasting ops: `Sum(grad, BroadcastGradientArgs(Shape(x), Shape(y)):k)` reshaped to the k-th shape.
+                // The shapes of the Shape replicas are per slice, but the reduction indices only depend on which dimensions are broadcast, and
+                // the batch dimension of a batched operand is never reduced. The shapes of these nodes are unknown, so they are marked here.
+                "BroadcastGradientArgs" if node.inputs.iter().any(|(id, index, _)| node.graph().nodes[*id].get_output(*index).has_flag(Tensor::IS_SHAPE)) => {
+                    node.get_output(0).set_flag(Tensor::IS_INVARIANT);
+                    node.get_output(1).set_flag(Tensor::IS_INVARIANT);
+                },
+                "Sum" => {
+                    let (id, index, _) = node.inputs[0];
+                    let (axes_id, axes_index, _) = node.inputs[1];
+                    let axes = &node.graph().nodes[axes_id];
+                    let keeps_batch = axes.raw_node.op == "BroadcastGradientArgs" && {
+                        let (shape_id, shape_index, _) = axes.inputs[axes_index];
+                        node.graph().nodes[shape_id].get_output(shape_index).has_flag(Tensor::IS_SHAPE)
+                    };
+                    if keeps_batch && node.graph().nodes[id].get_output(index).has_flag(Tensor::IS_BATCHED) {
+                        node.get_output(0).set_flag(Tensor::IS_BATCHED);
+                    }
+                },
+                "Reshape" => {
+                    let (id, index, _) = node.inputs[0];
+                    let (shape_id, shape_index, _) = node.inputs[1];
+                    if node.graph().nodes[id].get_output(index).has_flag(Tensor::IS_BATCHED) && node.graph().nodes[shape_id].get_output(shape_index).has_flag(Tensor::IS_SHAPE) {
+                        node.get_output(0).set_flag(Tensor::IS_BATCHED);
+                    }
+                },
                 "MatMul" => {
                     let (id, index, _) = &node.inputs[0];
                     if node.graph().nodes[*id].get_output(*index).has_flag(Tensor::IS_BATCHED) && !node.raw_node.attr["transpose_a"].get_b() {
                         node.get_output(0).set_flag(Tensor::IS_BATCHED);
                     }
                 }
+                op if node.graph().registry.get(op).map(|x| x.splittable).unwrap_or(false) => { // user declared that the op works on batch slices
+                    let (id, index, _) = &node.inputs[0];
+                    if node.graph().nodes[*id].get_output(*index).has_flag(Tensor::IS_BATCHED) {
+                        for i in 0..node.graph().registry.get(op).unwrap().dtypes.len() {
+                            node.get_output(i).set_flag(Tensor::IS_BATCHED);
+                        }
+                    }
+                }
                 _ => {}
                 // todo: Select?
                 // todo: shape -> fill or shape -> broadcast also gives a splittable tensor
@@ -139,25 +423,16 @@ impl Graph {
         // hacks
         for (node_id, node) in self.nodes.iter_mut().enumerate() {
             match &node.raw_node.op[..] {
-                "ApplyGradientDescent" => { // ensure gradients don't have batch dimension so they will be summed
-                    let (id, index, _) = &node.inputs[2];
-                    node.graph().nodes[*id].get_output(*index).unset_flag(Tensor::IS_BATCHED);
-                    // assign it with the variable to the same group
-                    let (id, _, _) = &node.inputs[0];
-                    node.group = Some(Rc::new(RefCell::new(vec![node_id, *id])));
-                    node.graph().nodes[*id].group = node.group.clone()
-                },
-                "ApplyAdam" => {
-                    let (id, index, _) = &node.inputs[9];
+                op if optimizer_inputs(op).is_some() => { // ensure gradients don't have batch dimension so they will be summed
+                    let (grad_index, states) = optimizer_inputs(op).unwrap();
+                    let (id, index, _) = &node.inputs[grad_index];
                     node.graph().nodes[*id].get_output(*index).unset_flag(Tensor::IS_BATCHED);
                     // assign it with the variable and optimizer states to the same group
-                    let (var_id, _, _) = &node.inputs[0];
-                    let (m_id, _, _) = &node.inputs[1];
-                    let (v_id, _, _) = &node.inputs[2];
-                    node.group = Some(Rc::new(RefCell::new(vec![node_id, *var_id, *m_id, *v_id])));
-                    node.graph().nodes[*var_id].group = node.group.clone();
-                    node.graph().nodes[*m_id].group = node.group.clone();
-                    node.graph().nodes[*v_id].group = node.group.clone()
+                    let group: Vec<_> = std::iter::once(node_id).chain(states.iter().map(|i| node.inputs[*i].0)).collect();
+                    node.group = Some(Rc::new(RefCell::new(group.clone())));
+                    for id in &group[1..] {
+                        node.graph().nodes[*id].group = node.group.clone()
+                    }
                 },
                 "ScatterSub" => { // these tensors, however, should be concated
                     let (indices_id, indices_index, _) = &node.inputs[1];
@@ -181,7 +456,7 @@ impl Graph {
 
             for (input_id, index, _) in node.inputs.iter() {
                 let input = &mut node.graph().nodes[*input_id];
-                if input.group.is_some() && !input.get_output(*index).has_flag(Tensor::IS_BATCHED) { // should be attached into the same group
+                if input.group.is_some() && !input.get_output(*index).has_flag(Tensor::IS_BATCHED | Tensor::IS_SPARSE) { // should be attached into the same group
                     let input_group = input.group.as_ref().cloned().unwrap();
                     match &node.group {
                         None => { // this node is not yet assigned into a group, so we just add it into the group of the input
@@ -206,505 +481,3657 @@ impl Graph {
         }
     }
 
-    pub fn get_groups(&self) -> BTreeMap<&str, Option<impl Hash + Ord>> {
-        self.nodes.iter().map(|node| {
-            (&node.raw_node.name[..], node.group.as_ref().map(|x| x.as_ptr()))
-        }).collect()
-    }
+    /// find the initializer subgraph of each variable: the value input of an `Assign` to the variable that initializes it, plus all its
+    /// ancestors that are consumed by nothing else. These nodes should run only once on the device of the variable. An Assign initializes
+    /// the variable if the init op (`Self::USER_INIT_OP`) runs it, or if its value is computed from neither variables nor model inputs,
+    /// e.g. a Const or a random initializer. Other assigns, like moving averages, copies and counters, run at every step as usual.
+    fn analyze_initializers(&mut self) {
+        let mut consumers = vec![vec![]; self.nodes.len()];
+        for (id, node) in self.nodes.iter().enumerate() {
+            for input_id in node.inputs.iter().map(|x| x.0).chain(node.controls.iter().copied()) {
+                consumers[input_id].push(id)
+            }
+        }
 
-    fn add_control_dependencies_for_collective_nodes(&mut self, target: &mut Target) {
-        // TODO: findout existing dependencies (added by fusing iterations) and avoid dead lock
-        for pair in self.collective_state.instances.windows(2) {
-            for &i in &pair[0] {
-                for &j in &pair[1] {
-                    let input = format!("^{}", target.pb.node.get(j).unwrap().name);
-                    target.pb.node.get_mut(i).unwrap().input.push(input)
-                }
+        // the ancestors of the init op of the input graph
+        let mut run_by_init = BTreeSet::new();
+        let mut queue: Vec<usize> = self.name_dict.get(Self::USER_INIT_OP).copied().filter(|x| self.nodes[*x].raw_node.op == "NoOp").into_iter().collect();
+        while let Some(id) = queue.pop() {
+            if run_by_init.insert(id) {
+                queue.extend(self.nodes[id].inputs.iter().map(|x| x.0).chain(self.nodes[id].controls.iter().copied()))
             }
         }
-    }
-}
 
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Copy)]
-pub enum FormKind { Full, Part }
+        // whether a node is computed from neither variables nor model inputs. The nodes are partially ordered, so the inputs come first
+        let mut constant = vec![false; self.nodes.len()];
+        for (id, node) in self.nodes.iter().enumerate() {
+            constant[id] = !node.is_variable() && !node.is_input() && node.inputs.iter().all(|x| constant[x.0])
+        }
 
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
-pub struct Form {
-    pub kind: FormKind,
-    pub devices: Vec<usize> // The Vec must be sorted and not empty, but may contains repeated elements (put multiple replicas on the same device)
-}
+        for (id, node) in self.nodes.iter().enumerate() {
+            if !node.is_assign() || node.inputs.len() < 2 || !self.nodes[node.inputs[0].0].is_variable() {
+                continue
+            }
+            if run_by_init.contains(&id) || constant[node.inputs[1].0] {
+                self.init_assigns.insert(id);
+            }
+        }
 
-impl Form {
-    pub fn is_full(&self) -> bool {
-        self.kind == FormKind::Full
-    }
+        // the nodes are partially ordered, so iterating reversely visits all consumers before a node
+        for id in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[id];
+            if node.is_variable() || node.is_input() || node.is_stateful() || consumers[id].is_empty() {
+                continue
+            }
 
-    pub fn is_part(&self) -> bool {
-        self.kind == FormKind::Part
-    }
+            let var = consumers[id].iter().map(|c| if self.init_assigns.contains(c) {
+                Some(self.nodes[*c].inputs[0].0)
+            } else {
+                self.initializers.get(c).copied()
+            }).collect::<Option<Vec<_>>>();
 
-    pub fn ndev(&self) -> usize {
-        self.devices.len()
+            if let Some(var) = var {
+                self.initializers.insert(id, var[0]);
+            }
+        }
     }
 
-    // TODO: use to_string() and parse()?
-    pub fn code(&self) -> String {
-        let mut x = String::from(if self.is_full() {"full"} else {"part"});
-        for d in self.devices.iter() {
-            x += "_";
-            x += &d.to_string();
+    /// a Const string node `tge_metadata` holding a JSON record of what readers of the compiled graph need to know, currently
+    /// `{"replica_format": .., "seed": ..}` (see `naming::ReplicaScheme` and `rng::RngConfig`). Only emitted for a non-default replica scheme
+    /// or an explicit seed, and the seed is left out if it was not set, so a missing record means the defaults.
+    fn add_metadata_node(&self, target: &mut Target) {
+        let scheme = &self.replica_scheme;
+        let seed = self.options.contains_key("seed").then(|| crate::rng::RngConfig::from_options(&self.options).seed);
+        if (*scheme == Default::default() && seed.is_none()) || target.pb.node.iter().any(|x| x.name == crate::naming::ReplicaScheme::METADATA_NODE) {
+            return
+        }
+        for node in self.nodes.iter().filter(|_| *scheme != Default::default()) {
+            if let Some((original, index)) = scheme.original_name(&node.raw_node.name) {
+                warn!("{} already reads as replica {} of {} under the replica format {}", node.raw_node.name, index, original, scheme.format())
+            }
         }
-        x
-    }
 
-    pub fn from_code(code: &str) -> Self {
-        let segs: Vec<_> = code.split('_').collect();
-        let kind = match segs[0] {
-            "full" => FormKind::Full,
-            "part" => FormKind::Part,
-            _ => unreachable!()
-        };
-        Self { kind, devices: segs[1..].iter().map(|x| x.parse().unwrap()).collect() }
+        let mut fields = vec![format!("\"replica_format\": {:?}", scheme.format())];
+        if let Some(seed) = seed {
+            fields.push(format!("\"seed\": {}", seed))
+        }
+        let mut node = NodeDef::new();
+        node.name = crate::naming::ReplicaScheme::METADATA_NODE.into();
+        node.op = "Const".into();
+        node.device = target.devices[0].clone();
+        node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_STRING)));
+        node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| {
+            t.set_dtype(DataType::DT_STRING);
+            t.string_val.push(format!("{{{}}}", fields.join(", ")).into_bytes());
+        }))));
+        target.pb.node.push(node)
     }
 
-    pub fn valid(&self) -> bool {
-        !self.devices.is_empty()
+    /// a NoOp that runs all variable initializers and aux variable initializers
+    fn add_init_op(&mut self, target: &mut Target) {
+        let mut init = NodeDef::new();
+        init.name = Self::INIT_OP.into();
+        init.op = "NoOp".into();
+        init.device = target.devices[0].clone();
+        for id in self.init_assigns.iter() {
+            let node = &self.nodes[*id];
+            for i in 0..node.form.ndev() {
+                init.input.push(format!("^{}", node.replica(i)))
+            }
+        }
+        if !target.aux_variables.is_empty() {
+            init.input.push(format!("^{}", Target::AUX_INIT_OP))
+        }
+        target.pb.node.push(init)
     }
-}
-
-type Group = Rc<RefCell<Vec<usize>>>;
 
-pub struct Node {
-    pub graph: *const Graph,
-    pub raw_node: NodeDef,
-    pub controls: Vec<usize>, // TODO: more consideration for control dependencies that added aux nodes
-    pub inputs: Vec<(usize, usize, FormKind)>, // nodeid, index, formkind (defaults to full)
-    pub outputs: Vec<Tensor>,
-    pub form: Form, // the form of the node, which is also a tensor form for all its outputs
-    pub group: Option<Group>,
-}
+    /// for each Placeholder compiled into per-replica placeholders, the replica names in the order of batch slices.
+    /// Empty if `placeholder_mode` is `split` since the original placeholders are kept then.
+    pub fn feed_mapping(&self) -> Vec<(&str, Vec<String>)> {
+        if self.options.get("placeholder_mode").map(|x| x == "split").unwrap_or(false) {
+            return vec![]
+        }
+        self.nodes.iter().filter(|node| node.raw_node.op == "Placeholder" && node.form.is_part()).map(|node| {
+            (&node.raw_node.name[..], (0..node.form.ndev()).map(|i| node.replica(i)).collect())
+        }).collect()
+    }
 
-impl Node {
-    pub fn new(graph: &Graph, raw_node: NodeDef) -> Self {
-        let mut inputs = vec![];
-        let mut controls = vec![];
+    /// the gradients of stragglers are not needed by the m-of-n updates (see `Tensor::aggregate_sum_backup`), so TF would prune them.
+    /// Make each replica of the sinks depend on the accumulator applies of the gradients computed on its device.
+    fn add_backup_targets(&mut self, target: &mut Target) {
+        let devices: BTreeMap<String, String> = target.pb.node.iter().filter(|x| x.name.contains("/aux_backup/local_step_")).map(|x| (x.name.clone(), x.device.clone())).collect();
+        let mut applies: BTreeMap<String, Vec<String>> = BTreeMap::new(); // device => applies
+        for node in target.pb.node.iter().filter(|x| x.op == "AccumulatorApplyGradient" && x.name.contains("/aux_backup/apply_")) {
+            applies.entry(devices[&node.input[1]].clone()).or_default().push(node.name.clone())
+        }
 
-        for input in raw_node.input.iter() {
-            if input.starts_with('^') {
-                controls.push(graph.name_dict[&input[1..]])
-            } else {
-                let (name, index) = parse_input(input);
-                let id = graph.name_dict[name];
-                inputs.push((id, index, FormKind::Full))
+        let sinks: BTreeSet<&str> = target.sinks.iter().map(|x| &x[..]).collect();
+        for node in target.pb.node.iter_mut() {
+            let origin = node.attr.get("_tge_origin").map(|x| String::from_utf8_lossy(x.get_s()).into_owned());
+            if origin.map(|x| sinks.contains(&x[..])).unwrap_or(false) {
+                for apply in applies.get(&node.device).into_iter().flatten() {
+                    node.input.push(format!("^{}", apply))
+                }
             }
         }
+    }
 
-        Self {
-            graph, raw_node, controls, inputs, outputs: vec![],
-            form: Form { kind: FormKind::Full, devices: vec![] },
-            group: None
+    /// the chief side of the SyncReplicasOptimizer pattern for the gradients aggregated with method 4 (see `Tensor::aggregate_sum_sync`).
+    /// After every variable is updated with the taken gradients, the global step is incremented and one token per device is enqueued.
+    /// Each replica of the sinks dequeues a token and stores it in its local step, so no replica starts the next step before the update,
+    /// and the gradients it then delivers are tagged with the new global step.
+    fn add_sync_replicas(&mut self, target: &mut Target) {
+        let takes: BTreeSet<String> = target.pb.node.iter().filter(|x| x.op == "AccumulatorTakeGradient" && x.name.contains("/aux_sync/")).map(|x| x.name.clone()).collect();
+        if takes.is_empty() {
+            return
+        }
+        let sums: BTreeSet<String> = takes.iter().map(|x| format!("{}/sum", &x[..x.len() - "/take".len()])).collect();
+        // the apply ops may read the sum through the aux nodes of `reduce_with_cast` and `finish_reduction`, which are followed to their consumers
+        let mut reads = sums;
+        let mut updates: Vec<String> = vec![];
+        loop {
+            let consumers: Vec<&NodeDef> = target.pb.node.iter().filter(|x| x.input.iter().any(|i| reads.contains(i))).collect();
+            if consumers.is_empty() {
+                break
+            }
+            let (aux, apply): (Vec<&NodeDef>, Vec<&NodeDef>) = consumers.into_iter().partition(|x| x.name.contains("/aux_cast_back_") || x.name.contains("/aux_mean/"));
+            updates.extend(apply.into_iter().map(|x| x.name.clone()));
+            reads = aux.into_iter().map(|x| x.name.clone()).collect();
         }
-    }
 
-    #[allow(clippy::mut_from_ref)]
-    pub fn graph<'a>(&self) -> &'a mut Graph {
-        unsafe { &mut *(self.graph as *mut Graph) }
-    }
+        let prefix = "tge_sync_replicas";
+        let global_step = target.create_aux_variable("sync_replicas/global_step", 0, DataType::DT_INT64, &[], 0.);
+        let device0 = target.devices[0].clone();
+        let host0 = target.host_cpu(0); // the queue ops only have CPU kernels
+        let int64 = AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT64));
+        let scalar = |name: String, dtype: DataType, value: i64| {
+            let mut node = NodeDef::new();
+            node.name = name;
+            node.op = "Const".into();
+            node.device = device0.clone();
+            node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(dtype)));
+            node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| {
+                t.set_dtype(dtype);
+                if dtype == DataType::DT_INT64 { t.int64_val.push(value) } else { t.int_val.push(value as _) }
+            }))));
+            node
+        };
 
-    pub fn get_output(&mut self, index: usize) -> &mut Tensor {
-        while self.outputs.len() <= index {
-            self.outputs.push(Tensor::new(self, self.outputs.len()))
+        let replica_devices: Vec<usize> = (0..target.ndev()).filter(|d| target.aux_variables.iter().any(|x| x.name.ends_with(&format!("sync_replicas/local_step_{}", d)))).collect();
+        let local_steps: Vec<String> = replica_devices.iter().map(|d| sync_local_step(target, *d)).collect();
+
+        let mut queue = NodeDef::new();
+        queue.name = format!("{}/token_queue", prefix);
+        queue.op = "FIFOQueueV2".into();
+        queue.device = host0.clone();
+        queue.attr.insert("component_types".into(), AttrValue::new().apply(|x| x.mut_list().field_type.push(DataType::DT_INT64)));
+        queue.attr.insert("shapes".into(), AttrValue::new().apply(|x| x.mut_list().shape.push(crate::proto::tensor_shape::TensorShapeProto::new())));
+        queue.attr.insert("capacity".into(), AttrValue::new().apply(|x| x.set_i(-1)));
+        queue.attr.insert("container".into(), AttrValue::new().apply(|x| x.set_s(vec![])));
+        queue.attr.insert("shared_name".into(), AttrValue::new().apply(|x| x.set_s(vec![])));
+
+        let one = scalar(format!("{}/one", prefix), DataType::DT_INT64, 1);
+        let mut increment = NodeDef::new();
+        increment.name = format!("{}/update_global_step", prefix);
+        increment.op = "AssignAdd".into();
+        increment.device = device0.clone();
+        increment.input.push(global_step);
+        increment.input.push(one.name.clone());
+        for update in updates.iter() {
+            increment.input.push(format!("^{}", update));
+        }
+        increment.attr.insert("T".into(), int64.clone());
+        increment.attr.insert("use_locking".into(), AttrValue::new().apply(|x| x.set_b(true)));
+
+        let mut count = scalar(format!("{}/token_count", prefix), DataType::DT_INT32, replica_devices.len() as _);
+        count.attr.get_mut("value").unwrap().mut_tensor().set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|s| { // Fill takes the dims as a vector
+            s.dim.push(crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|d| d.size = 1))
+        }));
+        let mut tokens = NodeDef::new();
+        tokens.name = format!("{}/tokens", prefix);
+        tokens.op = "Fill".into();
+        tokens.device = device0.clone();
+        tokens.input.push(count.name.clone());
+        tokens.input.push(increment.name.clone());
+        tokens.attr.insert("T".into(), int64.clone());
+        tokens.attr.insert("index_type".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+
+        let mut enqueue = NodeDef::new();
+        enqueue.name = format!("{}/enqueue_tokens", prefix);
+        enqueue.op = "QueueEnqueueManyV2".into();
+        enqueue.device = host0.clone();
+        enqueue.input.push(queue.name.clone());
+        enqueue.input.push(tokens.name.clone());
+        enqueue.attr.insert("Tcomponents".into(), AttrValue::new().apply(|x| x.mut_list().field_type.push(DataType::DT_INT64)));
+        enqueue.attr.insert("timeout_ms".into(), AttrValue::new().apply(|x| x.set_i(-1)));
+
+        let mut waits: BTreeMap<String, String> = BTreeMap::new(); // device name => the op that a replica of the sinks waits for
+        let mut new_nodes = vec![];
+        for (d, local_step) in replica_devices.iter().zip(local_steps) {
+            // queue ops are colocated with the queue, only the assignment happens on the replica
+            let mut dequeue = NodeDef::new();
+            dequeue.name = format!("{}/dequeue_{}", prefix, d);
+            dequeue.op = "QueueDequeueV2".into();
+            dequeue.device = host0.clone();
+            dequeue.input.push(queue.name.clone());
+            dequeue.input.push(format!("^{}", enqueue.name));
+            dequeue.attr.insert("component_types".into(), AttrValue::new().apply(|x| x.mut_list().field_type.push(DataType::DT_INT64)));
+            dequeue.attr.insert("timeout_ms".into(), AttrValue::new().apply(|x| x.set_i(-1)));
+
+            let mut assign = NodeDef::new();
+            assign.name = format!("{}/assign_local_step_{}", prefix, d);
+            assign.op = "Assign".into();
+            assign.device = target.devices[*d].clone();
+            assign.input.push(local_step);
+            assign.input.push(dequeue.name.clone());
+            assign.attr.insert("T".into(), int64.clone());
+            assign.attr.insert("use_locking".into(), AttrValue::new().apply(|x| x.set_b(true)));
+            assign.attr.insert("validate_shape".into(), AttrValue::new().apply(|x| x.set_b(true)));
+
+            waits.insert(assign.device.clone(), assign.name.clone());
+            new_nodes.push(dequeue);
+            new_nodes.push(assign);
         }
 
-        &mut self.outputs[index]
-    }
+        info!("sync replicas: {} accumulators, {} tokens per step", takes.len(), replica_devices.len());
 
-    pub fn replicated(&self) -> Option<bool> {
-        match self.form.ndev() {
-            0 => None,
-            1 => Some(false),
-            _ => Some(true)
+        let sinks: BTreeSet<&str> = target.sinks.iter().map(|x| &x[..]).collect();
+        for node in target.pb.node.iter_mut() {
+            let origin = node.attr.get("_tge_origin").map(|x| String::from_utf8_lossy(x.get_s()).into_owned());
+            if origin.map(|x| sinks.contains(&x[..])).unwrap_or(false) {
+                match waits.get(&node.device) {
+                    Some(wait) => node.input.push(format!("^{}", wait)),
+                    None => node.input.push(format!("^{}", enqueue.name)) // the chief must still run the update when it has no replica
+                }
+            }
+        }
+        for node in vec![queue, one, increment, count, tokens, enqueue].into_iter().chain(new_nodes) {
+            target.pb.node.push(node)
         }
     }
 
-    /// add an edited node into the target. Requires all inputs to be compiled first
-    fn compile(&mut self, target: &mut Target) {
-        if self.graph().options.get("log_forms").map(|x| x == "True").unwrap_or(false) {
-            info!("compile: {} {:?} {:?}", self.raw_node.name, self.form, self.inputs);
-        }
+    /// gradient accumulation: each replica of the gradients of optimizer ops adds its value to an aux accumulator in every step (a micro-batch),
+    /// and only every `steps` steps the mean of the accumulated values is passed on to the aggregation and the optimizer, after which the
+    /// accumulator is zeroed. The consumers of the gradient replicas read the true branch of a Switch on `step % steps == 0`, so the
+    /// aggregation and the update are skipped (their tensors are dead) in other steps, and so are the sinks, which TF treats as completed.
+    fn add_gradient_accumulation(&mut self, target: &mut Target, steps: usize) {
+        let (mut new_nodes, pred_name) = periodic_predicate(target, "tge_grad_accumulation", "grad_accumulation/step", steps);
 
-        for (replica_index, device_id) in self.form.devices.iter().enumerate() {
-            // 0. replace placeholders
-            if self.raw_node.op == "Placeholder" {
-                if let Some(batchsize) = self.graph().options.get("replace_placeholder") {
-                    let batchsize: usize = batchsize.parse().unwrap();
-                    let mut shape: Vec<Option<usize>> = self.raw_node.attr["_output_shapes"].get_list().shape[0].dim.iter().map(|x| x.size.try_into().ok()).collect();
-                    if self.form.is_part() {
-                        shape[0].replace(batchsize / self.form.ndev() as usize);
+        let mut grads: Vec<(usize, usize)> = self.nodes.iter().filter_map(|node| {
+            optimizer_inputs(&node.raw_node.op).map(|(grad_index, _)| (node.inputs[grad_index].0, node.inputs[grad_index].1))
+        }).collect();
+        grads.sort_unstable();
+        grads.dedup();
+
+        let mut resets = vec![];
+        let mut renames: BTreeMap<(String, usize), String> = BTreeMap::new(); // gradient replica => its accumulated mean
+        for (id, index) in grads.iter().copied() {
+            let grad = self.nodes[id].get_output(index);
+            let (dtype, shape, name) = (grad.dtype(), grad.get_shape(), grad.original_name());
+            let scalar = grad.node().raw_node.attr.get("_output_shapes").map(|x| x.get_list().shape.get(index).map(|s| !s.unknown_rank && s.dim.is_empty()).unwrap_or(false)).unwrap_or(false);
+            if shape.is_empty() && !scalar {
+                panic!("gradient accumulation needs the shape of {}", self.describe(&name))
+            }
+            if !matches!(dtype.get_field_type(), DataType::DT_FLOAT | DataType::DT_DOUBLE) {
+                panic!("gradient accumulation only supports float and double gradients, but {} is {:?}", name, dtype.get_field_type())
+            }
+            let node = &self.nodes[id];
+            let prefix = format!("{}/aux_accumulation/{}", node.raw_node.name, index);
+
+            for (i, device_id) in node.form.devices.iter().copied().enumerate() {
+                let device = target.devices[device_id].clone();
+                let acc = target.create_aux_variable(&format!("grad_accumulation/{}_{}/{}", node.raw_node.name, index, i), device_id, dtype.get_field_type(), &shape, 0.);
+
+                let mut add = node.make_node("AssignAdd".to_string());
+                add.name = format!("{}/add_{}", prefix, i);
+                add.device = device.clone();
+                add.input.push(acc.clone());
+                add.input.push(format!("{}:{}", node.replica(i), index));
+                add.attr.insert("T".into(), dtype.clone());
+                add.attr.insert("use_locking".into(), AttrValue::new().apply(|x| x.set_b(true)));
+
+                let mut switch = node.make_node("Switch".to_string());
+                switch.name = format!("{}/switch_{}", prefix, i);
+                switch.device = device.clone();
+                switch.input.push(add.name.clone());
+                switch.input.push(pred_name.clone());
+                switch.attr.insert("T".into(), dtype.clone());
+
+                let mut count = node.make_node("Const".to_string());
+                count.name = format!("{}/count_{}", prefix, i);
+                count.device = device.clone();
+                count.attr.insert("dtype".into(), dtype.clone());
+                count.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| {
+                    t.set_dtype(dtype.get_field_type());
+                    if dtype.get_field_type() == DataType::DT_DOUBLE {
+                        t.double_val.push(steps as _)
                     } else {
-                        shape[0].replace(batchsize);
+                        t.float_val.push(steps as _)
                     }
+                }))));
+
+                // the mean is a new tensor, so zeroing the accumulator after it is computed does not change what is sent
+                let mut mean = node.make_node("RealDiv".to_string());
+                mean.name = format!("{}/mean_{}", prefix, i);
+                mean.device = device.clone();
+                mean.input.push(format!("{}:1", switch.name));
+                mean.input.push(count.name.clone());
+                mean.attr.insert("T".into(), dtype.clone());
+
+                let mut zeros = node.make_node("ZerosLike".to_string());
+                zeros.name = format!("{}/zeros_{}", prefix, i);
+                zeros.device = device.clone();
+                zeros.input.push(mean.name.clone());
+                zeros.attr.insert("T".into(), dtype.clone());
+
+                let mut reset = node.make_node("Assign".to_string());
+                reset.name = format!("{}/reset_{}", prefix, i);
+                reset.device = device.clone();
+                reset.input.push(acc);
+                reset.input.push(zeros.name.clone());
+                reset.attr.insert("T".into(), dtype.clone());
+                reset.attr.insert("use_locking".into(), AttrValue::new().apply(|x| x.set_b(true)));
+                reset.attr.insert("validate_shape".into(), AttrValue::new().apply(|x| x.set_b(true)));
+
+                renames.insert((node.replica(i), index), mean.name.clone());
+                resets.push(reset.name.clone());
+                new_nodes.extend([add, switch, count, mean, zeros, reset]);
+            }
+        }
 
-                    let mut shape_node = self.make_node("Const".to_string());
-                    shape_node.name += &format!("/aux_replace_placeholder_{}/shape", replica_index);
-                    shape_node.device = target.devices[*device_id].clone();
-                    shape_node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
-                    let value = crate::proto::tensor::TensorProto::new().apply(|x| {
-                        x.set_dtype(DataType::DT_INT32);
-                        x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|s| s.set_dim([shape.len()].iter().map(|x| {crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|fuck| fuck.size = *x as _)}).collect())));
-                        for dim in shape {
-                            x.int_val.push(dim.unwrap() as _);
-                        }
-                    });
-                    shape_node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
-
-                    let mut random_node = NodeDef::new().apply(|x| x.op = "RandomUniform".to_string());
-                    random_node.name = self.replica(replica_index);
-                    random_node.device = target.devices[*device_id].clone();
-                    set_origin(&mut random_node, &self.raw_node.name);
-                    set_form(&mut random_node, &self.form.code());
-                    random_node.input.push(shape_node.name.clone());
-                    random_node.attr.insert("T".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
-                    random_node.attr.insert("dtype".into(), get_dtype(&self.raw_node, 0));
-
-                    target.pb.node.push(shape_node);
-                    target.pb.node.push(random_node);
+        // every consumer of the gradient replicas (the aggregation or the optimizer) reads the mean instead
+        let sinks: BTreeSet<&str> = target.sinks.iter().map(|x| &x[..]).collect();
+        for node in target.pb.node.iter_mut() {
+            for input in node.input.iter_mut() {
+                if input.starts_with('^') {
                     continue
                 }
+                let (name, index) = crate::naming::parse_input(input);
+                if let Some(mean) = renames.get(&(name.to_string(), index)) {
+                    *input = mean.clone()
+                }
+            }
+            let origin = node.attr.get("_tge_origin").map(|x| String::from_utf8_lossy(x.get_s()).into_owned());
+            if origin.map(|x| sinks.contains(&x[..])).unwrap_or(false) {
+                for reset in resets.iter() {
+                    node.input.push(format!("^{}", reset))
+                }
             }
+        }
 
-            // 1. setup basic node info
-            let mut node = self.raw_node.clone();
-            node.name = self.replica(replica_index);
-            node.device = target.devices[*device_id].clone();
-            set_origin(&mut node, &self.raw_node.name);
-            set_form(&mut node, &self.form.code());
+        info!("gradient accumulation: {} gradients are accumulated over {} steps", grads.len(), steps);
+        target.pb.node.extend(new_nodes);
+    }
 
-            // 2. link inputs and set size
-            node.input = self.inputs.iter().copied().enumerate().map(|(i, (node_id, index, kind))| {
-                let input_tensor = &mut self.graph().nodes[node_id].get_output(index);
-                set_input_size(&mut node, i, match kind {
-                    FormKind::Full => input_tensor.get_size(),
-                    FormKind::Part => input_tensor.get_size() / self.form.ndev() as u64,
-                });
-                let input_names = input_tensor.as_form(&Form { kind, devices: self.form.devices.clone() }, target);
-                input_names[replica_index].clone()
+    /// local SGD: replicas apply their own gradients (see `editor::edit`) and the variables are averaged every `period` steps.
+    /// A step counter is kept in an aux variable. The averaging runs in the true branch of a Switch on `step % period == 0`, so no traffic
+    /// happens in other steps, and a Merge per replica joins both branches so the sinks can depend on it.
+    fn add_periodic_averaging(&mut self, target: &mut Target, period: usize) {
+        let (mut new_nodes, pred_name) = periodic_predicate(target, "tge_local_sgd", "local_sgd/step", period);
+
+        let mut merges = vec![];
+        for node in self.nodes.iter() {
+            let states = match optimizer_inputs(&node.raw_node.op) {
+                Some((grad_index, states)) if node.form.ndev() > 1 => {
+                    let grad_node = &self.nodes[node.inputs[grad_index].0];
+                    if !grad_node.form.is_part() || grad_node.form.devices != node.form.devices {
+                        continue // aggregated as usual
+                    }
+                    states
+                }
+                _ => continue
+            };
+
+            let var = &self.nodes[node.inputs[states[0]].0];
+            let resource = var.raw_node.op == "VarHandleOp" || var.is_resource_placeholder();
+            let dtype = if resource { var.raw_node.attr["dtype"].clone() } else { get_dtype(var, 0) };
+            let n = var.form.ndev();
+            let var_prefix = format!("{}/aux_local_sgd", var.raw_node.name);
+
+            // the true branch of each replica's value, read after the local update of this step
+            let branches: Vec<String> = (0..n).map(|i| {
+                let mut read = var.make_node(if resource { "ReadVariableOp" } else { "Identity" }.to_string());
+                read.name = format!("{}/read_{}", var_prefix, i);
+                read.device = target.devices[var.form.devices[i]].clone();
+                read.input.push(var.replica(i));
+                read.input.push(format!("^{}", node.replica(i)));
+                read.attr.insert(if resource { "dtype" } else { "T" }.into(), dtype.clone());
+
+                let mut switch = var.make_node("Switch".to_string());
+                switch.name = format!("{}/switch_{}", var_prefix, i);
+                switch.device = read.device.clone();
+                switch.input.push(read.name.clone());
+                switch.input.push(pred_name.clone());
+                switch.attr.insert("T".into(), dtype.clone());
+
+                let name = switch.name.clone();
+                new_nodes.push(read);
+                new_nodes.push(switch);
+                name
             }).collect();
 
-            // 3. add control dependencies
-            if self.raw_node.op == "NoOp" { // TODO: what's the consequence?
-                for node_id in self.controls.iter() {
-                    let dep_node = &self.graph().nodes[*node_id];
+            let mut sum = var.make_node("AddN".to_string());
+            sum.name = format!("{}/sum", var_prefix);
+            sum.device = target.devices[var.form.devices[0]].clone();
+            for branch in branches.iter() {
+                sum.input.push(format!("{}:1", branch));
+            }
+            sum.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(n as _)));
+            sum.attr.insert("T".into(), dtype.clone());
+
+            let mut count = var.make_node("Const".to_string());
+            count.name = format!("{}/count", var_prefix);
+            count.device = sum.device.clone();
+            count.attr.insert("dtype".into(), dtype.clone());
+            count.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| {
+                t.set_dtype(dtype.get_field_type());
+                if dtype.get_field_type() == DataType::DT_DOUBLE {
+                    t.double_val.push(n as _)
+                } else {
+                    t.float_val.push(n as _)
+                }
+            }))));
+
+            let mut mean = var.make_node("RealDiv".to_string());
+            mean.name = format!("{}/mean", var_prefix);
+            mean.device = sum.device.clone();
+            mean.input.push(sum.name.clone());
+            mean.input.push(count.name.clone());
+            mean.attr.insert("T".into(), dtype.clone());
+
+            for (i, branch) in branches.iter().enumerate() {
+                let mut assign = var.make_node(if resource { "AssignVariableOp" } else { "Assign" }.to_string());
+                assign.name = format!("{}/assign_{}", var_prefix, i);
+                assign.device = target.devices[var.form.devices[i]].clone();
+                assign.input.push(var.replica(i));
+                assign.input.push(mean.name.clone());
+                if resource {
+                    assign.attr.insert("dtype".into(), dtype.clone());
+                } else {
+                    assign.attr.insert("T".into(), dtype.clone());
+                    assign.attr.insert("use_locking".into(), AttrValue::new().apply(|x| x.set_b(true)));
+                    assign.attr.insert("validate_shape".into(), AttrValue::new().apply(|x| x.set_b(true)));
+                }
 
-                    for i in 0..dep_node.form.ndev() {
-                        node.input.push(format!("^{}", dep_node.replica(i)))
+                // the merge is alive in both branches: the false branch passes the local value, the true branch waits for the assignment
+                let mut done = var.make_node("Identity".to_string());
+                done.name = format!("{}/done_{}", var_prefix, i);
+                done.device = assign.device.clone();
+                done.input.push(format!("{}:1", branch));
+                done.input.push(format!("^{}", assign.name));
+                done.attr.insert("T".into(), dtype.clone());
+
+                let mut merge = var.make_node("Merge".to_string());
+                merge.name = format!("{}/merge_{}", var_prefix, i);
+                merge.device = assign.device.clone();
+                merge.input.push(format!("{}:0", branch));
+                merge.input.push(done.name.clone());
+                merge.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(2)));
+                merge.attr.insert("T".into(), dtype.clone());
+
+                merges.push(merge.name.clone());
+                new_nodes.push(assign);
+                new_nodes.push(done);
+                new_nodes.push(merge);
+            }
+            new_nodes.push(sum);
+            new_nodes.push(count);
+            new_nodes.push(mean);
+        }
+
+        info!("local SGD: {} variables are averaged every {} steps", merges.len() / std::cmp::max(target.ndev(), 1), period);
+
+        // every replica of the sinks waits for the (possibly skipped) averaging
+        let sinks: BTreeSet<&str> = target.sinks.iter().map(|x| &x[..]).collect();
+        for node in target.pb.node.iter_mut() {
+            let origin = node.attr.get("_tge_origin").map(|x| String::from_utf8_lossy(x.get_s()).into_owned());
+            if origin.map(|x| sinks.contains(&x[..])).unwrap_or(false) {
+                for merge in merges.iter() {
+                    node.input.push(format!("^{}", merge))
+                }
+            }
+        }
+        for node in new_nodes {
+            target.pb.node.push(node)
+        }
+    }
+
+    /// bound the staleness of asynchronous SGD (option `async_sgd`, see `editor::edit`): every `period` steps, the replicas wait for each other
+    /// before applying their gradients. Replica r of the workers counts its steps in an aux variable on its device; on a sync step it puts a
+    /// token into the queue of every other replica and takes one from each of them out of its own queue, and all its Apply replicas wait for
+    /// that. Other steps bypass the queues through a Switch. The replicas can thus also be run on their own, e.g. one thread per replica.
+    fn add_async_barriers(&mut self, target: &mut Target, period: usize) {
+        let int64 = AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT64));
+        let scalar = |name: String, device: &str, dtype: DataType, value: i64| {
+            let mut node = NodeDef::new();
+            node.name = name;
+            node.op = "Const".into();
+            node.device = device.to_string();
+            node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(dtype)));
+            node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| {
+                t.set_dtype(dtype);
+                if dtype == DataType::DT_INT64 { t.int64_val.push(value) } else { t.int_val.push(value as _) }
+            }))));
+            node
+        };
+
+        // the Apply replicas of each worker replica, by the form of the gradients
+        let mut appliers: BTreeMap<Form, Vec<Vec<String>>> = BTreeMap::new();
+        for node in self.nodes.iter() {
+            match optimizer_inputs(&node.raw_node.op) {
+                Some((grad_index, _)) if node.inputs[grad_index].2 == FormKind::Part => {
+                    let workers = self.nodes[node.inputs[grad_index].0].form.clone();
+                    let replicas = appliers.entry(workers).or_insert_with(|| vec![vec![]; node.form.ndev()]);
+                    for (i, replica) in replicas.iter_mut().enumerate() {
+                        replica.push(node.replica(i))
                     }
                 }
+                _ => {}
+            }
+        }
+
+        let mut waits: BTreeMap<String, String> = BTreeMap::new(); // Apply replica => the barrier it waits for
+        let mut new_nodes = vec![];
+        for (workers, replicas) in appliers.iter() {
+            let n = workers.ndev();
+            if n < 2 {
+                continue
+            }
+            let prefix = format!("tge_async/{}", workers.code());
+            let devices: Vec<String> = workers.devices.iter().map(|d| target.devices[*d].clone()).collect();
+            let hosts: Vec<String> = workers.devices.iter().map(|d| target.host_cpu(*d)).collect(); // the queue ops only have CPU kernels
+            let queues: Vec<String> = (0..n).map(|r| {
+                let mut queue = NodeDef::new();
+                queue.name = format!("{}/queue_{}", prefix, r);
+                queue.op = "FIFOQueueV2".into();
+                queue.device = hosts[r].clone();
+                queue.attr.insert("component_types".into(), AttrValue::new().apply(|x| x.mut_list().field_type.push(DataType::DT_INT64)));
+                queue.attr.insert("shapes".into(), AttrValue::new().apply(|x| x.mut_list().shape.push(crate::proto::tensor_shape::TensorShapeProto::new())));
+                queue.attr.insert("capacity".into(), AttrValue::new().apply(|x| x.set_i(-1)));
+                queue.attr.insert("container".into(), AttrValue::new().apply(|x| x.set_s(vec![])));
+                queue.attr.insert("shared_name".into(), AttrValue::new().apply(|x| x.set_s(vec![])));
+                let name = queue.name.clone();
+                new_nodes.push(queue);
+                name
+            }).collect();
+
+            for r in 0..n {
+                let step = target.create_aux_variable(&format!("async/{}/step_{}", workers.code(), r), workers.devices[r], DataType::DT_INT64, &[], 0.);
+                let one = scalar(format!("{}/one_{}", prefix, r), &devices[r], DataType::DT_INT64, 1);
+                let period_node = scalar(format!("{}/period_{}", prefix, r), &devices[r], DataType::DT_INT64, period as _);
+                let zero = scalar(format!("{}/zero_{}", prefix, r), &devices[r], DataType::DT_INT64, 0);
+                let count = scalar(format!("{}/count_{}", prefix, r), &devices[r], DataType::DT_INT32, n as i64 - 1);
+
+                let mut increment = NodeDef::new();
+                increment.name = format!("{}/increment_{}", prefix, r);
+                increment.op = "AssignAdd".into();
+                increment.device = devices[r].clone();
+                increment.input.push(step);
+                increment.input.push(one.name.clone());
+                increment.attr.insert("T".into(), int64.clone());
+                increment.attr.insert("use_locking".into(), AttrValue::new().apply(|x| x.set_b(true)));
+
+                let mut modulo = NodeDef::new();
+                modulo.name = format!("{}/mod_{}", prefix, r);
+                modulo.op = "FloorMod".into();
+                modulo.device = devices[r].clone();
+                modulo.input.push(increment.name.clone());
+                modulo.input.push(period_node.name.clone());
+                modulo.attr.insert("T".into(), int64.clone());
+
+                let mut pred = NodeDef::new();
+                pred.name = format!("{}/pred_{}", prefix, r);
+                pred.op = "Equal".into();
+                pred.device = devices[r].clone();
+                pred.input.push(modulo.name.clone());
+                pred.input.push(zero.name.clone());
+                pred.attr.insert("T".into(), int64.clone());
+
+                let mut switch = NodeDef::new();
+                switch.name = format!("{}/switch_{}", prefix, r);
+                switch.op = "Switch".into();
+                switch.device = devices[r].clone();
+                switch.input.push(increment.name.clone());
+                switch.input.push(pred.name.clone());
+                switch.attr.insert("T".into(), int64.clone());
+
+                // queue ops are colocated with their queues on the host CPUs. The enqueues are dead on other steps, and so is the dequeue that waits for them
+                let mut dequeue = NodeDef::new();
+                dequeue.name = format!("{}/dequeue_{}", prefix, r);
+                dequeue.op = "QueueDequeueManyV2".into();
+                dequeue.device = hosts[r].clone();
+                dequeue.input.push(queues[r].clone());
+                dequeue.input.push(count.name.clone());
+                dequeue.attr.insert("component_types".into(), AttrValue::new().apply(|x| x.mut_list().field_type.push(DataType::DT_INT64)));
+                dequeue.attr.insert("timeout_ms".into(), AttrValue::new().apply(|x| x.set_i(-1)));
+                for s in (0..n).filter(|s| *s != r) {
+                    let mut enqueue = NodeDef::new();
+                    enqueue.name = format!("{}/enqueue_{}_{}", prefix, r, s);
+                    enqueue.op = "QueueEnqueueV2".into();
+                    enqueue.device = hosts[s].clone();
+                    enqueue.input.push(queues[s].clone());
+                    enqueue.input.push(format!("{}:1", switch.name));
+                    enqueue.attr.insert("Tcomponents".into(), AttrValue::new().apply(|x| x.mut_list().field_type.push(DataType::DT_INT64)));
+                    enqueue.attr.insert("timeout_ms".into(), AttrValue::new().apply(|x| x.set_i(-1)));
+                    dequeue.input.push(format!("^{}", enqueue.name));
+                    new_nodes.push(enqueue);
+                }
+
+                let mut done = NodeDef::new();
+                done.name = format!("{}/done_{}", prefix, r);
+                done.op = "Identity".into();
+                done.device = devices[r].clone();
+                done.input.push(format!("{}:1", switch.name));
+                done.input.push(format!("^{}", dequeue.name));
+                done.attr.insert("T".into(), int64.clone());
+
+                let mut merge = NodeDef::new();
+                merge.name = format!("{}/merge_{}", prefix, r);
+                merge.op = "Merge".into();
+                merge.device = devices[r].clone();
+                merge.input.push(format!("{}:0", switch.name));
+                merge.input.push(done.name.clone());
+                merge.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(2)));
+                merge.attr.insert("T".into(), int64.clone());
+
+                for applier in replicas[r].iter() {
+                    waits.insert(applier.clone(), merge.name.clone());
+                }
+                new_nodes.extend(vec![one, period_node, zero, count, increment, modulo, pred, switch, dequeue, done, merge]);
             }
+        }
 
+        info!("asynchronous SGD: {} groups of replicas wait for each other every {} steps", appliers.values().filter(|x| x.len() > 1).count(), period);
+
+        for node in target.pb.node.iter_mut() {
+            if let Some(merge) = waits.get(&node.name) {
+                node.input.push(format!("^{}", merge))
+            }
+        }
+        for node in new_nodes {
             target.pb.node.push(node)
         }
     }
 
-    fn replica(&self, index: usize) -> String { // TODO: should this method exist?
-        format!("{}/replica_{}", self.raw_node.name, index)
+    /// make the init op copy replica 0 of every replicated variable to the other replicas after running the initializers, so all replicas
+    /// start from identical weights even if their initializers are random and were replicated. Must be called after `add_init_op`.
+    fn add_init_broadcast(&mut self, target: &mut Target) {
+        let assigns: Vec<String> = self.init_assigns.iter().flat_map(|id| {
+            let node = &self.nodes[*id];
+            (0..node.form.ndev()).map(move |i| node.replica(i))
+        }).collect();
+        self.add_broadcast_op(target, Self::INIT_BROADCAST_OP, &assigns);
+        let init = target.pb.node.iter_mut().find(|x| x.name == Self::INIT_OP).unwrap();
+        init.input.push(format!("^{}", Self::INIT_BROADCAST_OP))
     }
 
-    pub fn is_input(&self) -> bool {
-        self.raw_node.op == "Placeholder" || self.raw_node.op == "IteratorGetNext"
+    /// a NoOp named `name` that copies the value of replica 0 of every replicated variable to the other replicas, so only replica 0 needs to
+    /// be restored. With the option `broadcast_method` = `nccl` the value is sent with a NcclBroadcast if the replicas are on one machine and the
+    /// variable has a static `shape` (not resource or placeholder variables); otherwise it is relayed along a tree of Identities where each
+    /// device receives from the already-covered device with the widest path to it. With the option `collective_backend`
+    /// = `horovod`, variables with replicas on every task are sent with a HorovodBroadcast instead. The value is read and the replicas are
+    /// overwritten only after the nodes in `after` ran.
+    fn add_broadcast_op(&mut self, target: &mut Target, name: &str, after: &[String]) {
+        let mut use_nccl = self.options.get("broadcast_method").map(|x| x == "nccl").unwrap_or(false);
+        if use_nccl && self.options.get("collective_backend").map(|x| x == "collective").unwrap_or(false) {
+            warn!("broadcast_method nccl is not available with the collective backend, relaying along a tree instead");
+            use_nccl = false
+        }
+        let use_horovod = self.options.get("collective_backend").map(|x| x == "horovod").unwrap_or(false);
+        let machines = target.machines();
+        let n = target.ndev();
+        let bandwidth = |from: usize, to: usize| if from == to {
+            u64::MAX
+        } else {
+            target.paths[from * n + to].iter().map(|link| target.links[*link]).min().unwrap_or(0)
+        };
+
+        let mut broadcast = NodeDef::new();
+        broadcast.name = name.into();
+        broadcast.op = "NoOp".into();
+        broadcast.device = target.devices[0].clone();
+
+        let mut new_nodes = vec![];
+        for node in self.nodes.iter_mut() {
+            if !node.is_variable() || node.form.ndev() < 2 {
+                continue
+            }
+
+            let resource = node.raw_node.op == "VarHandleOp" || node.is_resource_placeholder();
+            let dtype = if resource { node.raw_node.attr["dtype"].clone() } else { get_dtype(node, 0) };
+            let size = crate::analysis::variable_size(node);
+            let root = node.form.devices[0];
+            let prefix = format!("{}/aux_{}", node.raw_node.name, name.trim_start_matches("tge_"));
+
+            let mut source = node.make_node(if resource { "ReadVariableOp" } else { "Identity" }.to_string());
+            source.name = format!("{}/source", prefix);
+            source.device = target.devices[root].clone();
+            source.input.push(node.replica(0));
+            source.input.extend(after.iter().map(|x| format!("^{}", x)));
+            source.attr.insert(if resource { "dtype" } else { "T" }.into(), dtype.clone());
+            let source_name = source.name.clone();
+            new_nodes.push(source);
+
+            // the copy of the value on each device
+            let mut copies: BTreeMap<usize, String> = BTreeMap::new();
+            if use_horovod && target.covers_all_machines(&node.form.devices) {
+                // every rank passes its own first replica, which the value of the root rank overwrites
+                let root_rank = machines.iter().position(|x| x.contains(&root)).unwrap();
+                for (rank, devices) in machines.iter().enumerate() {
+                    let local: Vec<usize> = node.form.devices.iter().copied().filter(|d| devices.contains(d)).collect();
+                    let input = if rank == root_rank {
+                        source_name.clone()
+                    } else {
+                        let i = node.form.devices.iter().position(|d| *d == local[0]).unwrap();
+                        let mut read = node.make_node(if resource { "ReadVariableOp" } else { "Identity" }.to_string());
+                        read.name = format!("{}/read_{}", prefix, rank);
+                        read.device = target.devices[local[0]].clone();
+                        read.input.push(node.replica(i));
+                        read.attr.insert(if resource { "dtype" } else { "T" }.into(), dtype.clone());
+                        let name = read.name.clone();
+                        new_nodes.push(read);
+                        name
+                    };
+
+                    let mut hvd = node.make_node("HorovodBroadcast".to_string());
+                    hvd.name = format!("{}/task_{}/hvd_broadcast_{}", prefix, rank, horovod_name(&node.raw_node.name));
+                    hvd.device = target.devices[local[0]].clone();
+                    hvd.input.push(input);
+                    hvd.attr.insert("T".into(), dtype.clone());
+                    hvd.attr.insert("root_rank".into(), AttrValue::new().apply(|x| x.set_i(root_rank as _)));
+                    hvd.attr.insert("ignore_name_scope".into(), AttrValue::new().apply(|x| x.set_b(true)));
+                    set_input_size(&mut hvd, 0, size);
+                    for device_id in local.iter().copied().collect::<BTreeSet<_>>() {
+                        if device_id == local[0] {
+                            copies.insert(device_id, hvd.name.clone());
+                            continue
+                        }
+                        let mut identity = node.make_node("Identity".to_string());
+                        identity.name = format!("{}/recv_{}", prefix, device_id);
+                        identity.device = target.devices[device_id].clone();
+                        identity.input.push(hvd.name.clone());
+                        identity.attr.insert("T".into(), dtype.clone());
+                        set_input_size(&mut identity, 0, size);
+                        copies.insert(device_id, identity.name.clone());
+                        new_nodes.push(identity);
+                    }
+                    new_nodes.push(hvd);
+                }
+            } else if use_nccl && machines.iter().any(|machine| node.form.devices.iter().all(|d| machine.contains(d))) && node.raw_node.attr.contains_key("shape") {
+                let mut nccl = node.make_node("NcclBroadcast".to_string());
+                nccl.name = format!("{}/nccl", prefix);
+                nccl.device = target.devices[root].clone();
+                nccl.input.push(source_name);
+                nccl.attr.insert("T".into(), dtype.clone());
+                nccl.attr.insert("shape".into(), node.raw_node.attr["shape"].clone());
+                copies.insert(root, nccl.name.clone());
+                for device_id in node.form.devices.iter().skip(1).filter(|x| **x != root) {
+                    let mut identity = node.make_node("Identity".to_string());
+                    identity.name = format!("{}/recv_{}", prefix, device_id);
+                    identity.device = target.devices[*device_id].clone();
+                    identity.input.push(nccl.name.clone());
+                    identity.attr.insert("T".into(), dtype.clone());
+                    set_input_size(&mut identity, 0, size);
+                    copies.insert(*device_id, identity.name.clone());
+                    new_nodes.push(identity);
+                }
+                new_nodes.push(nccl);
+            } else {
+                copies.insert(root, source_name);
+                let mut remaining: BTreeSet<usize> = node.form.devices.iter().copied().filter(|x| *x != root).collect();
+                while !remaining.is_empty() { // maximum bottleneck spanning tree, grown one device at a time
+                    let (parent, child) = copies.keys().flat_map(|p| remaining.iter().map(move |c| (*p, *c)))
+                        .max_by_key(|(p, c)| (bandwidth(*p, *c), std::cmp::Reverse(*c))).unwrap();
+                    let mut identity = node.make_node("Identity".to_string());
+                    identity.name = format!("{}/relay_{}", prefix, child);
+                    identity.device = target.devices[child].clone();
+                    identity.input.push(copies[&parent].clone());
+                    identity.attr.insert("T".into(), dtype.clone());
+                    set_input_size(&mut identity, 0, size);
+                    copies.insert(child, identity.name.clone());
+                    remaining.remove(&child);
+                    new_nodes.push(identity);
+                }
+            }
+
+            for (i, device_id) in node.form.devices.iter().enumerate().skip(1) {
+                let mut assign = node.make_node(if resource { "AssignVariableOp" } else { "Assign" }.to_string());
+                assign.name = format!("{}/assign_{}", prefix, i);
+                assign.device = target.devices[*device_id].clone();
+                assign.input.push(node.replica(i));
+                assign.input.push(copies[device_id].clone());
+                assign.input.extend(after.iter().map(|x| format!("^{}", x)));
+                if resource {
+                    assign.attr.insert("dtype".into(), dtype.clone());
+                } else {
+                    assign.attr.insert("T".into(), dtype.clone());
+                    assign.attr.insert("use_locking".into(), AttrValue::new().apply(|x| x.set_b(true)));
+                    assign.attr.insert("validate_shape".into(), AttrValue::new().apply(|x| x.set_b(true)));
+                }
+                broadcast.input.push(format!("^{}", assign.name));
+                new_nodes.push(assign);
+            }
+        }
+
+        for node in new_nodes {
+            target.pb.node.push(node)
+        }
+        target.pb.node.push(broadcast)
     }
 
-    /**************************************
-    * following are graph editing methods *
-    **************************************/
+    /// the value of an integer tensor that can be computed at compile time: Consts, and Identity, Pack and ConcatV2 (along axis 0) of them.
+    /// Used to adjust shape operands like the `shape` of Reshape, see `Node::batch_shape_operand`.
+    pub fn constant_ints(&self, node_id: usize, index: usize) -> Option<Vec<i64>> {
+        let node = &self.nodes[node_id];
+        match &node.raw_node.op[..] {
+            "Const" if index == 0 => tensor_ints(node.raw_node.attr.get("value")?.get_tensor()),
+            "Identity" => node.inputs.first().and_then(|(id, index, _)| self.constant_ints(*id, *index)),
+            "Pack" if node.raw_node.attr.get("axis").map(|x| x.get_i()).unwrap_or(0) == 0 => {
+                node.inputs.iter().map(|(id, index, _)| self.constant_ints(*id, *index).filter(|x| x.len() == 1).map(|x| x[0])).collect()
+            }
+            "ConcatV2" => {
+                let (values, axis) = node.inputs.split_at(node.inputs.len().checked_sub(1)?);
+                if self.constant_ints(axis[0].0, axis[0].1)? != [0] {
+                    return None
+                }
+                values.iter().map(|(id, index, _)| self.constant_ints(*id, *index)).collect::<Option<Vec<_>>>().map(|x| x.concat())
+            }
+            _ => None
+        }
+    }
 
-    pub fn make_node(&self, op: String) -> NodeDef {
-        let mut node = NodeDef::new();
-        node.op = op;
-        node.name = self.raw_node.name.clone();
-        set_belong_to(&mut node, &self.raw_node.name);
-        node
+    /// the node ids in an order where every node comes after its inputs and control dependencies, as `simulator::sort_nodes` orders the
+    /// compiled nodes. Inputs from NextIteration are the back edges of while loops and ignored; nodes in other cycles are left out.
+    pub fn topological_order(&self) -> Vec<usize> {
+        let is_back_edge = |id: &usize| matches!(&self.nodes[*id].raw_node.op[..], "NextIteration" | "RefNextIteration");
+        let mut pending = vec![0; self.nodes.len()]; // the number of inputs that are not ordered yet
+        let mut consumers = vec![vec![]; self.nodes.len()];
+        for (id, node) in self.nodes.iter().enumerate() {
+            for input_id in node.inputs.iter().map(|x| x.0).chain(node.controls.iter().copied()).filter(|x| !is_back_edge(x)) {
+                pending[id] += 1;
+                consumers[input_id].push(id);
+            }
+        }
+
+        let mut queue: std::collections::VecDeque<usize> = (0..self.nodes.len()).filter(|id| pending[*id] == 0).collect();
+        let mut order = Vec::with_capacity(self.nodes.len());
+        while let Some(id) = queue.pop_front() {
+            order.push(id);
+            for consumer in consumers[id].iter().copied() {
+                pending[consumer] -= 1;
+                if pending[consumer] == 0 {
+                    queue.push_back(consumer)
+                }
+            }
+        }
+        if order.len() < self.nodes.len() {
+            warn!("{} nodes are in cycles other than while loops, e.g. {}", self.nodes.len() - order.len(), self.nodes[pending.iter().position(|x| *x > 0).unwrap()].raw_node.name);
+        }
+        order
     }
 
-    pub fn put_on_devices(&mut self, devices: &[usize]) {
-        assert!(self.replicated().is_none(), "already set replicas!");
-        self.form.devices.extend_from_slice(devices);
+    /// forget the collective groups and instances of the previous compilation
+    pub fn reset_collective_state(&mut self) {
+        self.collective_state = CollectiveState::default()
+    }
+
+    pub fn get_groups(&self) -> BTreeMap<&str, Option<impl Hash + Ord>> {
+        self.nodes.iter().map(|node| {
+            (&node.raw_node.name[..], node.group.as_ref().map(|x| x.as_ptr()))
+        }).collect()
+    }
+
+    fn add_control_dependencies_for_collective_nodes(&mut self, target: &mut Target) {
+        // TODO: findout existing dependencies (added by fusing iterations) and avoid dead lock
+        for pair in self.collective_state.instances.windows(2) {
+            for &i in &pair[0] {
+                for &j in &pair[1] {
+                    let input = format!("^{}", target.pb.node.get(j).unwrap().name);
+                    target.pb.node.get_mut(i).unwrap().input.push(input)
+                }
+            }
+        }
     }
 }
 
-pub struct Tensor {
-    pub node: *const Node,
-    pub index: usize,
-    pub forms: BTreeMap<Form, Box<[String]>>,
-    pub flags: u8, // flags indicate the types and roles of a tensor. It affects how the tensor is treated when changing forms
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Copy)]
+pub enum FormKind { Full, Part }
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Form {
+    pub kind: FormKind,
+    pub devices: Vec<usize>, // The Vec must be sorted and not empty, but may contains repeated elements (put multiple replicas on the same device)
+    pub axis: usize // the dimension that a part form is split along: 0 (the batch) unless weights are split by columns or the like. Always 0 for full forms
 }
 
-impl Tensor {
-    pub const IS_FROM_INPUT: u8 = 0x01; // this tensor is a descendant of an input node.
-    pub const IS_BATCHED: u8 = 0x02; // this tensor's first dimension is batch size.
-    pub const IS_SHAPE: u8 = 0x04; // currently not used
-    pub const IS_FIXED: u8 = 0x80; // this tensor's form is provided by strategy and should not be altered
+impl Form {
+    pub fn is_full(&self) -> bool {
+        self.kind == FormKind::Full
+    }
 
-    pub fn new(node: &Node, index: usize) -> Self {
-        Tensor { node, index, forms: BTreeMap::new(), flags: 0 }
+    pub fn is_part(&self) -> bool {
+        self.kind == FormKind::Part
     }
 
-    pub fn original_name(&self) -> String {
-        if self.index == 0 {
-            self.node().raw_node.name.clone()
-        } else {
-            format!("{}:{}", self.node().raw_node.name, self.index)
+    pub fn ndev(&self) -> usize {
+        self.devices.len()
+    }
+
+    // TODO: use to_string() and parse()?
+    /// e.g. `full_0_1`, `part_0_0_1` and `part1_0_1` for parts along the second dimension
+    pub fn code(&self) -> String {
+        let mut x = String::from(if self.is_full() {"full"} else {"part"});
+        if self.axis != 0 {
+            x += &self.axis.to_string();
+        }
+        for d in self.devices.iter() {
+            x += "_";
+            x += &d.to_string();
         }
+        x
     }
 
-    pub fn node<'a>(&self) -> &'a Node {
-        unsafe { &*self.node }
+    pub fn from_code(code: &str) -> Self {
+        let segs: Vec<_> = code.split('_').collect();
+        let (kind, axis) = match segs[0] {
+            "full" => (FormKind::Full, 0),
+            "part" => (FormKind::Part, 0),
+            x if x.starts_with("part") => (FormKind::Part, x[4..].parse().unwrap()),
+            _ => unreachable!()
+        };
+        Self { kind, devices: segs[1..].iter().map(|x| x.parse().unwrap()).collect(), axis }
     }
 
-    pub fn get_shape(&self) -> Vec<usize> {
-        // sucks: the output shape of BroadcastGradientArgs is always unknown even if inputs are fixed
-        // and ops like `Sum` (requires the dimension to sum along with) and `Fill` operates differently with different inputs
-        let mut shape: Vec<_> = self.node().raw_node.attr["_output_shapes"].get_list().shape[self.index].dim.iter().map(|x| x.size.try_into().ok()).collect();
-        if let Some(batchsize) = self.node().graph().options.get("fill_batchsize") {
-            // if self.has_flag(Self::IS_BATCHED) && self.has_flag(Self::IS_FROM_INPUT) && shape[0].is_none() && shape[1..].iter().all(|x| x.is_some()) {
-            if !shape.is_empty() && shape[0].is_none() { // relax the requirement
-                shape[0] = Some(batchsize.parse().unwrap());
+    pub fn valid(&self) -> bool {
+        !self.devices.is_empty()
+    }
+}
+
+type Group = Rc<RefCell<Vec<usize>>>;
+
+pub struct Node {
+    pub graph: *const Graph,
+    pub raw_node: NodeDef,
+    pub controls: Vec<usize>, // TODO: more consideration for control dependencies that added aux nodes
+    pub inputs: Vec<(usize, usize, FormKind)>, // nodeid, index, formkind (defaults to full)
+    pub outputs: Vec<Tensor>,
+    pub form: Form, // the form of the node, which is also a tensor form for all its outputs
+    pub group: Option<Group>,
+}
+
+impl Node {
+    pub fn new(graph: &Graph, raw_node: NodeDef) -> Self {
+        let mut inputs = vec![];
+        let mut controls = vec![];
+
+        for input in raw_node.input.iter() {
+            if input.starts_with('^') {
+                controls.push(graph.name_dict[&input[1..]])
+            } else {
+                let (name, index) = parse_input(input);
+                let id = graph.name_dict[name];
+                inputs.push((id, index, FormKind::Full))
             }
         }
-        shape.into_iter().collect::<Option<_>>().unwrap_or_else(Vec::new)
+
+        Self {
+            graph, raw_node, controls, inputs, outputs: vec![],
+            form: Form { kind: FormKind::Full, devices: vec![], axis: 0 },
+            group: None
+        }
     }
 
-    pub fn get_size(&self) -> u64 {
-        #[allow(clippy::unnecessary_fold)]
-        (self.get_shape().iter().fold(1, |x, y| x * y) * 4).try_into().unwrap()
+    #[allow(clippy::mut_from_ref)]
+    pub fn graph<'a>(&self) -> &'a mut Graph {
+        unsafe { &mut *(self.graph as *mut Graph) }
+    }
+
+    pub fn get_output(&mut self, index: usize) -> &mut Tensor {
+        while self.outputs.len() <= index {
+            self.outputs.push(Tensor::new(self, self.outputs.len()))
+        }
+
+        &mut self.outputs[index]
+    }
+
+    pub fn replicated(&self) -> Option<bool> {
+        match self.form.ndev() {
+            0 => None,
+            1 => Some(false),
+            _ => Some(true)
+        }
+    }
+
+    /// the form in which the i-th input is used: on the devices of the node, split along the dimension that matches the split axis of
+    /// the node if the input is Part (see `split_input_axis`)
+    pub fn input_form(&self, i: usize) -> Form {
+        let kind = self.inputs[i].2;
+        let axis = if kind == FormKind::Part { self.split_input_axis(i).unwrap_or(0) } else { 0 };
+        Form { kind, devices: self.form.devices.clone(), axis }
+    }
+
+    /// the dimension of the i-th input that follows the split axis of the node, or None if each replica needs the whole input. MatMul split
+    /// by columns needs the columns of its second operand only; other ops are taken as elementwise with broadcasting, so inputs of a lower
+    /// rank are aligned to the trailing dimensions, and those that do not reach the split axis or have a length of 1 there are used whole.
+    pub fn split_input_axis(&self, i: usize) -> Option<usize> {
+        let axis = self.form.axis;
+        if axis == 0 {
+            return Some(0)
+        }
+        let (id, index, _) = self.inputs[i];
+        let transposed = |name: &str| self.raw_node.attr.get(name).map(|x| x.get_b()).unwrap_or(false);
+        match &self.raw_node.op[..] {
+            "MatMul" if i == 1 && axis == 1 => Some(if transposed("transpose_b") { 0 } else { 1 }),
+            "MatMul" => None,
+            _ => {
+                let shape = self.graph().nodes[id].get_output(index).get_shape();
+                let rank = self.raw_node.attr.get("_output_shapes").and_then(|x| x.get_list().shape.first()).map(|x| x.dim.len()).unwrap_or(0);
+                if shape.is_empty() || rank == 0 { // unknown shapes are assumed to match the output
+                    return Some(axis)
+                }
+                let offset = rank.checked_sub(shape.len())?;
+                axis.checked_sub(offset).filter(|x| shape[*x] != 1)
+            }
+        }
+    }
+
+    /// identifies what `compile` emits: the node, its form, the names of its inputs and the forms of its control dependencies
+    fn cache_key(&mut self, target: &mut Target) -> String {
+        let mut key = format!("{}|{}", self.raw_node.name, self.form.code());
+        for (i, (node_id, index, _)) in self.inputs.clone().into_iter().enumerate() {
+            let names = self.graph().nodes[node_id].get_output(index).as_form(&self.input_form(i), target);
+            write!(key, "|{}", names.join(",")).unwrap();
+        }
+        if self.raw_node.op == "NoOp" {
+            for node_id in self.controls.iter() {
+                write!(key, "|^{}", self.graph().nodes[*node_id].form.code()).unwrap();
+            }
+        }
+        key
     }
 
-    pub fn has_flag(&self, flag: u8) -> bool {
-        self.flags & flag != 0
-    }
+    /// add an edited node into the target. Requires all inputs to be compiled first
+    fn compile(&mut self, target: &mut Target) {
+        if self.graph().options.get("log_forms").map(|x| x == "True").unwrap_or(false) {
+            info!("compile: {} {:?} {:?}", self.raw_node.name, self.form, self.inputs);
+        }
+
+        // split placeholders in graph so the user feeds the original one
+        if self.raw_node.op == "Placeholder" && self.form.is_part() && self.graph().options.get("placeholder_mode").map(|x| x == "split").unwrap_or(false) {
+            return self.compile_split_placeholder(target)
+        }
+
+        // the batch of each replica when split_ratios makes them uneven, for the ops whose shapes are set per replica below
+        let slices = if self.form.is_part() && (self.raw_node.op == "Placeholder" || self.batch_shape_operand().is_some()) {
+            let form = self.form.clone();
+            self.get_output(0).uneven_slices(&form)
+        } else {
+            None
+        };
+
+        for (replica_index, device_id) in self.form.devices.iter().enumerate() {
+            // 0. replace placeholders
+            if self.raw_node.op == "Placeholder" && self.is_input() {
+                if let Some(batchsize) = self.graph().options.get("replace_placeholder") {
+                    let batchsize: usize = batchsize.parse().unwrap();
+                    let mut shape: Vec<Option<usize>> = self.raw_node.attr["_output_shapes"].get_list().shape[0].dim.iter().map(|x| x.size.try_into().ok()).collect();
+                    if self.form.is_part() {
+                        shape[0].replace(slices.as_ref().map(|x| x[replica_index]).unwrap_or(batchsize / self.form.ndev()));
+                    } else {
+                        shape[0].replace(batchsize);
+                    }
+
+                    let mut shape_node = self.make_node("Const".to_string());
+                    shape_node.name += &format!("/aux_replace_placeholder_{}/shape", replica_index);
+                    shape_node.device = target.devices[*device_id].clone();
+                    shape_node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+                    let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+                        x.set_dtype(DataType::DT_INT32);
+                        x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|s| s.set_dim([shape.len()].iter().map(|x| {crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|fuck| fuck.size = *x as _)}).collect())));
+                        for dim in shape {
+                            x.int_val.push(dim.unwrap() as _);
+                        }
+                    });
+                    shape_node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+
+                    let mut random_node = NodeDef::new().apply(|x| x.op = "RandomUniform".to_string());
+                    random_node.name = self.replica(replica_index);
+                    random_node.device = target.devices[*device_id].clone();
+                    set_origin(&mut random_node, &self.raw_node.name);
+                    set_form(&mut random_node, &self.form.code());
+                    random_node.input.push(shape_node.name.clone());
+                    random_node.attr.insert("T".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+                    random_node.attr.insert("dtype".into(), get_dtype(self, 0));
+
+                    target.pb.node.push(shape_node);
+                    target.pb.node.push(random_node);
+                    continue
+                }
+            }
+
+            // 1. setup basic node info
+            let mut node = self.raw_node.clone();
+            node.name = self.replica(replica_index);
+            node.device = target.devices[*device_id].clone();
+            set_origin(&mut node, &self.raw_node.name);
+            if node.get_experimental_debug_info().original_node_names.is_empty() { // so TF reports errors of the replica under the original name
+                node.mut_experimental_debug_info().original_node_names.push(self.raw_node.name.clone())
+            }
+            set_form(&mut node, &self.form.code());
+
+            // each replica of a Part placeholder is fed with a slice of the batch, see `feed_mapping`
+            if self.raw_node.op == "Placeholder" && self.form.is_part() {
+                let slice = slices.as_ref().map(|x| x[replica_index] as i64);
+                if let Some(shape) = node.attr.get_mut("shape").filter(|x| x.has_shape()).map(|x| x.mut_shape()) {
+                    if let Some(dim) = shape.dim.get_mut(0) {
+                        if dim.size > 0 {
+                            dim.size = slice.unwrap_or(dim.size / self.form.ndev() as i64)
+                        }
+                    }
+                }
+            }
+
+            // 2. link inputs and set size
+            node.input = self.inputs.iter().copied().enumerate().map(|(i, (node_id, index, kind))| {
+                let input_tensor = &mut self.graph().nodes[node_id].get_output(index);
+                let form = self.input_form(i);
+                set_input_size(&mut node, i, match kind {
+                    FormKind::Full => input_tensor.get_size(),
+                    FormKind::Part => input_tensor.part_sizes(&form)[replica_index],
+                });
+                let input_names = input_tensor.as_form(&form, target);
+                input_names[replica_index].clone()
+            }).collect();
+
+            // 2.1 a constant shape operand still describes the whole batch, give each replica a copy with its slice of the batch
+            if let Some(i) = self.batch_shape_operand().filter(|i| self.form.is_part() && self.form.ndev() > 1 && self.inputs.get(*i).map(|x| x.2 == FormKind::Full).unwrap_or(false)) {
+                let (node_id, index, _) = self.inputs[i];
+                match self.graph().constant_ints(node_id, index) {
+                    Some(mut value) if !value.is_empty() && value[0] > 0 => {
+                        let slice = slices.as_ref().map(|x| x[replica_index] as i64);
+                        if slice.is_none() && value[0] % self.form.ndev() as i64 != 0 {
+                            warn!("{}: the batch dimension {} of the constant operand {} is not divisible by {}", self.raw_node.name, value[0], node.input[i], self.form.ndev())
+                        } else {
+                            value[0] = slice.unwrap_or(value[0] / self.form.ndev() as i64);
+                            let dtype = self.graph().nodes[node_id].get_output(index).dtype();
+                            let mut shape_node = self.make_node("Const".to_string());
+                            shape_node.name += &format!("/aux_batch_shape_{}", replica_index);
+                            shape_node.device = target.devices[*device_id].clone();
+                            shape_node.attr.insert("dtype".into(), dtype.clone());
+                            let tensor = crate::proto::tensor::TensorProto::new().apply(|x| {
+                                x.set_dtype(dtype.get_field_type());
+                                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|x| x.dim.push(crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|x| x.size = value.len() as _))));
+                                for v in value.iter() {
+                                    if dtype.get_field_type() == DataType::DT_INT64 { x.int64_val.push(*v) } else { x.int_val.push(*v as _) }
+                                }
+                            });
+                            shape_node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(tensor)));
+                            node.input[i] = shape_node.name.clone();
+                            target.pb.node.push(shape_node)
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // 3. add control dependencies
+            if self.raw_node.op == "NoOp" { // TODO: what's the consequence?
+                for node_id in self.controls.iter() {
+                    let dep_node = &self.graph().nodes[*node_id];
+
+                    for i in 0..dep_node.form.ndev() {
+                        node.input.push(format!("^{}", dep_node.replica(i)))
+                    }
+                }
+            }
+
+            target.pb.node.push(node)
+        }
+    }
+
+    /// keep the Placeholder under its original name on the first device and split it along the batch dimension.
+    /// Replicas are Identity nodes of the slices so consumers refer to them as usual.
+    fn compile_split_placeholder(&mut self, target: &mut Target) {
+        let first = target.devices[self.form.devices[0]].clone();
+
+        let mut placeholder = self.raw_node.clone();
+        placeholder.device = first.clone();
+        set_origin(&mut placeholder, &self.raw_node.name);
+        set_form(&mut placeholder, &Form { kind: FormKind::Full, devices: vec![self.form.devices[0]], axis: 0 }.code());
+
+        let form = self.form.clone();
+        let split = self.get_output(0).split_nodes(&form, &placeholder.name, "/aux_split_input", &first);
+        let sizes = self.get_output(0).part_sizes(&form);
+
+        let replicas: Vec<_> = self.form.devices.iter().enumerate().map(|(i, device_id)| {
+            let mut identity = NodeDef::new();
+            identity.name = self.replica(i);
+            identity.op = "Identity".into();
+            identity.device = target.devices[*device_id].clone();
+            identity.input.push(format!("{}:{}", split.last().unwrap().name, i));
+            identity.attr.insert("T".into(), get_dtype(self, 0));
+            set_origin(&mut identity, &self.raw_node.name);
+            set_form(&mut identity, &self.form.code());
+            set_input_size(&mut identity, 0, sizes[i]);
+            identity
+        }).collect();
+
+        target.pb.node.push(placeholder);
+        target.pb.node.extend(split);
+        for replica in replicas {
+            target.pb.node.push(replica)
+        }
+    }
+
+    fn replica(&self, index: usize) -> String { // TODO: should this method exist?
+        self.graph().replica_scheme.replica_name(&self.raw_node.name, index)
+    }
+
+    /// ops that produce the input batch. TF2 training functions get data through the sync and distributed variants of IteratorGetNext.
+    /// Placeholders of resource dtype are captured variables, not inputs.
+    pub fn is_input(&self) -> bool {
+        match &self.raw_node.op[..] {
+            "Placeholder" => !self.is_resource_placeholder(),
+            "IteratorGetNext" | "IteratorGetNextSync" | "MultiDeviceIteratorGetNextFromShard" | "OptionalGetValue" => true,
+            _ => false
+        }
+    }
+
+    /// a variable handle captured by a TF2 concrete function shows up as a resource Placeholder or _Arg
+    pub fn is_variable(&self) -> bool {
+        match &self.raw_node.op[..] {
+            "VariableV2" | "Variable" | "VarHandleOp" => true,
+            "Placeholder" | "_Arg" => self.is_resource_placeholder(),
+            _ => false
+        }
+    }
+
+    pub fn is_assign(&self) -> bool {
+        self.raw_node.op == "Assign" || self.raw_node.op == "AssignVariableOp"
+    }
+
+    fn is_resource_placeholder(&self) -> bool {
+        let dtype = self.raw_node.attr.get("dtype").or_else(|| self.raw_node.attr.get("T"));
+        dtype.map(|x| x.get_field_type() == DataType::DT_RESOURCE).unwrap_or(false)
+    }
+
+    /// the input that holds the output shape (or the multiples for Tile) of shape-dependent ops, whose first element is the batch size
+    /// when the node is Part. Tile only counts when its data input is whole, i.e. the batch is made by tiling.
+    fn batch_shape_operand(&self) -> Option<usize> {
+        match &self.raw_node.op[..] {
+            "Reshape" | "BroadcastTo" => Some(1),
+            "Tile" if self.inputs.first().map(|x| x.2 == FormKind::Full).unwrap_or(false) => Some(1),
+            "Fill" | "RandomUniform" | "RandomUniformInt" | "RandomStandardNormal" | "TruncatedNormal" => Some(0),
+            _ => None
+        }
+    }
+
+    /// whether the node can take Part form. Only custom ops declared unsplittable are ruled out for now.
+    pub fn is_splittable(&self) -> bool {
+        self.graph().registry.get(&self.raw_node.op).map(|x| x.splittable).unwrap_or(true)
+    }
+
+    /// stateful custom ops and iterator resources must not be replicated
+    pub fn is_stateful(&self) -> bool {
+        match &self.raw_node.op[..] {
+            "IteratorV2" | "AnonymousIteratorV2" | "MultiDeviceIterator" | "AnonymousMultiDeviceIterator" => true,
+            op => self.graph().registry.get(op).map(|x| x.stateful).unwrap_or(false)
+        }
+    }
+
+    /**************************************
+    * following are graph editing methods *
+    **************************************/
+
+    pub fn make_node(&self, op: String) -> NodeDef {
+        let mut node = NodeDef::new();
+        node.op = op;
+        node.name = self.raw_node.name.clone();
+        set_belong_to(&mut node, &self.raw_node.name);
+        node
+    }
+
+    pub fn put_on_devices(&mut self, devices: &[usize]) {
+        assert!(self.replicated().is_none(), "already set replicas!");
+        self.form.devices.extend_from_slice(devices);
+    }
+}
+
+pub struct Tensor {
+    pub node: *const Node,
+    pub index: usize,
+    pub forms: BTreeMap<Form, Box<[String]>>,
+    pub flags: u8, // flags indicate the types and roles of a tensor. It affects how the tensor is treated when changing forms
+    pub dtype_override: Option<DataType>, // set while emitting aux nodes that work on a casted copy of this tensor
+    pub reduction: Reduction, // how the summing aux nodes combine the replicas, set while aggregating a gradient (see `reduce_gradient`)
+    pub sparse_triple: Option<([(usize, usize); 3], usize)>, // the (indices, values, dense shape) of the SparseTensor of an `IS_SPARSE` tensor, and which of them it is
+}
+
+impl Tensor {
+    pub const IS_FROM_INPUT: u8 = 0x01; // this tensor is a descendant of an input node.
+    pub const IS_BATCHED: u8 = 0x02; // this tensor's first dimension is batch size.
+    pub const IS_SHAPE: u8 = 0x04; // this tensor is the shape of a batched tensor, so its first element is the batch size
+    pub const IS_INVARIANT: u8 = 0x08; // every replica holds the same value however the batch is split, e.g. the outputs of BroadcastGradientArgs
+    pub const IS_SPARSE: u8 = 0x10; // this tensor is a member of a SparseTensor whose rows are the batch, see `sparse_triple`. The members are converted together
+    pub const IS_RAGGED: u8 = 0x20; // this tensor is the flat values or row splits of a RaggedTensor, whose first dimension is not the batch. It is never split
+    pub const IS_FIXED: u8 = 0x80; // this tensor's form is provided by strategy and should not be altered
+
+    pub fn new(node: &Node, index: usize) -> Self {
+        Tensor { node, index, forms: BTreeMap::new(), flags: 0, dtype_override: None, reduction: Reduction::Sum, sparse_triple: None }
+    }
+
+    pub fn original_name(&self) -> String {
+        if self.index == 0 {
+            self.node().raw_node.name.clone()
+        } else {
+            format!("{}:{}", self.node().raw_node.name, self.index)
+        }
+    }
+
+    pub fn node<'a>(&self) -> &'a Node {
+        unsafe { &*self.node }
+    }
+
+    pub fn get_shape(&self) -> Vec<usize> {
+        // sucks: the output shape of BroadcastGradientArgs is always unknown even if inputs are fixed
+        // and ops like `Sum` (requires the dimension to sum along with) and `Fill` operates differently with different inputs
+        let mut shape: Vec<Option<usize>> = match (self.node().raw_node.attr.get("_output_shapes"), self.node().graph().registry.get(&self.node().raw_node.op)) {
+            (None, Some(custom_op)) => match custom_op.shapes.get(self.index) {
+                Some(ShapeFn::Fixed(dims)) => dims.clone(),
+                Some(ShapeFn::SameAsInput(i)) => {
+                    let (id, index, _) = self.node().inputs[*i];
+                    return self.node().graph().nodes[id].get_output(index).get_shape()
+                }
+                Some(ShapeFn::Unknown) | None => return vec![]
+            },
+            _ => self.node().raw_node.attr["_output_shapes"].get_list().shape[self.index].dim.iter().map(|x| x.size.try_into().ok()).collect()
+        };
+        if let Some(batchsize) = self.node().graph().options.get("fill_batchsize") {
+            // if self.has_flag(Self::IS_BATCHED) && self.has_flag(Self::IS_FROM_INPUT) && shape[0].is_none() && shape[1..].iter().all(|x| x.is_some()) {
+            if !shape.is_empty() && shape[0].is_none() { // relax the requirement
+                shape[0] = Some(batchsize.parse().unwrap());
+            }
+        }
+        shape.into_iter().collect::<Option<_>>().unwrap_or_else(Vec::new)
+    }
+
+    pub fn get_size(&self) -> u64 {
+        #[allow(clippy::unnecessary_fold)]
+        (self.get_shape().iter().fold(1, |x, y| x * y) as u64 * self.element_size())
+    }
+
+    /// bytes per element under the current dtype (see `dtype_override`). Falls back to 4 when the dtype is unknown or has variable length
+    pub fn element_size(&self) -> u64 {
+        self.dtype_override.or_else(|| self.known_dtype()).and_then(dtype_size).unwrap_or(4)
+    }
+
+    /// the dtype declared by the registry or the attrs of the node, None if neither tells
+    fn known_dtype(&self) -> Option<DataType> {
+        match self.node().graph().registry.get(&self.node().raw_node.op) {
+            Some(custom_op) => custom_op.dtypes.get(self.index).copied(),
+            None => dtype_of(&self.node().raw_node, self.index).map(|x| x.get_field_type())
+        }
+    }
+
+    /// the length along `form.axis` of each part of `form` when the option `split_ratios` gives its devices different weights, e.g.
+    /// `3,3,1,1` for two fast and two slow GPUs: each part gets a share proportional to the weight of its device (1 for devices not
+    /// listed), the remainder going to the largest fractions. Such parts are split with SplitV. None if the parts are even, which is
+    /// also the case when the length is unknown (see `fill_batchsize`).
+    pub fn uneven_slices(&self, form: &Form) -> Option<Vec<usize>> {
+        let ratios = self.node().graph().options.get("split_ratios")?;
+        if !form.is_part() {
+            return None
+        }
+        let ratios: Vec<u64> = ratios.split(',').map(|x| x.trim().parse().unwrap_or_else(|_| panic!("malformed split_ratios {}, expecting a weight for each device like 3,3,1,1", ratios))).collect();
+        let weights: Vec<u64> = form.devices.iter().map(|d| ratios.get(*d).copied().unwrap_or(1)).collect();
+        assert!(weights.iter().all(|x| *x > 0), "split_ratios must be positive");
+        if weights.iter().all(|x| *x == weights[0]) {
+            return None
+        }
+        match self.get_shape().get(form.axis) {
+            Some(len) => Some(proportional_slices(*len, &weights)),
+            None => {
+                warn!("{}: the length of axis {} is unknown, splitting it evenly in spite of split_ratios", self.original_name(), form.axis);
+                None
+            }
+        }
+    }
+
+    /// the bytes of each part of `form`
+    pub fn part_sizes(&self, form: &Form) -> Vec<u64> {
+        let size = self.get_size();
+        match self.uneven_slices(form) {
+            Some(slices) => {
+                let len: usize = slices.iter().sum();
+                slices.iter().map(|x| if len == 0 { 0 } else { size * *x as u64 / len as u64 }).collect()
+            }
+            None => vec![size / form.ndev() as u64; form.ndev()]
+        }
+    }
+
+    /// a Split of `input` into the parts of `form` along its axis, or a SplitV with a `size_splits` Const if they are uneven. The nodes
+    /// are named `<node><scope>/dim` and so on, the split being the last one.
+    fn split_nodes(&self, form: &Form, input: &str, scope: &str, device: &str) -> Vec<NodeDef> {
+        let int32 = |shape: Vec<i64>, values: Vec<i32>| AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|x| {
+            x.set_dtype(DataType::DT_INT32);
+            x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|x| for size in shape {
+                x.dim.push(crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|x| x.size = size))
+            }));
+            x.int_val = values;
+        })));
+        let make_const = |suffix: &str, value: AttrValue| {
+            let mut node = self.node().make_node("Const".to_string());
+            node.name += &format!("{}/{}", scope, suffix);
+            node.device = device.to_string();
+            node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            node.attr.insert("value".into(), value);
+            node
+        };
+
+        let dim = make_const("dim", int32(vec![], vec![form.axis as _]));
+        let mut split = self.node().make_node("Split".to_string());
+        split.name += &format!("{}/split", scope);
+        split.device = device.to_string();
+        split.attr.insert("T".into(), self.dtype());
+        split.attr.insert("num_split".into(), AttrValue::new().apply(|x| x.set_i(form.ndev() as _)));
+        match self.uneven_slices(form) {
+            None => {
+                split.input.push(dim.name.clone());
+                split.input.push(input.to_string());
+                set_input_size(&mut split, 1, self.get_size());
+                vec![dim, split]
+            }
+            Some(slices) => {
+                let sizes = make_const("size_splits", int32(vec![slices.len() as _], slices.iter().map(|x| *x as _).collect()));
+                split.op = "SplitV".into();
+                split.input.push(input.to_string());
+                split.input.push(sizes.name.clone());
+                split.input.push(dim.name.clone());
+                split.attr.insert("Tlen".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+                set_input_size(&mut split, 0, self.get_size());
+                vec![dim, sizes, split]
+            }
+        }
+    }
+
+    /// how this tensor is compressed while being summed: `none`, `fp16`, `topk`, `int8` or `powersgd`. The option `compression_overrides` (e.g.
+    /// `gradients/kernel=int8,gradients/bias=none`) sets it per tensor, by the original tensor name; other tensors follow the option `compression`.
+    pub fn compression(&self) -> String {
+        let options = &self.node().graph().options;
+        let name = self.original_name();
+        let method = options.get("compression_overrides").iter().flat_map(|x| x.split(',')).filter_map(|x| x.trim().split_once('='))
+            .find(|(tensor, _)| *tensor == name).map(|(_, method)| method.to_string())
+            .or_else(|| options.get("compression").cloned()).unwrap_or_else(|| "none".to_string());
+        assert!(["none", "fp16", "topk", "int8", "powersgd"].contains(&&method[..]), "unknown compression {} for {}, expecting none, fp16, topk, int8 or powersgd", method, name);
+        method
+    }
+
+    /// the summation that replaces the aggregation method of the strategy when this tensor is compressed by `topk`, `int8` or `powersgd`, None if the
+    /// tensor is summed by the method of the strategy (possibly casted, see `reduction_dtype`)
+    pub fn compressed_sum(&self) -> Option<Summation> {
+        match &self.compression()[..] {
+            "topk" if self.topk().is_some() => Some(Self::aggregate_topk),
+            "powersgd" if self.powersgd_rank().is_some() => Some(Self::aggregate_powersgd),
+            "int8" if get_dtype(self.node(), self.index).get_field_type() == DataType::DT_FLOAT && !self.get_shape().is_empty() => Some(Self::aggregate_quantized),
+            _ => None
+        }
+    }
+
+    /// the dtype that this tensor is casted to while being summed, None if it is summed as is. Small integers and bools are widened, floats
+    /// are compressed to half if its `compression` is `fp16`, and other dtypes (e.g. strings and complex numbers) and unknown ones are
+    /// passed through as is.
+    pub fn reduction_dtype(&self) -> Option<DataType> {
+        let dtype = self.known_dtype()?;
+        let compress = self.compression() == "fp16";
+        match dtype {
+            DataType::DT_FLOAT | DataType::DT_DOUBLE if compress => Some(DataType::DT_HALF),
+            DataType::DT_HALF | DataType::DT_FLOAT | DataType::DT_DOUBLE | DataType::DT_INT32 | DataType::DT_INT64 => None,
+            DataType::DT_BOOL | DataType::DT_INT8 | DataType::DT_UINT8 | DataType::DT_INT16 | DataType::DT_UINT16 => Some(DataType::DT_INT32),
+            DataType::DT_BFLOAT16 => Some(DataType::DT_FLOAT),
+            _ => None
+        }
+    }
+
+    /// bytes of this tensor as it crosses links while being summed, see `reduction_dtype`
+    pub fn reduced_size(&self) -> u64 {
+        match self.reduction_dtype().and_then(dtype_size) {
+            Some(size) => self.get_size() / self.element_size() * size,
+            None => self.get_size()
+        }
+    }
+
+    /// the number of values that each replica sends when this tensor is summed by `aggregate_topk`, None if it is summed densely. Only float
+    /// tensors of known shapes are sparsified, when their `compression` is `topk`, keeping a `topk_ratio` (0.01 by default) of the elements.
+    pub fn topk(&self) -> Option<usize> {
+        let options = &self.node().graph().options;
+        if self.compression() != "topk" {
+            return None
+        }
+        if !matches!(get_dtype(self.node(), self.index).get_field_type(), DataType::DT_FLOAT | DataType::DT_DOUBLE) || self.get_shape().is_empty() {
+            return None
+        }
+        let ratio: f64 = options.get("topk_ratio").map(|x| x.parse().expect("topk_ratio should be a number")).unwrap_or(0.01);
+        let n: usize = self.get_shape().iter().product();
+        let k = ((n as f64 * ratio).ceil() as usize).max(1);
+        (k < n).then_some(k)
+    }
+
+    /// the rank of the factors when this tensor is summed by `aggregate_powersgd`, None if it is summed densely. Only float matrices are
+    /// factorized, when their `compression` is `powersgd`, and only if the factors of rank `powersgd_rank` (4 by default) are smaller.
+    pub fn powersgd_rank(&self) -> Option<usize> {
+        if self.compression() != "powersgd" || !matches!(get_dtype(self.node(), self.index).get_field_type(), DataType::DT_FLOAT | DataType::DT_DOUBLE) {
+            return None
+        }
+        let rank: usize = self.node().graph().options.get("powersgd_rank").map(|x| x.parse().expect("powersgd_rank should be a number")).unwrap_or(4);
+        match self.get_shape()[..] {
+            [n, m] if rank > 0 && rank * (n + m) < n * m => Some(rank),
+            _ => None
+        }
+    }
+
+    /// the dtype attr for aux nodes that take this tensor as input
+    pub fn dtype(&self) -> AttrValue {
+        match self.dtype_override {
+            Some(dtype) => AttrValue::new().apply(|x| x.set_field_type(dtype)),
+            None => get_dtype(self.node(), self.index)
+        }
+    }
+
+    pub fn has_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn set_flag(&mut self, flag: u8) {
+        self.flags |= flag
+    }
+
+    pub fn unset_flag(&mut self, flag: u8) {
+        self.flags &= !flag
+    }
+
+    /// get the names as the specified form. The conversion is planned by `plan_conversion` from the form of the node, then emitted by `emit_conversion`.
+    /// Emitting reads the source names of this tensor through `source_names`, so its `forms` is not touched while a conversion is being built. Sparse
+    /// conversions are the exception that re-enters: `sparse_sources` calls `as_form` on the other members of the SparseTensor, which changes
+    /// their `forms` but never the one of this tensor.
+    pub fn as_form(&mut self, form: &Form, target: &mut Target) -> &[String] {
+        if !self.forms.contains_key(form) {
+            if self.has_flag(Self::IS_FIXED) {
+                panic!("BUG: no form {:?} provided for {}", form, self.original_name())
+            }
+
+            let source = self.node().form.clone();
+            let plan = plan_conversion(&source, form, self.flags).unwrap_or_else(|e| panic!("cannot convert {} to {:?}: {}", self.node().graph().describe(&self.original_name()), form, e));
+            let names = self.emit_conversion(plan, &source, form, target);
+            self.forms.insert(form.clone(), names);
+        }
+
+        &self.forms[form]
+    }
+
+    pub fn emit_conversion(&mut self, plan: Conversion, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        match plan {
+            Conversion::Identity => self.source_names(from),
+            Conversion::Broadcast => self.replicate_broadcast(from, to, target),
+            Conversion::Split => self.replicate_split(from, to, target),
+            Conversion::Concat => self.aggregate_cat(from, to, target),
+            Conversion::AllGather => self.all_gather(from, to, target),
+            Conversion::Sum => self.reduce_with_cast(from, to, target, Self::aggregate_sum),
+            Conversion::Resplit => self.resplit(from, to, target),
+            Conversion::AllToAll => self.resplit_all_to_all(from, to, target),
+            Conversion::ReduceScatter => self.reduce_with_cast(from, to, target, Self::reduce_scatter),
+            Conversion::ShapeTotal => self.shape_total(from, to, target),
+            Conversion::ShapeSlice => self.shape_slice(from, to, target),
+            Conversion::SparseSplit => self.sparse_split(from, to, target),
+            Conversion::SparseConcat => self.sparse_concat(from, to, target)
+        }
+    }
+
+    /// undo a temporary override of `forms[form]` made while emitting a conversion, given what the override replaced. The form of the node
+    /// itself is usually not in `forms` (see `source_names`), in which case the override is removed.
+    fn restore_form(&mut self, form: &Form, original: Option<Box<[String]>>) {
+        match original {
+            Some(names) => self.forms.insert(form.clone(), names),
+            None => self.forms.remove(form)
+        };
+    }
+
+    /// the names of this tensor in `from`, which is either the form of the node or an already converted one
+    pub fn source_names(&self, from: &Form) -> Box<[String]> {
+        match self.forms.get(from) {
+            Some(names) => names.clone(),
+            None => {
+                assert!(from == &self.node().form, "BUG: {} has no form {:?} to convert from", self.original_name(), from);
+                (0..from.ndev()).map(|i| format!("{}:{}", self.node().replica(i), self.index)).collect()
+            }
+        }
+    }
+
+    /**************************************
+    * following are graph editing methods *
+    **************************************/
+
+    /// run a summing conversion `f`, wrapping it with a pair of Casts if the tensor is summed in another dtype (see `reduction_dtype`).
+    /// Tensors of dtypes that cannot be casted, e.g. strings, are handed to `f` uncompressed.
+    /// The result is casted back to the original dtype on each destination device, so the shapes and dtypes seen by consumers are unchanged.
+    /// The sizes annotated on the aux nodes of `f` follow the casted dtype.
+    pub fn reduce_with_cast(&mut self, from: &Form, to: &Form, target: &mut Target, f: impl FnOnce(&mut Self, &Form, &Form, &mut Target) -> Box<[String]>) -> Box<[String]> {
+        let cast_to = match self.reduction_dtype() {
+            Some(x) => x,
+            None => return f(self, from, to, target)
+        };
+        let dtype = get_dtype(self.node(), self.index).get_field_type();
+        if cast_to == DataType::DT_HALF {
+            debug!("compressing {} to half around the aggregation", self.original_name())
+        } else {
+            warn!("{} has dtype {:?} which cannot be summed, casting to {:?} around the aggregation", self.original_name(), dtype, cast_to);
+        }
+
+        let size = self.get_size() / from.ndev() as u64;
+        let casted: Box<[String]> = self.source_names(from).into_vec().into_iter().enumerate().map(|(i, input)| {
+            let mut cast = self.node().make_node("Cast".to_string());
+            cast.name += &format!("/{}_{}/aux_cast_{}", self.index, from.code(), i);
+            cast.device = target.devices[from.devices[i]].clone();
+            cast.attr.insert("SrcT".into(), AttrValue::new().apply(|x| x.set_field_type(dtype)));
+            cast.attr.insert("DstT".into(), AttrValue::new().apply(|x| x.set_field_type(cast_to)));
+            cast.input.push(input);
+            set_input_size(&mut cast, 0, size);
+            let name = cast.name.clone();
+            target.pb.node.push(cast);
+            name
+        }).collect();
+
+        // temporarily pretend that the casted copies are this tensor
+        let original = self.forms.insert(from.clone(), casted);
+        self.dtype_override = Some(cast_to);
+        let reduced = f(self, from, to, target);
+        self.dtype_override = None;
+        self.restore_form(from, original);
+
+        let mut casted_back: BTreeMap<String, String> = BTreeMap::new();
+        reduced.iter().zip(to.devices.iter()).map(|(input, device_id)| {
+            if let Some(name) = casted_back.get(input) {
+                return name.clone()
+            }
+            let mut cast = self.node().make_node("Cast".to_string());
+            cast.name += &format!("/{}_{}/aux_cast_back_{}", self.index, to.code(), casted_back.len());
+            cast.device = target.devices[*device_id].clone();
+            cast.attr.insert("SrcT".into(), AttrValue::new().apply(|x| x.set_field_type(cast_to)));
+            cast.attr.insert("DstT".into(), AttrValue::new().apply(|x| x.set_field_type(dtype)));
+            cast.input.push(input.clone());
+            set_input_size(&mut cast, 0, self.reduced_size());
+            let name = cast.name.clone();
+            casted_back.insert(input.clone(), name.clone());
+            target.pb.node.push(cast);
+            name
+        }).collect()
+    }
+
+    /// run the aggregation `f` of a gradient with the reduction of the option `reduction` (see `Reduction`): the summing aux nodes emitted by
+    /// `f` combine the replicas with it instead, and a mean is summed and then divided by the number of replicas (see `finish_reduction`)
+    pub fn reduce_gradient(&mut self, from: &Form, to: &Form, target: &mut Target, f: impl FnOnce(&mut Self, &Form, &Form, &mut Target) -> Box<[String]>) -> Box<[String]> {
+        let reduction = Reduction::of(&self.node().graph().options);
+        self.reduction = reduction.combining();
+        let reduced = f(self, from, to, target);
+        self.reduction = Reduction::Sum;
+        self.finish_reduction(reduction, from, to, reduced, target)
+    }
+
+    /// divide `reduced`, the sum of the replicas of `from` on each device of `to`, by the number of replicas if `reduction` is mean. Each
+    /// distinct name is divided once, on the first device that uses it.
+    pub fn finish_reduction(&mut self, reduction: Reduction, from: &Form, to: &Form, reduced: Box<[String]>, target: &mut Target) -> Box<[String]> {
+        if reduction != Reduction::Mean {
+            return reduced
+        }
+
+        let dtype = get_dtype(self.node(), self.index);
+        let mut divided: BTreeMap<String, String> = BTreeMap::new();
+        reduced.iter().zip(to.devices.iter()).map(|(input, device_id)| {
+            if let Some(name) = divided.get(input) {
+                return name.clone()
+            }
+            let mut count = self.node().make_node("Const".to_string());
+            count.name += &format!("/{}_{}/aux_mean/count_{}", self.index, to.code(), divided.len());
+            count.device = target.devices[*device_id].clone();
+            count.attr.insert("dtype".into(), dtype.clone());
+            count.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| {
+                t.set_dtype(dtype.get_field_type());
+                match dtype.get_field_type() {
+                    DataType::DT_DOUBLE => t.double_val.push(from.ndev() as _),
+                    DataType::DT_HALF => t.half_val.push(half_of_int(from.ndev() as _)),
+                    DataType::DT_INT64 => t.int64_val.push(from.ndev() as _),
+                    DataType::DT_INT32 => t.int_val.push(from.ndev() as _),
+                    _ => t.float_val.push(from.ndev() as _)
+                }
+            }))));
+
+            let mut mean = self.node().make_node("RealDiv".to_string());
+            mean.name += &format!("/{}_{}/aux_mean/div_{}", self.index, to.code(), divided.len());
+            mean.device = target.devices[*device_id].clone();
+            mean.attr.insert("T".into(), dtype.clone());
+            mean.input.push(input.clone());
+            mean.input.push(count.name.clone());
+            set_input_size(&mut mean, 0, self.get_size());
+
+            let name = mean.name.clone();
+            divided.insert(input.clone(), name.clone());
+            target.pb.node.push(count);
+            target.pb.node.push(mean);
+            name
+        }).collect()
+    }
+
+    /// push a summing AddN emitted by an aggregation method. If the replicas are combined by max, min or prod instead (see `reduce_gradient`),
+    /// its inputs are stacked by a Pack and reduced along the first dimension by a node that takes the name of the AddN.
+    fn push_reduce(&self, addn: NodeDef, target: &mut Target) {
+        let op = match self.reduction.stacked_op() {
+            Some(op) => op,
+            None => return target.pb.node.push(addn)
+        };
+
+        let size: i64 = addn.attr.get("_tge_input_sizes").map(|x| x.get_list().i.iter().sum()).unwrap_or(0);
+        let mut stack = addn.clone();
+        stack.name += "/stack";
+        stack.op = "Pack".into();
+        stack.attr.insert("axis".into(), AttrValue::new().apply(|x| x.set_i(0)));
+
+        let mut axis = self.node().make_node("Const".to_string());
+        axis.name = format!("{}/axis", addn.name);
+        axis.device = addn.device.clone();
+        axis.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+        axis.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| {
+            t.set_dtype(DataType::DT_INT32);
+            t.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+            t.int_val.push(0);
+        }))));
+
+        let mut reduce = addn;
+        reduce.op = op.into();
+        reduce.input = vec![stack.name.clone(), axis.name.clone()].into();
+        reduce.attr.remove("N");
+        reduce.attr.remove("_tge_input_sizes");
+        reduce.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+        reduce.attr.insert("keep_dims".into(), AttrValue::new().apply(|x| x.set_b(false)));
+        set_input_size(&mut reduce, 0, size as _);
+
+        target.pb.node.push(axis);
+        target.pb.node.push(stack);
+        target.pb.node.push(reduce)
+    }
+
+    /// run an all-reduce `f` on k chunks of the tensor when it has at least `chunk_threshold` bytes (options `chunk_threshold` and `chunk_count`,
+    /// 4 by default), so the communication of one chunk overlaps the reduction of the next instead of the whole gradient going at once.
+    /// Each source is flattened and split into k chunks, `f` runs on each chunk, and the results are concatenated and reshaped on each
+    /// destination. The aux nodes of chunk c are renamed with a `/chunk_<c>` suffix, and on each device the nodes that read chunk c+1 wait
+    /// for those that read chunk c. Tensors whose number of elements is not a multiple of k are reduced at once.
+    pub fn reduce_chunked(&mut self, from: &Form, to: &Form, target: &mut Target, f: fn(&mut Self, &Form, &Form, &mut Target) -> Box<[String]>) -> Box<[String]> {
+        let options = &self.node().graph().options;
+        let threshold: Option<u64> = options.get("chunk_threshold").map(|x| x.parse().expect("chunk_threshold should be a number"));
+        let k: usize = options.get("chunk_count").map(|x| x.parse().expect("chunk_count should be a number")).unwrap_or(4);
+        match threshold {
+            Some(threshold) if self.get_size() >= threshold && k > 1 => {}
+            _ => return f(self, from, to, target)
+        }
+        if self.get_shape().iter().product::<usize>() % k != 0 {
+            debug!("{} cannot be split into {} chunks, reducing it at once", self.original_name(), k);
+            return f(self, from, to, target)
+        }
+
+        let dtype = self.dtype();
+        let psize = self.get_size() / from.ndev() as u64;
+        let tag = format!("{}_{}/aux_chunk", self.index, from.code());
+
+        // 1. flatten and split each source
+        let mut shapes = vec![];
+        let chunks: Vec<Vec<String>> = self.source_names(from).iter().enumerate().map(|(i, source)| {
+            let device = target.devices[from.devices[i]].clone();
+            let mut shape = self.node().make_node("Shape".to_string());
+            shape.name += &format!("/{}/shape_{}", tag, i);
+            shape.device = device.clone();
+            shape.attr.insert("T".into(), dtype.clone());
+            shape.input.push(source.clone());
+            set_input_size(&mut shape, 0, psize);
+
+            let mut flat_shape = self.node().make_node("Const".to_string());
+            flat_shape.name += &format!("/{}/flat_{}/shape", tag, i);
+            flat_shape.device = device.clone();
+            flat_shape.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+                x.set_dtype(DataType::DT_INT32);
+                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|x| x.dim.push(crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|x| x.size = 1))));
+                x.int_val.push(-1);
+            });
+            flat_shape.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+
+            let mut flat = self.node().make_node("Reshape".to_string());
+            flat.name += &format!("/{}/flat_{}/flat", tag, i);
+            flat.device = device.clone();
+            flat.attr.insert("T".into(), dtype.clone());
+            flat.input.push(source.clone());
+            flat.input.push(flat_shape.name.clone());
+            set_input_size(&mut flat, 0, psize);
+
+            let mut dim = self.node().make_node("Const".to_string());
+            dim.name += &format!("/{}/split_{}/dim", tag, i);
+            dim.device = device.clone();
+            dim.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+                x.set_dtype(DataType::DT_INT32);
+                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+                x.int_val.push(0);
+            });
+            dim.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+
+            let mut split = self.node().make_node("Split".to_string());
+            split.name += &format!("/{}/split_{}/split", tag, i);
+            split.device = device;
+            split.input.push(dim.name.clone());
+            split.input.push(flat.name.clone());
+            split.attr.insert("T".into(), dtype.clone());
+            split.attr.insert("num_split".into(), AttrValue::new().apply(|x| x.set_i(k as _)));
+            set_input_size(&mut split, 1, psize);
+
+            let name = split.name.clone();
+            shapes.push((from.devices[i], shape.name.clone()));
+            target.pb.node.push(shape);
+            target.pb.node.push(flat_shape);
+            target.pb.node.push(flat);
+            target.pb.node.push(dim);
+            target.pb.node.push(split);
+            (0..k).map(|c| format!("{}:{}", name, c)).collect()
+        }).collect();
+
+        // 2. reduce each chunk, pretending that the chunks are this tensor like `reduce_with_cast` does
+        let original = self.forms.insert(from.clone(), chunks.iter().map(|x| x[0].clone()).collect());
+        let mut entries: BTreeMap<String, Vec<String>> = BTreeMap::new(); // the nodes of the previous chunk that read its sources, by device
+        let mut reduced: Vec<Box<[String]>> = vec![];
+        for c in 0..k {
+            let sources: BTreeSet<String> = chunks.iter().map(|x| x[c].clone()).collect();
+            self.forms.insert(from.clone(), chunks.iter().map(|x| x[c].clone()).collect());
+            let start = target.pb.node.len();
+            let result = f(self, from, to, target);
+
+            let created: BTreeSet<String> = target.pb.node.iter().skip(start).map(|x| x.name.clone()).collect();
+            let rename = |name: &str| if created.contains(name) { format!("{}/chunk_{}", name, c) } else { name.to_string() };
+            crate::naming::rename_nodes(&mut target.pb.node.as_mut_slice()[start..], rename);
+            let mut current: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for node in target.pb.node.iter_mut().skip(start) {
+                if let Some(sizes) = node.attr.get_mut("_tge_input_sizes") {
+                    for size in sizes.mut_list().i.iter_mut() {
+                        *size /= k as i64
+                    }
+                }
+                if let Some(shared_name) = node.attr.get_mut("shared_name").filter(|x| !x.get_s().is_empty()) {
+                    let name = format!("{}/chunk_{}", String::from_utf8_lossy(shared_name.get_s()), c);
+                    shared_name.set_s(name.into_bytes())
+                }
+                if node.input.iter().any(|x| sources.contains(x)) {
+                    for entry in entries.get(&node.device).into_iter().flatten() {
+                        node.input.push(format!("^{}", entry))
+                    }
+                    current.entry(node.device.clone()).or_default().push(node.name.clone())
+                }
+            }
+            entries = current;
+            reduced.push(result.iter().map(|x| rename(x)).collect());
+        }
+        self.restore_form(from, original);
+
+        // 3. concat the chunks and restore the shape on each destination
+        let mut gathered: BTreeMap<usize, String> = BTreeMap::new();
+        to.devices.iter().enumerate().map(|(j, device_id)| {
+            if let Some(name) = gathered.get(device_id) {
+                return name.clone()
+            }
+            let mut axis = self.node().make_node("Const".to_string());
+            axis.name += &format!("/{}/concat_{}/axis", tag, device_id);
+            axis.device = target.devices[*device_id].clone();
+            axis.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+                x.set_dtype(DataType::DT_INT32);
+                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+                x.int_val.push(0);
+            });
+            axis.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+
+            let mut concat = self.node().make_node("ConcatV2".to_string());
+            concat.name += &format!("/{}/concat_{}/concat", tag, device_id);
+            concat.device = target.devices[*device_id].clone();
+            concat.input = reduced.iter().map(|x| x[j].clone()).collect();
+            concat.input.push(axis.name.clone());
+            concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(k as _)));
+            concat.attr.insert("T".into(), dtype.clone());
+            concat.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            for c in 0..k {
+                set_input_size(&mut concat, c, psize / k as u64)
+            }
+
+            let shape = shapes.iter().find(|(d, _)| d == device_id).unwrap_or(&shapes[0]).1.clone();
+            let mut reshape = self.node().make_node("Reshape".to_string());
+            reshape.name += &format!("/{}/reshape_{}", tag, device_id);
+            reshape.device = target.devices[*device_id].clone();
+            reshape.attr.insert("T".into(), dtype.clone());
+            reshape.input.push(concat.name.clone());
+            reshape.input.push(shape);
+            set_input_size(&mut reshape, 0, psize);
+
+            let name = reshape.name.clone();
+            gathered.insert(*device_id, name.clone());
+            target.pb.node.push(axis);
+            target.pb.node.push(concat);
+            target.pb.node.push(reshape);
+            name
+        }).collect()
+    }
+
+    /// all-reduce this tensor together with `others` as one fused buffer, like the tensor fusion of Horovod: on each source device the
+    /// members are flattened and concatenated (padded with zeros to a multiple of twice the number of replicas, so the ring and the double
+    /// tree can split it evenly), `f` runs once on the buffer, and on each destination the result is split back and reshaped. All members
+    /// must have the same dtype and known shapes. Members summed in another dtype (see `reduction_dtype`) are casted as whole buffers around `f`
+    /// by `reduce_with_cast`, so a bucket needs one pair of Casts per device rather than per member. Returns the names of each member, this tensor first.
+    pub fn reduce_fused(&mut self, others: &[&Tensor], from: &Form, to: &Form, target: &mut Target, f: fn(&mut Self, &Form, &Form, &mut Target) -> Box<[String]>) -> Vec<Box<[String]>> {
+        let counts: Vec<usize> = std::iter::once(&*self).chain(others.iter().copied()).map(|x| x.get_shape().iter().product()).collect();
+        let psizes: Vec<u64> = std::iter::once(&*self).chain(others.iter().copied()).map(|x| x.get_size() / from.ndev() as u64).collect();
+        let sources: Vec<Box<[String]>> = std::iter::once(&*self).chain(others.iter().copied()).map(|x| x.source_names(from)).collect();
+        let members: Vec<(&Node, usize)> = std::iter::once(&*self).chain(others.iter().copied()).map(|x| (x.node(), x.index)).collect();
+        let n = 2 * from.ndev();
+        let pad = (n - counts.iter().sum::<usize>() % n) % n;
+        let total: u64 = psizes.iter().sum::<u64>() + pad as u64 * self.element_size();
+        let dtype = self.dtype();
+        let tag = format!("{}_{}/aux_fused", self.index, from.code());
+        debug!("fusing {} tensors of {} bytes into one reduction", members.len(), total * from.ndev() as u64);
+
+        let int32_const = |node: &mut NodeDef, values: &[i64], vector: bool| {
+            node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+                x.set_dtype(DataType::DT_INT32);
+                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|s| if vector {
+                    s.dim.push(crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|d| d.size = values.len() as _))
+                }));
+                for v in values {
+                    x.int_val.push(*v as _)
+                }
+            });
+            node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+        };
+
+        // 1. record the shapes, flatten and concat the members on each source
+        let mut shapes: Vec<Vec<(usize, String)>> = vec![vec![]; members.len()];
+        let buffers: Box<[String]> = (0..from.ndev()).map(|i| {
+            let device = target.devices[from.devices[i]].clone();
+            let mut flat_shape = self.node().make_node("Const".to_string());
+            flat_shape.name += &format!("/{}/flat_shape_{}", tag, i);
+            flat_shape.device = device.clone();
+            int32_const(&mut flat_shape, &[-1], true);
+
+            let mut concat = self.node().make_node("ConcatV2".to_string());
+            concat.name += &format!("/{}/concat_{}", tag, i);
+            concat.device = device.clone();
+            for (m, (member, index)) in members.iter().enumerate() {
+                let mut shape = member.make_node("Shape".to_string());
+                shape.name += &format!("/{}_{}/aux_fused/shape_{}", index, from.code(), i);
+                shape.device = device.clone();
+                shape.attr.insert("T".into(), dtype.clone());
+                shape.input.push(sources[m][i].clone());
+                set_input_size(&mut shape, 0, psizes[m]);
+
+                let mut flat = member.make_node("Reshape".to_string());
+                flat.name += &format!("/{}_{}/aux_fused/flat_{}", index, from.code(), i);
+                flat.device = device.clone();
+                flat.attr.insert("T".into(), dtype.clone());
+                flat.input.push(sources[m][i].clone());
+                flat.input.push(flat_shape.name.clone());
+                set_input_size(&mut flat, 0, psizes[m]);
+
+                set_input_size(&mut concat, m, psizes[m]);
+                concat.input.push(flat.name.clone());
+                shapes[m].push((from.devices[i], shape.name.clone()));
+                target.pb.node.push(shape);
+                target.pb.node.push(flat);
+            }
+            if pad > 0 {
+                let mut zeros = self.node().make_node("Const".to_string());
+                zeros.name += &format!("/{}/pad_{}", tag, i);
+                zeros.device = device.clone();
+                zeros.attr.insert("dtype".into(), dtype.clone());
+                let value = crate::proto::tensor::TensorProto::new().apply(|x| { // no values: TF fills the tensor with zeros
+                    x.set_dtype(dtype.get_field_type());
+                    x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|s| s.dim.push(crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|d| d.size = pad as _))));
+                });
+                zeros.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+                concat.input.push(zeros.name.clone());
+                target.pb.node.push(zeros);
+            }
+            let mut axis = self.node().make_node("Const".to_string());
+            axis.name += &format!("/{}/concat_{}/axis", tag, i);
+            axis.device = device;
+            int32_const(&mut axis, &[0], false);
+            concat.input.push(axis.name.clone());
+            concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i((members.len() + (pad > 0) as usize) as _)));
+            concat.attr.insert("T".into(), dtype.clone());
+            concat.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+
+            let name = concat.name.clone();
+            target.pb.node.push(flat_shape);
+            target.pb.node.push(axis);
+            target.pb.node.push(concat);
+            name
+        }).collect();
+
+        // 2. reduce the buffer, pretending that it is this tensor like `reduce_with_cast` does. The sizes annotated by `f` and the casts are scaled to the buffer.
+        let original = self.forms.insert(from.clone(), buffers);
+        let start = target.pb.node.len();
+        let reduced = self.reduce_with_cast(from, to, target, f);
+        for node in target.pb.node.iter_mut().skip(start) {
+            if let Some(sizes) = node.attr.get_mut("_tge_input_sizes") {
+                for size in sizes.mut_list().i.iter_mut() {
+                    *size = (*size as u64 * total / psizes[0].max(1)) as _
+                }
+            }
+        }
+        self.restore_form(from, original);
+
+        // 3. split the result and restore the shape of each member on each destination
+        let mut unpacked: BTreeMap<String, Vec<String>> = BTreeMap::new(); // each reduced buffer => the reshaped members
+        for (j, device_id) in to.devices.iter().enumerate() {
+            if unpacked.contains_key(&reduced[j]) {
+                continue
+            }
+            let device = target.devices[*device_id].clone();
+            let mut size_splits = self.node().make_node("Const".to_string());
+            size_splits.name += &format!("/{}/split_{}/size_splits", tag, j);
+            size_splits.device = device.clone();
+            int32_const(&mut size_splits, &counts.iter().map(|x| *x as i64).chain(if pad > 0 { Some(pad as i64) } else { None }).collect::<Vec<_>>(), true);
+
+            let mut axis = self.node().make_node("Const".to_string());
+            axis.name += &format!("/{}/split_{}/axis", tag, j);
+            axis.device = device.clone();
+            int32_const(&mut axis, &[0], false);
+
+            let mut split = self.node().make_node("SplitV".to_string());
+            split.name += &format!("/{}/split_{}/split", tag, j);
+            split.device = device.clone();
+            split.input.push(reduced[j].clone());
+            split.input.push(size_splits.name.clone());
+            split.input.push(axis.name.clone());
+            split.attr.insert("num_split".into(), AttrValue::new().apply(|x| x.set_i((members.len() + (pad > 0) as usize) as _)));
+            split.attr.insert("T".into(), dtype.clone());
+            split.attr.insert("Tlen".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            set_input_size(&mut split, 0, total);
+
+            let names = members.iter().enumerate().map(|(m, (member, index))| {
+                let shape = shapes[m].iter().find(|(d, _)| d == device_id).unwrap_or(&shapes[m][0]).1.clone();
+                let mut reshape = member.make_node("Reshape".to_string());
+                reshape.name += &format!("/{}_{}/aux_fused/reshape_{}", index, to.code(), j);
+                reshape.device = device.clone();
+                reshape.attr.insert("T".into(), dtype.clone());
+                reshape.input.push(format!("{}:{}", split.name, m));
+                reshape.input.push(shape);
+                set_input_size(&mut reshape, 0, psizes[m]);
+                let name = reshape.name.clone();
+                target.pb.node.push(reshape);
+                name
+            }).collect();
+            unpacked.insert(reduced[j].clone(), names);
+            target.pb.node.push(size_splits);
+            target.pb.node.push(axis);
+            target.pb.node.push(split);
+        }
+        (0..members.len()).map(|m| reduced.iter().map(|x| unpacked[x][m].clone()).collect()).collect()
+    }
+
+    pub fn aggregate_sum(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full());
+
+        let mut addn = self.node().make_node("AddN".to_string());
+        addn.name += &format!("/{}_{}/aux_sum", self.index, to.code());
+        addn.device = target.devices[to.devices[0]].clone();
+        addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(from.ndev() as _)));
+        addn.attr.insert("T".into(), self.dtype());
+        addn.input = self.source_names(from).into_vec().into();
+        for i in 0..from.ndev() {
+            set_input_size(&mut addn, i, self.get_size() / from.ndev() as u64)
+        }
+
+        let result = vec![addn.name.clone(); to.ndev()].into_boxed_slice();
+        self.push_reduce(addn, target);
+        result
+    }
+
+    /// `aggregate_sum` divided by the number of replicas
+    pub fn aggregate_mean(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        let summed = self.aggregate_sum(from, to, target);
+        self.finish_reduction(Reduction::Mean, from, to, summed, target)
+    }
+
+    /// sum the replicas by sending only the k largest-magnitude values of each, with their indices (see `topk`). Each replica keeps what it
+    /// did not send in a residual aux variable and adds it to the next gradient (error feedback), so small values are delayed rather than
+    /// lost. Every destination device scatters the values of all replicas into a dense tensor, summing those at the same index.
+    pub fn aggregate_topk(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part());
+        let k = self.topk().expect("BUG: aggregate_topk on a tensor that is not sparsified");
+        let shape = self.get_shape();
+        let n: usize = shape.iter().product();
+        let dtype = self.dtype();
+        let element_size = self.element_size();
+        let prefix = format!("{}/{}_{}/aux_topk", self.node().raw_node.name, self.index, to.code());
+        let int_const = |name: String, device: &str, dims: &[i32]| {
+            let mut node = self.node().make_node("Const".to_string());
+            node.name = name;
+            node.device = device.to_string();
+            node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| {
+                t.set_dtype(DataType::DT_INT32);
+                t.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|s| {
+                    s.dim.push(crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|d| d.size = dims.len() as _))
+                }));
+                t.int_val.extend_from_slice(dims);
+            }))));
+            node
+        };
+        let op = |op: &str, name: String, device: &str, inputs: Vec<String>| {
+            let mut node = self.node().make_node(op.to_string());
+            node.name = name;
+            node.device = device.to_string();
+            node.input = inputs.into();
+            node
+        };
+
+        // 1. on each replica, add the residual, select the top k and keep the rest as the new residual
+        let mut sent = vec![]; // (values, indices) of each replica
+        for (i, source) in self.source_names(from).into_vec().into_iter().enumerate() {
+            let device = target.devices[from.devices[i]].clone();
+            let residual = target.create_aux_variable(&format!("topk_residual/{}_{}_{}/{}", self.node().raw_node.name, self.index, to.code(), i),
+                from.devices[i], dtype.get_field_type(), &[n], 0.);
+
+            let flat_shape = int_const(format!("{}/flat_{}/shape", prefix, i), &device, &[-1]);
+            let mut flat = op("Reshape", format!("{}/flat_{}", prefix, i), &device, vec![source, flat_shape.name.clone()]);
+            flat.attr.insert("T".into(), dtype.clone());
+            set_input_size(&mut flat, 0, self.get_size() / from.ndev() as u64);
+
+            let mut acc = op("Add", format!("{}/acc_{}", prefix, i), &device, vec![flat.name.clone(), residual.clone()]);
+            acc.attr.insert("T".into(), dtype.clone());
+
+            let mut abs = op("Abs", format!("{}/abs_{}", prefix, i), &device, vec![acc.name.clone()]);
+            abs.attr.insert("T".into(), dtype.clone());
+
+            let mut k_const = int_const(format!("{}/k_{}", prefix, i), &device, &[k as _]);
+            k_const.attr.get_mut("value").unwrap().mut_tensor().clear_tensor_shape(); // TopKV2 takes a scalar
+            let mut topk = op("TopKV2", format!("{}/topk_{}", prefix, i), &device, vec![abs.name.clone(), k_const.name.clone()]);
+            topk.attr.insert("T".into(), dtype.clone());
+            topk.attr.insert("sorted".into(), AttrValue::new().apply(|x| x.set_b(false)));
+
+            let index_shape = int_const(format!("{}/indices_{}/shape", prefix, i), &device, &[-1, 1]);
+            let mut indices = op("Reshape", format!("{}/indices_{}", prefix, i), &device, vec![format!("{}:1", topk.name), index_shape.name.clone()]);
+            indices.attr.insert("T".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+
+            let mut gather = op("GatherNd", format!("{}/values_{}", prefix, i), &device, vec![acc.name.clone(), indices.name.clone()]);
+            gather.attr.insert("Tparams".into(), dtype.clone());
+            gather.attr.insert("Tindices".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+
+            let dense_shape = int_const(format!("{}/dense_{}/shape", prefix, i), &device, &[n as _]);
+            let mut dense = op("ScatterNd", format!("{}/dense_{}", prefix, i), &device, vec![indices.name.clone(), gather.name.clone(), dense_shape.name.clone()]);
+            dense.attr.insert("T".into(), dtype.clone());
+            dense.attr.insert("Tindices".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+
+            let mut rest = op("Sub", format!("{}/rest_{}", prefix, i), &device, vec![acc.name.clone(), dense.name.clone()]);
+            rest.attr.insert("T".into(), dtype.clone());
+
+            let mut update = op("Assign", format!("{}/update_{}", prefix, i), &device, vec![residual, rest.name.clone()]);
+            update.attr.insert("T".into(), dtype.clone());
+            update.attr.insert("use_locking".into(), AttrValue::new().apply(|x| x.set_b(true)));
+            update.attr.insert("validate_shape".into(), AttrValue::new().apply(|x| x.set_b(true)));
+
+            // the values leave the device only after the residual is updated, so the next step reads the new one
+            let mut values = op("Identity", format!("{}/sent_{}", prefix, i), &device, vec![gather.name.clone(), format!("^{}", update.name)]);
+            values.attr.insert("T".into(), dtype.clone());
+
+            sent.push((values.name.clone(), indices.name.clone()));
+            target.pb.node.extend([flat_shape, flat, acc, abs, k_const, topk, index_shape, indices, gather, dense_shape, dense, rest, update, values]);
+        }
+
+        // 2. on each destination, scatter-add the values of all replicas and restore the shape
+        let mut gathered: BTreeMap<usize, String> = BTreeMap::new();
+        to.devices.iter().map(|device_id| {
+            if let Some(name) = gathered.get(device_id) {
+                return name.clone()
+            }
+            let device = target.devices[*device_id].clone();
+            let axis = int_const(format!("{}/gather_{}/axis", prefix, device_id), &device, &[0]).apply(|x| {
+                x.attr.get_mut("value").unwrap().mut_tensor().clear_tensor_shape()
+            });
+            let concat = |what: &str, inputs: Vec<String>, dtype: AttrValue, size: u64| {
+                let mut concat = op("ConcatV2", format!("{}/gather_{}/{}", prefix, device_id, what), &device, inputs);
+                concat.input.push(axis.name.clone());
+                concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(from.ndev() as _)));
+                concat.attr.insert("T".into(), dtype);
+                concat.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+                for i in 0..from.ndev() {
+                    set_input_size(&mut concat, i, size)
+                }
+                concat
+            };
+            let values = concat("values", sent.iter().map(|x| x.0.clone()).collect(), dtype.clone(), k as u64 * element_size);
+            let indices = concat("indices", sent.iter().map(|x| x.1.clone()).collect(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)), k as u64 * 4);
+
+            let dense_shape = int_const(format!("{}/gather_{}/dense_shape", prefix, device_id), &device, &[n as _]);
+            let mut dense = op("ScatterNd", format!("{}/gather_{}/dense", prefix, device_id), &device, vec![indices.name.clone(), values.name.clone(), dense_shape.name.clone()]);
+            dense.attr.insert("T".into(), dtype.clone());
+            dense.attr.insert("Tindices".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+
+            let original_shape = int_const(format!("{}/gather_{}/shape", prefix, device_id), &device, &shape.iter().map(|x| *x as _).collect::<Vec<_>>());
+            let mut reshape = op("Reshape", format!("{}/gather_{}/reshape", prefix, device_id), &device, vec![dense.name.clone(), original_shape.name.clone()]);
+            reshape.attr.insert("T".into(), dtype.clone());
+
+            let name = reshape.name.clone();
+            gathered.insert(*device_id, name.clone());
+            target.pb.node.extend([axis, values, indices, dense_shape, dense, original_shape, reshape]);
+            name
+        }).collect()
+    }
+
+    /// sum the replicas of a [n, m] matrix by sending low-rank factors, as PowerSGD (Vogels et al., 2019) does with one power iteration per step:
+    ///
+    /// ```text
+    /// M_i = G_i + E_i              the gradient of replica i plus its error feedback, on its device
+    /// P = orthogonalize(sum_i M_i Q)  [n, r], Q is the [m, r] factor of the previous step
+    /// Q' = sum_i M_i^T P           [m, r], also kept for the next step
+    /// E_i = M_i - P (M_i^T P)^T    what replica i did not send
+    /// sum ~= P Q'^T                on each destination
+    /// ```
+    ///
+    /// The factors of all replicas are summed on every source device, so the sources compute identical P and Q' in the same order. Q starts
+    /// from the same pseudo-random matrix on every device, drawn from the stream of the tensor (see `rng`). Destinations that hold no replica
+    /// use the factors of the first source.
+    pub fn aggregate_powersgd(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part());
+        let rank = self.powersgd_rank().expect("BUG: aggregate_powersgd on a tensor that is not factorized");
+        let (n, m) = match self.get_shape()[..] {
+            [n, m] => (n, m),
+            _ => unreachable!()
+        };
+        let dtype = self.dtype();
+        let element_size = self.element_size();
+        let prefix = format!("{}/{}_{}/aux_powersgd", self.node().raw_node.name, self.index, to.code());
+        let var_name = |what: &str, i: usize| format!("powersgd_{}/{}_{}_{}/{}", what, self.node().raw_node.name, self.index, to.code(), i);
+        let op = |op: &str, name: String, device: &str, inputs: Vec<String>| {
+            let mut node = self.node().make_node(op.to_string());
+            node.name = name;
+            node.device = device.to_string();
+            node.attr.insert("T".into(), dtype.clone());
+            node.input = inputs.into();
+            node
+        };
+        let matmul = |name: String, device: &str, a: String, b: String, transpose_a: bool, transpose_b: bool| {
+            let mut node = op("MatMul", name, device, vec![a, b]);
+            node.attr.insert("transpose_a".into(), AttrValue::new().apply(|x| x.set_b(transpose_a)));
+            node.attr.insert("transpose_b".into(), AttrValue::new().apply(|x| x.set_b(transpose_b)));
+            node
+        };
+        let addn = |name: String, device: &str, inputs: Vec<String>, size: u64| {
+            let mut node = op("AddN", name, device, inputs);
+            node.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(from.ndev() as _)));
+            for i in 0..from.ndev() {
+                set_input_size(&mut node, i, size)
+            }
+            node
+        };
+        let assign = |name: String, device: &str, var: String, value: String| {
+            let mut node = op("Assign", name, device, vec![var, value]);
+            node.attr.insert("use_locking".into(), AttrValue::new().apply(|x| x.set_b(true)));
+            node.attr.insert("validate_shape".into(), AttrValue::new().apply(|x| x.set_b(true)));
+            node
+        };
+
+        // a seeded initial Q in [-1, 1), identical on all devices
+        let mut rng = crate::rng::RngConfig::from_options(&self.node().graph().options).stream(&format!("powersgd/{}:{}", self.node().raw_node.name, self.index));
+        let initial_q: Vec<f64> = (0..m * rank).map(|_| 2. * rng.unit() - 1.).collect();
+
+        // 1. on each replica, add the error feedback and project onto the previous Q
+        let sources = self.source_names(from);
+        let (mut ms, mut qs, mut ps) = (vec![], vec![], vec![]);
+        for (i, source) in sources.iter().enumerate() {
+            let device = target.devices[from.devices[i]].clone();
+            let error = target.create_aux_variable(&var_name("error", i), from.devices[i], dtype.get_field_type(), &[n, m], 0.);
+            let q = target.create_aux_variable_with(&var_name("q", i), from.devices[i], dtype.get_field_type(), &[m, rank], &initial_q);
+            let mut acc = op("Add", format!("{}/acc_{}", prefix, i), &device, vec![source.clone(), error.clone()]);
+            set_input_size(&mut acc, 0, self.get_size() / from.ndev() as u64);
+            let p = matmul(format!("{}/p_{}", prefix, i), &device, acc.name.clone(), q.clone(), false, false);
+            ms.push((acc.name.clone(), error));
+            qs.push(q);
+            ps.push(p.name.clone());
+            target.pb.node.extend([acc, p]);
+        }
+
+        // 2. on each source device, sum and orthogonalize P, then project back to get this replica's share of Q'
+        let mut orthogonal = vec![];
+        let mut q_parts = vec![];
+        for (i, (acc, _)) in ms.iter().enumerate() {
+            let device = target.devices[from.devices[i]].clone();
+            let sum = addn(format!("{}/p_sum_{}", prefix, i), &device, ps.clone(), (n * rank) as u64 * element_size);
+            let mut qr = op("Qr", format!("{}/qr_{}", prefix, i), &device, vec![sum.name.clone()]);
+            qr.attr.insert("full_matrices".into(), AttrValue::new().apply(|x| x.set_b(false)));
+            let q_part = matmul(format!("{}/q_{}", prefix, i), &device, acc.clone(), qr.name.clone(), true, false);
+            orthogonal.push(qr.name.clone());
+            q_parts.push(q_part.name.clone());
+            target.pb.node.extend([sum, qr, q_part]);
+        }
+
+        // 3. on each source device, sum Q', keep it for the next step, and keep what was not sent as the error feedback
+        let mut factors = vec![]; // (P, Q') on each source device
+        let mut updates = vec![];
+        for (i, (acc, error)) in ms.iter().enumerate() {
+            let device = target.devices[from.devices[i]].clone();
+            let sum = addn(format!("{}/q_sum_{}", prefix, i), &device, q_parts.clone(), (m * rank) as u64 * element_size);
+            let update_q = assign(format!("{}/update_q_{}", prefix, i), &device, qs[i].clone(), sum.name.clone());
+            let sent = matmul(format!("{}/sent_{}", prefix, i), &device, orthogonal[i].clone(), q_parts[i].clone(), false, true);
+            let rest = op("Sub", format!("{}/rest_{}", prefix, i), &device, vec![acc.clone(), sent.name.clone()]);
+            let update_error = assign(format!("{}/update_error_{}", prefix, i), &device, error.clone(), rest.name.clone());
+            factors.push((orthogonal[i].clone(), sum.name.clone()));
+            updates.push(update_q.name.clone());
+            updates.push(update_error.name.clone());
+            target.pb.node.extend([sum, update_q, sent, rest, update_error]);
+        }
+
+        // 4. on each destination, reconstruct the sum once the states are updated
+        let mut reconstructed: BTreeMap<usize, String> = BTreeMap::new();
+        to.devices.iter().map(|device_id| {
+            if let Some(name) = reconstructed.get(device_id) {
+                return name.clone()
+            }
+            let source = from.devices.iter().position(|d| d == device_id).unwrap_or(0);
+            let (p, q) = factors[source].clone();
+            let device = target.devices[*device_id].clone();
+            let mut result = matmul(format!("{}/result_{}", prefix, device_id), &device, p, q, false, true);
+            set_input_size(&mut result, 0, (n * rank) as u64 * element_size);
+            set_input_size(&mut result, 1, (m * rank) as u64 * element_size);
+            result.input.extend(updates.iter().map(|x| format!("^{}", x)));
+            let name = result.name.clone();
+            reconstructed.insert(*device_id, name.clone());
+            target.pb.node.push(result);
+            name
+        }).collect()
+    }
+
+    /// sum the replicas by sending them as 8-bit integers (see `compression`). Each replica is quantized by QuantizeV2 to qint8 on its own
+    /// device, scaled by its largest magnitude, and sent with its range. Every destination device dequantizes the replicas and adds them up,
+    /// as the replicas have different scales. Only float tensors of known shapes are quantized. TF has only CPU kernels for QuantizeV2 and
+    /// Dequantize, so they need soft placement on GPUs, which runs them on the CPU of the task (see `kernels::predict_soft_placement`).
+    pub fn aggregate_quantized(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part());
+        let shape = self.get_shape();
+        let n: usize = shape.iter().product();
+        let dtype = self.dtype();
+        let prefix = format!("{}/{}_{}/aux_int8", self.node().raw_node.name, self.index, to.code());
+        let qint8 = AttrValue::new().apply(|x| x.set_field_type(DataType::DT_QINT8));
+        let int_const = |name: String, device: &str, dims: &[i32]| {
+            let mut node = self.node().make_node("Const".to_string());
+            node.name = name;
+            node.device = device.to_string();
+            node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| {
+                t.set_dtype(DataType::DT_INT32);
+                t.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|s| {
+                    s.dim.push(crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|d| d.size = dims.len() as _))
+                }));
+                t.int_val.extend_from_slice(dims);
+            }))));
+            node
+        };
+        let op = |op: &str, name: String, device: &str, inputs: Vec<String>| {
+            let mut node = self.node().make_node(op.to_string());
+            node.name = name;
+            node.device = device.to_string();
+            node.input = inputs.into();
+            node
+        };
+        let scaled = |node: &mut NodeDef| {
+            node.attr.insert("T".into(), qint8.clone());
+            node.attr.insert("mode".into(), AttrValue::new().apply(|x| x.set_s(b"SCALED".to_vec())));
+        };
+
+        // 1. on each replica, quantize the flattened tensor into [-max|x|, max|x|]
+        let quantized: Vec<String> = self.source_names(from).into_vec().into_iter().enumerate().map(|(i, source)| {
+            let device = target.devices[from.devices[i]].clone();
+            let flat_shape = int_const(format!("{}/flat_{}/shape", prefix, i), &device, &[-1]);
+            let mut flat = op("Reshape", format!("{}/flat_{}", prefix, i), &device, vec![source, flat_shape.name.clone()]);
+            flat.attr.insert("T".into(), dtype.clone());
+            set_input_size(&mut flat, 0, self.get_size() / from.ndev() as u64);
+
+            let mut abs = op("Abs", format!("{}/abs_{}", prefix, i), &device, vec![flat.name.clone()]);
+            abs.attr.insert("T".into(), dtype.clone());
+            let axis = int_const(format!("{}/max_{}/axis", prefix, i), &device, &[0]);
+            let mut max = op("Max", format!("{}/max_{}", prefix, i), &device, vec![abs.name.clone(), axis.name.clone()]);
+            max.attr.insert("T".into(), dtype.clone());
+            max.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            max.attr.insert("keep_dims".into(), AttrValue::new().apply(|x| x.set_b(false)));
+            let mut min = op("Neg", format!("{}/min_{}", prefix, i), &device, vec![max.name.clone()]);
+            min.attr.insert("T".into(), dtype.clone());
+
+            let mut quantize = op("QuantizeV2", format!("{}/quantize_{}", prefix, i), &device, vec![flat.name.clone(), min.name.clone(), max.name.clone()]);
+            scaled(&mut quantize);
+            quantize.attr.insert("round_mode".into(), AttrValue::new().apply(|x| x.set_s(b"HALF_TO_EVEN".to_vec())));
+
+            let name = quantize.name.clone();
+            target.pb.node.extend([flat_shape, flat, abs, axis, max, min, quantize]);
+            name
+        }).collect();
+
+        // 2. on each destination, dequantize every replica, add them up and restore the shape
+        let mut summed: BTreeMap<usize, String> = BTreeMap::new();
+        to.devices.iter().map(|device_id| {
+            if let Some(name) = summed.get(device_id) {
+                return name.clone()
+            }
+            let device = target.devices[*device_id].clone();
+            let mut addn = op("AddN", format!("{}/sum_{}", prefix, device_id), &device, vec![]);
+            addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(from.ndev() as _)));
+            addn.attr.insert("T".into(), dtype.clone());
+            for (i, quantize) in quantized.iter().enumerate() {
+                let mut dequantize = op("Dequantize", format!("{}/sum_{}/dequantize_{}", prefix, device_id, i), &device,
+                    vec![quantize.clone(), format!("{}:1", quantize), format!("{}:2", quantize)]);
+                scaled(&mut dequantize);
+                set_input_size(&mut dequantize, 0, n as u64);
+                set_input_size(&mut dequantize, 1, 4);
+                set_input_size(&mut dequantize, 2, 4);
+                addn.input.push(dequantize.name.clone());
+                target.pb.node.push(dequantize)
+            }
+
+            let original_shape = int_const(format!("{}/sum_{}/shape", prefix, device_id), &device, &shape.iter().map(|x| *x as _).collect::<Vec<_>>());
+            let mut reshape = op("Reshape", format!("{}/sum_{}/reshape", prefix, device_id), &device, vec![addn.name.clone(), original_shape.name.clone()]);
+            reshape.attr.insert("T".into(), dtype.clone());
+
+            let name = reshape.name.clone();
+            summed.insert(*device_id, name.clone());
+            target.pb.node.extend([addn, original_shape, reshape]);
+            name
+        }).collect()
+    }
+
+    /// like `aggregate_sum`, but proceeds once `required` of the replicas have delivered their gradients, so stragglers do not hold the update.
+    /// The gradients go through a ConditionalAccumulator on the destination device. Each replica tags its gradient with the step counter
+    /// it read when starting, so gradients of a straggler that arrive after the update of their step are dropped by the accumulator.
+    /// The accumulator averages, the result is scaled back to a sum over all replicas.
+    pub fn aggregate_sum_backup(&mut self, from: &Form, to: &Form, target: &mut Target, required: usize) -> Box<[String]> {
+        self.aggregate_sum_accumulated(from, to, target, required, None)
+    }
+
+    /// the SyncReplicasOptimizer pattern: like `aggregate_sum_backup`, but the local steps are the per-device variables kept by the token queue
+    /// of `Graph::add_sync_replicas` rather than a counter of this tensor
+    pub fn aggregate_sum_sync(&mut self, from: &Form, to: &Form, target: &mut Target, required: usize) -> Box<[String]> {
+        let local_steps = from.devices.iter().map(|d| sync_local_step(target, *d)).collect();
+        self.aggregate_sum_accumulated(from, to, target, required, Some(local_steps))
+    }
+
+    fn aggregate_sum_accumulated(&mut self, from: &Form, to: &Form, target: &mut Target, required: usize, local_steps: Option<Vec<String>>) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full());
+        assert!(self.reduction == Reduction::Sum, "{} cannot be reduced by {:?}: the accumulators of sync and backup workers can only sum", self.original_name(), self.reduction);
+
+        let required = if required > from.ndev() {
+            warn!("{} has only {} replicas, waiting for all of them instead of {}", self.original_name(), from.ndev(), required);
+            from.ndev()
+        } else {
+            required
+        };
+
+        let inputs = self.source_names(from).into_vec();
+        let prefix = format!("{}/{}_{}/{}", self.node().raw_node.name, self.index, to.code(), if local_steps.is_some() { "aux_sync" } else { "aux_backup" });
+        let device = target.devices[to.devices[0]].clone();
+        let host = target.host_cpu(to.devices[0]); // the accumulator ops only have CPU kernels
+        let dtype = self.dtype();
+        let int_const = |name: String, dtype: DataType, value: i64| {
+            let mut node = self.node().make_node("Const".to_string());
+            node.name = name;
+            node.device = device.clone();
+            node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(dtype)));
+            node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| {
+                t.set_dtype(dtype);
+                match dtype {
+                    DataType::DT_INT64 => t.int64_val.push(value),
+                    DataType::DT_INT32 => t.int_val.push(value as _),
+                    DataType::DT_DOUBLE => t.double_val.push(value as _),
+                    DataType::DT_HALF => t.half_val.push(half_of_int(value as _)),
+                    _ => t.float_val.push(value as _)
+                }
+            }))));
+            node
+        };
+
+        let counter = match &local_steps {
+            Some(_) => None,
+            None => Some(target.create_aux_variable(&format!("backup_step/{}_{}", self.node().raw_node.name, self.index), to.devices[0], DataType::DT_INT64, &[], 0.))
+        };
+
+        let mut accumulator = self.node().make_node("ConditionalAccumulator".to_string());
+        accumulator.name = format!("{}/accumulator", prefix);
+        accumulator.device = host.clone();
+        accumulator.attr.insert("dtype".into(), dtype.clone());
+        accumulator.attr.insert("shape".into(), AttrValue::new().apply(|x| x.set_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|s| s.unknown_rank = true))));
+        accumulator.attr.insert("container".into(), AttrValue::new().apply(|x| x.set_s(vec![])));
+        accumulator.attr.insert("shared_name".into(), AttrValue::new().apply(|x| x.set_s(vec![])));
+        accumulator.attr.insert("reduction_type".into(), AttrValue::new().apply(|x| x.set_s(b"MEAN".to_vec())));
+
+        for (i, input) in inputs.into_iter().enumerate() {
+            // read as early as possible on the replica, so the tag reflects the step that the gradient is computed in
+            let mut local_step = self.node().make_node("Identity".to_string());
+            local_step.name = format!("{}/local_step_{}", prefix, i);
+            local_step.device = target.devices[from.devices[i]].clone();
+            local_step.input.push(match &local_steps {
+                Some(x) => x[i].clone(),
+                None => counter.clone().unwrap()
+            });
+            local_step.attr.insert("T".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT64)));
+
+            let mut apply = self.node().make_node("AccumulatorApplyGradient".to_string());
+            apply.name = format!("{}/apply_{}", prefix, i);
+            apply.device = host.clone();
+            apply.input.push(accumulator.name.clone());
+            apply.input.push(local_step.name.clone());
+            apply.input.push(input);
+            apply.attr.insert("dtype".into(), dtype.clone());
+            set_input_size(&mut apply, 2, self.get_size() / from.ndev() as u64);
+
+            target.pb.node.push(local_step);
+            target.pb.node.push(apply);
+        }
+
+        let mut num_required = int_const(format!("{}/num_required", prefix), DataType::DT_INT32, required as _);
+        num_required.device = host.clone();
+        let mut take = self.node().make_node("AccumulatorTakeGradient".to_string());
+        take.name = format!("{}/take", prefix);
+        take.device = host;
+        take.input.push(accumulator.name.clone());
+        take.input.push(num_required.name.clone());
+        take.attr.insert("dtype".into(), dtype.clone());
+
+        let scale = int_const(format!("{}/scale", prefix), dtype.get_field_type(), from.ndev() as _);
+        let mut sum = self.node().make_node("Mul".to_string());
+        sum.name = format!("{}/sum", prefix);
+        sum.device = device.clone();
+        sum.input.push(take.name.clone());
+        sum.input.push(scale.name.clone());
+        sum.attr.insert("T".into(), dtype);
+
+        // the accumulator increments its own global step on each take, keep the counter in sync. With local_steps it is done by the token queue
+        if let Some(counter) = counter {
+            let one = int_const(format!("{}/one", prefix), DataType::DT_INT64, 1);
+            let mut increment = self.node().make_node("AssignAdd".to_string());
+            increment.name = format!("{}/increment", prefix);
+            increment.device = device;
+            increment.input.push(counter);
+            increment.input.push(one.name.clone());
+            increment.input.push(format!("^{}", take.name));
+            increment.attr.insert("T".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT64)));
+            increment.attr.insert("use_locking".into(), AttrValue::new().apply(|x| x.set_b(true)));
+            sum.input.push(format!("^{}", increment.name));
+            target.pb.node.push(one);
+            target.pb.node.push(increment);
+        }
+
+        let result = vec![sum.name.clone(); to.ndev()].into_boxed_slice();
+        for node in [accumulator, num_required, take, scale, sum] {
+            target.pb.node.push(node)
+        }
+        result
+    }
+
+    // TODO: share the same axis nodes for all concating (and do the same thing for dim nodes in splitting)
+    pub fn aggregate_cat(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full());
+
+        let mut axis = self.node().make_node("Const".to_string());
+        axis.name += &format!("/{}_{}/aux_concat/axis", self.index, to.code());
+        axis.device = target.devices[to.devices[0]].clone();
+        axis.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+        let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+            x.set_dtype(DataType::DT_INT32);
+            x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+            x.int_val.push(from.axis as _);
+        });
+        axis.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+
+        let mut concat = self.node().make_node("ConcatV2".to_string());
+        concat.name += &format!("/{}_{}/aux_concat/concat", self.index, to.code());
+        concat.device = target.devices[to.devices[0]].clone();
+        concat.input = self.source_names(from).into_vec().into();
+        concat.input.push(axis.name.clone());
+        concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(from.ndev() as _)));
+        concat.attr.insert("T".into(), self.dtype());
+        concat.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+        for (i, size) in self.part_sizes(from).into_iter().enumerate() {
+            set_input_size(&mut concat, i, size)
+        }
+
+        let result = vec![concat.name.clone(); to.ndev()].into_boxed_slice();
+        target.pb.node.push(axis);
+        target.pb.node.push(concat);
+        result
+    }
+
+    /// concat the parts on every destination device, instead of on the first one and sending the whole tensor from there like `aggregate_cat`.
+    /// Each destination receives the parts it does not hold, so no device becomes a hot spot. With the collective backend, parts on distinct
+    /// devices are gathered with CollectiveGather.
+    pub fn all_gather(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full());
+
+        if self.use_collective_backend() && from.axis == 0 && from.devices == to.devices && BTreeSet::from_iter(from.devices.iter()).len() == from.devices.len() {
+            return self.all_reduce_cat_collective(from, to, target)
+        }
+
+        let sources = self.source_names(from);
+        let sizes = self.part_sizes(from);
+        let mut gathered: BTreeMap<usize, String> = BTreeMap::new();
+        for device_id in to.devices.iter().copied() {
+            if gathered.contains_key(&device_id) {
+                continue
+            }
+
+            let mut axis = self.node().make_node("Const".to_string());
+            axis.name += &format!("/{}_{}/aux_all_gather_{}/axis", self.index, to.code(), device_id);
+            axis.device = target.devices[device_id].clone();
+            axis.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+                x.set_dtype(DataType::DT_INT32);
+                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+                x.int_val.push(from.axis as _);
+            });
+            axis.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+
+            let mut concat = self.node().make_node("ConcatV2".to_string());
+            concat.name += &format!("/{}_{}/aux_all_gather_{}/concat", self.index, to.code(), device_id);
+            concat.device = target.devices[device_id].clone();
+            concat.input = sources.iter().cloned().collect();
+            concat.input.push(axis.name.clone());
+            concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(from.ndev() as _)));
+            concat.attr.insert("T".into(), self.dtype());
+            concat.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            for (i, size) in sizes.iter().enumerate() {
+                set_input_size(&mut concat, i, *size)
+            }
+
+            gathered.insert(device_id, concat.name.clone());
+            target.pb.node.push(axis);
+            target.pb.node.push(concat);
+        }
+
+        to.devices.iter().map(|device_id| gathered[device_id].clone()).collect()
+    }
+
+    /// sum the partial values of an unbatched tensor (e.g. the gradients of split replicas) and leave the i-th slice (along `to.axis`) of the
+    /// sum on the i-th device of `to`: every source is split into `to.ndev()` slices and each destination sums the slices it owns. It moves
+    /// (n-1)/n of the tensor per device, while `aggregate_sum` followed by `replicate_split` funnels everything through one device.
+    /// `plan_conversion` picks it for unbatched part to part conversions, e.g. for sharded optimizer updates followed by `all_gather`.
+    pub fn reduce_scatter(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_part());
+
+        let psize = self.get_size() / from.ndev() as u64;
+        let dtype = self.dtype();
+        let slices: Vec<String> = self.source_names(from).iter().enumerate().map(|(i, source)| {
+            let mut dim = self.node().make_node("Const".to_string());
+            dim.name += &format!("/{}_{}/aux_reduce_scatter/split_{}/dim", self.index, to.code(), i);
+            dim.device = target.devices[from.devices[i]].clone();
+            dim.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+                x.set_dtype(DataType::DT_INT32);
+                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+                x.int_val.push(to.axis as _);
+            });
+            dim.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+
+            let mut split = self.node().make_node("Split".to_string());
+            split.name += &format!("/{}_{}/aux_reduce_scatter/split_{}/split", self.index, to.code(), i);
+            split.device = target.devices[from.devices[i]].clone();
+            split.input.push(dim.name.clone());
+            split.input.push(source.clone());
+            split.attr.insert("T".into(), dtype.clone());
+            split.attr.insert("num_split".into(), AttrValue::new().apply(|x| x.set_i(to.ndev() as _)));
+            set_input_size(&mut split, 1, psize);
+
+            let name = split.name.clone();
+            target.pb.node.push(dim);
+            target.pb.node.push(split);
+            name
+        }).collect();
+
+        to.devices.iter().enumerate().map(|(j, device_id)| {
+            let mut addn = self.node().make_node("AddN".to_string());
+            addn.name += &format!("/{}_{}/aux_reduce_scatter/sum_{}", self.index, to.code(), j);
+            addn.device = target.devices[*device_id].clone();
+            addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(slices.len() as _)));
+            addn.attr.insert("T".into(), dtype.clone());
+            addn.input = slices.iter().map(|split| format!("{}:{}", split, j)).collect();
+            for i in 0..slices.len() {
+                set_input_size(&mut addn, i, psize / to.ndev() as u64)
+            }
+            let name = addn.name.clone();
+            self.push_reduce(addn, target);
+            name
+        }).collect()
+    }
+
+    /// each destination uses the replica on the same device, or the first replica. Also converts between Part and Full for tensors whose replicas
+    /// are all the same (`IS_INVARIANT`). With the option `backend_selection`, a Full tensor may instead be sent to the devices without a replica
+    /// with a NcclBroadcast from the first replica, see `BackendSelection::select_broadcast`.
+    pub fn replicate_broadcast(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && ((from.is_full() && to.is_full()) || self.has_flag(Self::IS_INVARIANT)));
+
+        let raw = self.source_names(from);
+        let remote: Vec<usize> = to.devices.iter().copied().filter(|d| !from.devices.contains(d)).collect::<BTreeSet<_>>().into_iter().collect();
+        let selected = crate::backend::BackendSelection::from_options(&self.node().graph().options)
+            .map(|x| x.select_broadcast(self.get_size(), from.devices[0], &remote, target, &self.node().graph().options)).unwrap_or(false);
+        let shape = self.get_shape();
+        let received = if selected && from.is_full() && to.is_full() && !shape.is_empty() { // the receivers allocate by the shape attr
+            self.nccl_broadcast(&raw[0], from.devices[0], &remote, &shape, &format!("{}_{}/aux_nccl_broadcast", to.code(), self.index), target)
+        } else {
+            BTreeMap::new()
+        };
+
+        to.devices.iter().map(|device_id| {
+            from.devices.iter().position(|x| *x == *device_id).map(|ind| raw[ind].clone())
+                .or_else(|| received.get(device_id).cloned())
+                .unwrap_or_else(|| raw[0].clone())
+        }).collect()
+    }
+
+    /// send `source` on the device `root` to each of `destinations` with a NcclBroadcast, returning the copy on each destination
+    fn nccl_broadcast(&mut self, source: &str, root: usize, destinations: &[usize], shape: &[usize], tag: &str, target: &mut Target) -> BTreeMap<usize, String> {
+        let dtype = self.dtype();
+        let size = self.get_size();
+
+        let mut nccl = self.node().make_node("NcclBroadcast".to_string());
+        nccl.name += &format!("/{}/send", tag);
+        nccl.device = target.devices[root].clone();
+        nccl.input.push(source.to_string());
+        nccl.attr.insert("T".into(), dtype.clone());
+        nccl.attr.insert("shape".into(), AttrValue::new().apply(|x| x.set_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|s| {
+            s.dim = shape.iter().map(|n| crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|d| d.size = *n as _)).collect()
+        }))));
+        set_input_size(&mut nccl, 0, size);
+
+        let received = destinations.iter().map(|device_id| {
+            let mut identity = self.node().make_node("Identity".to_string());
+            identity.name += &format!("/{}/recv_{}", tag, device_id);
+            identity.device = target.devices[*device_id].clone();
+            identity.attr.insert("T".into(), dtype.clone());
+            identity.input.push(nccl.name.clone());
+            set_input_size(&mut identity, 0, size);
+            let name = identity.name.clone();
+            target.pb.node.push(identity);
+            (*device_id, name)
+        }).collect();
+        target.pb.node.push(nccl);
+        received
+    }
+
+    /// the shape of the whole batch from the shapes of its slices (see `IS_SHAPE`): the first elements are added up on the first destination
+    /// and the remaining elements are taken from the first slice. Summing the shapes like other unbatched tensors would add up every dimension.
+    pub fn shape_total(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && self.has_flag(Self::IS_SHAPE));
+
+        let tag = format!("{}_{}/aux_shape_total", self.index, to.code());
+        let device = target.devices[to.devices[0]].clone();
+        let sources = self.source_names(from);
+        let heads: Vec<String> = sources.iter().enumerate().map(|(i, source)| self.emit_shape_slice(source, 0, 1, &format!("{}/head_{}", tag, i), &device, target)).collect();
+        let rest = self.emit_shape_slice(&sources[0], 1, -1, &format!("{}/rest", tag), &device, target);
+
+        let mut addn = self.node().make_node("AddN".to_string());
+        addn.name += &format!("/{}/batch", tag);
+        addn.device = device.clone();
+        addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(heads.len() as _)));
+        addn.attr.insert("T".into(), self.dtype());
+        addn.input = heads.into();
+
+        let concat = self.emit_shape_concat(&addn.name, &rest, &tag, &device, target);
+        target.pb.node.push(addn);
+        vec![concat; to.ndev()].into_boxed_slice()
+    }
+
+    /// the shape of a slice of the batch from the shape of the whole batch (see `IS_SHAPE`): the first element is divided by the number of parts
+    /// on the first source device. Like `replicate_split`, it assumes the batch divides evenly.
+    pub fn shape_slice(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_full() && to.is_part() && self.has_flag(Self::IS_SHAPE));
+
+        let tag = format!("{}_{}/aux_shape_slice", self.index, to.code());
+        let device = target.devices[from.devices[0]].clone();
+        let source = self.source_names(from)[0].clone();
+        let head = self.emit_shape_slice(&source, 0, 1, &format!("{}/head", tag), &device, target);
+        let rest = self.emit_shape_slice(&source, 1, -1, &format!("{}/rest", tag), &device, target);
+
+        let dtype = self.dtype();
+        let mut n = self.node().make_node("Const".to_string());
+        n.name += &format!("/{}/n", tag);
+        n.device = device.clone();
+        n.attr.insert("dtype".into(), dtype.clone());
+        let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+            x.set_dtype(dtype.get_field_type());
+            x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|x| x.dim.push(crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|x| x.size = 1))));
+            if dtype.get_field_type() == DataType::DT_INT64 { x.int64_val.push(to.ndev() as _) } else { x.int_val.push(to.ndev() as _) }
+        });
+        n.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+
+        let mut div = self.node().make_node("FloorDiv".to_string());
+        div.name += &format!("/{}/batch", tag);
+        div.device = device.clone();
+        div.attr.insert("T".into(), dtype);
+        div.input.push(head);
+        div.input.push(n.name.clone());
+
+        let concat = self.emit_shape_concat(&div.name, &rest, &tag, &device, target);
+        target.pb.node.push(n);
+        target.pb.node.push(div);
+        vec![concat; to.ndev()].into_boxed_slice()
+    }
+
+    /// `source[begin:begin+size]` of a shape vector, where size -1 means to the end
+    fn emit_shape_slice(&self, source: &str, begin: i32, size: i32, name: &str, device: &str, target: &mut Target) -> String {
+        let vector = |name: String, value: i32| self.node().make_node("Const".to_string()).apply(|x| {
+            x.name += &name;
+            x.device = device.to_string();
+            x.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+                x.set_dtype(DataType::DT_INT32);
+                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|x| x.dim.push(crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|x| x.size = 1))));
+                x.int_val.push(value);
+            });
+            x.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+        });
+        let begin = vector(format!("/{}/begin", name), begin);
+        let size = vector(format!("/{}/size", name), size);
+
+        let mut slice = self.node().make_node("Slice".to_string());
+        slice.name += &format!("/{}/slice", name);
+        slice.device = device.to_string();
+        slice.attr.insert("T".into(), self.dtype());
+        slice.attr.insert("Index".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+        slice.input.push(source.to_string());
+        slice.input.push(begin.name.clone());
+        slice.input.push(size.name.clone());
+
+        let result = slice.name.clone();
+        target.pb.node.push(begin);
+        target.pb.node.push(size);
+        target.pb.node.push(slice);
+        result
+    }
+
+    /// the shape vector `[batch, rest...]`
+    fn emit_shape_concat(&self, batch: &str, rest: &str, tag: &str, device: &str, target: &mut Target) -> String {
+        let mut axis = self.node().make_node("Const".to_string());
+        axis.name += &format!("/{}/axis", tag);
+        axis.device = device.to_string();
+        axis.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+        let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+            x.set_dtype(DataType::DT_INT32);
+            x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+            x.int_val.push(0);
+        });
+        axis.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+
+        let mut concat = self.node().make_node("ConcatV2".to_string());
+        concat.name += &format!("/{}/concat", tag);
+        concat.device = device.to_string();
+        concat.input.push(batch.to_string());
+        concat.input.push(rest.to_string());
+        concat.input.push(axis.name.clone());
+        concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(2)));
+        concat.attr.insert("T".into(), self.dtype());
+        concat.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+
+        let result = concat.name.clone();
+        target.pb.node.push(axis);
+        target.pb.node.push(concat);
+        result
+    }
+
+    /// split a batched SparseTensor (see `IS_SPARSE`) by rows with SparseSplit on the first source device, which also shifts the row indices
+    /// and shrinks the dense shape of each part. The three members are split by one op, and the parts of the other two are recorded in their
+    /// form caches, so consumers of the same triple agree on the rows. Like `replicate_split`, the parts get the same number of rows, with the
+    /// first ones taking the remainder. SparseSplit has no GPU kernel, so sources on GPUs need `soft_placement`.
+    pub fn sparse_split(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_full() && to.is_part() && self.has_flag(Self::IS_SPARSE));
+
+        let (triple, role) = self.sparse_triple.expect("BUG: sparse tensor without its triple");
+        let sources = self.sparse_sources(&triple, role, from, target);
+        let n = to.ndev();
+
+        let mut dim = self.node().make_node("Const".to_string());
+        dim.name += &format!("/{}_{}/aux_sparse_split/dim", self.index, to.code());
+        dim.device = target.devices[from.devices[0]].clone();
+        dim.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT64)));
+        let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+            x.set_dtype(DataType::DT_INT64);
+            x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+            x.int64_val.push(0);
+        });
+        dim.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+
+        let mut split = self.node().make_node("SparseSplit".to_string());
+        split.name += &format!("/{}_{}/aux_sparse_split/split", self.index, to.code());
+        split.device = target.devices[from.devices[0]].clone();
+        split.input.push(dim.name.clone());
+        for (r, (id, index)) in triple.iter().enumerate() {
+            split.input.push(sources[r][0].clone());
+            set_input_size(&mut split, r + 1, self.node().graph().nodes[*id].get_output(*index).get_size());
+        }
+        split.attr.insert("T".into(), self.node().graph().nodes[triple[1].0].get_output(triple[1].1).dtype());
+        split.attr.insert("num_split".into(), AttrValue::new().apply(|x| x.set_i(n as _)));
+
+        // the outputs are the lists of indices, values and shapes, each of `n` parts
+        let parts: Vec<Box<[String]>> = (0..3).map(|r| (0..n).map(|i| format!("{}:{}", split.name, r * n + i)).collect()).collect();
+        target.pb.node.push(dim);
+        target.pb.node.push(split);
+        self.record_sparse_parts(&triple, role, to, &parts);
+        parts[role].clone()
+    }
+
+    /// concat the row slices of a batched SparseTensor (see `IS_SPARSE`) with SparseConcat on the first destination, which offsets the row
+    /// indices of each part by the rows before it and adds up the dense shapes. The three members are concatenated by one op like `sparse_split`.
+    pub fn sparse_concat(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && self.has_flag(Self::IS_SPARSE));
+
+        let (triple, role) = self.sparse_triple.expect("BUG: sparse tensor without its triple");
+        let sources = self.sparse_sources(&triple, role, from, target);
+
+        let mut concat = self.node().make_node("SparseConcat".to_string());
+        concat.name += &format!("/{}_{}/aux_sparse_concat", self.index, to.code());
+        concat.device = target.devices[to.devices[0]].clone();
+        for (r, (id, index)) in triple.iter().enumerate() {
+            let size = self.node().graph().nodes[*id].get_output(*index).get_size() / from.ndev() as u64;
+            for (i, source) in sources[r].iter().enumerate() {
+                concat.input.push(source.clone());
+                set_input_size(&mut concat, r * from.ndev() + i, size);
+            }
+        }
+        concat.attr.insert("concat_dim".into(), AttrValue::new().apply(|x| x.set_i(0)));
+        concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(from.ndev() as _)));
+        concat.attr.insert("T".into(), self.node().graph().nodes[triple[1].0].get_output(triple[1].1).dtype());
+
+        let parts: Vec<Box<[String]>> = (0..3).map(|r| vec![format!("{}:{}", concat.name, r); to.ndev()].into_boxed_slice()).collect();
+        target.pb.node.push(concat);
+        self.record_sparse_parts(&triple, role, to, &parts);
+        parts[role].clone()
+    }
+
+    /// the names of the members of a SparseTensor in `from`. This tensor is at `role`; the others are converted to `from` first if they are
+    /// produced in another form, which is only a broadcast for the members that the sparse op does not replicate (e.g. a constant dense shape).
+    fn sparse_sources(&self, triple: &[(usize, usize); 3], role: usize, from: &Form, target: &mut Target) -> Vec<Box<[String]>> {
+        triple.iter().enumerate().map(|(r, (id, index))| if r == role {
+            self.source_names(from)
+        } else {
+            self.node().graph().nodes[*id].get_output(*index).as_form(from, target).into()
+        }).collect()
+    }
+
+    /// record the converted members of a SparseTensor other than this one, unless they already have the form
+    fn record_sparse_parts(&self, triple: &[(usize, usize); 3], role: usize, to: &Form, parts: &[Box<[String]>]) {
+        for (r, (id, index)) in triple.iter().enumerate().filter(|(r, _)| *r != role) {
+            self.node().graph().nodes[*id].get_output(*index).forms.entry(to.clone()).or_insert_with(|| parts[r].clone());
+        }
+    }
+
+    // currenly we only split from the first replica. Future we can split on every device and use the local copy to reduce transfering
+    pub fn replicate_split(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_full() && to.is_part());
+
+        let nodes = self.split_nodes(to, &self.source_names(from)[0], &format!("/{}_{}/aux_split", self.index, to.code()), &target.devices[from.devices[0]]);
+        let result = (0..to.ndev()).map(|i| format!("{}:{}", nodes.last().unwrap().name, i)).collect();
+        target.pb.node.extend(nodes);
+        result
+    }
+
+    /// regroup the parts: groups of consecutive sources are concatenated on their first device and split among the destinations they
+    /// cover. Parts along another axis, and uneven parts (see `uneven_slices`) unless they match, are gathered on the first destination
+    /// (see `aggregate_cat`) and split again from there.
+    pub fn resplit(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_part());
+
+        let (from_slices, to_slices) = (self.uneven_slices(from), self.uneven_slices(to));
+        let uneven = (from_slices.is_some() || to_slices.is_some()) && !(from.ndev() == to.ndev() && from_slices == to_slices);
+        if from.axis != to.axis || uneven {
+            let full = Form { kind: FormKind::Full, devices: vec![to.devices[0]], axis: 0 };
+            if !self.forms.contains_key(&full) {
+                let whole = self.aggregate_cat(from, &full, target);
+                self.forms.insert(full.clone(), whole);
+            }
+            return self.replicate_split(&full, to, target)
+        }
+
+        if from.ndev() == to.ndev() { // special case: if the number are the same, just forward. TODO: use replicas on the same device when possible
+            return self.source_names(from)
+        }
+
+        let gcd = { // the number of intermediat concated nodes
+            let mut a = from.ndev();
+            let mut b = to.ndev();
+            while a != b {
+                if a > b {
+                    a -= b;
+                } else {
+                    b -= a;
+                }
+            }
+            a
+        };
+
+        self.source_names(from).into_vec().chunks(from.ndev() / gcd).enumerate().map(|(i, chunk)| {
+            let dest = from.devices[i * chunk.len()];
+
+            // special case: no need to concat
+            if chunk.len() == 1 {
+                return (dest, chunk[0].clone())
+            }
+
+            let mut axis = self.node().make_node("Const".to_string());
+            axis.name += &format!("/{}_{}/aux_resplit_{}/concat_axis", self.index, to.code(), i);
+            axis.device = target.devices[dest].clone();
+            axis.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+                x.set_dtype(DataType::DT_INT32);
+                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+                x.int_val.push(from.axis as _);
+            });
+            axis.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+
+            let mut concat = self.node().make_node("ConcatV2".to_string());
+            concat.name += &format!("/{}_{}/aux_resplit_{}/concat", self.index, to.code(), i);
+            concat.device = target.devices[dest].clone();
+            concat.input = chunk.iter().cloned().collect();
+            concat.input.push(axis.name.clone());
+            concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(chunk.len() as _)));
+            concat.attr.insert("T".into(), self.dtype());
+            concat.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            for j in 0..chunk.len() {
+                set_input_size(&mut concat, j, self.get_size() / from.ndev() as u64)
+            }
+
+            let result = concat.name.clone();
+            target.pb.node.push(axis);
+            target.pb.node.push(concat);
+            (dest, result)
+        }).collect::<Vec<_>>().iter().zip(to.devices.chunks(to.ndev() / gcd)).enumerate().flat_map(|(i, ((concat_place, concated), devices))| {
+            if devices.len() == 1 { // special case: no need to split
+                return vec![concated.clone()] // TODO: use another return type for this closure? Ideally do not collect and return a dyn IntoIterator instead
+            }
+
+            let mut dim = self.node().make_node("Const".to_string());
+            dim.name += &format!("/{}_{}/aux_resplit_{}/split_dim", self.index, to.code(), i);
+            dim.device = target.devices[*concat_place].clone();
+            dim.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+                x.set_dtype(DataType::DT_INT32);
+                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+                x.int_val.push(from.axis as _);
+            });
+            dim.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+
+            let mut split = self.node().make_node("Split".to_string());
+            split.name += &format!("/{}_{}/aux_resplit_{}/split", self.index, to.code(), i);
+            split.device = target.devices[*concat_place].clone();
+            split.input.push(dim.name.clone());
+            split.input.push(concated.clone());
+            split.attr.insert("T".into(), self.dtype());
+            split.attr.insert("num_split".into(), AttrValue::new().apply(|x| x.set_i(devices.len() as _)));
+            set_input_size(&mut split, 1, self.get_size() / gcd as u64);
+
+            let result = (0..to.ndev() / gcd).map({
+                let name = split.name.clone();
+                move |i| format!("{}:{}", name, i)
+            }).collect();
+            target.pb.node.push(dim);
+            target.pb.node.push(split);
+            result
+        }).collect()
+    }
+
+    /// all-to-all exchange between the parts of `from` and `to`: each source splits its part into `to.ndev()` pieces along `from.axis`
+    /// and destination j concats the j-th piece of every source along `to.axis`, n * m point-to-point edges in all. Unlike `resplit`, each destination gets
+    /// rows of every source, so this is not a conversion between forms of the same tensor: expert-parallel (MoE) layers use it to dispatch
+    /// the tokens of each expert and, with the forms swapped, to combine them back in the original order. The split dimension of the
+    /// parts must be divisible by `to.ndev()`.
+    pub fn all_to_all(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_part());
+
+        let tag = format!("{}_{}/aux_all_to_all", self.index, to.code());
+        self.exchange(from, to, target, &tag, to.ndev(), |_, k| k)
+    }
+
+    /// resplit between forms on the same devices with a different number of parts in one hop: with L the least common multiple of the numbers
+    /// of parts, each source splits its part into L / n pieces and sends each piece to the destination whose range covers it, which concats
+    /// its L / m pieces. `resplit` instead concats groups of sources on one of them and splits the result again, which is also used for
+    /// uneven parts.
+    pub fn resplit_all_to_all(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_part() && from.axis == to.axis);
+        if self.uneven_slices(from).is_some() || self.uneven_slices(to).is_some() { // the pieces would not line up
+            return self.resplit(from, to, target)
+        }
+
+        let l = from.ndev() / crate::plan::gcd(from.ndev(), to.ndev()) * to.ndev();
+        let (per_source, per_destination) = (l / from.ndev(), l / to.ndev());
+        let tag = format!("{}_{}/aux_resplit_all_to_all", self.index, to.code());
+        self.exchange(from, to, target, &tag, per_source, |i, k| (i * per_source + k) / per_destination)
+    }
+
+    /// split each part of `from` into `splits` pieces along its axis and send piece k of part i to the part `route(i, k)` of `to`, which
+    /// concats its pieces along its own axis in the order of (i, k). Destinations with a single piece use it as is.
+    fn exchange(&mut self, from: &Form, to: &Form, target: &mut Target, tag: &str, splits: usize, route: impl Fn(usize, usize) -> usize) -> Box<[String]> {
+        let dtype = self.dtype();
+        let psize = self.get_size() / from.ndev() as u64;
+        let int32_scalar = |value: i32| AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|x| {
+            x.set_dtype(DataType::DT_INT32);
+            x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+            x.int_val.push(value);
+        })));
+
+        let mut received: Vec<Vec<String>> = vec![vec![]; to.ndev()];
+        for (i, source) in self.source_names(from).iter().enumerate() {
+            if splits == 1 {
+                received[route(i, 0)].push(source.clone());
+                continue
+            }
+
+            let mut dim = self.node().make_node("Const".to_string());
+            dim.name += &format!("/{}/split_dim_{}", tag, i);
+            dim.device = target.devices[from.devices[i]].clone();
+            dim.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            dim.attr.insert("value".into(), int32_scalar(from.axis as _));
+
+            let mut split = self.node().make_node("Split".to_string());
+            split.name += &format!("/{}/split_{}", tag, i);
+            split.device = target.devices[from.devices[i]].clone();
+            split.input.push(dim.name.clone());
+            split.input.push(source.clone());
+            split.attr.insert("T".into(), dtype.clone());
+            split.attr.insert("num_split".into(), AttrValue::new().apply(|x| x.set_i(splits as _)));
+            set_input_size(&mut split, 1, psize);
+
+            for k in 0..splits {
+                received[route(i, k)].push(format!("{}:{}", split.name, k))
+            }
+            target.pb.node.push(dim);
+            target.pb.node.push(split);
+        }
+
+        received.into_iter().enumerate().map(|(j, pieces)| {
+            if pieces.len() == 1 {
+                return pieces.into_iter().next().unwrap()
+            }
+
+            let mut axis = self.node().make_node("Const".to_string());
+            axis.name += &format!("/{}/concat_axis_{}", tag, j);
+            axis.device = target.devices[to.devices[j]].clone();
+            axis.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            axis.attr.insert("value".into(), int32_scalar(to.axis as _));
+
+            let mut concat = self.node().make_node("ConcatV2".to_string());
+            concat.name += &format!("/{}/concat_{}", tag, j);
+            concat.device = target.devices[to.devices[j]].clone();
+            concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(pieces.len() as _)));
+            concat.attr.insert("T".into(), dtype.clone());
+            concat.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            for k in 0..pieces.len() {
+                set_input_size(&mut concat, k, psize / splits as u64)
+            }
+            concat.input = pieces.into();
+            concat.input.push(axis.name.clone());
+
+            let result = concat.name.clone();
+            target.pb.node.push(axis);
+            target.pb.node.push(concat);
+            result
+        }).collect()
+    }
+
+    /// whether the option `collective_backend` asks for TF's collective ops (CollectiveReduce and CollectiveGather, which run on the collective
+    /// executor across workers without NCCL) in place of the Nccl* ops
+    pub fn use_collective_backend(&self) -> bool {
+        match self.node().graph().options.get("collective_backend").map(|x| &x[..]) {
+            None | Some("nccl") | Some("horovod") => false,
+            Some("collective") => true,
+            Some(x) => panic!("unknown collective_backend {}", x)
+        }
+    }
+
+    pub fn all_reduce_sum_nccl(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        // to all_sum n tensors (can be on the same device), one should have n NcclAllReduce nodes with the same shared_name attr
+        // each node have only *one* input, and should be on the same device of the input. The output of these nodes will be the same
+
+        if self.use_collective_backend() {
+            return self.all_reduce_sum_collective(from, to, target)
+        }
+
+        assert!(target.devices.windows(2).all(|w| task_name(&w[0]) == task_name(&w[1]))); // This nodes only works intra-task
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices);
+
+        let index = self.index;
+
+        for (i, device_id) in from.devices.iter().enumerate() {
+            let mut nccl = self.node().make_node("NcclAllReduce".to_string());
+            nccl.name += &format!("/{}_{}/aux_nccl_{}", index, to.code(), i);
+            nccl.device = target.devices[*device_id].clone();
+            nccl.attr.insert("reduction".into(), AttrValue::new().apply(|x| x.set_s(self.reduction.nccl_name().as_bytes().to_vec())));
+            nccl.attr.insert("T".into(), self.dtype());
+            nccl.attr.insert("num_devices".into(), AttrValue::new().apply(|x| x.set_i(from.ndev() as _)));
+            nccl.attr.insert("shared_name".into(), AttrValue::new().apply(|x| x.set_s(self.original_name().into_bytes())));
+            nccl.input.push(self.source_names(from)[i].clone());
+            set_input_size(&mut nccl, 0, self.get_size() / from.ndev() as u64);
+
+            target.pb.node.push(nccl)
+        }
+
+        (0..from.ndev()).map(|i| format!("{}/{}_{}/aux_nccl_{}", self.node().raw_node.name, self.index, to.code(), i)).collect()
+    }
+
+    pub fn all_reduce_sum_collective(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        // each node have only *one* input, and should be on the same device of the input. The output of these nodes will be the same
+        // group_key: not sure, guess is nccl group, so operations on *the same set of devices* could share the same group_key
+        // instance_key: each operation use a unique instance_key, pretty much like "shared_name" in NcclAllReduce
+
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices);
+
+        let part_size = self.get_size() / from.ndev() as u64;
+
+        let mut local_groups: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for (i, device_id) in from.devices.iter().copied().enumerate() {
+            let name = self.source_names(from)[i].clone();
+            local_groups.entry(device_id).or_default().push(name)
+        }
+
+        let local_summed: BTreeMap<_, _> = local_groups.iter().map(|(device_id, local_nodes)| (*device_id, match local_nodes.len() {
+            0 => unreachable!(),
+            1 => local_nodes[0].clone(), // TODO: destruct the vector and take the element
+            _ => {
+                let mut addn = self.node().make_node("AddN".to_string());
+                addn.name += &format!("/{}_{}_{}/aux_sum", self.index, to.code(), device_id);
+                addn.device = target.devices[*device_id].clone();
+                addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(local_nodes.len() as _)));
+                addn.attr.insert("T".into(), self.dtype());
+                addn.input = local_nodes.iter().cloned().collect();
+                for i in 0..local_nodes.len() {
+                    set_input_size(&mut addn, i, part_size)
+                }
+                let name = addn.name.clone();
+                self.push_reduce(addn, target);
+                name
+            }
+        })).collect();
+
+        let (index, code) = (self.index, to.code());
+        let local_reduced = self.collective_reduce(&local_summed, part_size, |device_id| format!("/{}_{}_{}/aux_collective", index, code, device_id), target);
+
+        from.devices.iter().map(|device_id| local_reduced[device_id].clone()).collect()
+    }
+
+    /// one CollectiveReduce on each device of `inputs` (device => name), summing them across the devices. Node names are suffixed by `name`.
+    fn collective_reduce(&mut self, inputs: &BTreeMap<usize, String>, size: u64, name: impl Fn(usize) -> String, target: &mut Target) -> BTreeMap<usize, String> {
+        let state = &mut self.node().graph().collective_state;
+        let group_key = state.get_group(&inputs.keys().copied().collect::<Vec<_>>()); // it is sorted by BTreeMap
+        let (instance, instance_key) = state.new_instance();
+
+        inputs.iter().map(|(device_id, local_name)| {
+            let mut node = self.node().make_node("CollectiveReduce".to_string());
+            node.name += &name(*device_id);
+            node.device = target.devices[*device_id].clone();
+            node.attr.insert("T".into(), self.dtype());
+            node.attr.insert("final_op".into(), AttrValue::new().apply(|x| x.set_s(b"Id".to_vec())));
+            node.attr.insert("merge_op".into(), AttrValue::new().apply(|x| x.set_s(self.reduction.merge_op().as_bytes().to_vec())));
+            node.attr.insert("group_key".into(), AttrValue::new().apply(|x| x.set_i(group_key as _)));
+            node.attr.insert("group_size".into(), AttrValue::new().apply(|x| x.set_i(inputs.len() as _)));
+            node.attr.insert("instance_key".into(), AttrValue::new().apply(|x| x.set_i(instance_key as _)));
+            node.attr.insert("subdiv_offsets".into(), AttrValue::new().apply(|x| x.mut_list().i = vec![0]));
+            node.input.push(local_name.clone());
+            set_input_size(&mut node, 0, size);
+
+            instance.push(target.pb.node.len());
+            let name = node.name.clone();
+            target.pb.node.push(node);
+            (*device_id, name)
+        }).collect()
+    }
+
+    pub fn all_reduce_cat_collective(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices && BTreeSet::from_iter(from.devices.iter()).len() == from.devices.len());
+
+        let part_size = self.get_size() / from.ndev() as u64;
+        let state = &mut self.node().graph().collective_state;
+        let group_key = state.get_group(&from.devices.clone().apply(|x| x.sort_unstable())); // sorted
+        let (instance, instance_key) = state.new_instance();
+
+        let list = self.source_names(from).into_vec();
+        let local_reduced: BTreeMap<_, _> = from.devices.iter().zip(list.iter()).map(|(device_id, local_name)| {
+            let mut node = self.node().make_node("CollectiveGather".to_string());
+            node.name += &format!("/{}_{}_{}/aux_collective", self.index, to.code(), device_id);
+            node.device = target.devices[*device_id].clone();
+            node.attr.insert("T".into(), self.dtype());
+            node.attr.insert("group_key".into(), AttrValue::new().apply(|x| x.set_i(group_key as _)));
+            node.attr.insert("group_size".into(), AttrValue::new().apply(|x| x.set_i(from.devices.len() as _)));
+            node.attr.insert("instance_key".into(), AttrValue::new().apply(|x| x.set_i(instance_key as _)));
+            node.attr.insert("shape".into(), AttrValue::new().apply(|x| x.mut_shape().ignore()));
+            node.input.push(local_name.clone());
+            set_input_size(&mut node, 0, part_size);
+
+            instance.push(target.pb.node.len());
+            let name = node.name.clone();
+            target.pb.node.push(node);
+            (device_id, name)
+        }).collect();
 
-    pub fn set_flag(&mut self, flag: u8) {
-        self.flags |= flag
+        from.devices.iter().map(|device_id| local_reduced[device_id].clone()).collect()
     }
 
-    pub fn unset_flag(&mut self, flag: u8) {
-        self.flags &= !flag
-    }
+    pub fn all_reduce_sum_ring(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices);
 
-    // get the names as the specified form
-    pub fn as_form(&mut self, form: &Form, target: &mut Target) -> &[String] {
-        if !self.forms.contains_key(form) {
-            if self.has_flag(Self::IS_FIXED) {
-                panic!("BUG: no form {:?} provided for {}", form, self.original_name())
-            }
+        // position k of the ring is the `order[k]`-th device of the form
+        let order = match self.node().graph().options.get("ring_order").map(|x| &x[..]) {
+            None | Some("index") => (0..from.ndev()).collect(),
+            Some("bandwidth") => ring_order(target, &from.devices),
+            Some(x) => panic!("unknown ring_order {}", x)
+        };
+        let devices: Vec<_> = order.iter().map(|k| target.devices[from.devices[*k]].clone()).collect();
+        let sources = self.source_names(from);
+        let list: Vec<String> = order.iter().map(|k| sources[*k].clone()).collect();
+        let psize = self.get_size() / from.ndev() as u64;
+        let reduced = self.ring_all_reduce(&list, &devices, &format!("{}_{}", to.code(), self.index), psize, target);
 
-            let names = if form == &self.node().form {
-                (0..form.ndev()).map(|i| format!("{}:{}", self.node().replica(i), self.index)).collect()
-            } else {
-                let node_kind = self.node().form.kind;
-                match (form.kind, node_kind) {
-                    (FormKind::Full, FormKind::Full) => self.replicate_broadcast(&self.node().form, form, target),
-                    (FormKind::Part, FormKind::Full) => {
-                        if self.has_flag(Self::IS_SHAPE) {
-                            unimplemented!()
-                        }
+        let mut results = vec![String::new(); reduced.len()];
+        for (i, name) in reduced.into_iter().enumerate() {
+            results[order[i]] = name
+        }
+        results.into_boxed_slice()
+    }
 
-//                        if self.has_flag(Self::IS_BATCHED) {
-                            self.replicate_split(&self.node().form, form, target)
-//                        } else {
-//                            panic!("cannot split a unbatched tensor")
-//                        }
-                    },
-                    (FormKind::Full, FormKind::Part) => {
-                        if self.has_flag(Self::IS_SHAPE) {
-                            unimplemented!()
-                        }
+    /// all-reduce in three levels for clusters of multi-GPU machines: replicas on the same device are summed with AddN, the devices of each
+    /// machine (see `Target::machines`) sum their results with NcclAllReduce, the first device of each machine joins a ring all-reduce
+    /// across machines, and the other devices of the machine copy the result from it. Only one copy of the tensor crosses each inter-machine hop.
+    pub fn all_reduce_hierarchical(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices);
 
-                        if self.has_flag(Self::IS_BATCHED) {
-                            self.aggregate_cat(&self.node().form, form, target)
-                        } else { // if it is not batched but is split, the only possibility is it is inherited from a split parent, so it must be a gradient
-                            self.aggregate_sum(&self.node().form, form, target)
-                        }
-                    },
-                    (FormKind::Part, FormKind::Part) => {
-                        if self.has_flag(Self::IS_SHAPE) {
-                            unimplemented!()
-                        }
+        let dtype = self.dtype();
+        let psize = self.get_size() / from.ndev() as u64;
+        let sources = self.source_names(from);
+        let tag = format!("{}_{}/aux_hier", to.code(), self.index);
 
-                        // if self.has_flag(Self::IS_BATCHED) {
-                        //     self.resplit(&self.node().form, form, target)
-                        // } else {
-                            // unimplemented!("cannot resplit a unbatched tensor")
-                            // there is currently a hack in resplit that copy parts if the number matches. Move this logic out and mark this case a bug.
-                            self.resplit(&self.node().form, form, target)
-                        // }
-                    },
+        // 1. sum the replicas on the same device
+        let mut local_groups: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for (i, device_id) in from.devices.iter().copied().enumerate() {
+            local_groups.entry(device_id).or_default().push(sources[i].clone())
+        }
+        let local_summed: BTreeMap<usize, String> = local_groups.into_iter().map(|(device_id, local_nodes)| (device_id, match local_nodes.len() {
+            1 => local_nodes.into_iter().next().unwrap(),
+            _ => {
+                let mut addn = self.node().make_node("AddN".to_string());
+                addn.name += &format!("/{}/local_{}", tag, device_id);
+                addn.device = target.devices[device_id].clone();
+                addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(local_nodes.len() as _)));
+                addn.attr.insert("T".into(), dtype.clone());
+                for i in 0..local_nodes.len() {
+                    set_input_size(&mut addn, i, psize)
                 }
-            };
+                addn.input = local_nodes.into();
+                let name = addn.name.clone();
+                self.push_reduce(addn, target);
+                name
+            }
+        })).collect();
 
-            self.forms.insert(form.clone(), names);
+        // 2. sum within each machine
+        let machines: Vec<Vec<usize>> = target.machines().into_iter()
+            .map(|devices| devices.into_iter().filter(|d| local_summed.contains_key(d)).collect::<Vec<_>>())
+            .filter(|devices| !devices.is_empty()).collect();
+        let mut machine_summed: BTreeMap<usize, String> = BTreeMap::new();
+        let collective = self.use_collective_backend();
+        for (m, devices) in machines.iter().enumerate() {
+            if devices.len() == 1 {
+                machine_summed.insert(devices[0], local_summed[&devices[0]].clone());
+                continue
+            }
+            if collective {
+                let inputs: BTreeMap<usize, String> = devices.iter().map(|d| (*d, local_summed[d].clone())).collect();
+                let tag = tag.clone();
+                machine_summed.extend(self.collective_reduce(&inputs, psize, |device_id| format!("/{}/collective_{}", tag, device_id), target));
+                continue
+            }
+            for device_id in devices.iter().copied() {
+                let mut nccl = self.node().make_node("NcclAllReduce".to_string());
+                nccl.name += &format!("/{}/nccl_{}", tag, device_id);
+                nccl.device = target.devices[device_id].clone();
+                nccl.attr.insert("reduction".into(), AttrValue::new().apply(|x| x.set_s(self.reduction.nccl_name().as_bytes().to_vec())));
+                nccl.attr.insert("T".into(), dtype.clone());
+                nccl.attr.insert("num_devices".into(), AttrValue::new().apply(|x| x.set_i(devices.len() as _)));
+                nccl.attr.insert("shared_name".into(), AttrValue::new().apply(|x| x.set_s(format!("{}/machine_{}", self.original_name(), m).into_bytes())));
+                nccl.input.push(local_summed[&device_id].clone());
+                set_input_size(&mut nccl, 0, psize);
+                machine_summed.insert(device_id, nccl.name.clone());
+                target.pb.node.push(nccl)
+            }
         }
 
-        &self.forms[form]
-    }
-
-    /**************************************
-    * following are graph editing methods *
-    **************************************/
-
-    pub fn aggregate_sum(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
-        assert!(from.valid() && to.valid() && from.is_part() && to.is_full());
+        // 3. ring across the first devices of the machines
+        let leaders: Vec<usize> = machines.iter().map(|x| x[0]).collect();
+        let mut global: BTreeMap<usize, String> = BTreeMap::new();
+        if leaders.len() == 1 {
+            global.insert(leaders[0], machine_summed[&leaders[0]].clone());
+        } else {
+            let order = match self.node().graph().options.get("ring_order").map(|x| &x[..]) {
+                None | Some("index") => (0..leaders.len()).collect(),
+                Some("bandwidth") => ring_order(target, &leaders),
+                Some(x) => panic!("unknown ring_order {}", x)
+            };
+            let devices: Vec<String> = order.iter().map(|k| target.devices[leaders[*k]].clone()).collect();
+            let list: Vec<String> = order.iter().map(|k| machine_summed[&leaders[*k]].clone()).collect();
+            let reduced = self.ring_all_reduce(&list, &devices, &tag, psize, target);
+            for (i, name) in reduced.into_iter().enumerate() {
+                global.insert(leaders[order[i]], name);
+            }
+        }
 
-        let mut addn = self.node().make_node("AddN".to_string());
-        addn.name += &format!("/{}_{}/aux_sum", self.index, to.code());
-        addn.device = target.devices[to.devices[0]].clone();
-        addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(from.ndev() as _)));
-        addn.attr.insert("T".into(), get_dtype(&self.node().raw_node, self.index));
-        addn.input = self.as_form(from, target).iter().cloned().collect();
-        for i in 0..from.ndev() {
-            set_input_size(&mut addn, i, self.get_size() / from.ndev() as u64)
+        // 4. copy back to the other devices of each machine
+        for devices in machines.iter() {
+            let leader = global[&devices[0]].clone();
+            for device_id in devices.iter().skip(1).copied() {
+                let mut identity = self.node().make_node("Identity".to_string());
+                identity.name += &format!("/{}/broadcast_{}", tag, device_id);
+                identity.device = target.devices[device_id].clone();
+                identity.attr.insert("T".into(), dtype.clone());
+                identity.input.push(leader.clone());
+                set_input_size(&mut identity, 0, psize);
+                global.insert(device_id, identity.name.clone());
+                target.pb.node.push(identity)
+            }
         }
 
-        let result = vec![addn.name.clone(); to.ndev()].into_boxed_slice();
-        target.pb.node.push(addn);
-        result
+        from.devices.iter().map(|device_id| global[device_id].clone()).collect()
     }
 
-    // TODO: share the same axis nodes for all concating (and do the same thing for dim nodes in splitting)
-    pub fn aggregate_cat(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
-        assert!(from.valid() && to.valid() && from.is_part() && to.is_full());
+    /// all-reduce along a binary tree: the devices sum what they receive from their children and send it to their parent, then the total is
+    /// relayed back down the same tree. The tree is filled level by level from the first device so its depth is logarithmic, and each device
+    /// joins under the parent (with a free slot on the shallowest level) that has the widest path to it. Replicas on the same device are summed
+    /// first. It takes 2 log(n) hops instead of the 2(n-1) of a ring, so it suits small tensors on many devices where latency dominates.
+    pub fn all_reduce_tree(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices);
 
-        let mut axis = self.node().make_node("Const".to_string());
-        axis.name += &format!("/{}_{}/aux_concat/axis", self.index, to.code());
-        axis.device = target.devices[to.devices[0]].clone();
-        axis.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
-        let value = crate::proto::tensor::TensorProto::new().apply(|x| {
-            x.set_dtype(DataType::DT_INT32);
-            x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
-            x.int_val.push(0);
-        });
-        axis.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+        let n = target.ndev();
+        let bandwidth = |a: usize, b: usize| std::cmp::min(
+            target.paths[a * n + b].iter().map(|link| target.links[*link]).min().unwrap_or(u64::MAX),
+            target.paths[b * n + a].iter().map(|link| target.links[*link]).min().unwrap_or(u64::MAX)
+        );
+        let psize = self.get_size() / from.ndev() as u64;
+        let sources = self.source_names(from);
+        let tag = format!("{}_{}/aux_tree", to.code(), self.index);
 
-        let mut concat = self.node().make_node("ConcatV2".to_string());
-        concat.name += &format!("/{}_{}/aux_concat/concat", self.index, to.code());
-        concat.device = target.devices[to.devices[0]].clone();
-        concat.input = self.as_form(from, target).iter().cloned().collect();
-        concat.input.push(axis.name.clone());
-        concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(from.ndev() as _)));
-        concat.attr.insert("T".into(), get_dtype(&self.node().raw_node, self.index));
-        concat.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
-        for i in 0..from.ndev() {
-            set_input_size(&mut concat, i, self.get_size() / from.ndev() as u64)
+        let mut local_groups: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for (i, device_id) in from.devices.iter().copied().enumerate() {
+            local_groups.entry(device_id).or_default().push(sources[i].clone())
         }
 
-        let result = vec![concat.name.clone(); to.ndev()].into_boxed_slice();
-        target.pb.node.push(axis);
-        target.pb.node.push(concat);
-        result
-    }
+        // 1. build the tree. `members` is in the order of joining, so parents come before their children
+        let root = from.devices[0];
+        let mut members = vec![root];
+        let mut depth: BTreeMap<usize, usize> = BTreeMap::new();
+        depth.insert(root, 0);
+        let mut children: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        let mut remaining: BTreeSet<usize> = local_groups.keys().copied().filter(|x| *x != root).collect();
+        while !remaining.is_empty() {
+            let (parent, child) = members.iter().filter(|p| children.get(p).map(|x| x.len()).unwrap_or(0) < 2)
+                .flat_map(|p| remaining.iter().map(move |c| (*p, *c)))
+                .max_by_key(|(p, c)| (std::cmp::Reverse(depth[p]), bandwidth(*p, *c), std::cmp::Reverse(*c))).unwrap();
+            children.entry(parent).or_default().push(child);
+            depth.insert(child, depth[&parent] + 1);
+            members.push(child);
+            remaining.remove(&child);
+        }
 
-    pub fn replicate_broadcast(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
-        assert!(from.valid() && to.valid() && from.is_full() && to.is_full());
+        // 2. reduce towards the root and broadcast back
+        let total = self.tree_reduce_broadcast(&local_groups, &members, &children, &tag, psize, target);
 
-        let raw = self.as_form(&self.node().form, target).to_vec(); // TODO: no clone?
-        to.devices.iter().map(|device_id| {
-            from.devices.iter().position(|x| *x == *device_id).map(|ind| raw[ind].clone()).unwrap_or_else(|| raw[0].clone())
-        }).collect()
+        from.devices.iter().map(|device_id| total[device_id].clone()).collect()
     }
 
-    // currenly we only split from the first replica. Future we can split on every device and use the local copy to reduce transfering
-    pub fn replicate_split(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
-        assert!(from.valid() && to.valid() && from.is_full() && to.is_part());
+    /// all-reduce with Horovod (the option `collective_backend` = `horovod`), for clusters that already deploy it and use the compiler only for
+    /// placement and splitting. Horovod runs one rank per task, numbered in the order of `Target::machines`, and every rank must join each
+    /// collective, so the editor only uses it when each task holds a replica. The replicas of a task are summed on its first device, summed
+    /// across tasks by a HorovodAllreduce and copied to the other devices of the task. The ops match across ranks by the last component of their
+    /// names (`ignore_name_scope`), and need the Horovod ops (0.21 or later) to be loaded before the graph is imported.
+    pub fn all_reduce_horovod(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices);
 
-        let mut dim = self.node().make_node("Const".to_string());
-        dim.name += &format!("/{}_{}/aux_split/dim", self.index, to.code());
-        dim.device = target.devices[from.devices[0]].clone();
-        dim.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
-        let value = crate::proto::tensor::TensorProto::new().apply(|x| {
-            x.set_dtype(DataType::DT_INT32);
-            x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
-            x.int_val.push(0);
-        });
-        dim.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+        let dtype = self.dtype();
+        let psize = self.get_size() / from.ndev() as u64;
+        let sources = self.source_names(from);
+        let tag = format!("{}_{}/aux_horovod", to.code(), self.index);
+        let key = format!("hvd_allreduce_{}_{}", horovod_name(&self.original_name()), to.code());
+
+        let mut result: BTreeMap<usize, String> = BTreeMap::new();
+        for (rank, devices) in target.machines().into_iter().enumerate() {
+            let local: Vec<usize> = (0..from.ndev()).filter(|i| devices.contains(&from.devices[*i])).collect();
+            assert!(!local.is_empty(), "BUG: task {} has no replica of {} to join the horovod all-reduce", rank, self.original_name());
+            let leader = from.devices[local[0]];
+
+            let summed = if local.len() == 1 {
+                sources[local[0]].clone()
+            } else {
+                let mut addn = self.node().make_node("AddN".to_string());
+                addn.name += &format!("/{}/local_{}", tag, rank);
+                addn.device = target.devices[leader].clone();
+                addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(local.len() as _)));
+                addn.attr.insert("T".into(), dtype.clone());
+                addn.input = local.iter().map(|i| sources[*i].clone()).collect();
+                for i in 0..local.len() {
+                    set_input_size(&mut addn, i, psize)
+                }
+                let name = addn.name.clone();
+                self.push_reduce(addn, target);
+                name
+            };
 
-        let mut split = self.node().make_node("Split".to_string());
-        split.name += &format!("/{}_{}/aux_split/split", self.index, to.code());
-        split.device = target.devices[from.devices[0]].clone();
-        split.input.push(dim.name.clone());
-        split.input.push(self.as_form(from, target)[0].clone());
-        split.attr.insert("T".into(), get_dtype(&self.node().raw_node, self.index));
-        split.attr.insert("num_split".into(), AttrValue::new().apply(|x| x.set_i(to.ndev() as _)));
-        set_input_size(&mut split, 1, self.get_size());
+            let mut allreduce = self.node().make_node("HorovodAllreduce".to_string());
+            allreduce.name += &format!("/{}/task_{}/{}", tag, rank, key);
+            allreduce.device = target.devices[leader].clone();
+            allreduce.attr.insert("T".into(), dtype.clone());
+            allreduce.attr.insert("reduce_op".into(), AttrValue::new().apply(|x| x.set_i(self.reduction.horovod_op())));
+            allreduce.attr.insert("prescale_factor".into(), AttrValue::new().apply(|x| x.set_f(1.)));
+            allreduce.attr.insert("postscale_factor".into(), AttrValue::new().apply(|x| x.set_f(1.)));
+            allreduce.attr.insert("ignore_name_scope".into(), AttrValue::new().apply(|x| x.set_b(true)));
+            allreduce.input.push(summed);
+            set_input_size(&mut allreduce, 0, psize);
+            result.insert(leader, allreduce.name.clone());
+
+            for device_id in local.iter().map(|i| from.devices[*i]).filter(|d| *d != leader).collect::<BTreeSet<_>>() {
+                let mut identity = self.node().make_node("Identity".to_string());
+                identity.name += &format!("/{}/copy_{}", tag, device_id);
+                identity.device = target.devices[device_id].clone();
+                identity.attr.insert("T".into(), dtype.clone());
+                identity.input.push(allreduce.name.clone());
+                set_input_size(&mut identity, 0, psize);
+                result.insert(device_id, identity.name.clone());
+                target.pb.node.push(identity)
+            }
+            target.pb.node.push(allreduce)
+        }
 
-        let result = (0..to.ndev()).map(|i| format!("{}:{}", split.name, i)).collect();
-        target.pb.node.push(dim);
-        target.pb.node.push(split);
-        result
+        from.devices.iter().map(|device_id| result[device_id].clone()).collect()
     }
 
-    pub fn resplit(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
-        assert!(from.valid() && to.valid() && from.is_part() && to.is_part());
-
-        if from.ndev() == to.ndev() { // special case: if the number are the same, just forward. TODO: use replicas on the same device when possible
-            return self.as_form(from, target).to_vec().into_boxed_slice()
-        }
+    /// all-reduce along two complementary binary trees, like the double binary trees of NCCL: the flattened tensor is halved and each half is
+    /// reduced and broadcast along its own tree. The first tree is the in-order binary tree over the devices, whose leaves are the devices at
+    /// even positions; the second is the first mirrored (or shifted by one when the number of devices is odd), so the leaves of one are the
+    /// inner nodes of the other and every device sends and receives in both phases. It keeps the 2 log(n) hops of `all_reduce_tree` while
+    /// using both directions of the links. Neighbors in the order exchange the most, so the devices are ordered like a ring (see the option
+    /// `ring_order`). Replicas on the same device are summed first, and tensors with an odd number of elements fall back to a single tree.
+    pub fn all_reduce_double_tree(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices);
 
-        let gcd = { // the number of intermediat concated nodes
-            let mut a = from.ndev();
-            let mut b = to.ndev();
-            while a != b {
-                if a > b {
-                    a -= b;
-                } else {
-                    b -= a;
-                }
+        let mut devices: Vec<usize> = vec![];
+        for device_id in from.devices.iter() {
+            if !devices.contains(device_id) {
+                devices.push(*device_id)
             }
-            a
+        }
+        if devices.len() < 2 || self.get_shape().iter().product::<usize>() % 2 == 1 {
+            return self.all_reduce_tree(from, to, target)
+        }
+        let devices: Vec<usize> = match self.node().graph().options.get("ring_order").map(|x| &x[..]) {
+            Some("bandwidth") => ring_order(target, &devices).into_iter().map(|i| devices[i]).collect(),
+            _ => devices
         };
 
-        self.as_form(from, target).to_vec().chunks(from.ndev() / gcd).enumerate().map(|(i, chunk)| {
-            let dest = from.devices[i * chunk.len()];
+        let m = devices.len();
+        let dtype = self.dtype();
+        let psize = self.get_size() / from.ndev() as u64;
+        let sources = self.source_names(from);
+        let tag = format!("{}_{}/aux_double_tree", to.code(), self.index);
+
+        // 1. sum the local replicas, then flatten and halve
+        let mut shapes: BTreeMap<usize, String> = BTreeMap::new();
+        let mut halves: [BTreeMap<usize, Vec<String>>; 2] = Default::default();
+        for device_id in devices.iter().copied() {
+            let local: Vec<String> = (0..from.ndev()).filter(|i| from.devices[*i] == device_id).map(|i| sources[i].clone()).collect();
+            let summed = if local.len() == 1 {
+                local[0].clone()
+            } else {
+                let mut addn = self.node().make_node("AddN".to_string());
+                addn.name += &format!("/{}/local_{}", tag, device_id);
+                addn.device = target.devices[device_id].clone();
+                addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(local.len() as _)));
+                addn.attr.insert("T".into(), dtype.clone());
+                for i in 0..local.len() {
+                    set_input_size(&mut addn, i, psize)
+                }
+                addn.input = local.into();
+                let name = addn.name.clone();
+                self.push_reduce(addn, target);
+                name
+            };
 
-            // special case: no need to concat
-            if chunk.len() == 1 {
-                return (dest, chunk[0].clone())
-            }
+            let mut shape = self.node().make_node("Shape".to_string());
+            shape.name += &format!("/{}/shape_{}", tag, device_id);
+            shape.device = target.devices[device_id].clone();
+            shape.attr.insert("T".into(), dtype.clone());
+            shape.input.push(summed.clone());
+            set_input_size(&mut shape, 0, psize);
 
-            let mut axis = self.node().make_node("Const".to_string());
-            axis.name += &format!("/{}_{}/aux_resplit_{}/concat_axis", self.index, to.code(), i);
-            axis.device = target.devices[dest].clone();
-            axis.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            let mut flat_shape = self.node().make_node("Const".to_string());
+            flat_shape.name += &format!("/{}/flat_{}/shape", tag, device_id);
+            flat_shape.device = target.devices[device_id].clone();
+            flat_shape.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
             let value = crate::proto::tensor::TensorProto::new().apply(|x| {
                 x.set_dtype(DataType::DT_INT32);
-                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
-                x.int_val.push(0);
+                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|x| x.dim.push(crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|x| x.size = 1))));
+                x.int_val.push(-1);
             });
-            axis.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
-
-            let mut concat = self.node().make_node("ConcatV2".to_string());
-            concat.name += &format!("/{}_{}/aux_resplit_{}/concat", self.index, to.code(), i);
-            concat.device = target.devices[dest].clone();
-            concat.input = chunk.iter().cloned().collect();
-            concat.input.push(axis.name.clone());
-            concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(chunk.len() as _)));
-            concat.attr.insert("T".into(), get_dtype(&self.node().raw_node, self.index));
-            concat.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
-            for j in 0..chunk.len() {
-                set_input_size(&mut concat, j, self.get_size() / from.ndev() as u64)
-            }
+            flat_shape.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
 
-            let result = concat.name.clone();
-            target.pb.node.push(axis);
-            target.pb.node.push(concat);
-            (dest, result)
-        }).collect::<Vec<_>>().iter().zip(to.devices.chunks(to.ndev() / gcd)).enumerate().flat_map(|(i, ((concat_place, concated), devices))| {
-            if devices.len() == 1 { // special case: no need to split
-                return vec![concated.clone()] // TODO: use another return type for this closure? Ideally do not collect and return a dyn IntoIterator instead
-            }
+            let mut flat = self.node().make_node("Reshape".to_string());
+            flat.name += &format!("/{}/flat_{}/flat", tag, device_id);
+            flat.device = target.devices[device_id].clone();
+            flat.attr.insert("T".into(), dtype.clone());
+            flat.input.push(summed);
+            flat.input.push(flat_shape.name.clone());
+            set_input_size(&mut flat, 0, psize);
 
             let mut dim = self.node().make_node("Const".to_string());
-            dim.name += &format!("/{}_{}/aux_resplit_{}/split_dim", self.index, to.code(), i);
-            dim.device = target.devices[*concat_place].clone();
+            dim.name += &format!("/{}/split_{}/dim", tag, device_id);
+            dim.device = target.devices[device_id].clone();
             dim.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
             let value = crate::proto::tensor::TensorProto::new().apply(|x| {
                 x.set_dtype(DataType::DT_INT32);
@@ -714,154 +4141,308 @@ impl Tensor {
             dim.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
 
             let mut split = self.node().make_node("Split".to_string());
-            split.name += &format!("/{}_{}/aux_resplit_{}/split", self.index, to.code(), i);
-            split.device = target.devices[*concat_place].clone();
+            split.name += &format!("/{}/split_{}/split", tag, device_id);
+            split.device = target.devices[device_id].clone();
             split.input.push(dim.name.clone());
-            split.input.push(concated.clone());
-            split.attr.insert("T".into(), get_dtype(&self.node().raw_node, self.index));
-            split.attr.insert("num_split".into(), AttrValue::new().apply(|x| x.set_i(devices.len() as _)));
-            set_input_size(&mut split, 1, self.get_size() / gcd as u64);
+            split.input.push(flat.name.clone());
+            split.attr.insert("T".into(), dtype.clone());
+            split.attr.insert("num_split".into(), AttrValue::new().apply(|x| x.set_i(2)));
+            set_input_size(&mut split, 1, psize);
 
-            let result = (0..to.ndev() / gcd).map({
-                let name = split.name.clone();
-                move |i| format!("{}:{}", name, i)
-            }).collect();
+            shapes.insert(device_id, shape.name.clone());
+            for (t, half) in halves.iter_mut().enumerate() {
+                half.insert(device_id, vec![format!("{}:{}", split.name, t)]);
+            }
+            target.pb.node.push(shape);
+            target.pb.node.push(flat_shape);
+            target.pb.node.push(flat);
             target.pb.node.push(dim);
             target.pb.node.push(split);
-            result
-        }).collect()
-    }
-
-    pub fn all_reduce_sum_nccl(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
-        // to all_sum n tensors (can be on the same device), one should have n NcclAllReduce nodes with the same shared_name attr
-        // each node have only *one* input, and should be on the same device of the input. The output of these nodes will be the same
+        }
 
-        assert!(target.devices.windows(2).all(|w| task_name(&w[0]) == task_name(&w[1]))); // This nodes only works intra-task
-        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices);
+        // 2. reduce and broadcast each half along its tree
+        let mut totals = vec![];
+        for (t, half) in halves.iter().enumerate() {
+            let position = |p: usize| match t {
+                0 => p,
+                _ if m % 2 == 0 => m - 1 - p,
+                _ => (p + 1) % m
+            };
+            let mut children: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+            let mut root = 0;
+            for p in 0..m {
+                match btree_parent(p + 1, m) {
+                    Some(parent) => children.entry(devices[position(parent - 1)]).or_default().push(devices[position(p)]),
+                    None => root = devices[position(p)]
+                }
+            }
+            let mut members = vec![root];
+            let mut i = 0;
+            while i < members.len() {
+                members.extend(children.get(&members[i]).into_iter().flatten().copied());
+                i += 1
+            }
+            totals.push(self.tree_reduce_broadcast(half, &members, &children, &format!("{}/tree_{}", tag, t), psize / 2, target));
+        }
 
-        let index = self.index;
+        // 3. concat the halves and restore the shape
+        let mut result: BTreeMap<usize, String> = BTreeMap::new();
+        for device_id in devices.iter().copied() {
+            let mut axis = self.node().make_node("Const".to_string());
+            axis.name += &format!("/{}/concat_{}/axis", tag, device_id);
+            axis.device = target.devices[device_id].clone();
+            axis.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+                x.set_dtype(DataType::DT_INT32);
+                x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+                x.int_val.push(0);
+            });
+            axis.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
 
-        for (i, device_id) in from.devices.iter().enumerate() {
-            let mut nccl = self.node().make_node("NcclAllReduce".to_string());
-            nccl.name += &format!("/{}_{}/aux_nccl_{}", index, to.code(), i);
-            nccl.device = target.devices[*device_id].clone();
-            nccl.attr.insert("reduction".into(), AttrValue::new().apply(|x| x.set_s(b"sum".to_vec())));
-            nccl.attr.insert("T".into(), get_dtype(&self.node().raw_node, self.index));
-            nccl.attr.insert("num_devices".into(), AttrValue::new().apply(|x| x.set_i(from.ndev() as _)));
-            nccl.attr.insert("shared_name".into(), AttrValue::new().apply(|x| x.set_s(self.original_name().into_bytes())));
-            nccl.input.push(self.as_form(from, target)[i].clone());
-            set_input_size(&mut nccl, 0, self.get_size() / from.ndev() as u64);
+            let mut concat = self.node().make_node("ConcatV2".to_string());
+            concat.name += &format!("/{}/concat_{}/concat", tag, device_id);
+            concat.device = target.devices[device_id].clone();
+            concat.input = totals.iter().map(|x| x[&device_id].clone()).collect();
+            concat.input.push(axis.name.clone());
+            concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(2)));
+            concat.attr.insert("T".into(), dtype.clone());
+            concat.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            set_input_size(&mut concat, 0, psize / 2);
+            set_input_size(&mut concat, 1, psize / 2);
 
-            target.pb.node.push(nccl)
+            let mut reshape = self.node().make_node("Reshape".to_string());
+            reshape.name += &format!("/{}/reshape_{}", tag, device_id);
+            reshape.device = target.devices[device_id].clone();
+            reshape.attr.insert("T".into(), dtype.clone());
+            reshape.input.push(concat.name.clone());
+            reshape.input.push(shapes[&device_id].clone());
+            set_input_size(&mut reshape, 0, psize);
+
+            result.insert(device_id, reshape.name.clone());
+            target.pb.node.push(axis);
+            target.pb.node.push(concat);
+            target.pb.node.push(reshape);
         }
 
-        (0..from.ndev()).map(|i| format!("{}/{}_{}/aux_nccl_{}", self.node().raw_node.name, self.index, to.code(), i)).collect()
+        from.devices.iter().map(|device_id| result[device_id].clone()).collect()
     }
 
-    pub fn all_reduce_sum_collective(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
-        // each node have only *one* input, and should be on the same device of the input. The output of these nodes will be the same
-        // group_key: not sure, guess is nccl group, so operations on *the same set of devices* could share the same group_key
-        // instance_key: each operation use a unique instance_key, pretty much like "shared_name" in NcclAllReduce
-
+    /// all-reduce through the host CPUs, for GPUs without peer access or NCCL: the replicas are summed by an AddN on the host CPU of their
+    /// devices (see `Target::host_cpu`), the sums of the hosts are added on the first host and copied back to the others, and every device
+    /// copies the total from its host. It only relies on host-device copies, which every TensorFlow build supports, at the cost of crossing
+    /// PCIe twice. Each tensor crosses the network once per host rather than once per device.
+    pub fn all_reduce_cpu_staged(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
         assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices);
 
-        let part_size = self.get_size() / from.ndev() as u64;
+        let dtype = self.dtype();
+        let psize = self.get_size() / from.ndev() as u64;
+        let sources = self.source_names(from);
+        let tag = format!("{}_{}/aux_cpu", to.code(), self.index);
 
-        let mut local_groups: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        // 1. stage the replicas on their hosts and sum them there
+        let mut hosts: Vec<(String, Vec<String>)> = vec![]; // in the order of the first replica on each host
         for (i, device_id) in from.devices.iter().copied().enumerate() {
-            let name = self.as_form(from, target)[i].clone();
-            local_groups.entry(device_id).or_default().push(name)
+            let host = target.host_cpu(device_id);
+            match hosts.iter_mut().find(|(x, _)| *x == host) {
+                Some((_, inputs)) => inputs.push(sources[i].clone()),
+                None => hosts.push((host, vec![sources[i].clone()]))
+            }
         }
+        let summed: Vec<String> = hosts.iter().enumerate().map(|(h, (host, inputs))| {
+            let mut addn = self.node().make_node("AddN".to_string());
+            addn.name += &format!("/{}/host_{}", tag, h);
+            addn.device = host.clone();
+            addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(inputs.len() as _)));
+            addn.attr.insert("T".into(), dtype.clone());
+            for i in 0..inputs.len() {
+                set_input_size(&mut addn, i, psize)
+            }
+            addn.input = inputs.clone().into();
+            let name = addn.name.clone();
+            self.push_reduce(addn, target);
+            name
+        }).collect();
 
-        let local_summed: BTreeMap<_, _> = local_groups.iter().map(|(device_id, local_nodes)| (*device_id, match local_nodes.len() {
-            0 => unreachable!(),
-            1 => local_nodes[0].clone(), // TODO: destruct the vector and take the element
-            _ => {
-                let mut addn = self.node().make_node("AddN".to_string());
-                addn.name += &format!("/{}_{}_{}/aux_sum", self.index, to.code(), device_id);
-                addn.device = target.devices[*device_id].clone();
-                addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(local_nodes.len() as _)));
-                addn.attr.insert("T".into(), get_dtype(&self.node().raw_node, self.index));
-                addn.input = local_nodes.iter().cloned().collect();
-                for i in 0..local_nodes.len() {
-                    set_input_size(&mut addn, i, part_size)
-                }
-                let name = addn.name.clone();
-                target.pb.node.push(addn);
-                name
+        // 2. sum across the hosts on the first one and copy the total back to the others
+        let mut totals: BTreeMap<String, String> = BTreeMap::new();
+        if hosts.len() == 1 {
+            totals.insert(hosts[0].0.clone(), summed[0].clone());
+        } else {
+            let mut addn = self.node().make_node("AddN".to_string());
+            addn.name += &format!("/{}/total", tag);
+            addn.device = hosts[0].0.clone();
+            addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(hosts.len() as _)));
+            addn.attr.insert("T".into(), dtype.clone());
+            for i in 0..hosts.len() {
+                set_input_size(&mut addn, i, psize)
             }
-        })).collect();
+            addn.input = summed.into();
+            totals.insert(hosts[0].0.clone(), addn.name.clone());
+            for (h, (host, _)) in hosts.iter().enumerate().skip(1) {
+                let mut identity = self.node().make_node("Identity".to_string());
+                identity.name += &format!("/{}/host_{}/total", tag, h);
+                identity.device = host.clone();
+                identity.attr.insert("T".into(), dtype.clone());
+                identity.input.push(addn.name.clone());
+                set_input_size(&mut identity, 0, psize);
+                totals.insert(host.clone(), identity.name.clone());
+                target.pb.node.push(identity)
+            }
+            self.push_reduce(addn, target)
+        }
 
-        let state = &mut self.node().graph().collective_state;
-        let group_key = state.get_group(&local_summed.keys().copied().collect::<Vec<_>>()); // it is sorted by BTreeMap
-        let (instance, instance_key) = state.new_instance();
+        // 3. copy the total to each device
+        let mut result: BTreeMap<usize, String> = BTreeMap::new();
+        for device_id in from.devices.iter().copied().collect::<BTreeSet<_>>() {
+            let mut identity = self.node().make_node("Identity".to_string());
+            identity.name += &format!("/{}/copy_{}", tag, device_id);
+            identity.device = target.devices[device_id].clone();
+            identity.attr.insert("T".into(), dtype.clone());
+            identity.input.push(totals[&target.host_cpu(device_id)].clone());
+            set_input_size(&mut identity, 0, psize);
+            result.insert(device_id, identity.name.clone());
+            target.pb.node.push(identity)
+        }
 
-        let local_reduced: BTreeMap<_, _> = local_summed.iter().map(|(device_id, local_name)| {
-            let mut node = self.node().make_node("CollectiveReduce".to_string());
-            node.name += &format!("/{}_{}_{}/aux_collective", self.index, to.code(), device_id);
-            node.device = target.devices[*device_id].clone();
-            node.attr.insert("T".into(), get_dtype(&self.node().raw_node, self.index));
-            node.attr.insert("final_op".into(), AttrValue::new().apply(|x| x.set_s(b"Id".to_vec())));
-            node.attr.insert("merge_op".into(), AttrValue::new().apply(|x| x.set_s(b"Add".to_vec())));
-            node.attr.insert("group_key".into(), AttrValue::new().apply(|x| x.set_i(group_key as _)));
-            node.attr.insert("group_size".into(), AttrValue::new().apply(|x| x.set_i(local_summed.len() as _)));
-            node.attr.insert("instance_key".into(), AttrValue::new().apply(|x| x.set_i(instance_key as _)));
-            node.attr.insert("subdiv_offsets".into(), AttrValue::new().apply(|x| x.mut_list().i = vec![0]));
-            node.input.push(local_name.clone());
-            set_input_size(&mut node, 0, part_size);
+        from.devices.iter().map(|device_id| result[device_id].clone()).collect()
+    }
 
-            instance.push(target.pb.node.len());
-            let name = node.name.clone();
-            target.pb.node.push(node);
-            (device_id, name)
-        }).collect();
+    /// all-reduce across machines without NCCL: the replicas of each machine (see `Target::machines`) are summed by an AddN on its first
+    /// device, which reaches the other GPUs of the machine by peer copies, the sums of the machines join a ring all-reduce on the host CPUs
+    /// of those first devices (see `Target::host_cpu`), and every device copies the total from the host CPU of its machine. The network
+    /// hops only involve CPUs, so it runs where NCCL cannot span hosts, and each tensor crosses the network 2(k-1)/k times for k machines.
+    pub fn all_reduce_cpu_ring(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
+        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices);
 
-        from.devices.iter().map(|device_id| local_reduced[device_id].clone()).collect()
-    }
+        let dtype = self.dtype();
+        let psize = self.get_size() / from.ndev() as u64;
+        let sources = self.source_names(from);
+        let tag = format!("{}_{}/aux_cpu_ring", to.code(), self.index);
+
+        // 1. sum the replicas of each machine on its first device
+        let machines: Vec<Vec<usize>> = target.machines().into_iter()
+            .map(|devices| devices.into_iter().filter(|d| from.devices.contains(d)).collect::<Vec<_>>())
+            .filter(|devices| !devices.is_empty()).collect();
+        let summed: Vec<String> = machines.iter().enumerate().map(|(m, devices)| {
+            let inputs: Vec<String> = from.devices.iter().enumerate().filter(|(_, d)| devices.contains(d)).map(|(i, _)| sources[i].clone()).collect();
+            if inputs.len() == 1 {
+                return inputs.into_iter().next().unwrap()
+            }
 
-    pub fn all_reduce_cat_collective(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
-        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices && BTreeSet::from_iter(from.devices.iter()).len() == from.devices.len());
+            let mut addn = self.node().make_node("AddN".to_string());
+            addn.name += &format!("/{}/machine_{}", tag, m);
+            addn.device = target.devices[devices[0]].clone();
+            addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(inputs.len() as _)));
+            addn.attr.insert("T".into(), dtype.clone());
+            for i in 0..inputs.len() {
+                set_input_size(&mut addn, i, psize)
+            }
+            addn.input = inputs.into();
+            let name = addn.name.clone();
+            self.push_reduce(addn, target);
+            name
+        }).collect();
 
-        let part_size = self.get_size() / from.ndev() as u64;
-        let state = &mut self.node().graph().collective_state;
-        let group_key = state.get_group(&from.devices.clone().apply(|x| x.sort_unstable())); // sorted
-        let (instance, instance_key) = state.new_instance();
+        // 2. ring across the host CPUs of the machines
+        let leaders: Vec<usize> = machines.iter().map(|x| x[0]).collect();
+        let mut totals = vec![String::new(); machines.len()];
+        if machines.len() == 1 {
+            totals[0] = summed[0].clone();
+        } else {
+            let order = match self.node().graph().options.get("ring_order").map(|x| &x[..]) {
+                None | Some("index") => (0..leaders.len()).collect(),
+                Some("bandwidth") => ring_order(target, &leaders),
+                Some(x) => panic!("unknown ring_order {}", x)
+            };
+            let devices: Vec<String> = order.iter().map(|k| target.host_cpu(leaders[*k])).collect();
+            let list: Vec<String> = order.iter().map(|k| summed[*k].clone()).collect();
+            let reduced = self.ring_all_reduce(&list, &devices, &tag, psize, target);
+            for (i, name) in reduced.into_iter().enumerate() {
+                totals[order[i]] = name
+            }
+        }
 
-        let list = self.as_form(from, target).to_vec();
-        let local_reduced: BTreeMap<_, _> = from.devices.iter().zip(list.iter()).map(|(device_id, local_name)| {
-            let mut node = self.node().make_node("CollectiveGather".to_string());
-            node.name += &format!("/{}_{}_{}/aux_collective", self.index, to.code(), device_id);
-            node.device = target.devices[*device_id].clone();
-            node.attr.insert("T".into(), get_dtype(&self.node().raw_node, self.index));
-            node.attr.insert("group_key".into(), AttrValue::new().apply(|x| x.set_i(group_key as _)));
-            node.attr.insert("group_size".into(), AttrValue::new().apply(|x| x.set_i(from.devices.len() as _)));
-            node.attr.insert("instance_key".into(), AttrValue::new().apply(|x| x.set_i(instance_key as _)));
-            node.attr.insert("shape".into(), AttrValue::new().apply(|x| x.mut_shape().ignore()));
-            node.input.push(local_name.clone());
-            set_input_size(&mut node, 0, part_size);
+        // 3. copy the total to each device, except the first device of a single machine which already has it
+        let mut result: BTreeMap<usize, String> = BTreeMap::new();
+        for (m, devices) in machines.iter().enumerate() {
+            for device_id in devices.iter().copied() {
+                if machines.len() == 1 && device_id == leaders[0] {
+                    result.insert(device_id, totals[m].clone());
+                    continue
+                }
 
-            instance.push(target.pb.node.len());
-            let name = node.name.clone();
-            target.pb.node.push(node);
-            (device_id, name)
-        }).collect();
+                let mut identity = self.node().make_node("Identity".to_string());
+                identity.name += &format!("/{}/copy_{}", tag, device_id);
+                identity.device = target.devices[device_id].clone();
+                identity.attr.insert("T".into(), dtype.clone());
+                identity.input.push(totals[m].clone());
+                set_input_size(&mut identity, 0, psize);
+                result.insert(device_id, identity.name.clone());
+                target.pb.node.push(identity)
+            }
+        }
 
-        from.devices.iter().map(|device_id| local_reduced[device_id].clone()).collect()
+        from.devices.iter().map(|device_id| result[device_id].clone()).collect()
     }
 
-    pub fn all_reduce_sum_ring(&mut self, from: &Form, to: &Form, target: &mut Target) -> Box<[String]> {
-        assert!(from.valid() && to.valid() && from.is_part() && to.is_full() && from.devices == to.devices);
+    /// sum `values` (the values on each device) along a tree towards its root `members[0]` and relay the total back down, returning the total
+    /// on each member. `members` lists the devices with parents before their children.
+    fn tree_reduce_broadcast(&mut self, values: &BTreeMap<usize, Vec<String>>, members: &[usize], children: &BTreeMap<usize, Vec<usize>>, tag: &str, psize: u64, target: &mut Target) -> BTreeMap<usize, String> {
+        let dtype = self.dtype();
+
+        // reduce towards the root, leaves first
+        let mut partial: BTreeMap<usize, String> = BTreeMap::new();
+        for device_id in members.iter().rev().copied() {
+            let inputs: Vec<String> = values[&device_id].iter().cloned()
+                .chain(children.get(&device_id).into_iter().flatten().map(|child| partial[child].clone())).collect();
+            let name = if inputs.len() == 1 {
+                inputs[0].clone()
+            } else {
+                let mut addn = self.node().make_node("AddN".to_string());
+                addn.name += &format!("/{}/reduce_{}", tag, device_id);
+                addn.device = target.devices[device_id].clone();
+                addn.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(inputs.len() as _)));
+                addn.attr.insert("T".into(), dtype.clone());
+                for i in 0..inputs.len() {
+                    set_input_size(&mut addn, i, psize)
+                }
+                addn.input = inputs.into();
+                let name = addn.name.clone();
+                self.push_reduce(addn, target);
+                name
+            };
+            partial.insert(device_id, name);
+        }
+
+        // broadcast from the root
+        let mut total: BTreeMap<usize, String> = BTreeMap::new();
+        total.insert(members[0], partial[&members[0]].clone());
+        for device_id in members {
+            for child in children.get(device_id).into_iter().flatten().copied() {
+                let mut identity = self.node().make_node("Identity".to_string());
+                identity.name += &format!("/{}/broadcast_{}", tag, child);
+                identity.device = target.devices[child].clone();
+                identity.attr.insert("T".into(), dtype.clone());
+                identity.input.push(total[device_id].clone());
+                set_input_size(&mut identity, 0, psize);
+                total.insert(child, identity.name.clone());
+                target.pb.node.push(identity)
+            }
+        }
+
+        total
+    }
 
-        let devices: Vec<_> = from.devices.iter().map(|id| target.devices[*id].clone()).collect();
+    /// sum `list` with a ring all-reduce, where `list[i]` is on `devices[i]` and position i receives from position i+1. Returns the sum on each
+    /// position. The aux nodes are named `<tag>/aux_ring/...` under the node of the tensor.
+    fn ring_all_reduce(&mut self, list: &[String], devices: &[String], tag: &str, psize: u64, target: &mut Target) -> Vec<String> {
         let n = devices.len();
-        let dtype = get_dtype(&self.node().raw_node, self.index);
-        let psize = self.get_size() / from.ndev() as u64;
-        let list = self.as_form(from, target).to_vec();
+        let dtype = self.dtype();
 
         // 1. recording the shape
         let shapes: Vec<_> = (0..n).map(|i| {
             let mut shape = self.node().make_node("Shape".to_string());
-            shape.name += &format!("/{}_{}/aux_ring/shape_{}", to.code(), self.index, i);
+            shape.name += &format!("/{}/aux_ring/shape_{}", tag, i);
             shape.device = devices[i].clone();
             shape.attr.insert("T".into(), dtype.clone());
             shape.input.push(list[i].clone());
@@ -874,7 +4455,7 @@ impl Tensor {
         // 2. flattening
         let flats: Vec<_> = (0..n).map(|i| {
             let mut shape = self.node().make_node("Const".to_string());
-            shape.name += &format!("/{}_{}/aux_ring/flat_{}/shape", to.code(), self.index, i);
+            shape.name += &format!("/{}/aux_ring/flat_{}/shape", tag, i);
             shape.device = devices[i].clone();
             shape.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
             let mut value = crate::proto::tensor::TensorProto::new();
@@ -888,7 +4469,7 @@ impl Tensor {
             shape.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
 
             let mut flat = self.node().make_node("Reshape".to_string());
-            flat.name += &format!("/{}_{}/aux_ring/flat_{}/flat", to.code(), self.index, i);
+            flat.name += &format!("/{}/aux_ring/flat_{}/flat", tag, i);
             flat.device = devices[i].clone();
             flat.attr.insert("T".into(), dtype.clone());
             flat.input.push(list[i].clone());
@@ -904,7 +4485,7 @@ impl Tensor {
         // 3. chunking
         let mut chunks: Vec<Vec<String>> = (0..n).map(|i| {
             let mut dim = self.node().make_node("Const".to_string());
-            dim.name += &format!("/{}_{}/aux_ring/split_{}/dim", to.code(), self.index, i);
+            dim.name += &format!("/{}/aux_ring/split_{}/dim", tag, i);
             dim.device = devices[i].clone();
             dim.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
             let mut value = crate::proto::tensor::TensorProto::new();
@@ -915,7 +4496,7 @@ impl Tensor {
             dim.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
 
             let mut split = self.node().make_node("Split".to_string());
-            split.name += &format!("/{}_{}/aux_ring/split_{}/split", to.code(), self.index, i);
+            split.name += &format!("/{}/aux_ring/split_{}/split", tag, i);
             split.device = devices[i].clone();
             split.input.push(dim.name.clone());
             split.input.push(flats[i].clone());
@@ -934,8 +4515,8 @@ impl Tensor {
         for round in 0..n-1 {
             // at the r round, the r+i chunk on i node is replaced by the sum of r+i and r+i+1
             for i in 0..n {
-                let mut add = self.node().make_node("Add".to_string());
-                add.name += &format!("/{}_{}/aux_ring/add_{}_{}", to.code(), self.index, i, round);
+                let mut add = self.node().make_node(self.reduction.binary_op().to_string());
+                add.name += &format!("/{}/aux_ring/add_{}_{}", tag, i, round);
                 add.device = devices[i].clone();
                 add.input.push(chunks[i][(round+i) % n].clone());
                 add.input.push(chunks[(i+1) % n][(round+i) % n].clone());
@@ -951,7 +4532,7 @@ impl Tensor {
         for round in 0..n-1 {
             for i in 0..n {
                 let mut identity = self.node().make_node("Identity".to_string());
-                identity.name += &format!("/{}_{}/aux_ring/identity_{}_{}", to.code(), self.index, i, round);
+                identity.name += &format!("/{}/aux_ring/identity_{}_{}", tag, i, round);
                 identity.device = devices[i].clone();
                 identity.attr.insert("T".into(), dtype.clone());
                 identity.input.push(chunks[(i+1) % n][(i+round+n-1) % n].clone());
@@ -964,7 +4545,7 @@ impl Tensor {
         // 6. concating
         let concated: Vec<_> = chunks.into_iter().enumerate().map(|(i, chunk)| {
             let mut axis = self.node().make_node("Const".to_string());
-            axis.name += &format!("/{}_{}/aux_ring/concat_{}/axis", to.code(), self.index, i);
+            axis.name += &format!("/{}/aux_ring/concat_{}/axis", tag, i);
             axis.device = devices[i].clone();
             axis.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
             let mut value = crate::proto::tensor::TensorProto::new();
@@ -976,7 +4557,7 @@ impl Tensor {
 
             let len = chunk.len(); // save it here since we will destruct it later
             let mut concat = self.node().make_node("ConcatV2".to_string());
-            concat.name += &format!("/{}_{}/aux_ring/concat_{}/concat", to.code(), self.index, i);
+            concat.name += &format!("/{}/aux_ring/concat_{}/concat", tag, i);
             concat.device = devices[i].clone();
             concat.input = chunk.into_iter().collect();
             concat.input.push(axis.name.clone());
@@ -994,20 +4575,93 @@ impl Tensor {
         }).collect();
 
         // 7. restore shapes
-        concated.into_iter().zip(shapes).enumerate().map(|(i, (concat, shape))| {
+        let mut results = vec![String::new(); n];
+        for (i, (concat, shape)) in concated.into_iter().zip(shapes).enumerate() {
             let mut reshape = self.node().make_node("Reshape".to_string());
-            reshape.name += &format!("/{}_{}/aux_ring/reshape_{}", to.code(), self.index, i);
+            reshape.name += &format!("/{}/aux_ring/reshape_{}", tag, i);
             reshape.device = devices[i].clone();
             reshape.attr.insert("T".into(), dtype.clone());
             reshape.input.push(concat);
             reshape.input.push(shape);
             set_input_size(&mut reshape, 0, psize);
 
-            let ret = reshape.name.clone();
+            results[i] = reshape.name.clone();
             target.pb.node.push(reshape);
-            ret
-        }).collect()
+        }
+        results
+    }
+}
+
+/// order the devices of a ring all-reduce (as positions in `devices`) so the slowest hop of the cycle is as fast as possible, breaking
+/// ties by the total time of the hops. In the ring, position k receives from position k+1. Exhaustive for up to 8 devices, otherwise
+/// it starts from the widest-neighbor tour and applies 2-opt moves until no move improves it.
+pub fn ring_order(target: &Target, devices: &[usize]) -> Vec<usize> {
+    let n = devices.len();
+    let bandwidth = |a: usize, b: usize| target.paths[devices[a] * target.ndev() + devices[b]].iter().map(|link| target.links[*link]).min().unwrap_or(u64::MAX);
+    let score = |order: &[usize]| { // (bottleneck, -total time) to be maximized
+        let hops: Vec<u64> = (0..n).map(|k| bandwidth(order[(k + 1) % n], order[k])).collect();
+        (*hops.iter().min().unwrap(), -hops.iter().map(|b| 1. / *b as f64).sum::<f64>())
+    };
+    let better = |a: (u64, f64), b: (u64, f64)| a.0 > b.0 || (a.0 == b.0 && a.1 > b.1 + 1e-12);
+    if n <= 3 {
+        return (0..n).collect() // every cycle is the same up to direction
+    }
+
+    let mut best: Vec<usize> = (0..n).collect();
+    let mut best_score = score(&best);
+    if n <= 8 { // position 0 is fixed since rotations are equivalent
+        let mut order = best.clone();
+        while next_permutation(&mut order[1..]) {
+            let x = score(&order);
+            if better(x, best_score) {
+                best = order.clone();
+                best_score = x
+            }
+        }
+    } else {
+        let mut order = vec![0];
+        let mut rest: Vec<usize> = (1..n).collect();
+        while !rest.is_empty() {
+            let last = *order.last().unwrap();
+            let i = (0..rest.len()).max_by_key(|i| (std::cmp::min(bandwidth(rest[*i], last), bandwidth(last, rest[*i])), std::cmp::Reverse(rest[*i]))).unwrap();
+            order.push(rest.remove(i))
+        }
+        let mut order_score = score(&order);
+        let mut improved = true;
+        while improved {
+            improved = false;
+            for i in 1..n-1 {
+                for j in i+1..n {
+                    let mut candidate = order.clone();
+                    candidate[i..=j].reverse();
+                    let x = score(&candidate);
+                    if better(x, order_score) {
+                        order = candidate;
+                        order_score = x;
+                        improved = true
+                    }
+                }
+            }
+        }
+        if better(order_score, best_score) {
+            best = order;
+            best_score = order_score
+        }
     }
+
+    debug!("ring order {:?} with bottleneck bandwidth {}", best, best_score.0);
+    best
+}
+
+fn next_permutation(x: &mut [usize]) -> bool {
+    let i = match (1..x.len()).rev().find(|i| x[i - 1] < x[*i]) {
+        Some(i) => i - 1,
+        None => return false
+    };
+    let j = (i + 1..x.len()).rev().find(|j| x[*j] > x[i]).unwrap();
+    x.swap(i, j);
+    x[i + 1..].reverse();
+    true
 }
 
 fn set_origin(node: &mut NodeDef, origin: &str) {
@@ -1031,25 +4685,610 @@ fn set_form(node: &mut NodeDef, form_code: &str) {
 }
 
 // TODO: This function is currently a stub. Need to parse ops.pbtxt and follow type or type_attr.
-fn get_dtype(x: &NodeDef, i: usize) -> AttrValue {
-    match &x.op[..] {
-        "Greater" | "GreaterEqual" => AttrValue::new().apply(|x| x.set_field_type(DataType::DT_BOOL)),
+fn get_dtype(node: &Node, i: usize) -> AttrValue {
+    let x = &node.raw_node;
+    if let Some(custom_op) = node.graph().registry.get(&x.op) {
+        let dtype = *custom_op.dtypes.get(i).unwrap_or_else(|| panic!("custom op {} declared no dtype for output {}", x.op, i));
+        return AttrValue::new().apply(|x| x.set_field_type(dtype))
+    }
+
+    dtype_of(x, i).unwrap_or_else(|| panic!("cannot determine dtype for {}", x.op))
+}
+
+/// the positions of the (indices, values, dense shape) inputs of each SparseTensor that an op takes. Empty for ops without SparseTensor inputs.
+/// Ops that take only part of a triple, e.g. SparseReshape which has no values, are not included.
+pub fn sparse_inputs(op: &str) -> &'static [[usize; 3]] {
+    match op {
+        "SparseTensorDenseMatMul" | "SparseTensorDenseAdd" | "SparseReorder" | "SparseFillEmptyRows" | "SparseSoftmax" |
+        "SparseReduceSum" | "SparseReduceMax" | "SparseDenseCwiseAdd" | "SparseDenseCwiseMul" | "SparseDenseCwiseDiv" => &[[0, 1, 2]],
+        "SparseToDense" => &[[0, 2, 1]],
+        "SparseAdd" => &[[0, 1, 2], [3, 4, 5]],
+        _ => &[]
+    }
+}
+
+/// the positions of the inputs of an op that are components (flat values or row splits) of RaggedTensors. Ops with a variable number of
+/// row splits are resolved from the node. Empty for ops without ragged inputs.
+pub fn ragged_inputs(node: &NodeDef) -> Vec<usize> {
+    let n = node.input.iter().filter(|x| !x.starts_with('^')).count();
+    match &node.op[..] {
+        "RaggedTensorToSparse" | "RaggedTensorToVariant" => (0..n).collect(), // the nested splits, then the values
+        "RaggedGather" => (0..n.saturating_sub(1)).collect(), // the nested splits and the values of params, then the dense indices
+        "RaggedTensorToTensor" => std::iter::once(1).chain(3..n).collect(), // shape, values, default value, then the row partitions
+        "StringNGrams" | "RaggedBincount" => vec![0, 1],
+        _ => vec![]
+    }
+}
+
+/// a boolean `{prefix}/pred` on the first device that is true in every `period`-th step, counted by the aux variable `counter` (which
+/// starts from 0 and is incremented in every step before the check). Returns the nodes to add and the name of the predicate.
+fn periodic_predicate(target: &mut Target, prefix: &str, counter: &str, period: usize) -> (Vec<NodeDef>, String) {
+    let counter = target.create_aux_variable(counter, 0, DataType::DT_INT64, &[], 0.);
+    let device0 = target.devices[0].clone();
+    let int64 = AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT64));
+
+    let mut one = NodeDef::new();
+    one.name = format!("{}/one", prefix);
+    one.op = "Const".into();
+    one.device = device0.clone();
+    one.attr.insert("dtype".into(), int64.clone());
+    one.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| { t.set_dtype(DataType::DT_INT64); t.int64_val.push(1) }))));
+    let mut period_node = one.clone();
+    period_node.name = format!("{}/period", prefix);
+    period_node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| { t.set_dtype(DataType::DT_INT64); t.int64_val.push(period as _) }))));
+    let mut zero = one.clone();
+    zero.name = format!("{}/zero", prefix);
+    zero.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| { t.set_dtype(DataType::DT_INT64); t.int64_val.push(0) }))));
+
+    let mut increment = NodeDef::new();
+    increment.name = format!("{}/increment", prefix);
+    increment.op = "AssignAdd".into();
+    increment.device = device0.clone();
+    increment.input.push(counter);
+    increment.input.push(one.name.clone());
+    increment.attr.insert("T".into(), int64.clone());
+    increment.attr.insert("use_locking".into(), AttrValue::new().apply(|x| x.set_b(true)));
+
+    let mut modulo = NodeDef::new();
+    modulo.name = format!("{}/mod", prefix);
+    modulo.op = "FloorMod".into();
+    modulo.device = device0.clone();
+    modulo.input.push(increment.name.clone());
+    modulo.input.push(period_node.name.clone());
+    modulo.attr.insert("T".into(), int64.clone());
+
+    let mut pred = NodeDef::new();
+    pred.name = format!("{}/pred", prefix);
+    pred.op = "Equal".into();
+    pred.device = device0.clone();
+    pred.input.push(modulo.name.clone());
+    pred.input.push(zero.name.clone());
+    pred.attr.insert("T".into(), int64);
+    let pred_name = pred.name.clone();
+    (vec![one, period_node, zero, increment, modulo, pred], pred_name)
+}
+
+/// the index of the gradient and the indexes of the variable and optimizer states (which are put in the same group) of an optimizer op.
+/// The Resource* variants used by TF2 training functions have the same signatures.
+pub fn optimizer_inputs(op: &str) -> Option<(usize, &'static [usize])> {
+    match op {
+        "ApplyGradientDescent" | "ResourceApplyGradientDescent" => Some((2, &[0])),
+        "ApplyAdam" | "ResourceApplyAdam" => Some((9, &[0, 1, 2])),
+        "ApplyMomentum" | "ResourceApplyMomentum" | "ResourceApplyKerasMomentum" => Some((3, &[0, 1])),
+        "ApplyAdagradV2" | "ResourceApplyAdagradV2" => Some((4, &[0, 1])),
+        "ApplyRMSProp" | "ResourceApplyRMSProp" => Some((7, &[0, 1, 2])),
+        _ => None
+    }
+}
+
+/// the collective ops that accept a `timeout_seconds` attr (TF 2.4 and later), after which they fail with DeadlineExceeded
+const TIMED_COLLECTIVES: &[&str] = &["CollectiveReduce", "CollectiveGather", "CollectiveBcastSend", "CollectiveBcastRecv"];
+
+/// make the collectives fail after `seconds` instead of waiting forever for a hung or dead member, so the error names the instance that
+/// stalled. NCCL and Horovod ops cannot time out in the graph; they are counted in a warning pointing at the alternatives.
+fn set_collective_timeouts(target: &mut Target, seconds: f32) {
+    let mut timed = 0;
+    let mut untimed: BTreeMap<String, usize> = BTreeMap::new();
+    for node in target.pb.node.iter_mut() {
+        if TIMED_COLLECTIVES.contains(&&node.op[..]) {
+            node.attr.insert("timeout_seconds".into(), AttrValue::new().apply(|x| x.set_f(seconds)));
+            timed += 1
+        } else if node.op.starts_with("Nccl") || node.op.starts_with("Horovod") {
+            *untimed.entry(node.op.clone()).or_default() += 1
+        }
+    }
+    if timed > 0 {
+        info!("{} collective ops time out after {} seconds", timed, seconds)
+    }
+    if !untimed.is_empty() {
+        let ops: Vec<String> = untimed.iter().map(|(op, n)| format!("{} {}", n, op)).collect();
+        warn!("{} cannot time out. Use the collective backend, HOROVOD_STALL_SHUTDOWN_TIME_SECONDS for Horovod, or the timeout_in_ms of RunOptions", ops.join(", "))
+    }
+}
+
+/// a Const string node holding a JSON record of the compilation: `{"strategy": .., "plan_hash": .., "compiled_at": .., "tge_version": ..}`.
+/// The plan hash covers the names, ops, devices and inputs of all nodes (FNV-1a, stable across builds), so two compilations
+/// produce the same hash iff they emit the same graph structure. `compiled_at` is in seconds since the Unix epoch.
+fn add_audit_node(target: &mut Target, strategy_name: &str) {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |bytes: &[u8]| for b in bytes.iter().chain(&[0]) {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    };
+    for node in target.pb.node.iter() {
+        feed(node.name.as_bytes());
+        feed(node.op.as_bytes());
+        feed(node.device.as_bytes());
+        for input in node.input.iter() {
+            feed(input.as_bytes());
+        }
+    }
+
+    let compiled_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0);
+    let record = format!("{{\"strategy\": {:?}, \"plan_hash\": \"{:016x}\", \"compiled_at\": {}, \"tge_version\": {:?}}}", strategy_name, hash, compiled_at, env!("CARGO_PKG_VERSION"));
+    info!("audit record: {}", record);
+
+    let mut node = NodeDef::new();
+    node.name = Graph::AUDIT_NODE.into();
+    node.op = "Const".into();
+    node.device = target.devices[0].clone();
+    node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_STRING)));
+    node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| {
+        t.set_dtype(DataType::DT_STRING);
+        t.string_val.push(record.into_bytes());
+    }))));
+    target.pb.node.push(node)
+}
+
+/// how a tensor is converted between forms, see `plan_conversion`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conversion {
+    Identity, // the replicas themselves
+    Broadcast, // full to full: each destination uses the replica on the same device, or the first replica
+    Split, // full to part: split the first replica
+    Concat, // part to full of a batched tensor
+    AllGather, // part to full of a batched tensor on the same devices: every device concats the parts
+    Sum, // part to full of an unbatched tensor, which can only be a gradient inherited from a split parent
+    Resplit, // part to part. There is currently a hack in resplit that copies parts if the numbers match. Parts along different axes gather to one device first
+    AllToAll, // part to part on the same devices in a different number of parts: the pieces of each part go directly to the parts that cover them
+    ReduceScatter, // part to part of an unbatched tensor: the partial values are summed and each destination keeps its slice of the sum
+    ShapeTotal, // part to full of a shape tensor: the batch sizes of the parts are added up
+    ShapeSlice, // full to part of a shape tensor: the batch size is divided among the parts
+    SparseSplit, // full to part of a member of a SparseTensor: the rows are split and the indices shifted, for all three members at once
+    SparseConcat // part to full of a member of a SparseTensor: the parts are concatenated with their row indices offset, for all three members at once
+}
+
+/// decide the conversion from the form of a node to another form, given the flags of the tensor. Pure, so it can be checked without a graph.
+pub fn plan_conversion(from: &Form, to: &Form, flags: u8) -> Result<Conversion, String> {
+    if from == to {
+        return Ok(Conversion::Identity)
+    }
+    let is_shape = flags & Tensor::IS_SHAPE != 0;
+    let is_sparse = flags & Tensor::IS_SPARSE != 0;
+    if (is_shape || is_sparse) && from.axis + to.axis != 0 {
+        return Err("shape and sparse tensors can only be split along the batch".into())
+    }
+    Ok(match (to.kind, from.kind) {
+        (FormKind::Full, FormKind::Full) => Conversion::Broadcast,
+        _ if flags & Tensor::IS_INVARIANT != 0 => Conversion::Broadcast,
+        (FormKind::Full, FormKind::Part) if is_shape => Conversion::ShapeTotal,
+        (FormKind::Part, FormKind::Full) if is_shape => Conversion::ShapeSlice,
+        _ if is_shape => return Err("shape tensors cannot be resplit".into()),
+        (FormKind::Full, FormKind::Part) if is_sparse => Conversion::SparseConcat,
+        (FormKind::Part, FormKind::Full) if is_sparse => Conversion::SparseSplit,
+        _ if is_sparse => return Err("sparse tensors cannot be resplit".into()),
+        _ if flags & Tensor::IS_RAGGED != 0 => return Err("the components of RaggedTensors cannot be split or concatenated by rows".into()),
+        (FormKind::Part, FormKind::Full) => Conversion::Split,
+        (FormKind::Full, FormKind::Part) if (flags & Tensor::IS_BATCHED != 0 || from.axis != 0) && to.ndev() > 1 && to.devices == from.devices => Conversion::AllGather,
+        (FormKind::Full, FormKind::Part) if flags & Tensor::IS_BATCHED != 0 || from.axis != 0 => Conversion::Concat, // parts along other axes are slices, never partial sums
+        (FormKind::Full, FormKind::Part) => Conversion::Sum,
+        (FormKind::Part, FormKind::Part) if flags & Tensor::IS_BATCHED == 0 && from.axis == 0 => Conversion::ReduceScatter,
+        (FormKind::Part, FormKind::Part) if from.ndev() != to.ndev() && from.axis == to.axis && same_devices(from, to) => Conversion::AllToAll,
+        (FormKind::Part, FormKind::Part) => Conversion::Resplit
+    })
+}
+
+/// whether two forms use the same set of devices, regardless of the order and the number of replicas on each
+fn same_devices(a: &Form, b: &Form) -> bool {
+    a.devices.iter().collect::<BTreeSet<_>>() == b.devices.iter().collect::<BTreeSet<_>>()
+}
+
+/// how the replicas of a gradient are combined (option `reduction`). Other tensors that are summed across replicas, e.g. partial sums
+/// of a split parent (see `Conversion::Sum`), are always summed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reduction {
+    Sum,
+    Mean, // the sum divided by the number of replicas, so the learning rate does not depend on the replication
+    Max,
+    Min,
+    Prod
+}
+
+impl Reduction {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "sum" => Some(Reduction::Sum),
+            "mean" => Some(Reduction::Mean),
+            "max" => Some(Reduction::Max),
+            "min" => Some(Reduction::Min),
+            "prod" => Some(Reduction::Prod),
+            _ => None
+        }
+    }
+
+    /// the reduction of the gradients given by the option `reduction`, sum by default
+    pub fn of(options: &BTreeMap<String, String>) -> Self {
+        options.get("reduction").map(|x| Self::parse(x).unwrap_or_else(|| panic!("unknown reduction {}, expecting sum, mean, max, min or prod", x))).unwrap_or(Reduction::Sum)
+    }
+
+    /// the reduction that the aggregation methods emit: a mean is summed and divided afterwards (see `Tensor::finish_reduction`)
+    pub fn combining(self) -> Self {
+        match self {
+            Reduction::Mean => Reduction::Sum,
+            x => x
+        }
+    }
+
+    /// the binary op that combines two values, as in the ring all-reduce
+    fn binary_op(self) -> &'static str {
+        match self.combining() {
+            Reduction::Max => "Maximum",
+            Reduction::Min => "Minimum",
+            Reduction::Prod => "Mul",
+            _ => "Add"
+        }
+    }
+
+    /// the op that reduces a stack of values along the first dimension, None for sums which use AddN instead
+    fn stacked_op(self) -> Option<&'static str> {
+        match self.combining() {
+            Reduction::Max => Some("Max"),
+            Reduction::Min => Some("Min"),
+            Reduction::Prod => Some("Prod"),
+            _ => None
+        }
+    }
+
+    /// the `reduction` attr of NcclAllReduce
+    fn nccl_name(self) -> &'static str {
+        match self.combining() {
+            Reduction::Max => "max",
+            Reduction::Min => "min",
+            Reduction::Prod => "prod",
+            _ => "sum"
+        }
+    }
+
+    /// the `merge_op` attr of CollectiveReduce
+    fn merge_op(self) -> &'static str {
+        match self.combining() {
+            Reduction::Max => "Max",
+            Reduction::Min => "Min",
+            Reduction::Prod => "Mul",
+            _ => "Add"
+        }
+    }
+
+    /// the `reduce_op` attr of HorovodAllreduce
+    fn horovod_op(self) -> i64 {
+        match self.combining() {
+            Reduction::Max => 4,
+            Reduction::Min => 3,
+            Reduction::Prod => 5,
+            _ => 1
+        }
+    }
+}
+
+/// the per-device local step variable of the SyncReplicasOptimizer pattern, created on first use
+fn sync_local_step(target: &mut Target, device_id: usize) -> String {
+    let name = format!("sync_replicas/local_step_{}", device_id);
+    match target.aux_variables.iter().find(|x| x.name.ends_with(&name)) {
+        Some(x) => x.name.clone(),
+        None => target.create_aux_variable(&name, device_id, DataType::DT_INT64, &[], 0.)
+    }
+}
+
+/// the bits of a small non-negative integer as a float16, as TensorProto stores halves in `half_val`
+fn half_of_int(x: u64) -> i32 {
+    if x == 0 {
+        return 0
+    }
+    let exp = 63 - x.leading_zeros() as i32; // x = 1.m * 2^exp
+    let mantissa = if exp <= 10 { (x << (10 - exp)) & 0x3ff } else { (x >> (exp - 10)) & 0x3ff };
+    ((exp + 15) << 10) | mantissa as i32
+}
+
+/// the dtype of the i-th output of a NodeDef, using only its attrs
+pub fn dtype_of(x: &NodeDef, i: usize) -> Option<AttrValue> {
+    Some(match &x.op[..] {
+        "Greater" | "GreaterEqual" | "Less" | "LessEqual" | "Equal" | "NotEqual" | "LogicalAnd" | "LogicalOr" | "LogicalNot" |
+        "IsNan" | "IsInf" | "IsFinite" | "InTopK" | "InTopKV2" => AttrValue::new().apply(|x| x.set_field_type(DataType::DT_BOOL)),
+        "ArgMax" | "ArgMin" => x.attr.get("output_type").cloned().unwrap_or_else(|| AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT64))),
+        "Size" | "Rank" => x.attr.get("out_type").cloned().unwrap_or_else(|| AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32))),
         "Shape" | "ShapeN" => x.attr.get("out_type").cloned().unwrap_or_else(|| AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32))),
-        "Cast" => x.attr.get("DstT").cloned().unwrap(),
-        "IteratorGetNext" => AttrValue::new().apply(|v| v.set_field_type(x.attr.get("output_types").unwrap().get_list().get_field_type()[i])),
-        _ => x.attr.get("dtype").or_else(|| x.attr.get("T")).unwrap_or_else(|| panic!("cannot determine dtype for {}", x.op)).clone()
+        "Cast" => x.attr.get("DstT")?.clone(),
+        "IteratorGetNext" | "IteratorGetNextSync" | "MultiDeviceIteratorGetNextFromShard" | "OptionalGetValue" => {
+            let dtype = *x.attr.get("output_types")?.get_list().get_field_type().get(i)?;
+            AttrValue::new().apply(|v| v.set_field_type(dtype))
+        }
+        _ => x.attr.get("dtype").or_else(|| x.attr.get("T"))?.clone()
+    })
+}
+
+/// split `len` in proportion to `weights`, giving the remainder to the largest fractions (the first ones on ties)
+fn proportional_slices(len: usize, weights: &[u64]) -> Vec<usize> {
+    let total: u64 = weights.iter().sum();
+    let mut slices: Vec<usize> = weights.iter().map(|w| (len as u64 * w / total) as usize).collect();
+    let mut order: Vec<usize> = (0..weights.len()).collect();
+    order.sort_by_key(|i| std::cmp::Reverse(len as u64 * weights[*i] % total));
+    for i in order.into_iter().take(len - slices.iter().sum::<usize>()) {
+        slices[i] += 1
     }
+    slices
 }
 
-fn parse_input(x: &str) -> (&str, usize) {
-    match x.find(':') {
-        Some(i) => (&x[..i], x[i+1..].parse().unwrap()),
-        None => (x, 0)
+/// bytes per element, None for variable-length types (strings, resources and variants)
+pub fn dtype_size(dtype: DataType) -> Option<u64> {
+    use DataType::*;
+    Some(match dtype {
+        DT_BOOL | DT_INT8 | DT_UINT8 | DT_QINT8 | DT_QUINT8 => 1,
+        DT_HALF | DT_BFLOAT16 | DT_INT16 | DT_UINT16 | DT_QINT16 | DT_QUINT16 => 2,
+        DT_FLOAT | DT_INT32 | DT_UINT32 | DT_QINT32 => 4,
+        DT_DOUBLE | DT_INT64 | DT_UINT64 | DT_COMPLEX64 => 8,
+        DT_COMPLEX128 => 16,
+        _ => return None
+    })
+}
+
+/// the elements of an integer TensorProto, from whichever field holds them. A single value stands for all elements as in TF.
+fn tensor_ints(tensor: &crate::proto::tensor::TensorProto) -> Option<Vec<i64>> {
+    let len = tensor.get_tensor_shape().dim.iter().map(|x| x.size.max(0) as usize).product::<usize>();
+    let mut values: Vec<i64> = match tensor.dtype {
+        DataType::DT_INT32 if !tensor.tensor_content.is_empty() => tensor.tensor_content.chunks(4).map(|x| i32::from_le_bytes(x.try_into().unwrap()) as _).collect(),
+        DataType::DT_INT64 if !tensor.tensor_content.is_empty() => tensor.tensor_content.chunks(8).map(|x| i64::from_le_bytes(x.try_into().unwrap())).collect(),
+        DataType::DT_INT32 => tensor.int_val.iter().map(|x| *x as _).collect(),
+        DataType::DT_INT64 => tensor.int64_val.to_vec(),
+        _ => return None
+    };
+    if values.len() == 1 && len > 1 {
+        values = vec![values[0]; len]
+    }
+    Some(values)
+}
+
+/// the parent of position `r` (1-based) in the in-order binary tree over 1..=m, whose leaves are the odd positions and whose root is the
+/// largest power of two not above m. Ancestors beyond m are skipped. None for the root.
+fn btree_parent(mut r: usize, m: usize) -> Option<usize> {
+    if r == 1 << (usize::BITS - 1 - m.leading_zeros()) {
+        return None
+    }
+    loop {
+        let low = r & r.wrapping_neg();
+        let parent = (r | (low << 1)) & !low;
+        if parent <= m {
+            return Some(parent)
+        }
+        r = parent
     }
 }
 
+/// a name component that Horovod ranks agree on: the tensor name with scopes and output indices flattened
+fn horovod_name(x: &str) -> String {
+    x.replace(['/', ':'], "_")
+}
+
 // TODO: use task id?
 fn task_name(x: &str) -> String {
     let p = x.rfind('/').expect("unrecognized device name");
     x[..p].to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn form(code: &str) -> Form {
+        Form::from_code(code)
+    }
+
+    #[test]
+    fn plan_conversion_of_plain_tensors() {
+        let batched = Tensor::IS_BATCHED;
+        assert_eq!(plan_conversion(&form("part_0_1"), &form("part_0_1"), batched), Ok(Conversion::Identity));
+        assert_eq!(plan_conversion(&form("full_0"), &form("full_0_1"), 0), Ok(Conversion::Broadcast));
+        assert_eq!(plan_conversion(&form("full_0"), &form("part_0_1"), batched), Ok(Conversion::Split));
+        assert_eq!(plan_conversion(&form("part_0_1"), &form("full_0_1"), batched), Ok(Conversion::AllGather));
+        assert_eq!(plan_conversion(&form("part_0_1"), &form("full_2"), batched), Ok(Conversion::Concat));
+        assert_eq!(plan_conversion(&form("part_0_1"), &form("full_0_1"), 0), Ok(Conversion::Sum));
+        assert_eq!(plan_conversion(&form("part1_0_1"), &form("full_2"), 0), Ok(Conversion::Concat));
+        assert_eq!(plan_conversion(&form("part_0_1"), &form("part_0_0_1_1"), batched), Ok(Conversion::AllToAll));
+        assert_eq!(plan_conversion(&form("part_0_1"), &form("part_0_1_2"), 0), Ok(Conversion::ReduceScatter));
+        assert_eq!(plan_conversion(&form("part_0_1"), &form("part1_2_3"), 0), Ok(Conversion::ReduceScatter));
+        assert_eq!(plan_conversion(&form("part1_0_1"), &form("part_0_1"), 0), Ok(Conversion::Resplit)); // slices, not partial values
+        assert_eq!(plan_conversion(&form("part_0_1"), &form("part_2_3"), batched), Ok(Conversion::Resplit));
+        assert_eq!(plan_conversion(&form("part_0_1"), &form("part1_0_1"), batched), Ok(Conversion::Resplit));
+        assert_eq!(plan_conversion(&form("part_0_1"), &form("full_0_1"), batched | Tensor::IS_INVARIANT), Ok(Conversion::Broadcast));
+    }
+
+    #[test]
+    fn backup_accumulators_run_on_host_cpus() {
+        let nodes = testing::linear_model(8, 4, 2);
+        let strategy = testing::replicate_all(&nodes, &[0, 1], 0);
+        let target = testing::compile(&nodes, testing::fake_target(2, 1000), &strategy, &[("backup_required", "1")]);
+        testing::assert_op_count(&target, "ConditionalAccumulator", 2);
+        testing::assert_cpu_only_ops_on_cpus(&target);
+    }
+
+    #[test]
+    fn sync_replicas_waits_for_the_updates_behind_casts() {
+        let nodes = testing::linear_model(8, 4, 2);
+        let strategy = testing::replicate_all(&nodes, &[0, 1], 4);
+        let target = testing::compile(&nodes, testing::fake_target(2, 1000), &strategy, &[("compression", "fp16")]);
+        let increment = target.pb.node.iter().find(|x| x.name == "tge_sync_replicas/update_global_step").unwrap();
+        let applies: Vec<_> = target.pb.node.iter().filter(|x| x.op == "ApplyGradientDescent").collect();
+        assert!(!applies.is_empty());
+        for apply in applies {
+            assert!(increment.input.contains(&format!("^{}", apply.name)), "the global step does not wait for {}", apply.name)
+        }
+        testing::assert_cpu_only_ops_on_cpus(&target);
+    }
+
+    #[test]
+    fn subgraph_cache_does_not_replay_collectives() {
+        let nodes = testing::linear_model(8, 4, 2);
+        let strategy = testing::replicate_all(&nodes, &[0, 1], 1);
+        let mut graph = Graph::new(&nodes);
+        for (name, value) in [("subgraph_cache", "True"), ("collective_backend", "collective"), ("unsplit_nodes", "loss/Square dense/BiasAdd")] {
+            graph.options.insert(name.into(), value.into());
+        }
+        let compile = |graph: &mut Graph| {
+            let mut target = testing::fake_target(2, 1000);
+            crate::editor::reset(graph);
+            crate::editor::edit(graph, &mut target, &strategy.iter().map(|(name, decision)| (&name[..], decision.clone())).collect());
+            crate::batch::compile_checked(graph, &mut target).unwrap();
+            let mut gathers: Vec<_> = target.pb.node.iter().filter(|x| x.op == "CollectiveGather").map(|x| (x.name.clone(), x.input.to_vec())).collect();
+            gathers.sort();
+            gathers
+        };
+        let first = compile(&mut graph);
+        assert!(first.len() >= 4, "expected two gathers on each device, found {:?}", first);
+        assert_eq!(compile(&mut graph), first);
+    }
+
+    #[test]
+    fn only_initializing_assigns_are_initializers() {
+        let mut nodes = testing::linear_model(8, 4, 2);
+        let var = nodes.iter().find(|x| x.name == "dense/kernel").unwrap().clone();
+        let assign = nodes.iter().find(|x| x.name == "dense/kernel/Assign").unwrap().clone();
+        nodes.push(var.clone().apply(|x| x.name = "ema".into()));
+        nodes.push(assign.clone().apply(|x| {
+            x.name = "ema/Assign".into();
+            x.input[0] = "ema".into();
+            x.input[1] = "dense/kernel".into(); // a copy of another variable, run at every step
+        }));
+        nodes.push(var.apply(|x| x.name = "copy".into()));
+        nodes.push(assign.apply(|x| {
+            x.name = "copy/Assign".into();
+            x.input[0] = "copy".into();
+            x.input[1] = "dense/kernel".into();
+        }));
+        let mut init = NodeDef::new();
+        init.name = Graph::USER_INIT_OP.into();
+        init.op = "NoOp".into();
+        init.input.push("^copy/Assign".into());
+        nodes.push(init);
+
+        let graph = Graph::new(&nodes);
+        let assigns: BTreeSet<&str> = graph.init_assigns.iter().map(|x| &graph.nodes[*x].raw_node.name[..]).collect();
+        assert_eq!(assigns, ["copy/Assign", "dense/bias/Assign", "dense/kernel/Assign"].iter().copied().collect());
+    }
+
+    #[test]
+    fn async_barriers_run_on_host_cpus() {
+        let nodes = testing::linear_model(8, 4, 2);
+        let mut strategy = testing::replicate_all(&nodes, &[0, 1], 0);
+        for node in nodes.iter().filter(|x| x.op == "VariableV2") {
+            strategy.insert(node.name.clone(), (vec![0], 0)); // the gradients are applied asynchronously only to variables with a single copy
+        }
+        let target = testing::compile(&nodes, testing::fake_target(2, 1000), &strategy, &[("async_sgd", "True"), ("async_sync_period", "2"), ("placeholder_mode", "split")]);
+        testing::assert_op_count(&target, "FIFOQueueV2", 2);
+        testing::assert_cpu_only_ops_on_cpus(&target);
+    }
+
+    #[test]
+    fn plan_conversion_of_shape_sparse_and_ragged_tensors() {
+        let shape = Tensor::IS_SHAPE;
+        let sparse = Tensor::IS_SPARSE;
+        assert_eq!(plan_conversion(&form("part_0_1"), &form("full_0"), shape), Ok(Conversion::ShapeTotal));
+        assert_eq!(plan_conversion(&form("full_0"), &form("part_0_1"), shape), Ok(Conversion::ShapeSlice));
+        assert!(plan_conversion(&form("part_0_1"), &form("part_2_3"), shape).is_err());
+        assert!(plan_conversion(&form("full_0"), &form("part1_0_1"), shape).is_err());
+        assert_eq!(plan_conversion(&form("part_0_1"), &form("full_0"), sparse), Ok(Conversion::SparseConcat));
+        assert_eq!(plan_conversion(&form("full_0"), &form("part_0_1"), sparse), Ok(Conversion::SparseSplit));
+        assert!(plan_conversion(&form("part_0_1"), &form("part_0_1_2"), sparse).is_err());
+        assert!(plan_conversion(&form("full_0"), &form("part_0_1"), Tensor::IS_RAGGED).is_err());
+        assert_eq!(plan_conversion(&form("full_0"), &form("full_1"), Tensor::IS_RAGGED), Ok(Conversion::Broadcast));
+    }
+
+    #[test]
+    fn column_split_matmul_gathers_along_the_columns() {
+        let nodes = testing::linear_model(8, 4, 2);
+        let strategy = testing::replicate_all(&nodes, &[0], 0);
+        let target = testing::compile(&nodes, testing::fake_target(2, 1000), &strategy, &[("split_forms", "dense/MatMul:part1_0_1")]);
+        testing::assert_devices(&target, "dense/MatMul", &[0, 1]);
+        testing::assert_devices(&target, "dense/BiasAdd", &[0]);
+        let axis_of = |suffix: &str| target.pb.node.iter().find(|x| x.name.ends_with(suffix)).unwrap_or_else(|| panic!("no {} in the compiled graph", suffix)).attr["value"].get_tensor().int_val.to_vec();
+        assert_eq!(axis_of("aux_split/dim"), [1]); // the kernel is split by columns
+        assert_eq!(axis_of("aux_concat/axis"), [1]); // and the product is gathered by columns
+        let matmul = target.pb.node.iter().find(|x| x.op == "MatMul" && x.device == target.devices[1] && crate::naming::owner_of(x) == "dense/MatMul").unwrap();
+        assert_eq!(matmul.attr["_tge_input_sizes"].get_list().i[..], [8 * 4 * 4, 4 * 2 * 4 / 2], "every replica takes the whole batch and half of the kernel");
+    }
+
+    #[test]
+    fn strings_and_unknown_dtypes_are_reduced_as_is() {
+        let node = |name: &str, dtype: Option<DataType>| NodeDef::new().apply(|x| {
+            x.name = name.into();
+            x.op = "Placeholder".into();
+            if let Some(dtype) = dtype {
+                x.attr.insert("dtype".into(), AttrValue::new().apply(|v| v.set_field_type(dtype)));
+            }
+        });
+        let mut graph = Graph::new(&[node("words", Some(DataType::DT_STRING)), node("unknown", None), node("flags", Some(DataType::DT_BOOL))]);
+        assert_eq!(graph.nodes[0].get_output(0).reduction_dtype(), None);
+        assert_eq!(graph.nodes[1].get_output(0).reduction_dtype(), None);
+        assert_eq!(graph.nodes[2].get_output(0).reduction_dtype(), Some(DataType::DT_INT32));
+    }
+
+    #[test]
+    fn lone_fused_gradients_are_still_compressed() {
+        let nodes = testing::linear_model(8, 4, 2);
+        let strategy = testing::replicate_all(&nodes, &[0, 1], 2);
+        // the kernel gradient (32 bytes) and the bias gradient (8 bytes) are both small, but do not fit in one bucket
+        let target = testing::compile(&nodes, testing::fake_target(2, 1000), &strategy, &[("compression", "fp16"), ("fusion_bucket_size", "33")]);
+        let casts: Vec<_> = target.pb.node.iter().filter(|x| x.op == "Cast" && x.attr["DstT"].get_field_type() == DataType::DT_HALF).collect();
+        assert_eq!(casts.len(), 4, "expected each replica of both gradients to be casted to half, found {:?}", casts.iter().map(|x| &x.name).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn subgraph_cache_splits_sparse_tensors_once() {
+        let nodes = testing::sparse_model(8, 4, 2, 16);
+        let strategy = testing::replicate_all(&nodes, &[0, 1], 0);
+        let mut graph = Graph::new(&nodes);
+        graph.options.insert("subgraph_cache".into(), "True".into());
+        for _ in 0..2 {
+            let mut target = testing::fake_target(2, 1000);
+            crate::editor::reset(&mut graph);
+            crate::editor::edit(&mut graph, &mut target, &strategy.iter().map(|(name, decision)| (&name[..], decision.clone())).collect());
+            crate::batch::compile_checked(&mut graph, &mut target).unwrap();
+            testing::assert_op_count(&target, "SparseSplit", 1);
+        }
+    }
+
+    #[test]
+    fn nccl_broadcast_falls_back_to_a_relay_across_machines() {
+        let nodes = testing::linear_model(8, 4, 2);
+        let strategy = testing::replicate_all(&nodes, &[0, 1, 2, 3], 0);
+        let single = testing::compile(&nodes, testing::fake_target(4, 1000), &strategy, &[("broadcast_op", "True"), ("broadcast_method", "nccl")]);
+        testing::assert_op_count(&single, "NcclBroadcast", 2);
+
+        let mut target = testing::fake_target(4, 1000);
+        for i in 2..4 {
+            target.devices[i] = format!("/job:worker/replica:0/task:1/device:GPU:{}", i - 2)
+        }
+        let multi = testing::compile(&nodes, target, &strategy, &[("broadcast_op", "True"), ("broadcast_method", "nccl")]);
+        testing::assert_op_count(&multi, "NcclBroadcast", 0);
+        let relays = multi.pb.node.iter().filter(|x| x.name.starts_with("dense/kernel/aux_broadcast/relay_")).count();
+        assert_eq!(relays, 3, "expected the kernel to be relayed to the three other replicas");
+    }
+
+    #[test]
+    fn replicas_follow_the_replica_format() {
+        let nodes = testing::linear_model(8, 4, 2);
+        let strategy = testing::replicate_all(&nodes, &[0, 1], 0);
+        let target = testing::compile(&nodes, testing::fake_target(2, 1000), &strategy, &[("replica_format", "{name}.r{index}")]);
+        assert!(target.pb.node.iter().any(|x| x.name == "dense/MatMul.r1"), "the replicas are not named by the replica format");
+        assert!(target.pb.node.iter().all(|x| !x.name.ends_with("/replica_1")));
+    }
+}
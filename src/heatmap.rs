@@ -0,0 +1,108 @@
+//! Where the compute of a compiled graph landed: one row per original op (or per name scope), one column per device, and the profiled
+//! time spent there in each cell. The nodes created by the compiler are attributed to the op they belong to, so the aggregation and
+//! conversion of a gradient show up in the row of that gradient. Exported as JSON for scripts and as a self-contained HTML table for people.
+
+use oh_my_rust::*;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::misc::{Target, Profiler};
+use crate::naming::owner_of;
+
+#[derive(Debug, Clone)]
+pub struct HeatmapRow {
+    pub name: String,
+    pub ops: BTreeSet<String>, // the op types of the nodes in the row
+    pub time: Vec<u64>, // the profiled time on each device
+    pub nodes: Vec<usize> // the number of compiled nodes on each device
+}
+
+#[derive(Debug, Clone)]
+pub struct Heatmap {
+    pub devices: Vec<String>,
+    pub rows: Vec<HeatmapRow> // sorted by name, so the ops of a layer are adjacent
+}
+
+impl Heatmap {
+    /// build the heatmap of an edited target. With `scope_depth`, nodes are grouped by the first `scope_depth` components of the name of
+    /// the op they belong to (e.g. 2 gives `model/dense_1`), which is usually what a layer is. Nodes on unknown devices are skipped.
+    pub fn new(target: &Target, profiler: &impl Profiler, scope_depth: Option<usize>) -> Self {
+        let ndev = target.ndev();
+        let device_ids: BTreeMap<&str, usize> = target.devices.iter().enumerate().map(|(i, x)| (&x[..], i)).collect();
+
+        let mut rows: BTreeMap<String, HeatmapRow> = BTreeMap::new();
+        for node in target.pb.node.iter() {
+            let device_id = match device_ids.get(&node.device[..]) {
+                Some(x) => *x,
+                None => continue
+            };
+            let owner = owner_of(node);
+            let name = match scope_depth {
+                Some(depth) => owner.split('/').take(depth.max(1)).collect::<Vec<_>>().join("/"),
+                None => owner
+            };
+            let row = rows.entry(name.clone()).or_insert_with(|| HeatmapRow { name, ops: BTreeSet::new(), time: vec![0; ndev], nodes: vec![0; ndev] });
+            row.ops.insert(node.op.clone());
+            row.time[device_id] += profiler.profile(node, device_id).unwrap_or(0);
+            row.nodes[device_id] += 1;
+        }
+
+        debug!("placement heatmap of {} rows from {} nodes", rows.len(), target.pb.node.len());
+        Heatmap { devices: target.devices.to_vec(), rows: rows.into_values().collect() }
+    }
+
+    /// the total profiled time on each device
+    pub fn totals(&self) -> Vec<u64> {
+        (0..self.devices.len()).map(|i| self.rows.iter().map(|x| x.time[i]).sum()).collect()
+    }
+
+    pub fn write_json<W: std::io::Write>(&self, out: &mut W) -> std::io::Result<()> {
+        let list = |x: &mut dyn Iterator<Item=String>| x.collect::<Vec<_>>().join(", ");
+        writeln!(out, "{{ \"devices\": [{}],", list(&mut self.devices.iter().map(|x| format!("\"{}\"", x))))?;
+        writeln!(out, "  \"totals\": [{}],", list(&mut self.totals().iter().map(|x| x.to_string())))?;
+        write!(out, "  \"rows\": [")?;
+        for (i, row) in self.rows.iter().enumerate() {
+            if i != 0 {
+                write!(out, ",")?;
+            }
+            write!(out, "\n    {{ \"name\": \"{}\", \"ops\": [{}], \"time\": [{}], \"nodes\": [{}] }}", row.name,
+                list(&mut row.ops.iter().map(|x| format!("\"{}\"", x))), list(&mut row.time.iter().map(|x| x.to_string())), list(&mut row.nodes.iter().map(|x| x.to_string())))?;
+        }
+        writeln!(out, "\n  ]\n}}")
+    }
+
+    /// a table whose cells are shaded by their share of the most expensive cell. Cells without nodes are blank. The tooltips list the op
+    /// types of each row and the node count of each cell.
+    pub fn write_html<W: std::io::Write>(&self, out: &mut W) -> std::io::Result<()> {
+        let max = self.rows.iter().flat_map(|x| x.time.iter()).copied().max().unwrap_or(0).max(1) as f64;
+
+        writeln!(out, "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>placement heatmap</title><style>")?;
+        writeln!(out, "table {{ border-collapse: collapse; font: 12px monospace; }} td, th {{ border: 1px solid #ddd; padding: 2px 6px; text-align: right; }}")?;
+        writeln!(out, "th {{ position: sticky; top: 0; background: white; }} td:first-child {{ text-align: left; }}")?;
+        writeln!(out, "</style></head><body><table>")?;
+        write!(out, "<tr><th>op</th>")?;
+        for device in self.devices.iter() {
+            write!(out, "<th>{}</th>", device)?;
+        }
+        writeln!(out, "</tr>")?;
+
+        write!(out, "<tr><td><b>total</b></td>")?;
+        for total in self.totals() {
+            write!(out, "<td><b>{}</b></td>", total)?;
+        }
+        writeln!(out, "</tr>")?;
+
+        let ops = |row: &HeatmapRow| row.ops.iter().cloned().collect::<Vec<_>>().join(" ");
+        for row in self.rows.iter() {
+            write!(out, "<tr><td title=\"{}\">{}</td>", ops(row), row.name)?;
+            for (time, nodes) in row.time.iter().zip(row.nodes.iter()) {
+                if *nodes == 0 {
+                    write!(out, "<td></td>")?;
+                } else {
+                    write!(out, "<td style=\"background: rgba(70, 130, 180, {:.3})\" title=\"{} nodes\">{}</td>", *time as f64 / max, nodes, time)?;
+                }
+            }
+            writeln!(out, "</tr>")?;
+        }
+
+        writeln!(out, "</table></body></html>")
+    }
+}
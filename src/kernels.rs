@@ -0,0 +1,132 @@
+use oh_my_rust::*;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::graph::Graph;
+use crate::misc::Target;
+use crate::naming::owner_of;
+
+/// ops that TF registers no GPU kernel for (as of 1.15, see the REGISTER_KERNEL_BUILDER calls under tensorflow/core/kernels).
+/// Placing them on a GPU fails at session creation unless soft placement is on. Ops missing here are assumed to have both kernels.
+pub const CPU_ONLY_OPS: &[&str] = &[
+    // strings
+    "AsString", "DecodeBase64", "EncodeBase64", "ReduceJoin", "RegexFullMatch", "RegexReplace", "StaticRegexReplace", "StringFormat",
+    "StringJoin", "StringLength", "StringLower", "StringUpper", "StringSplit", "StringSplitV2", "StringStrip", "StringToHashBucket",
+    "StringToHashBucketFast", "StringToHashBucketStrong", "StringToNumber", "Substr", "UnicodeDecode", "UnicodeEncode",
+    // parsing and decoding
+    "DecodeCSV", "DecodeRaw", "DecodeJpeg", "DecodePng", "DecodeGif", "DecodeBmp", "DecodeAndCropJpeg", "DecodeImage", "EncodeJpeg",
+    "EncodePng", "ParseExample", "ParseExampleV2", "ParseSingleExample", "ParseSequenceExample", "ParseSingleSequenceExample",
+    "ParseTensor", "SerializeTensor", "ReadFile", "WriteFile", "MatchingFiles",
+    // sparse
+    "SparseAdd", "SparseConcat", "SparseReorder", "SparseReshape", "SparseSlice", "SparseSplit", "SparseToSparseSetOperation",
+    "SparseFillEmptyRows", "SparseFillEmptyRowsGrad", "SparseCross", "SparseTensorSliceDataset", "DeserializeSparse", "SerializeSparse",
+    // lookup tables
+    "HashTable", "HashTableV2", "MutableHashTable", "MutableHashTableV2", "MutableDenseHashTableV2", "LookupTableFind", "LookupTableFindV2",
+    "LookupTableInsertV2", "LookupTableImportV2", "LookupTableExportV2", "LookupTableSizeV2", "InitializeTable", "InitializeTableV2",
+    "InitializeTableFromTextFile", "InitializeTableFromTextFileV2",
+    // checkpoints, summaries and host callbacks
+    "Save", "SaveV2", "SaveSlices", "Restore", "RestoreV2", "RestoreSlice", "MergeV2Checkpoints", "ShardedFilename", "ShardedFilespec",
+    "ScalarSummary", "HistogramSummary", "ImageSummary", "AudioSummaryV2", "TensorSummaryV2", "MergeSummary", "WriteScalarSummary",
+    "WriteHistogramSummary", "WriteImageSummary", "WriteAudioSummary", "WriteSummary", "FlushSummaryWriter", "CreateSummaryFileWriter",
+    "PyFunc", "PyFuncStateless", "EagerPyFunc", "Print", "PrintV2",
+    // input pipelines
+    "MakeIterator", "IteratorV2", "AnonymousIteratorV2", "IteratorToStringHandle", "IteratorFromStringHandleV2", "DeserializeIterator",
+    "SerializeIterator", "TFRecordReaderV2", "TextLineReaderV2", "ReaderReadV2",
+    // queues and accumulators
+    "FIFOQueue", "FIFOQueueV2", "RandomShuffleQueueV2", "PaddingFIFOQueueV2", "QueueEnqueueV2", "QueueEnqueueManyV2", "QueueDequeueV2",
+    "QueueDequeueManyV2", "QueueCloseV2", "QueueSizeV2", "ConditionalAccumulator", "AccumulatorApplyGradient", "AccumulatorTakeGradient",
+    "AccumulatorNumAccumulated", "AccumulatorSetGlobalStep",
+    // quantization
+    "QuantizeV2", "Dequantize"
+];
+
+/// which ops can only run on CPUs: `CPU_ONLY_OPS` adjusted by the option `kernel_overrides`, a comma-separated list of `<op>=cpu`
+/// (the op has no GPU kernel, e.g. a custom op built without CUDA) and `<op>=any` (the op has a GPU kernel despite the table)
+#[derive(Debug, Clone)]
+pub struct KernelTable {
+    pub cpu_only: BTreeSet<String>
+}
+
+impl KernelTable {
+    pub fn from_options(options: &BTreeMap<String, String>) -> Self {
+        let mut cpu_only: BTreeSet<String> = CPU_ONLY_OPS.iter().map(|x| x.to_string()).collect();
+        for entry in options.get("kernel_overrides").iter().flat_map(|x| x.split(',')).map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            match entry.split_once('=') {
+                Some((op, "cpu")) => { cpu_only.insert(op.to_string()); }
+                Some((op, "any")) => { cpu_only.remove(op); }
+                _ => panic!("malformed kernel override `{}`, expecting `<op>=cpu` or `<op>=any`", entry)
+            }
+        }
+        KernelTable { cpu_only }
+    }
+
+    pub fn has_gpu_kernel(&self, op: &str) -> bool {
+        !self.cpu_only.contains(op)
+    }
+}
+
+pub fn is_gpu(device: &str) -> bool {
+    device.contains("GPU") || device.contains("gpu")
+}
+
+/// reject placements that put an op without a GPU kernel on a GPU. Must be called after editing.
+/// With the option `soft_placement`, which should match `allow_soft_placement` of the session, they are only reported.
+pub fn check_placement(graph: &Graph, target: &Target) -> Result<(), String> {
+    let soft = graph.options.get("soft_placement").map(|x| x == "True").unwrap_or(false);
+    let table = KernelTable::from_options(&graph.options);
+    let violations: Vec<String> = graph.nodes.iter().filter(|node| !table.has_gpu_kernel(&node.raw_node.op)).filter_map(|node| {
+        let gpus: Vec<&str> = node.form.devices.iter().map(|d| &target.devices[*d][..]).filter(|x| is_gpu(x)).collect();
+        if gpus.is_empty() {
+            None
+        } else {
+            Some(format!("{} ({}) on {}", graph.describe(&node.raw_node.name), node.raw_node.op, gpus.join(",")))
+        }
+    }).collect();
+
+    if violations.is_empty() {
+        return Ok(())
+    }
+    for violation in violations.iter() {
+        warn!("no GPU kernel: {}", violation)
+    }
+    if soft {
+        return Ok(())
+    }
+    Err(format!("{} nodes without GPU kernels are placed on GPUs, e.g. {}", violations.len(), violations[0]))
+}
+
+/// a compiled node that TF would move to the CPU of its task under `allow_soft_placement`
+#[derive(Debug, Clone)]
+pub struct Relocation {
+    pub node: String,
+    pub op: String,
+    pub from: String,
+    pub to: String,
+    pub reason: String, // `no GPU kernel` or `colocated with <node>`
+    pub owner: String // the op of the input graph that the node comes from, see `naming::owner_of`
+}
+
+/// predict which nodes of the compiled graph TF would relocate when soft placement is on: nodes without GPU kernels on GPUs, and the other
+/// members of their colocation groups (the `loc:@` entries of `_class`, if not removed), which TF keeps on the same device.
+/// Aux nodes created by the compiler are included, so the report reflects what will actually run where.
+pub fn predict_soft_placement(target: &Target, table: &KernelTable) -> Vec<Relocation> {
+    let cpu_of = |device: &str| format!("{}/device:CPU:0", &device[..device.rfind('/').unwrap_or(0)]);
+    let group_of = |node: &crate::proto::node_def::NodeDef| node.attr.get("_class").and_then(|x| {
+        x.get_list().s.iter().filter_map(|x| std::str::from_utf8(x).ok()?.strip_prefix("loc:@")).next().map(|x| x.to_string())
+    }).unwrap_or_else(|| node.name.clone());
+
+    let mut relocated_groups: BTreeMap<String, String> = BTreeMap::new(); // colocation group => the node that forces the relocation
+    let mut result = vec![];
+    for node in target.pb.node.iter().filter(|x| is_gpu(&x.device) && !table.has_gpu_kernel(&x.op)) {
+        relocated_groups.entry(group_of(node)).or_insert_with(|| node.name.clone());
+        result.push(Relocation { node: node.name.clone(), op: node.op.clone(), from: node.device.clone(), to: cpu_of(&node.device), reason: "no GPU kernel".into(), owner: owner_of(node) })
+    }
+    for node in target.pb.node.iter().filter(|x| is_gpu(&x.device) && table.has_gpu_kernel(&x.op)) {
+        if let Some(cause) = relocated_groups.get(&group_of(node)) {
+            result.push(Relocation {
+                node: node.name.clone(), op: node.op.clone(), from: node.device.clone(), to: cpu_of(&node.device), reason: format!("colocated with {}", cause), owner: owner_of(node)
+            })
+        }
+    }
+
+    info!("soft placement would relocate {} nodes", result.len());
+    result
+}
@@ -0,0 +1,81 @@
+use oh_my_rust::*;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::proto::attr_value::AttrValue_oneof_value;
+use crate::proto::function::FunctionDefLibrary;
+use crate::proto::node_def::NodeDef;
+
+/// the functions a node refers to: its op (if it names a function) and the function-valued attrs, e.g. `f` of PartitionedCall
+/// or `then_branch` of If. Names that are not in the library are included as well, so they can be reported.
+pub fn referenced_functions(node: &NodeDef) -> Vec<String> {
+    let mut names = vec![node.op.clone()];
+    for attr in node.attr.values() {
+        match &attr.value {
+            Some(AttrValue_oneof_value::func(f)) => names.push(f.name.clone()),
+            Some(AttrValue_oneof_value::list(list)) => names.extend(list.func.iter().map(|f| f.name.clone())),
+            _ => {}
+        }
+    }
+    names
+}
+
+/// the subset of `library` needed by `nodes`: the functions they refer to, transitively through function bodies,
+/// and the gradient functions registered for them
+pub fn required(library: &FunctionDefLibrary, nodes: &[NodeDef]) -> FunctionDefLibrary {
+    let functions: BTreeMap<&str, usize> = library.function.iter().enumerate().map(|(i, x)| (&x.get_signature().name[..], i)).collect();
+    let gradients: BTreeMap<&str, &str> = library.gradient.iter().map(|x| (&x.function_name[..], &x.gradient_func[..])).collect();
+
+    let mut keep = BTreeSet::new();
+    let mut queue: Vec<String> = nodes.iter().flat_map(referenced_functions).collect();
+    while let Some(name) = queue.pop() {
+        let id = match functions.get(&name[..]) {
+            Some(x) => *x,
+            None => continue
+        };
+        if keep.insert(id) {
+            queue.extend(library.function[id].node_def.iter().flat_map(referenced_functions));
+            queue.extend(gradients.get(&name[..]).map(|x| x.to_string()))
+        }
+    }
+
+    let mut result = FunctionDefLibrary::new();
+    for id in keep {
+        result.function.push(library.function[id].clone())
+    }
+    let names: BTreeSet<&str> = result.function.iter().map(|x| &x.get_signature().name[..]).collect();
+    for gradient in library.gradient.iter().filter(|x| names.contains(&x.function_name[..])) {
+        result.gradient.push(gradient.clone())
+    }
+    result
+}
+
+/// add the functions and gradients of `from` that `into` does not have. A function or gradient defined differently in both is an error.
+pub fn merge(into: &mut FunctionDefLibrary, from: &FunctionDefLibrary) -> Result<(), String> {
+    for function in from.function.iter() {
+        let name = &function.get_signature().name;
+        match into.function.iter().find(|x| &x.get_signature().name == name) {
+            Some(existing) if existing != function => return Err(format!("function {} is defined differently in the target graph", name)),
+            Some(_) => {}
+            None => into.function.push(function.clone())
+        }
+    }
+    for gradient in from.gradient.iter() {
+        match into.gradient.iter().find(|x| x.function_name == gradient.function_name) {
+            Some(existing) if existing.gradient_func != gradient.gradient_func => {
+                return Err(format!("function {} has different gradients {} and {}", gradient.function_name, existing.gradient_func, gradient.gradient_func))
+            }
+            Some(_) => {}
+            None => into.gradient.push(gradient.clone())
+        }
+    }
+    Ok(())
+}
+
+/// warn about function-valued attrs of `nodes` that name functions missing from the library. Ops are not checked as most of them are builtin.
+pub fn check(library: &FunctionDefLibrary, nodes: &[NodeDef]) {
+    let functions: BTreeSet<&str> = library.function.iter().map(|x| &x.get_signature().name[..]).collect();
+    for node in nodes.iter() {
+        for name in referenced_functions(node).iter().skip(1).filter(|x| !functions.contains(&x[..])) {
+            warn!("{} ({}) refers to function {} which is not in the library", node.name, node.op, name)
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use oh_my_rust::*;
+use crate::graph::{Form, FormKind};
+
+/// an n-dimensional arrangement of devices, e.g. a 2x4 mesh for data-parallel x model-parallel.
+/// `devices` are device ids in row-major order, so the last axis varies fastest.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DeviceMesh {
+    pub shape: Vec<usize>,
+    pub devices: Vec<usize>
+}
+
+impl DeviceMesh {
+    pub fn new(shape: Vec<usize>, devices: Vec<usize>) -> Self {
+        assert!(!shape.is_empty() && shape.iter().product::<usize>() == devices.len(), "mesh shape {:?} does not match {} devices", shape, devices.len());
+        DeviceMesh { shape, devices }
+    }
+
+    /// a mesh over devices 0..n in order
+    pub fn with_shape(shape: Vec<usize>) -> Self {
+        let n = shape.iter().product();
+        Self::new(shape, (0..n).collect())
+    }
+
+    /// `2x4` or `2x4:0,1,2,3,4,5,6,7`
+    pub fn from_code(code: &str) -> Self {
+        let (shape, devices) = match code.find(':') {
+            Some(i) => (&code[..i], Some(&code[i+1..])),
+            None => (code, None)
+        };
+        let shape: Vec<usize> = shape.split('x').map(|x| x.parse().unwrap()).collect();
+        match devices {
+            Some(devices) => Self::new(shape, devices.split(',').map(|x| x.parse().unwrap()).collect()),
+            None => Self::with_shape(shape)
+        }
+    }
+
+    pub fn code(&self) -> String {
+        let shape: Vec<_> = self.shape.iter().map(|x| x.to_string()).collect();
+        let devices: Vec<_> = self.devices.iter().map(|x| x.to_string()).collect();
+        format!("{}:{}", shape.join("x"), devices.join(","))
+    }
+
+    pub fn ndim(&self) -> usize {
+        self.shape.len()
+    }
+
+    pub fn size(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn coords(&self, flat_index: usize) -> Vec<usize> {
+        let mut rest = flat_index;
+        let mut coords = vec![0; self.ndim()];
+        for (axis, len) in self.shape.iter().enumerate().rev() {
+            coords[axis] = rest % len;
+            rest /= len;
+        }
+        coords
+    }
+
+    pub fn flat_index(&self, coords: &[usize]) -> usize {
+        coords.iter().zip(self.shape.iter()).fold(0, |acc, (c, len)| acc * len + c)
+    }
+
+    pub fn device_at(&self, coords: &[usize]) -> usize {
+        self.devices[self.flat_index(coords)]
+    }
+
+    /// group the devices into the 1-d slices along `axis`: devices in the same slice differ only in the coordinate of that axis
+    pub fn slices(&self, axis: usize) -> Vec<Vec<usize>> {
+        self.slices_along(&[axis])
+    }
+
+    /// group the devices into sub-meshes spanned by `axes`. Devices in the same group share the coordinates on all other axes.
+    pub fn slices_along(&self, axes: &[usize]) -> Vec<Vec<usize>> {
+        let mut groups: std::collections::BTreeMap<Vec<usize>, Vec<usize>> = std::collections::BTreeMap::new();
+        for i in 0..self.size() {
+            let key: Vec<_> = self.coords(i).into_iter().enumerate().filter(|(axis, _)| !axes.contains(axis)).map(|(_, c)| c).collect();
+            groups.entry(key).or_default().push(self.devices[i])
+        }
+        groups.into_values().collect()
+    }
+}
+
+/// the form of a tensor on a mesh: for each axis, Part means the tensor is split among devices along this axis and Full means it is replicated
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MeshForm {
+    pub kinds: Vec<FormKind>
+}
+
+impl MeshForm {
+    pub fn code(&self) -> String {
+        self.kinds.iter().map(|x| match x { FormKind::Full => "full", FormKind::Part => "part" }).collect::<Vec<_>>().join("/")
+    }
+
+    pub fn from_code(code: &str) -> Self {
+        MeshForm { kinds: code.split('/').map(|x| match x {
+            "full" => FormKind::Full,
+            "part" => FormKind::Part,
+            _ => panic!("unknown mesh form {}", code)
+        }).collect() }
+    }
+
+    pub fn part_axes(&self) -> Vec<usize> {
+        self.kinds.iter().enumerate().filter(|(_, k)| **k == FormKind::Part).map(|(i, _)| i).collect()
+    }
+
+    /// how many pieces the tensor is split into
+    pub fn nparts(&self, mesh: &DeviceMesh) -> usize {
+        self.part_axes().iter().map(|axis| mesh.shape[*axis]).product()
+    }
+
+    /// the equivalent flat forms, one for each replica group. Each group holds a complete copy of the tensor, split among the devices of the group.
+    pub fn forms(&self, mesh: &DeviceMesh) -> Vec<Form> {
+        assert_eq!(self.kinds.len(), mesh.ndim(), "mesh form {} does not match mesh {:?}", self.code(), mesh.shape);
+        let part_axes = self.part_axes();
+        let kind = if part_axes.is_empty() { FormKind::Full } else { FormKind::Part };
+        mesh.slices_along(&part_axes).into_iter().map(|devices| Form { kind, devices: devices.apply(|x| x.sort_unstable()), axis: 0 }).collect()
+    }
+
+    /// lower to flat forms: the single exactly equivalent form if there is one, i.e. when there is only one replica group or when the tensor
+    /// is fully replicated so all groups merge into a single full form. Otherwise one form per replica group as in `forms`, e.g. part/full
+    /// on a 2x4 mesh lowers to four 2-way part forms over {0,4}, {1,5}, {2,6} and {3,7}, and a node placed on slice i takes the i-th.
+    pub fn to_forms(&self, mesh: &DeviceMesh) -> Vec<Form> {
+        let forms = self.forms(mesh);
+        if forms.len() > 1 && self.part_axes().is_empty() {
+            vec![Form { kind: FormKind::Full, devices: mesh.devices.clone().apply(|x| x.sort_unstable()), axis: 0 }]
+        } else {
+            forms
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn mesh_forms_place_nodes_on_their_slices() {
+        let mesh = DeviceMesh::with_shape(vec![2, 2]);
+        let columns: Vec<_> = MeshForm::from_code("part/full").to_forms(&mesh).into_iter().map(|x| x.devices).collect();
+        assert_eq!(columns, vec![vec![0, 2], vec![1, 3]]);
+        assert_eq!(MeshForm::from_code("full/full").to_forms(&mesh), vec![Form { kind: FormKind::Full, devices: vec![0, 1, 2, 3], axis: 0 }]);
+
+        let nodes = testing::linear_model(8, 4, 2);
+        let strategy = testing::replicate_all(&nodes, &[0, 1, 2, 3], 3);
+        let forms: Vec<_> = nodes.iter().filter(|x| x.op != "Placeholder").map(|x| format!("{}:part/full:1", x.name)).collect();
+        let mut target = testing::fake_target(4, 1000);
+        target.mesh = Some(mesh);
+        let target = testing::compile(&nodes, target, &strategy, &[("mesh_forms", &forms.join(" "))]);
+        testing::assert_devices(&target, "dense/MatMul", &[1, 3]);
+        testing::assert_devices(&target, "update/kernel", &[1, 3]);
+    }
+}
@@ -1,6 +1,9 @@
 use crate::graph::Form;
+use crate::mesh::DeviceMesh;
 use crate::proto::{graph::GraphDef, node_def::NodeDef, attr_value::AttrValue, types::DataType};
 use std::collections::BTreeMap;
+use oh_my_rust::*;
+use protobuf::Message;
 
 pub struct Target {
     pub pb: GraphDef,
@@ -8,17 +11,257 @@ pub struct Target {
     pub links: Box<[u64]>, // the bandwidth of each link
     pub paths: Box<[Box<[usize]>]>, // the i*n+j element is the links that i->j uses (currently only one path between each pair)
     pub sinks: Box<[String]>, // sink nodes
-    pub nccls: BTreeMap<String, [f64; 4]> // the key is a comma separated sorted list of device names, the values are [coef1, interc1, coef2, interc2]. The model is time = max( coef1 * size + interc1, coef2 * size + interc2 ). The size unit is KB.
+    pub nccls: BTreeMap<String, [f64; 4]>, // the key is a comma separated sorted list of device names, the values are [coef1, interc1, coef2, interc2]. The model is time = max( coef1 * size + interc1, coef2 * size + interc2 ). The size unit is KB.
+    pub mesh: Option<DeviceMesh>, // optional logical arrangement of the devices, which the option `mesh_forms` refers to (see `editor::edit`)
+    pub aux_variables: Vec<AuxVariable>, // persistent variables created by the compiler
+    pub cpus: Vec<CpuDevice> // host CPUs (e.g. one per NUMA node). They are not in `devices` and strategies never place ops on them, only the compiler stages tensors there (see `host_cpu`)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveFormat {
+    Binary,
+    Text
+}
+
+impl SaveFormat {
+    /// the format and whether to gzip, by the extension of `path`, see `save_graph`
+    pub fn from_path(path: &str) -> (Self, bool) {
+        let gzip = path.ends_with(".gz");
+        let path = path.strip_suffix(".gz").unwrap_or(path);
+        (if path.ends_with(".pbtxt") { SaveFormat::Text } else { SaveFormat::Binary }, gzip)
+    }
+}
+
+pub fn serialize_graph(pb: &GraphDef, format: SaveFormat) -> Vec<u8> {
+    match format {
+        SaveFormat::Binary => pb.write_to_bytes().unwrap(),
+        SaveFormat::Text => protobuf::text_format::print_to_string(pb).into_bytes()
+    }
+}
+
+/// write `pb` to `path`: text format if the path ends with `.pbtxt` (before an optional `.gz`), binary otherwise, and gzipped if it ends
+/// with `.gz` (requires the `gzip` feature). The file is written next to `path` and renamed over it, so readers never see a partial graph.
+/// Returns the number of bytes written.
+pub fn save_graph(pb: &GraphDef, path: &str) -> std::io::Result<u64> {
+    let (format, gzip) = SaveFormat::from_path(path);
+    let mut bytes = serialize_graph(pb, format);
+    let raw_size = bytes.len();
+    if gzip {
+        bytes = gzip_bytes(&bytes)?
+    }
+
+    let temp = format!("{}.tmp{}", path, std::process::id());
+    let written = (|| {
+        let mut file = std::fs::File::create(&temp)?;
+        std::io::Write::write_all(&mut file, &bytes)?;
+        file.sync_all()?;
+        std::fs::rename(&temp, path)
+    })();
+    if let Err(e) = written {
+        std::fs::remove_file(&temp).ignore();
+        return Err(e)
+    }
+
+    info!("saved {} nodes to {}: {} bytes{}", pb.node.len(), path, bytes.len(), if gzip { format!(" ({} before compression)", raw_size) } else { String::new() });
+    Ok(bytes.len() as _)
+}
+
+#[cfg(feature = "gzip")]
+fn gzip_bytes(bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+    std::io::Write::write_all(&mut encoder, bytes)?;
+    encoder.finish()
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip_bytes(_bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+    Err(std::io::Error::new(std::io::ErrorKind::Other, "writing gzipped graphs requires tge built with the gzip feature"))
+}
+
+/// a CPU device of a host, typically one per socket/NUMA node. GPUs attached to other sockets reach it through the inter-socket link, which is reflected by a lower bandwidth.
+#[derive(Debug, Clone)]
+pub struct CpuDevice {
+    pub name: String, // e.g. /job:worker/replica:0/task:0/device:CPU:1
+    pub bandwidth: BTreeMap<usize, u64> // index of GPU in `devices` => bandwidth to it. GPUs of other hosts are absent
+}
+
+/// a variable created by the compiler to keep states across steps (accumulators, error feedbacks, etc.).
+/// They are not part of the model and should be excluded from checkpoints.
+#[derive(Debug, Clone)]
+pub struct AuxVariable {
+    pub name: String,
+    pub device: usize,
+    pub dtype: DataType,
+    pub shape: Vec<usize>,
+    pub initializer: String // the Assign node that initializes it
 }
 
 impl Target {
     pub fn new(pb: GraphDef, devices: Box<[String]>, links: Box<[u64]>, paths: Box<[Box<[usize]>]>, sinks: Box<[String]>, nccls: BTreeMap<String, [f64; 4]>) -> Self {
-        Target { pb, devices, links, paths, sinks, nccls }
+        Target { pb, devices, links, paths, sinks, nccls, mesh: None, aux_variables: vec![], cpus: vec![] }
     }
 
     pub fn ndev(&self) -> usize {
         self.devices.len()
     }
+
+    /// indices of the devices grouped by machine, i.e. the task part of their names (`/job:worker/replica:0/task:1`), in the order of their first devices
+    pub fn machines(&self) -> Vec<Vec<usize>> {
+        let mut machines: Vec<(&str, Vec<usize>)> = vec![];
+        for (i, device) in self.devices.iter().enumerate() {
+            let task = &device[..device.rfind('/').expect("unrecognized device name")];
+            match machines.iter_mut().find(|(x, _)| *x == task) {
+                Some((_, devices)) => devices.push(i),
+                None => machines.push((task, vec![i]))
+            }
+        }
+        machines.into_iter().map(|(_, devices)| devices).collect()
+    }
+
+    /// whether every machine has at least one of `devices`
+    pub fn covers_all_machines(&self, devices: &[usize]) -> bool {
+        self.machines().iter().all(|machine| machine.iter().any(|x| devices.contains(x)))
+    }
+
+    /// the CPU with the highest bandwidth to the GPU, i.e. the one on the same NUMA node
+    pub fn local_cpu(&self, gpu: usize) -> Option<usize> {
+        self.cpus.iter().enumerate().filter_map(|(i, cpu)| cpu.bandwidth.get(&gpu).map(|b| (*b, std::cmp::Reverse(i)))).max().map(|(_, i)| i.0)
+    }
+
+    /// the host CPU that stages the tensors of the GPU (see `Tensor::all_reduce_cpu_staged` and `Tensor::all_reduce_cpu_ring`): its local CPU if the CPUs
+    /// are declared, otherwise the first CPU of its task
+    pub fn host_cpu(&self, gpu: usize) -> String {
+        match self.local_cpu(gpu) {
+            Some(i) => self.cpus[i].name.clone(),
+            None => {
+                let device = &self.devices[gpu];
+                format!("{}/device:CPU:0", &device[..device.rfind('/').expect("unrecognized device name")])
+            }
+        }
+    }
+
+    /// the host CPUs of the GPUs by name, each with the first GPU it serves. The simulator and the scheduler, which only model GPUs,
+    /// charge the nodes placed on a host CPU to that GPU
+    pub fn host_cpus(&self) -> BTreeMap<String, usize> {
+        let mut hosts = BTreeMap::new();
+        for gpu in 0..self.ndev() {
+            hosts.entry(self.host_cpu(gpu)).or_insert(gpu);
+        }
+        hosts
+    }
+
+    /// split the compiled graph into one GraphDef per device or task with explicit `_Send`/`_Recv` pairs, see `partition::partition`
+    pub fn partition(&self, by: crate::partition::PartitionBy, incarnations: &BTreeMap<String, u64>) -> BTreeMap<String, GraphDef> {
+        crate::partition::partition(self, by, incarnations)
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.pb.node.len()
+    }
+
+    /// the names of the nodes of the compiled graph, in the order they were emitted
+    pub fn node_names(&self) -> impl Iterator<Item=&str> {
+        self.pb.node.iter().map(|x| &x.name[..])
+    }
+
+    /// the op and device of a node of the compiled graph
+    pub fn node_placement(&self, name: &str) -> Option<(&str, &str)> {
+        self.pb.node.iter().find(|x| x.name == name).map(|x| (&x.op[..], &x.device[..]))
+    }
+
+    /// the compiled graph as a serialized GraphDef
+    pub fn serialize(&self, format: SaveFormat) -> Vec<u8> {
+        serialize_graph(&self.pb, format)
+    }
+
+    /// write the compiled graph to `path`, see `save_graph`. Returns the number of bytes written.
+    pub fn save(&self, path: &str) -> std::io::Result<u64> {
+        save_graph(&self.pb, path)
+    }
+
+    pub const AUX_VARIABLE_SCOPE: &'static str = "tge_aux";
+    pub const AUX_INIT_OP: &'static str = "tge_aux/init";
+
+    /// create a persistent variable under the `tge_aux/` namespace, initialized with `init_value` broadcasted to `shape`. Returns the name of the variable node.
+    pub fn create_aux_variable(&mut self, name: &str, device: usize, dtype: DataType, shape: &[usize], init_value: f64) -> String {
+        self.create_aux_variable_with(name, device, dtype, shape, &[init_value])
+    }
+
+    /// like `create_aux_variable`, but initialized with `init_values` in row-major order, which has either one or all elements of `shape`
+    pub fn create_aux_variable_with(&mut self, name: &str, device: usize, dtype: DataType, shape: &[usize], init_values: &[f64]) -> String {
+        assert!(init_values.len() == 1 || init_values.len() == shape.iter().product::<usize>(), "wrong number of initial values for aux variable {}", name);
+        let name = format!("{}/{}", Self::AUX_VARIABLE_SCOPE, name);
+        assert!(self.aux_variables.iter().all(|x| x.name != name), "aux variable {} already exists", name);
+
+        let shape_proto = crate::proto::tensor_shape::TensorShapeProto::new().apply(|s| s.set_dim(shape.iter().map(|x| {
+            crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|d| d.size = *x as _)
+        }).collect()));
+
+        let mut var = NodeDef::new();
+        var.name = name.clone();
+        var.op = "VariableV2".into();
+        var.device = self.devices[device].clone();
+        var.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(dtype)));
+        var.attr.insert("shape".into(), AttrValue::new().apply(|x| x.set_shape(shape_proto.clone())));
+        var.attr.insert("container".into(), AttrValue::new().apply(|x| x.set_s(vec![])));
+        var.attr.insert("shared_name".into(), AttrValue::new().apply(|x| x.set_s(vec![])));
+
+        let mut value = NodeDef::new();
+        value.name = format!("{}/initial_value", name);
+        value.op = "Const".into();
+        value.device = self.devices[device].clone();
+        value.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(dtype)));
+        let tensor = crate::proto::tensor::TensorProto::new().apply(|x| {
+            x.set_dtype(dtype);
+            x.set_tensor_shape(shape_proto);
+            match dtype { // a single value is broadcasted to the whole shape by TF
+                DataType::DT_DOUBLE => x.double_val.extend_from_slice(init_values),
+                DataType::DT_INT32 => x.int_val.extend(init_values.iter().map(|v| *v as i32)),
+                DataType::DT_INT64 => x.int64_val.extend(init_values.iter().map(|v| *v as i64)),
+                DataType::DT_FLOAT => x.float_val.extend(init_values.iter().map(|v| *v as f32)),
+                _ => panic!("unsupported dtype {:?} for aux variable {}", dtype, name)
+            }
+        });
+        value.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(tensor)));
+
+        let mut assign = NodeDef::new();
+        assign.name = format!("{}/Assign", name);
+        assign.op = "Assign".into();
+        assign.device = self.devices[device].clone();
+        assign.input.push(var.name.clone());
+        assign.input.push(value.name.clone());
+        assign.attr.insert("T".into(), AttrValue::new().apply(|x| x.set_field_type(dtype)));
+        assign.attr.insert("use_locking".into(), AttrValue::new().apply(|x| x.set_b(true)));
+        assign.attr.insert("validate_shape".into(), AttrValue::new().apply(|x| x.set_b(true)));
+
+        self.aux_variables.push(AuxVariable { name: name.clone(), device, dtype, shape: shape.to_vec(), initializer: assign.name.clone() });
+        self.pb.node.push(var);
+        self.pb.node.push(value);
+        self.pb.node.push(assign);
+        name
+    }
+
+    /// the nodes that the compiler adds for the user to run besides the sinks: the init and broadcast ops and the aux variable initializers.
+    /// Passes that prune the graph from the sinks keep them too. Only those present in the graph are returned.
+    pub fn init_ops(&self) -> Vec<String> {
+        let names = [crate::graph::Graph::INIT_OP, crate::graph::Graph::BROADCAST_OP, crate::graph::Graph::INIT_BROADCAST_OP, Self::AUX_INIT_OP].iter()
+            .map(|x| x.to_string()).chain(self.aux_variables.iter().map(|x| x.initializer.clone()));
+        names.filter(|name| self.pb.node.iter().any(|x| x.name == *name)).collect()
+    }
+
+    /// add a NoOp named `tge_aux/init` that runs all aux variable initializers. Does nothing if there is no aux variable.
+    pub fn finalize_aux_variables(&mut self) {
+        if self.aux_variables.is_empty() || self.pb.node.iter().any(|x| x.name == Self::AUX_INIT_OP) {
+            return
+        }
+
+        let mut init = NodeDef::new();
+        init.name = Self::AUX_INIT_OP.into();
+        init.op = "NoOp".into();
+        init.device = self.devices[0].clone();
+        init.input = self.aux_variables.iter().map(|x| format!("^{}", x.initializer)).collect();
+        self.pb.node.push(init)
+    }
 }
 
 pub trait Profiler {
@@ -0,0 +1,243 @@
+use std::fmt;
+use std::collections::BTreeMap;
+use crate::proto::graph::GraphDef;
+use crate::proto::node_def::NodeDef;
+
+/// naming conventions of the compiled graph and the surgery on the input strings of NodeDef.
+///
+/// ```text
+/// dense/MatMul          -> dense/MatMul/replica_0, dense/MatMul/replica_1, ... (see `ReplicaScheme` for other suffixes)
+/// dense/MatMul:1        -> the second output
+/// ^dense/MatMul         -> a control dependency
+/// loc:@dense/kernel     -> an entry of the `_class` colocation attr
+/// ```
+pub const DEFAULT_REPLICA_FORMAT: &str = "{name}/replica_{index}";
+
+/// an entry of `NodeDef::input`. All syntaxes of TF are supported:
+///
+/// ```text
+/// node            output 0 of node
+/// node:3          output 3 of node
+/// ^node           control dependency on node
+/// node:out:3      the 3rd tensor of the output arg `out` of node, used in the bodies of FunctionDef
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Input<'a> {
+    pub name: &'a str,
+    pub output: Option<&'a str>, // the output arg name, only in function bodies
+    pub index: usize, // always 0 for control dependencies. Relative to `output` if it is present
+    pub control: bool
+}
+
+impl<'a> Input<'a> {
+    pub fn parse(x: &'a str) -> Self {
+        Self::try_parse(x).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_parse(x: &'a str) -> Result<Self, String> {
+        let valid_name = |name: &str| !name.is_empty() && !name.contains(|c: char| c == '^' || c.is_whitespace());
+        if let Some(name) = x.strip_prefix('^') {
+            if !valid_name(name) || name.contains(':') {
+                return Err(format!("malformed control input `{}`", x))
+            }
+            return Ok(Input { name, output: None, index: 0, control: true })
+        }
+
+        let parse_index = |i: &str| i.parse().map_err(|_| format!("malformed output index in input `{}`", x));
+        let mut segs = x.split(':');
+        let input = match (segs.next(), segs.next(), segs.next(), segs.next()) {
+            (Some(name), None, None, None) => Input { name, output: None, index: 0, control: false },
+            (Some(name), Some(index), None, None) => Input { name, output: None, index: parse_index(index)?, control: false },
+            (Some(name), Some(output), Some(index), None) if !output.is_empty() => Input { name, output: Some(output), index: parse_index(index)?, control: false },
+            _ => return Err(format!("malformed input `{}`", x))
+        };
+        if !valid_name(input.name) {
+            return Err(format!("malformed node name in input `{}`", x))
+        }
+        Ok(input)
+    }
+
+    /// the same reference to another node
+    pub fn with_name(&self, name: &str) -> String {
+        Input { name, ..*self }.to_string()
+    }
+}
+
+impl fmt::Display for Input<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.control, self.output, self.index) {
+            (true, _, _) => write!(f, "^{}", self.name),
+            (false, Some(output), i) => write!(f, "{}:{}:{}", self.name, output, i),
+            (false, None, 0) => write!(f, "{}", self.name),
+            (false, None, i) => write!(f, "{}:{}", self.name, i)
+        }
+    }
+}
+
+/// the name and output index of a data input. Control dependencies give their node with index 0.
+/// For the `node:out:i` syntax of function bodies, the index is relative to the output arg.
+pub fn parse_input(x: &str) -> (&str, usize) {
+    let input = Input::parse(x);
+    (input.name, input.index)
+}
+
+/// the original op a compiled node comes from: replicas carry `_tge_origin`, the aux nodes of the editor carry `_tge_belong_to`
+pub fn owner_of(node: &NodeDef) -> String {
+    ["_tge_origin", "_tge_belong_to"].iter().filter_map(|key| node.attr.get(*key)).map(|x| String::from_utf8_lossy(x.get_s()).into_owned()).next()
+        .unwrap_or_else(|| node.name.clone())
+}
+
+/// how replicas are named, given by a format string of the form `{name}<separator>{index}<suffix>` (the option `replica_format`,
+/// `{name}/replica_{index}` by default). The separator must be non-empty and not end with a digit, and the suffix must not start with one,
+/// so every replica name has exactly one reading and replicas of different nodes never share a name. A non-default scheme is recorded in
+/// the `tge_metadata` node of the compiled graph, see `of_graph`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicaScheme {
+    separator: String,
+    suffix: String
+}
+
+impl Default for ReplicaScheme {
+    fn default() -> Self {
+        Self::parse(DEFAULT_REPLICA_FORMAT).unwrap()
+    }
+}
+
+impl ReplicaScheme {
+    pub const METADATA_NODE: &'static str = "tge_metadata";
+
+    pub fn parse(format: &str) -> Result<Self, String> {
+        let rest = format.strip_prefix("{name}").ok_or_else(|| format!("replica format `{}` should start with {{name}}", format))?;
+        let (separator, suffix) = rest.split_once("{index}").ok_or_else(|| format!("replica format `{}` has no {{index}}", format))?;
+        let valid = |x: &str| x.chars().all(|c| c.is_ascii_alphanumeric() || "_.-/>".contains(c));
+        if separator.is_empty() || !valid(separator) || !valid(suffix) {
+            return Err(format!("replica format `{}` should separate {{name}} and {{index}} with characters allowed in node names", format))
+        }
+        if separator.ends_with(|c: char| c.is_ascii_digit()) || suffix.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(format!("replica format `{}` should not put digits next to {{index}}, or replica names become ambiguous", format))
+        }
+        Ok(ReplicaScheme { separator: separator.to_string(), suffix: suffix.to_string() })
+    }
+
+    pub fn from_options(options: &BTreeMap<String, String>) -> Self {
+        match options.get("replica_format") {
+            Some(format) => Self::parse(format).unwrap_or_else(|e| panic!("{}", e)),
+            None => Self::default()
+        }
+    }
+
+    /// the scheme that a compiled graph was emitted with
+    pub fn of_graph(graph: &GraphDef) -> Self {
+        let format = graph.node.iter().find(|x| x.name == Self::METADATA_NODE).and_then(|node| {
+            let record = String::from_utf8_lossy(node.attr.get("value")?.get_tensor().string_val.first()?).into_owned();
+            let start = record.find("\"replica_format\": \"")? + "\"replica_format\": \"".len();
+            Some(record[start..start + record[start..].find('"')?].to_string())
+        });
+        format.map(|x| Self::parse(&x).unwrap_or_else(|e| panic!("{}", e))).unwrap_or_default()
+    }
+
+    pub fn format(&self) -> String {
+        format!("{{name}}{}{{index}}{}", self.separator, self.suffix)
+    }
+
+    pub fn replica_name(&self, name: &str, index: usize) -> String {
+        format!("{}{}{}{}", name, self.separator, index, self.suffix)
+    }
+
+    /// the original node and replica index of a replica name, None for aux nodes and other names
+    pub fn original_name<'a>(&self, replica: &'a str) -> Option<(&'a str, usize)> {
+        let replica = replica.strip_suffix(&self.suffix[..])?;
+        let digits = replica.len() - replica.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        let (rest, index) = replica.split_at(replica.len() - digits);
+        if index.is_empty() || (index.len() > 1 && index.starts_with('0')) {
+            return None
+        }
+        Some((rest.strip_suffix(&self.separator[..])?, index.parse().ok()?))
+    }
+}
+
+/// rename the node referred by an input string, keeping the control mark and the output index
+pub fn rename_input(input: &str, f: impl FnOnce(&str) -> String) -> String {
+    let input = Input::parse(input);
+    input.with_name(&f(input.name))
+}
+
+/// rename nodes along with all references to them: inputs and the `loc:@` entries of `_class`. Other attrs are untouched.
+pub fn rename_nodes(nodes: &mut [NodeDef], f: impl Fn(&str) -> String) {
+    for node in nodes.iter_mut() {
+        node.name = f(&node.name);
+        for input in node.input.iter_mut() {
+            *input = rename_input(input, &f);
+        }
+        if let Some(class) = node.attr.get_mut("_class") {
+            for entry in class.mut_list().s.iter_mut() {
+                if let Some(name) = std::str::from_utf8(entry).ok().and_then(|x| x.strip_prefix("loc:@")) {
+                    *entry = format!("loc:@{}", f(name)).into_bytes();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use oh_my_rust::*;
+    use crate::proto::attr_value::AttrValue;
+
+    #[test]
+    fn replica_names_round_trip() {
+        let scheme = ReplicaScheme::default();
+        assert_eq!(scheme.replica_name("dense/MatMul", 12), "dense/MatMul/replica_12");
+        assert_eq!(scheme.original_name("dense/MatMul/replica_12"), Some(("dense/MatMul", 12)));
+        assert_eq!(scheme.original_name("dense/MatMul/replica_012"), None);
+        assert_eq!(scheme.original_name("dense/MatMul/aux_sum"), None);
+        assert_eq!(scheme.original_name("dense/MatMul"), None);
+    }
+
+    #[test]
+    fn parse_all_input_syntaxes() {
+        assert_eq!(Input::parse("a/b"), Input { name: "a/b", output: None, index: 0, control: false });
+        assert_eq!(Input::parse("a/b:3"), Input { name: "a/b", output: None, index: 3, control: false });
+        assert_eq!(Input::parse("^a/b"), Input { name: "a/b", output: None, index: 0, control: true });
+        assert_eq!(Input::parse("a/b:out:2"), Input { name: "a/b", output: Some("out"), index: 2, control: false });
+        assert_eq!(parse_input("^a/b"), ("a/b", 0));
+        for x in ["a/b", "a/b:3", "^a/b", "a/b:out:2"] {
+            assert_eq!(Input::parse(x).to_string(), x)
+        }
+        assert_eq!(Input::parse("a:0").to_string(), "a");
+        assert_eq!(rename_input("f:out:1", |_| "g".into()), "g:out:1");
+    }
+
+    #[test]
+    fn reject_malformed_inputs() {
+        for x in ["", "^", "^a:1", "a:", "a:x", "a::1", "a:out:", "a:b:c:1", ":1", "a b", "^^a"] {
+            assert!(Input::try_parse(x).is_err(), "`{}` is accepted", x)
+        }
+    }
+
+    #[test]
+    fn rename_input_keeps_control_and_index() {
+        let f = |name: &str| ReplicaScheme::default().replica_name(name, 0);
+        assert_eq!(rename_input("a", f), "a/replica_0");
+        assert_eq!(rename_input("a:2", f), "a/replica_0:2");
+        assert_eq!(rename_input("^a", f), "^a/replica_0");
+    }
+
+    #[test]
+    fn rename_nodes_follows_references() {
+        let mut a = NodeDef::new();
+        a.name = "a".into();
+        let mut b = NodeDef::new();
+        b.name = "b".into();
+        b.input.push("a:1".into());
+        b.input.push("^a".into());
+        b.attr.insert("_class".into(), AttrValue::new().apply(|x| x.mut_list().s.push(b"loc:@a".to_vec())));
+        let mut nodes = vec![a, b];
+        rename_nodes(&mut nodes, |name| format!("x/{}", name));
+        assert_eq!(nodes[0].name, "x/a");
+        assert_eq!(nodes[1].name, "x/b");
+        assert_eq!(nodes[1].input.to_vec(), vec!["x/a:1".to_string(), "^x/a".to_string()]);
+        assert_eq!(nodes[1].attr["_class"].get_list().s[0], b"loc:@x/a".to_vec());
+    }
+}
@@ -0,0 +1,214 @@
+//! Splitting a compiled graph into one GraphDef per device or per task, for runtimes that expect pre-partitioned graphs (e.g. the
+//! `RegisterGraph` of TF workers). Every edge between partitions is cut into a `_Send` on the producer's device and a `_Recv` on the
+//! consumer's device that agree on `tensor_name`, the devices and the incarnation of the sending device, like TF's own graph partitioning.
+//! Int32 tensors live in host memory on GPUs, so their sends and receives on GPUs use `_HostSend` and `_HostRecv`. Control edges send a
+//! dummy scalar. Each tensor is received once per consuming device. The pairs carry the size annotated on the cut edge, and the simulator
+//! links them by `tensor_name`, so the partitions merged back into one GraphDef simulate with the traffic between them explicit.
+//! Collective ops stay on their devices and keep their keys, so the runtimes of the partitions join them as in the whole graph.
+//!
+//! `export` writes the partitions to files with a JSON manifest, for runtimes where each worker imports only its own subgraph:
+//!
+//! ```text
+//! {
+//!   "by": "device",
+//!   "partitions": [
+//!     {"key": "/job:worker/replica:0/task:0/device:GPU:0", "file": "job_worker_replica_0_task_0_device_GPU_0.pb", "nodes": 120, "sends": 4, "recvs": 2},
+//!     ...
+//!   ],
+//!   "transfers": [{"tensor": "edge_1_dense/MatMul:0", "from": "<key>", "to": "<key>"}, ...],
+//!   "collectives": [{"key": "instance_3", "partitions": ["<key>", ...]}, {"key": "nccl_dense/kernel/machine_0", "partitions": [...]}, ...]
+//! }
+//! ```
+
+use oh_my_rust::*;
+use std::collections::{BTreeMap, HashMap};
+use crate::misc::{Target, save_graph};
+use crate::naming::parse_input;
+use crate::proto::attr_value::AttrValue;
+use crate::proto::graph::GraphDef;
+use crate::proto::node_def::NodeDef;
+use crate::proto::types::DataType;
+
+/// the incarnation of devices missing in the map given to `partition`. Only transfers between tasks check it against the device
+pub const DEFAULT_INCARNATION: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionBy {
+    Device, // one partition per device name
+    Task // one partition per task (`/job:worker/replica:0/task:1`), keeping the edges between devices of a task
+}
+
+impl PartitionBy {
+    pub fn name(self) -> &'static str {
+        match self {
+            PartitionBy::Device => "device",
+            PartitionBy::Task => "task"
+        }
+    }
+
+    /// the partition of a node on `device`
+    pub fn key(self, device: &str) -> &str {
+        match self {
+            PartitionBy::Device => device,
+            PartitionBy::Task => &device[..device.rfind('/').expect("unrecognized device name")]
+        }
+    }
+}
+
+pub fn is_send(node: &NodeDef) -> bool {
+    node.op == "_Send" || node.op == "_HostSend"
+}
+
+pub fn is_recv(node: &NodeDef) -> bool {
+    node.op == "_Recv" || node.op == "_HostRecv"
+}
+
+/// split the graph of `target` by `by`, see the module doc. `incarnations` maps device names to the incarnations reported by the workers.
+/// Returns the partitions by their keys (see `PartitionBy::key`), each with the versions and the function library of the graph.
+pub fn partition(target: &Target, by: PartitionBy, incarnations: &BTreeMap<String, u64>) -> BTreeMap<String, GraphDef> {
+    let nodes = &target.pb.node;
+    let node_dict: HashMap<&str, &NodeDef> = nodes.iter().map(|x| (&x.name[..], x)).collect();
+    let mut parts: BTreeMap<String, GraphDef> = BTreeMap::new();
+    let mut received: BTreeMap<(String, String), String> = BTreeMap::new(); // (input, device of the consumer) => the receiving node
+    let mut count = 0;
+
+    for node in nodes.iter() {
+        let mut node = node.clone();
+        let part = by.key(&node.device).to_string();
+        for i in 0..node.input.len() {
+            let input = node.input[i].clone();
+            let is_control = input.starts_with('^');
+            let (name, index) = if is_control { (&input[1..], 0) } else { parse_input(&input) };
+            let producer = node_dict.get(name).unwrap_or_else(|| panic!("{} has an unknown input {}", node.name, input));
+            if by.key(&producer.device) == part {
+                continue
+            }
+
+            let size = node.attr.get("_tge_input_sizes").and_then(|x| x.get_list().i.get(i)).copied().unwrap_or(0);
+            let recv = received.entry((input.clone(), node.device.clone())).or_insert_with(|| {
+                count += 1;
+                let (send, recv, dummy) = cut_edge(producer, index, is_control, &node.device, size, count, incarnations);
+                let sender = parts.entry(by.key(&producer.device).to_string()).or_insert_with(|| new_partition(target));
+                sender.node.extend(dummy);
+                sender.node.push(send);
+                let name = recv.name.clone();
+                parts.entry(part.clone()).or_insert_with(|| new_partition(target)).node.push(recv);
+                name
+            });
+            node.input[i] = if is_control { format!("^{}", recv) } else { recv.clone() };
+        }
+        parts.entry(part).or_insert_with(|| new_partition(target)).node.push(node);
+    }
+
+    info!("partitioned {} nodes into {} graphs with {} send/recv pairs", nodes.len(), parts.len(), count);
+    parts
+}
+
+/// partition the graph of `target` and write each partition to `<dir>/<key><suffix>`, with the key made a file name (e.g.
+/// `job_worker_replica_0_task_0_device_GPU_0.pb`), and the manifest (see the module doc) to `<dir>/manifest.json`. The suffix
+/// decides the format as in `save_graph`, e.g. `.pbtxt` or `.pb.gz`. `dir` is created if missing. Returns the number of partitions.
+pub fn export(target: &Target, by: PartitionBy, incarnations: &BTreeMap<String, u64>, dir: &str, suffix: &str) -> std::io::Result<usize> {
+    let parts = partition(target, by, incarnations);
+    std::fs::create_dir_all(dir)?;
+
+    let mut keys: BTreeMap<&str, &str> = BTreeMap::new(); // device => the key of its partition
+    let mut entries = vec![];
+    for (key, graph) in parts.iter() {
+        let file = format!("{}{}", file_name(key), suffix);
+        save_graph(graph, &std::path::Path::new(dir).join(&file).to_string_lossy())?;
+        entries.push(format!("{{\"key\": \"{}\", \"file\": \"{}\", \"nodes\": {}, \"sends\": {}, \"recvs\": {}}}",
+            key, file, graph.node.len(), graph.node.iter().filter(|x| is_send(x)).count(), graph.node.iter().filter(|x| is_recv(x)).count()));
+        keys.extend(graph.node.iter().map(|x| (&x.device[..], &key[..])));
+    }
+
+    let transfers: Vec<String> = parts.iter().flat_map(|(key, graph)| graph.node.iter().filter(|x| is_recv(x)).map(move |recv| {
+        let send_device = String::from_utf8_lossy(recv.attr["send_device"].get_s()).to_string();
+        format!("{{\"tensor\": \"{}\", \"from\": \"{}\", \"to\": \"{}\"}}", String::from_utf8_lossy(recv.attr["tensor_name"].get_s()), by.key(&send_device), key)
+    })).collect();
+
+    // CollectiveReduce and friends meet by instance key, the NCCL ops by shared name
+    let mut collectives: BTreeMap<String, std::collections::BTreeSet<&str>> = BTreeMap::new();
+    for node in target.pb.node.iter() {
+        let key = match (node.attr.get("instance_key"), node.attr.get("shared_name")) {
+            (Some(instance), _) if node.op.starts_with("Collective") => format!("instance_{}", instance.get_i()),
+            (_, Some(name)) if node.op.starts_with("Nccl") => format!("nccl_{}", String::from_utf8_lossy(name.get_s())),
+            _ => continue
+        };
+        collectives.entry(key).or_default().insert(keys[&node.device[..]]);
+    }
+    let collectives: Vec<String> = collectives.iter().map(|(key, members)| {
+        format!("{{\"key\": \"{}\", \"partitions\": [{}]}}", key, members.iter().map(|x| format!("\"{}\"", x)).collect::<Vec<_>>().join(", "))
+    }).collect();
+
+    let manifest = format!("{{\n  \"by\": \"{}\",\n  \"partitions\": [\n    {}\n  ],\n  \"transfers\": [{}],\n  \"collectives\": [{}]\n}}\n",
+        by.name(), entries.join(",\n    "), transfers.join(", "), collectives.join(", "));
+    std::fs::write(std::path::Path::new(dir).join("manifest.json"), manifest)?;
+    info!("exported {} partitions to {}", parts.len(), dir);
+    Ok(parts.len())
+}
+
+/// a partition key as a file name: the characters other than letters, digits, `-` and `.` become `_`, without the leading ones
+fn file_name(key: &str) -> String {
+    key.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' }).collect::<String>().trim_start_matches('_').to_string()
+}
+
+fn new_partition(target: &Target) -> GraphDef {
+    GraphDef::new().apply(|x| {
+        x.set_versions(target.pb.get_versions().clone());
+        if target.pb.has_library() {
+            x.set_library(target.pb.get_library().clone())
+        }
+    })
+}
+
+/// the `_Send`, the `_Recv` and, for control edges, the dummy Const sent in place of the control signal
+fn cut_edge(producer: &NodeDef, index: usize, is_control: bool, recv_device: &str, size: i64, id: usize, incarnations: &BTreeMap<String, u64>) -> (NodeDef, NodeDef, Option<NodeDef>) {
+    let dummy = if is_control {
+        let mut dummy = NodeDef::new();
+        dummy.name = format!("{}/_tge_control_{}", producer.name, id);
+        dummy.op = "Const".into();
+        dummy.device = producer.device.clone();
+        dummy.input.push(format!("^{}", producer.name));
+        dummy.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_FLOAT)));
+        dummy.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(crate::proto::tensor::TensorProto::new().apply(|t| {
+            t.set_dtype(DataType::DT_FLOAT);
+            t.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new());
+            t.float_val.push(0.)
+        }))));
+        Some(dummy)
+    } else {
+        None
+    };
+    let (source, dtype) = match &dummy {
+        Some(dummy) => (dummy.name.clone(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_FLOAT))),
+        None => (format!("{}:{}", producer.name, index), crate::graph::dtype_of(producer, index).unwrap_or_else(|| panic!("cannot determine the dtype of {}:{} to send it", producer.name, index)))
+    };
+    let in_host_memory = |device: &str| dtype.get_field_type() == DataType::DT_INT32 && !device.contains("CPU");
+    let tensor_name = format!("edge_{}_{}", id, source);
+
+    let common = |node: &mut NodeDef| {
+        node.attr.insert("tensor_name".into(), AttrValue::new().apply(|x| x.set_s(tensor_name.clone().into_bytes())));
+        node.attr.insert("send_device".into(), AttrValue::new().apply(|x| x.set_s(producer.device.clone().into_bytes())));
+        node.attr.insert("send_device_incarnation".into(), AttrValue::new().apply(|x| x.set_i(incarnations.get(&producer.device).copied().unwrap_or(DEFAULT_INCARNATION) as _)));
+        node.attr.insert("recv_device".into(), AttrValue::new().apply(|x| x.set_s(recv_device.as_bytes().to_vec())));
+        node.attr.insert("client_terminated".into(), AttrValue::new().apply(|x| x.set_b(false)));
+        node.attr.insert("_tge_input_sizes".into(), AttrValue::new().apply(|x| x.mut_list().i.push(size)));
+    };
+
+    let mut send = NodeDef::new();
+    send.name = format!("{}/_tge_send_{}", producer.name, id);
+    send.op = if in_host_memory(&producer.device) { "_HostSend" } else { "_Send" }.into();
+    send.device = producer.device.clone();
+    send.input.push(source);
+    send.attr.insert("T".into(), dtype.clone());
+    common(&mut send);
+
+    let mut recv = NodeDef::new();
+    recv.name = format!("{}/_tge_recv_{}", producer.name, id);
+    recv.op = if in_host_memory(recv_device) { "_HostRecv" } else { "_Recv" }.into();
+    recv.device = recv_device.to_string();
+    recv.attr.insert("tensor_type".into(), dtype);
+    common(&mut recv);
+
+    (send, recv, dummy)
+}
@@ -0,0 +1,245 @@
+use oh_my_rust::*;
+use std::collections::BTreeMap;
+use crate::graph::{Graph, Form, Conversion, Tensor, plan_conversion};
+use crate::misc::Target;
+use crate::cache::SubgraphCache;
+
+type SumBuckets = BTreeMap<(Form, Form, i32, usize), Vec<Vec<(usize, u64)>>>; // (from, to, dtype, depth) => buckets of (transfer, size)
+
+/// a conversion that the compiled graph needs: output `index` of node `node` is consumed in form `to` while produced in `from`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedTransfer {
+    pub node: usize,
+    pub index: usize,
+    pub from: Form,
+    pub to: Form,
+    pub conversion: Conversion,
+    pub intermediates: Vec<usize>, // devices that run the aux ops of the conversion (e.g. the AddN of Sum), in addition to `from` and `to`
+    pub size: u64 // bytes of the whole tensor
+}
+
+/// all conversions of a graph, planned before any NodeDef is emitted. Each (tensor, destination form) appears once.
+/// Conversions that the editor already provides (aggregations chosen by the strategy) are not included.
+#[derive(Debug, Clone, Default)]
+pub struct TransferPlan {
+    pub transfers: Vec<PlannedTransfer>,
+    pub fused: Vec<Vec<usize>> // groups of Sum transfers (indices into `transfers`) emitted as one buffer by `Tensor::reduce_fused`, see `fuse_sums`
+}
+
+impl TransferPlan {
+    /// the planned transfers of output `index` of `node`
+    pub fn of_tensor(&self, node: usize, index: usize) -> impl Iterator<Item=&PlannedTransfer> {
+        self.transfers.iter().filter(move |x| x.node == node && x.index == index)
+    }
+
+    /// number of transfers and total bytes by conversion
+    pub fn summary(&self) -> BTreeMap<String, (usize, u64)> {
+        let mut summary: BTreeMap<String, (usize, u64)> = BTreeMap::new();
+        for transfer in self.transfers.iter() {
+            let entry = summary.entry(format!("{:?}", transfer.conversion)).or_default();
+            entry.0 += 1;
+            entry.1 += transfer.size;
+        }
+        summary
+    }
+}
+
+/// collect the conversions needed by the inputs of every node, in the order that `Graph::compile` would emit them. Requires the graph to be edited.
+pub fn plan_transfers(graph: &mut Graph) -> TransferPlan {
+    let mut plan = TransferPlan::default();
+    let mut seen: std::collections::BTreeSet<(usize, usize, Form)> = std::collections::BTreeSet::new();
+
+    for node_id in 0..graph.nodes.len() {
+        let devices = graph.nodes[node_id].form.devices.clone();
+        if devices.is_empty() {
+            continue
+        }
+        for (i, (input_id, index, _)) in graph.nodes[node_id].inputs.clone().into_iter().enumerate() {
+            let to = graph.nodes[node_id].input_form(i);
+            let tensor: &mut Tensor = graph.nodes[input_id].get_output(index);
+            if tensor.forms.contains_key(&to) || tensor.has_flag(Tensor::IS_FIXED) || !seen.insert((input_id, index, to.clone())) {
+                continue
+            }
+
+            let from = tensor.node().form.clone();
+            let conversion = plan_conversion(&from, &to, tensor.flags).unwrap_or_else(|e| panic!("cannot convert {} to {:?}: {}", tensor.node().graph().describe(&tensor.original_name()), to, e));
+            plan.transfers.push(PlannedTransfer {
+                intermediates: intermediates(conversion, &from, &to),
+                node: input_id, index, from, to, conversion, size: tensor.get_size()
+            })
+        }
+    }
+
+    if graph.options.get("reuse_conversions").map(|x| x != "False").unwrap_or(true) {
+        reuse_aggregations(graph, &mut plan);
+    }
+    if let Some(bucket_size) = graph.options.get("fusion_bucket_size") {
+        fuse_sums(graph, &mut plan, bucket_size.parse().expect("fusion_bucket_size should be a number"));
+    }
+    plan
+}
+
+/// derive conversions from a full form that the tensor already needs instead of aggregating its parts again. When a tensor produced in
+/// parts is consumed in full somewhere (aggregated by the editor, or by a planned Concat, AllGather or Sum), its other full forms are
+/// broadcast from that one and its batched parts on a different number of devices are split from it. The planned aggregation is moved
+/// before the transfers that reuse it. Disabled by the option `reuse_conversions` = `False`.
+fn reuse_aggregations(graph: &mut Graph, plan: &mut TransferPlan) {
+    let mut anchors: BTreeMap<(usize, usize), (Form, Option<usize>)> = BTreeMap::new(); // the full form to reuse and the position of its planned transfer
+    for (i, transfer) in plan.transfers.iter().enumerate() {
+        let tensor: &mut Tensor = graph.nodes[transfer.node].get_output(transfer.index);
+        // the members of a SparseTensor are converted together, see `Tensor::sparse_concat`
+        if !transfer.from.is_part() || !transfer.to.is_full() || transfer.conversion == Conversion::Broadcast || tensor.has_flag(Tensor::IS_SPARSE) {
+            continue
+        }
+        anchors.entry((transfer.node, transfer.index)).or_insert_with(|| {
+            match tensor.forms.keys().find(|x| x.is_full()) { // provided by the editor
+                Some(form) => (form.clone(), None),
+                None => (transfer.to.clone(), Some(i))
+            }
+        });
+    }
+
+    let mut reused = 0;
+    for (i, transfer) in plan.transfers.iter_mut().enumerate() {
+        let (anchor, _) = match anchors.get(&(transfer.node, transfer.index)) {
+            Some(x) if x.1 != Some(i) => x,
+            _ => continue
+        };
+        let flags = graph.nodes[transfer.node].get_output(transfer.index).flags;
+        let conversion = match transfer.conversion {
+            Conversion::Concat | Conversion::AllGather | Conversion::Sum | Conversion::ShapeTotal => Conversion::Broadcast,
+            Conversion::Resplit | Conversion::AllToAll if transfer.from.ndev() != transfer.to.ndev() && flags & Tensor::IS_BATCHED != 0 => Conversion::Split,
+            Conversion::ReduceScatter => Conversion::Split, // the slices of the sum
+            _ => continue
+        };
+        debug!("{}:{} to {} reuses {} ({:?} instead of {:?})", graph.nodes[transfer.node].raw_node.name, transfer.index, transfer.to.code(),
+            anchor.code(), conversion, transfer.conversion);
+        transfer.from = anchor.clone();
+        transfer.conversion = conversion;
+        transfer.intermediates = intermediates(conversion, &transfer.from, &transfer.to);
+        reused += 1;
+    }
+
+    // emit each planned aggregation before the first transfer of the same tensor
+    let mut moved = std::collections::BTreeSet::new();
+    let mut transfers = Vec::with_capacity(plan.transfers.len());
+    for (i, transfer) in plan.transfers.iter().enumerate() {
+        if let Some((_, Some(position))) = anchors.get(&(transfer.node, transfer.index)) {
+            if moved.insert(*position) {
+                transfers.push(plan.transfers[*position].clone())
+            }
+            if *position == i {
+                continue
+            }
+        }
+        transfers.push(transfer.clone())
+    }
+    plan.transfers = transfers;
+    debug!("{} transfers reuse an aggregation of the same tensor", reused);
+}
+
+/// group the planned Sums of tensors smaller than `bucket_size` bytes (option `fusion_bucket_size`) that go between the same forms and have
+/// the same dtype, so each group sums one concatenated buffer instead of sending every tensor separately. Only tensors whose producers have
+/// the same depth (the longest path from a source) share a group: neither can then depend on a consumer of the other, so fusing never forms
+/// a cycle, and they tend to be ready at the same time. Buckets are filled in plan order, and a tensor joins at most one group.
+fn fuse_sums(graph: &mut Graph, plan: &mut TransferPlan, bucket_size: u64) {
+    let mut depth: Vec<Option<usize>> = vec![None; graph.nodes.len()]; // None for nodes in cycles, which are never fused
+    for id in graph.topological_order() {
+        let node = &graph.nodes[id];
+        depth[id] = Some(node.inputs.iter().map(|(x, _, _)| *x).chain(node.controls.iter().copied()).filter_map(|x| depth[x]).map(|x| x + 1).max().unwrap_or(0));
+    }
+
+    let mut groups: SumBuckets = BTreeMap::new();
+    let mut grouped = std::collections::BTreeSet::new();
+    for (i, transfer) in plan.transfers.iter().enumerate() {
+        let tensor: &mut Tensor = graph.nodes[transfer.node].get_output(transfer.index);
+        // tensors aggregated by the editor may already be fused there, see `editor::fuse_all_reduces`
+        if transfer.conversion != Conversion::Sum || transfer.size >= bucket_size || tensor.get_shape().is_empty() || !tensor.forms.is_empty() ||
+           !grouped.insert((transfer.node, transfer.index)) {
+            continue
+        }
+        let key = match depth[transfer.node] {
+            Some(depth) => (transfer.from.clone(), transfer.to.clone(), tensor.dtype().get_field_type() as i32, depth),
+            None => continue
+        };
+        let buckets = groups.entry(key).or_default();
+        match buckets.last_mut() {
+            Some(bucket) if bucket.iter().map(|x| x.1).sum::<u64>() + transfer.size <= bucket_size => bucket.push((i, transfer.size)),
+            _ => buckets.push(vec![(i, transfer.size)])
+        }
+    }
+
+    plan.fused = groups.into_values().flatten().filter(|x| x.len() > 1).map(|x| x.into_iter().map(|x| x.0).collect()).collect();
+    debug!("fused {} planned sums into {} buffers", plan.fused.iter().map(|x| x.len()).sum::<usize>(), plan.fused.len());
+}
+
+/// emit the NodeDefs of the planned conversions into the target and record the results in the form caches, so `Graph::compile` links to them.
+/// With a cache, conversions emitted by previous compilations are reused, except for those with collective ops: their group and instance keys
+/// are assigned per compilation and the collective control ordering needs their positions in the target (see `CollectiveState`), and sparse
+/// ones: they also record the forms of the other members of the SparseTensor, which a replay would not restore.
+pub fn emit_transfers(graph: &mut Graph, plan: &TransferPlan, target: &mut Target, mut cache: Option<&mut SubgraphCache>) {
+    let fused: BTreeMap<usize, &Vec<usize>> = plan.fused.iter().flat_map(|group| group.iter().map(move |i| (*i, group))).collect();
+    for (i, transfer) in plan.transfers.iter().enumerate() {
+        let tensor = graph.nodes[transfer.node].get_output(transfer.index);
+        if tensor.forms.contains_key(&transfer.to) {
+            continue
+        }
+
+        if let Some(group) = fused.get(&i) { // the whole group is emitted with its first member, bypassing the cache
+            let others: Vec<&Tensor> = group[1..].iter().map(|j| &*tensor.node().graph().nodes[plan.transfers[*j].node].get_output(plan.transfers[*j].index)).collect();
+            let results = tensor.reduce_fused(&others, &transfer.from, &transfer.to, target, Tensor::aggregate_sum);
+            for (j, names) in group.iter().zip(results) {
+                let member = &plan.transfers[*j];
+                graph.nodes[member.node].get_output(member.index).forms.insert(member.to.clone(), names);
+            }
+            continue
+        }
+
+        let key = format!("{}|{}|{}|{:?}", tensor.original_name(), transfer.from.code(), transfer.to.code(), transfer.conversion);
+        let cacheable = !matches!(transfer.conversion, Conversion::SparseSplit | Conversion::SparseConcat);
+        let names = match cache.as_mut().filter(|_| cacheable).and_then(|x| x.transfer(&key)) {
+            Some((names, nodes)) => {
+                cache.as_mut().unwrap().hits += 1;
+                for node in nodes {
+                    target.pb.node.push(node)
+                }
+                names
+            }
+            None => {
+                let start = target.pb.node.len();
+                let names = tensor.emit_conversion(transfer.conversion, &transfer.from, &transfer.to, target);
+                if let Some(cache) = cache.as_mut().filter(|_| cacheable) {
+                    cache.misses += 1;
+                    if target.pb.node.iter().skip(start).all(|x| !x.attr.contains_key("instance_key")) {
+                        cache.insert_transfer(key, names.clone(), target.pb.node.iter().skip(start).cloned().collect());
+                    }
+                }
+                names
+            }
+        };
+        tensor.forms.insert(transfer.to.clone(), names);
+    }
+    debug!("emitted {} planned transfers", plan.transfers.len());
+}
+
+fn intermediates(conversion: Conversion, from: &Form, to: &Form) -> Vec<usize> {
+    match conversion {
+        Conversion::Identity | Conversion::Broadcast | Conversion::AllGather | Conversion::AllToAll | Conversion::ReduceScatter => vec![],
+        Conversion::Split | Conversion::ShapeSlice | Conversion::SparseSplit => vec![from.devices[0]],
+        Conversion::Concat | Conversion::Sum | Conversion::ShapeTotal | Conversion::SparseConcat => vec![to.devices[0]],
+        Conversion::Resplit if from.ndev() == to.ndev() => vec![],
+        Conversion::Resplit => { // the concats of `Tensor::resplit`
+            let chunk = from.ndev() / gcd(from.ndev(), to.ndev());
+            if chunk == 1 { vec![] } else { from.devices.iter().step_by(chunk).copied().collect() }
+        }
+    }
+}
+
+pub fn gcd(mut a: usize, mut b: usize) -> usize {
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    a
+}
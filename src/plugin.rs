@@ -0,0 +1,135 @@
+//! Strategies implemented outside of this crate. In-process strategies implement `StrategyPlugin`. Strategies shipped as shared objects
+//! are loaded by `DynamicPlugin` and talk to the compiler through a versioned C ABI, so they can be written in any language and built
+//! against any version of tge that speaks the same ABI version. A plugin exports:
+//!
+//! ```text
+//! uint32_t tge_plugin_abi_version(void);      // must return ABI_VERSION
+//! const char *tge_plugin_name(void);           // a static, null-terminated name used in logs
+//! int32_t tge_plugin_strategy_v1(const PluginGraphV1 *graph, uint32_t *result, char *error, uint32_t error_capacity);
+//! ```
+//!
+//! `result` has one zero-initialized row of `ndev + 1` elements per node: the aggregation method followed by the number of replicas on
+//! each device, the same layout as the strategies passed through the Python API (the methods are numbered as in `strategy::METHODS`).
+//! Rows left all-zero are nodes without a decision.
+//! A non-zero return value is an error, described by the null-terminated message written into `error`.
+//! The pointers in `PluginGraphV1` are only valid during the call.
+
+use oh_my_rust::*;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use crate::graph::Graph;
+use crate::misc::Target;
+use crate::editor::Strategy;
+
+/// bumped whenever `PluginGraphV1` or the exported symbols change incompatibly
+pub const ABI_VERSION: u32 = 1;
+
+pub trait StrategyPlugin {
+    fn name(&self) -> &str;
+    fn strategy(&self, graph: &Graph, target: &Target) -> Result<Strategy, String>;
+}
+
+/// the graph as seen by plugins of ABI version 1
+#[repr(C)]
+pub struct PluginGraphV1 {
+    pub n_nodes: u32,
+    pub names: *const *const c_char,
+    pub ops: *const *const c_char,
+    pub input_offsets: *const u32, // n_nodes + 1 elements, the inputs of node i are inputs[input_offsets[i]..input_offsets[i+1]]
+    pub inputs: *const u32, // indices of the nodes that produce the inputs, control dependencies excluded
+    pub ndev: u32,
+    pub devices: *const *const c_char,
+    pub bandwidth: *const u64 // ndev * ndev elements, the i*ndev+j element is the bottleneck bandwidth of the path from i to j
+}
+
+type AbiVersionFn = unsafe extern fn() -> u32;
+type NameFn = unsafe extern fn() -> *const c_char;
+type StrategyV1Fn = unsafe extern fn(*const PluginGraphV1, *mut u32, *mut c_char, u32) -> i32;
+
+/// a strategy plugin loaded from a shared object. The library stays loaded as long as the plugin lives.
+pub struct DynamicPlugin {
+    name: String,
+    library: libloading::Library
+}
+
+impl DynamicPlugin {
+    pub fn load(path: &str) -> Result<Self, String> {
+        unsafe {
+            let library = libloading::Library::new(path).map_err(|e| format!("cannot load {}: {}", path, e))?;
+            let version = library.get::<AbiVersionFn>(b"tge_plugin_abi_version\0").map_err(|e| format!("{} is not a tge plugin: {}", path, e))?();
+            if version != ABI_VERSION {
+                return Err(format!("{} is built for plugin ABI version {}, but this tge speaks version {}", path, version, ABI_VERSION))
+            }
+            library.get::<StrategyV1Fn>(b"tge_plugin_strategy_v1\0").map_err(|e| format!("{} does not export a strategy: {}", path, e))?;
+            let name = match library.get::<NameFn>(b"tge_plugin_name\0") {
+                Ok(f) if !f().is_null() => CStr::from_ptr(f()).to_string_lossy().into_owned(),
+                _ => path.to_string()
+            };
+            info!("loaded strategy plugin {} from {}", name, path);
+            Ok(DynamicPlugin { name, library })
+        }
+    }
+}
+
+impl StrategyPlugin for DynamicPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn strategy(&self, graph: &Graph, target: &Target) -> Result<Strategy, String> {
+        let c_strings = |x: &mut dyn Iterator<Item=&str>| x.map(|x| CString::new(x).unwrap()).collect::<Vec<_>>();
+        let names = c_strings(&mut graph.nodes.iter().map(|x| &x.raw_node.name[..]));
+        let ops = c_strings(&mut graph.nodes.iter().map(|x| &x.raw_node.op[..]));
+        let devices = c_strings(&mut target.devices.iter().map(|x| &x[..]));
+        let pointers = |x: &[CString]| x.iter().map(|x| x.as_ptr()).collect::<Vec<_>>();
+        let (name_pointers, op_pointers, device_pointers) = (pointers(&names), pointers(&ops), pointers(&devices));
+
+        let mut input_offsets = vec![0];
+        let mut inputs = vec![];
+        for node in graph.nodes.iter() {
+            inputs.extend(node.inputs.iter().map(|(id, _, _)| *id as u32));
+            input_offsets.push(inputs.len() as u32)
+        }
+
+        let ndev = target.ndev();
+        let bandwidth: Vec<u64> = (0..ndev * ndev).map(|i| {
+            target.paths[i].iter().map(|link| target.links[*link]).min().unwrap_or(std::u64::MAX)
+        }).collect();
+
+        let input = PluginGraphV1 {
+            n_nodes: graph.nodes.len() as _,
+            names: name_pointers.as_ptr(),
+            ops: op_pointers.as_ptr(),
+            input_offsets: input_offsets.as_ptr(),
+            inputs: inputs.as_ptr(),
+            ndev: ndev as _,
+            devices: device_pointers.as_ptr(),
+            bandwidth: bandwidth.as_ptr()
+        };
+        let mut result = vec![0u32; graph.nodes.len() * (ndev + 1)];
+        let mut error = vec![0 as c_char; 1024];
+        let status = unsafe {
+            let f = self.library.get::<StrategyV1Fn>(b"tge_plugin_strategy_v1\0").unwrap();
+            f(&input, result.as_mut_ptr(), error.as_mut_ptr(), error.len() as _)
+        };
+        if status != 0 {
+            *error.last_mut().unwrap() = 0;
+            let message = unsafe { CStr::from_ptr(error.as_ptr()) }.to_string_lossy().into_owned();
+            return Err(format!("plugin {} failed with status {}: {}", self.name, status, message))
+        }
+
+        let mut strategy = Strategy::new();
+        for (node, row) in graph.nodes.iter().zip(result.chunks(ndev + 1)) {
+            if row[1..].iter().all(|x| *x == 0) {
+                continue
+            }
+            if row[0] as usize >= crate::strategy::METHODS.len() {
+                return Err(format!("plugin {} chose unknown aggregation method {} for {}", self.name, row[0], node.raw_node.name))
+            }
+            let devices = row[1..].iter().enumerate().flat_map(|(device_id, n)| std::iter::repeat(device_id).take(*n as usize)).collect();
+            strategy.insert(node.raw_node.name.clone(), (devices, row[0] as u8));
+        }
+        debug!("plugin {} decided {} of {} nodes", self.name, strategy.len(), graph.nodes.len());
+        Ok(strategy)
+    }
+}
@@ -3,6 +3,9 @@ use crate::misc::*;
 use crate::graph::*;
 use crate::proto::graph::GraphDef;
 use crate::proto::node_def::NodeDef;
+use std::collections::{BTreeMap, BTreeSet};
+
+type PackGroups = BTreeMap<(usize, usize, usize, i32), Vec<(String, Vec<i64>, u64)>>; // (from, to, depth, dtype) => [(tensor, shape, size)]
 
 // if we do not remove these, we need to modify this field so that it has the correct node name of replicated operators
 pub fn remove_collocation_hint(target: &mut Target) {
@@ -21,20 +24,414 @@ pub fn remove_shape_hint(target: &mut Target) {
     }
 }
 
+/// move the input pipeline (dataset and iterator ops) that feeds each GPU to the CPU on the same NUMA node as that GPU.
+/// Pipelines on GPUs without a known local CPU are left untouched.
+pub fn place_input_pipelines(target: &mut Target) {
+    let local_cpus: std::collections::HashMap<String, String> = target.devices.iter().enumerate()
+        .filter_map(|(i, x)| Some((x.clone(), target.cpus[target.local_cpu(i)?].name.clone()))).collect();
+    for node in target.pb.node.iter_mut() {
+        let is_pipeline = node.op.ends_with("Dataset") || node.op.ends_with("DatasetV2") || node.op.starts_with("Iterator") || node.op.starts_with("AnonymousIterator") || node.op == "MakeIterator";
+        if !is_pipeline {
+            continue
+        }
+        if let Some(cpu) = local_cpus.get(&node.device) {
+            node.device = cpu.clone()
+        }
+    }
+}
+
+pub const STREAM_ATTR: &str = "_tge_stream";
+pub const COMPUTE_STREAM: i64 = 0;
+pub const COLLECTIVE_STREAM: i64 = 1;
+
+/// tag the nodes that carry all-reduce traffic with a dedicated stream id so the runtime can overlap them with computation.
+/// The simulator also reads this attr to place such nodes on a separate queue of their device.
+pub fn assign_collective_streams(target: &mut Target) {
+    for node in target.pb.node.iter_mut() {
+        let is_collective = match &node.op[..] {
+            "NcclAllReduce" | "CollectiveReduce" | "CollectiveGather" => true,
+            _ => node.name.contains("/aux_ring/") && (node.op == "Add" || node.op == "Identity")
+        };
+        let stream = if is_collective { COLLECTIVE_STREAM } else { COMPUTE_STREAM };
+        node.attr.insert(STREAM_ATTR.into(), crate::proto::attr_value::AttrValue::new().apply(|x| x.set_i(stream)));
+    }
+}
+
+pub const MARKER_PREFIX: &str = "tge_marker";
+
+/// add a NoOp per device at the end of the forward pass, the backward pass and the gradient aggregation, named
+/// `tge_marker/device_{i}/{phase}_end`, so the step stats of a real run can be aligned with the phases of the simulation.
+/// Each marker depends on the last nodes of its phase on that device, and the sink replicas on the device depend on the markers so they get executed.
+/// Replicas of nodes under `gradients/` are the backward pass, aux nodes of gradients are the aggregation, other replicas except optimizers are the forward pass.
+/// Only nodes that the sinks already run are marked, so the init ops, the initializers and the save and restore ops do not become part of every step.
+pub fn insert_step_markers(target: &mut Target) {
+    const PHASES: [&str; 3] = ["forward", "backward", "aggregation"];
+    let device_dict: std::collections::HashMap<String, usize> = target.devices.iter().enumerate().map(|(i, x)| (x.clone(), i)).collect();
+    let get_s = |node: &NodeDef, attr: &str| node.attr.get(attr).map(|x| String::from_utf8_lossy(x.get_s()).into_owned());
+
+    // the ancestors of the sink replicas, including control dependencies
+    let dict: std::collections::HashMap<&str, Vec<&str>> = target.pb.node.iter().map(|node| {
+        (&node.name[..], node.input.iter().map(|x| crate::naming::parse_input(x).0).collect())
+    }).collect();
+    let mut reached = std::collections::HashSet::new();
+    let mut queue: std::collections::VecDeque<&str> = target.pb.node.iter()
+        .filter(|x| get_s(x, "_tge_origin").map(|x| target.sinks.contains(&x)).unwrap_or(false)).map(|x| &x.name[..]).collect();
+    while let Some(x) = queue.pop_front() {
+        if reached.insert(x) {
+            queue.extend(dict.get(x).into_iter().flatten());
+        }
+    }
+    let init_ops = target.init_ops();
+
+    let mut groups: BTreeMap<(usize, usize), Vec<usize>> = BTreeMap::new(); // (device, phase) => node ids
+    for (node_id, node) in target.pb.node.iter().enumerate() {
+        let device = match device_dict.get(&node.device) {
+            Some(x) => *x,
+            None => continue
+        };
+        if !reached.contains(&node.name[..]) || init_ops.contains(&node.name) || is_save_or_restore(node) {
+            continue
+        }
+        let phase = match (get_s(node, "_tge_belong_to"), get_s(node, "_tge_origin")) {
+            (None, Some(origin)) if target.sinks.contains(&origin) => continue, // the sinks will depend on the markers
+            (Some(belong_to), _) if belong_to.contains("gradients") => 2,
+            (Some(_), _) => continue,
+            (None, Some(origin)) if origin.starts_with("gradients") || origin.contains("/gradients") => 1,
+            (None, Some(_)) if optimizer_inputs(&node.op).is_none() => 0,
+            _ => continue
+        };
+        groups.entry((device, phase)).or_default().push(node_id)
+    }
+
+    let mut markers: BTreeMap<usize, Vec<String>> = BTreeMap::new(); // device => marker names
+    let mut new_nodes = vec![];
+    for ((device, phase), members) in groups {
+        let names: BTreeSet<&str> = members.iter().map(|&i| &target.pb.node[i].name[..]).collect();
+        let consumed: BTreeSet<&str> = members.iter().flat_map(|&i| target.pb.node[i].input.iter())
+            .map(|x| crate::naming::parse_input(x).0).filter(|x| names.contains(x)).collect();
+
+        let mut marker = NodeDef::new();
+        marker.name = format!("{}/device_{}/{}_end", MARKER_PREFIX, device, PHASES[phase]);
+        marker.op = "NoOp".into();
+        marker.device = target.devices[device].clone();
+        marker.input = names.difference(&consumed).map(|x| format!("^{}", x)).collect();
+        markers.entry(device).or_default().push(marker.name.clone());
+        new_nodes.push(marker)
+    }
+
+    let mut nsinks = 0;
+    let sinks = &target.sinks;
+    for node in target.pb.node.iter_mut() {
+        let is_sink = get_s(node, "_tge_origin").map(|x| sinks.contains(&x)).unwrap_or(false);
+        if let (true, Some(names)) = (is_sink, device_dict.get(&node.device).and_then(|x| markers.get(x))) {
+            node.input.extend(names.iter().map(|x| format!("^{}", x)));
+            nsinks += 1
+        }
+    }
+    if nsinks == 0 && !new_nodes.is_empty() {
+        warn!("no sink replica to attach the step markers, they run only if fetched")
+    }
+
+    info!("inserted {} step markers", new_nodes.len());
+    for node in new_nodes {
+        target.pb.node.push(node)
+    }
+}
+
+/// the ops of a `tf.train.Saver`, which are run on their own rather than as part of a step
+fn is_save_or_restore(node: &NodeDef) -> bool {
+    matches!(&node.op[..], "SaveV2" | "Save" | "SaveSlices" | "RestoreV2" | "Restore" | "RestoreSlice" | "MergeV2Checkpoints") || node.name.starts_with("save/")
+}
+
+/// find transfers between different devices that have no path in the topology. If `hub` is given, insert an Identity on the hub device
+/// to relay each such tensor (shared by all consumers on the same destination). Otherwise, or if the hub cannot reach either end, report them.
+/// Control dependencies carry no data and are left alone either way.
+pub fn route_via_hub(target: &mut Target, hub: Option<usize>) -> Result<(), String> {
+    let n = target.ndev();
+    let device_dict: std::collections::HashMap<String, usize> = target.devices.iter().enumerate().map(|(i, x)| (x.clone(), i)).collect();
+    let node_dict: std::collections::HashMap<String, usize> = target.pb.node.iter().enumerate().map(|(i, x)| (x.name.clone(), i)).collect();
+    let reachable: Vec<bool> = (0..n * n).map(|x| x / n == x % n || !target.paths[x].is_empty()).collect();
+    let has_path = |from: usize, to: usize| reachable[from * n + to];
+
+    let mut unroutable = vec![];
+    let mut relays: std::collections::BTreeMap<String, String> = std::collections::BTreeMap::new(); // input string => relay identity
+    let mut new_nodes = vec![];
+    for node_id in 0..target.pb.node.len() {
+        let to = match device_dict.get(&target.pb.node[node_id].device) {
+            Some(x) => *x,
+            None => continue
+        };
+
+        for input_id in 0..target.pb.node[node_id].input.len() {
+            let input = target.pb.node[node_id].input[input_id].clone();
+            if input.starts_with('^') {
+                continue
+            }
+            let (name, index) = crate::naming::parse_input(&input);
+            let producer = &target.pb.node[node_dict[name]];
+            let from = match device_dict.get(&producer.device) {
+                Some(x) => *x,
+                None => continue
+            };
+            if has_path(from, to) {
+                continue
+            }
+
+            match hub {
+                Some(h) if has_path(from, h) && has_path(h, to) => {
+                    let relay = relays.entry(input.clone()).or_insert_with(|| {
+                        let mut identity = NodeDef::new();
+                        identity.name = format!("{}/{}_aux_relay", name, index);
+                        identity.op = "Identity".into();
+                        identity.device = target.devices[h].clone();
+                        identity.attr.insert("T".into(), crate::graph::dtype_of(producer, index).unwrap_or_else(|| panic!("cannot determine dtype of {} to relay it", input)));
+                        identity.input.push(input.clone());
+                        if let Some(size) = target.pb.node[node_id].attr.get("_tge_input_sizes").and_then(|x| x.get_list().i.get(input_id)) {
+                            identity.attr.insert("_tge_input_sizes".into(), crate::proto::attr_value::AttrValue::new().apply(|x| x.mut_list().i.push(*size)));
+                        }
+                        new_nodes.push(identity);
+                        new_nodes.last().unwrap().name.clone()
+                    }).clone();
+                    target.pb.node[node_id].input[input_id] = relay;
+                }
+                _ => unroutable.push(format!("{} ({}) -> {} ({})", input, target.devices[from], target.pb.node[node_id].name, target.devices[to]))
+            }
+        }
+    }
+
+    for node in new_nodes {
+        target.pb.node.push(node)
+    }
+
+    if unroutable.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("{} transfers have no path in the topology{}, e.g. {}", unroutable.len(),
+            if hub.is_some() { " even via the hub" } else { " and there is no hub device" }, unroutable[..std::cmp::min(5, unroutable.len())].join(", ")))
+    }
+}
+
+/// pack tensors smaller than `threshold` bytes that go from the same device to the same device at the same graph depth into one transfer.
+/// Each group is flattened and concatenated on the source, split by the offset table (a SplitV size_splits const) on the destination and reshaped back.
+/// Only tensors with fully known static shapes are packed. The original tensor names of each group are recorded in the `_tge_coalesced` attr of its SplitV.
+pub fn coalesce_transfers(target: &mut Target, threshold: u64) {
+    use std::collections::HashMap;
+    use crate::proto::attr_value::AttrValue;
+    use crate::proto::types::DataType;
+
+    let device_dict: HashMap<&str, usize> = target.devices.iter().enumerate().map(|(i, x)| (&x[..], i)).collect();
+    let node_dict: HashMap<&str, usize> = target.pb.node.iter().enumerate().map(|(i, x)| (&x.name[..], i)).collect();
+    // the length of the longest path from a source, computed with an explicit stack since graphs can be deep. Inputs from NextIteration are
+    // the back edges of while loops and ignored, as are inputs missing in the graph (see `stats::depth`)
+    let inputs: Vec<Vec<usize>> = target.pb.node.iter().map(|node| node.input.iter().filter_map(|x| {
+        node_dict.get(crate::naming::parse_input(x).0).copied().filter(|id| target.pb.node[*id].op != "NextIteration" && target.pb.node[*id].op != "RefNextIteration")
+    }).collect()).collect();
+    let mut depth: Vec<Option<usize>> = vec![None; target.pb.node.len()];
+    for root in 0..target.pb.node.len() {
+        let mut stack = vec![root];
+        while let Some(&id) = stack.last() {
+            if depth[id].is_some() {
+                stack.pop();
+                continue
+            }
+            let inputs = &inputs[id];
+            let pending: Vec<usize> = inputs.iter().copied().filter(|x| depth[*x].is_none()).collect();
+            if pending.is_empty() {
+                depth[id] = Some(inputs.iter().map(|x| depth[*x].unwrap() + 1).max().unwrap_or(0));
+                stack.pop();
+            } else {
+                stack.extend(pending)
+            }
+        }
+    }
+
+    // the static shape of a compiled tensor of `size` bytes. For Part forms, the length along the split axis is derived from the size,
+    // which is annotated per replica (see `Tensor::part_sizes`), so uneven parts get their own length; None if it does not divide evenly.
+    let static_shape = |node: &NodeDef, index: usize, dtype: DataType, size: u64| -> Option<Vec<i64>> {
+        let mut shape: Vec<i64> = node.attr.get("_output_shapes")?.get_list().shape.get(index)?.dim.iter().map(|x| x.size).collect();
+        if shape.iter().any(|x| *x < 0) {
+            return None
+        }
+        let form = Form::from_code(std::str::from_utf8(node.attr.get("_tge_form")?.get_s()).ok()?);
+        if form.is_part() {
+            let len = *shape.get(form.axis)?;
+            let row = crate::graph::dtype_size(dtype)? * shape.iter().enumerate().filter(|(i, _)| *i != form.axis).map(|(_, x)| *x as u64).product::<u64>();
+            if row == 0 || size / row * row != size || size / row > len as u64 {
+                return None
+            }
+            shape[form.axis] = (size / row) as i64
+        }
+        Some(shape)
+    };
+
+    let mut groups: PackGroups = BTreeMap::new();
+    for node in target.pb.node.iter() {
+        let to = match device_dict.get(&node.device[..]) {
+            Some(x) => *x,
+            None => continue
+        };
+        for (i, input) in node.input.iter().enumerate() {
+            if input.starts_with('^') {
+                continue
+            }
+            let size = match node.attr.get("_tge_input_sizes").and_then(|x| x.get_list().i.get(i)) {
+                Some(x) if *x > 0 && (*x as u64) < threshold => *x as u64,
+                _ => continue
+            };
+            let (name, index) = crate::naming::parse_input(input);
+            let producer_id = match node_dict.get(name) {
+                Some(x) => *x,
+                None => continue
+            };
+            let producer = &target.pb.node[producer_id];
+            let from = match device_dict.get(&producer.device[..]) {
+                Some(x) if *x != to => *x,
+                _ => continue
+            };
+            let dtype = match crate::graph::dtype_of(producer, index) {
+                Some(x) => x.get_field_type(),
+                None => continue
+            };
+            let shape = match static_shape(producer, index, dtype, size) {
+                Some(x) => x,
+                None => continue
+            };
+            let tensor = format!("{}:{}", name, index);
+            let group = groups.entry((from, to, depth[producer_id].unwrap(), dtype as i32)).or_default();
+            if group.iter().all(|x| x.0 != tensor) {
+                group.push((tensor, shape, size))
+            }
+        }
+    }
+
+    let int32_const = |name: String, device: &str, values: &[i64], vector: bool| -> NodeDef {
+        let mut node = NodeDef::new();
+        node.name = name;
+        node.op = "Const".into();
+        node.device = device.to_string();
+        node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+        let tensor = crate::proto::tensor::TensorProto::new().apply(|x| {
+            x.set_dtype(DataType::DT_INT32);
+            x.set_tensor_shape(crate::proto::tensor_shape::TensorShapeProto::new().apply(|s| if vector {
+                s.set_dim([values.len()].iter().map(|n| crate::proto::tensor_shape::TensorShapeProto_Dim::new().apply(|d| d.size = *n as _)).collect())
+            }));
+            for v in values {
+                x.int_val.push(*v as _)
+            }
+        });
+        node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(tensor)));
+        node
+    };
+    let set_sizes = |node: &mut NodeDef, sizes: &[u64]| {
+        node.attr.insert("_tge_input_sizes".into(), AttrValue::new().apply(|x| x.mut_list().i = sizes.iter().map(|s| *s as i64).collect()));
+    };
+
+    let mut new_nodes = vec![];
+    let mut replace: BTreeMap<(usize, String), String> = BTreeMap::new(); // (destination, tensor) => unpacked tensor
+    let mut npacked = 0;
+    for ((from, to, d, dtype), tensors) in groups {
+        if tensors.len() < 2 {
+            continue
+        }
+        npacked += tensors.len();
+        let prefix = format!("tge_coalesce/{}_{}_{}_{}", from, to, d, dtype);
+        let (src, dst) = (target.devices[from].clone(), target.devices[to].clone());
+        let dtype_attr = AttrValue::new().apply(|x| x.set_field_type(protobuf::ProtobufEnum::from_i32(dtype).unwrap()));
+        let total: u64 = tensors.iter().map(|x| x.2).sum();
+
+        new_nodes.push(int32_const(format!("{}/flat_shape", prefix), &src, &[-1], true));
+        let mut concat = NodeDef::new();
+        concat.name = format!("{}/pack", prefix);
+        concat.op = "ConcatV2".into();
+        concat.device = src.clone();
+        for (i, (tensor, _, _)) in tensors.iter().enumerate() {
+            let mut flat = NodeDef::new();
+            flat.name = format!("{}/flat_{}", prefix, i);
+            flat.op = "Reshape".into();
+            flat.device = src.clone();
+            flat.input.push(tensor.clone());
+            flat.input.push(format!("{}/flat_shape", prefix));
+            flat.attr.insert("T".into(), dtype_attr.clone());
+            flat.attr.insert("Tshape".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            concat.input.push(flat.name.clone());
+            new_nodes.push(flat);
+        }
+        new_nodes.push(int32_const(format!("{}/pack_axis", prefix), &src, &[0], false));
+        concat.input.push(format!("{}/pack_axis", prefix));
+        concat.attr.insert("N".into(), AttrValue::new().apply(|x| x.set_i(tensors.len() as _)));
+        concat.attr.insert("T".into(), dtype_attr.clone());
+        concat.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+        new_nodes.push(concat);
+
+        let offsets: Vec<i64> = tensors.iter().map(|x| x.1.iter().product()).collect();
+        new_nodes.push(int32_const(format!("{}/offsets", prefix), &dst, &offsets, true));
+        new_nodes.push(int32_const(format!("{}/unpack_axis", prefix), &dst, &[0], false));
+        let mut split = NodeDef::new();
+        split.name = format!("{}/unpack", prefix);
+        split.op = "SplitV".into();
+        split.device = dst.clone();
+        split.input.push(format!("{}/pack", prefix));
+        split.input.push(format!("{}/offsets", prefix));
+        split.input.push(format!("{}/unpack_axis", prefix));
+        split.attr.insert("num_split".into(), AttrValue::new().apply(|x| x.set_i(tensors.len() as _)));
+        split.attr.insert("T".into(), dtype_attr.clone());
+        split.attr.insert("Tlen".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+        split.attr.insert("_tge_coalesced".into(), AttrValue::new().apply(|x| x.mut_list().s = tensors.iter().map(|t| t.0.as_bytes().to_vec()).collect()));
+        set_sizes(&mut split, &[total]);
+        new_nodes.push(split);
+
+        for (i, (tensor, shape, size)) in tensors.iter().enumerate() {
+            new_nodes.push(int32_const(format!("{}/shape_{}", prefix, i), &dst, shape, true));
+            let mut unflat = NodeDef::new();
+            unflat.name = format!("{}/unpacked_{}", prefix, i);
+            unflat.op = "Reshape".into();
+            unflat.device = dst.clone();
+            unflat.input.push(format!("{}/unpack:{}", prefix, i));
+            unflat.input.push(format!("{}/shape_{}", prefix, i));
+            unflat.attr.insert("T".into(), dtype_attr.clone());
+            unflat.attr.insert("Tshape".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+            set_sizes(&mut unflat, &[*size]);
+            replace.insert((to, tensor.clone()), unflat.name.clone());
+            new_nodes.push(unflat);
+        }
+    }
+
+    for node in target.pb.node.iter_mut() {
+        let to = match device_dict.get(&node.device[..]) {
+            Some(x) => *x,
+            None => continue
+        };
+        for input in node.input.iter_mut() {
+            if input.starts_with('^') {
+                continue
+            }
+            let (name, index) = crate::naming::parse_input(input);
+            if let Some(x) = replace.get(&(to, format!("{}:{}", name, index))) {
+                *input = x.clone()
+            }
+        }
+    }
+
+    for node in new_nodes {
+        target.pb.node.push(node)
+    }
+    info!("coalesced {} small transfers into {} packs", npacked, replace.values().map(|x| x.rsplit_once('/').unwrap().0).collect::<BTreeSet<_>>().len());
+}
+
 pub fn remove_dangling_nodes(target: &mut Target) {
-    let sinks: Vec<_> = target.sinks.iter().map(|x| format!("{}/replica_0", x)).collect();
+    let scheme = crate::naming::ReplicaScheme::of_graph(&target.pb);
+    let mut sinks: Vec<_> = target.sinks.iter().map(|x| scheme.replica_name(x, 0)).collect();
+    for name in [crate::graph::Graph::AUDIT_NODE, crate::naming::ReplicaScheme::METADATA_NODE] {
+        if target.pb.node.iter().any(|x| x.name == name) {
+            sinks.push(name.to_string())
+        }
+    }
+    sinks.extend(target.pb.node.iter().filter(|x| x.name.starts_with(MARKER_PREFIX)).map(|x| x.name.clone()));
+    sinks.extend(target.init_ops());
 
     // note: don't forget control dependency
     let dict: std::collections::HashMap<_, Vec<_>> = target.pb.node.iter().map(|node| {
-        (&node.name[..], node.input.iter().map(|x| {
-            if x.starts_with('^') {
-                return &x[1..]
-            }
-            match x.find(':') {
-                Some(i) => &x[..i],
-                None => x
-            }
-        }).collect())
+        (&node.name[..], node.input.iter().map(|x| crate::naming::parse_input(x).0).collect())
     }).collect();
     let mut keep = std::collections::HashSet::new();
     let mut queue: std::collections::VecDeque<_> = sinks.iter().map(|x| &x[..]).collect();
@@ -52,12 +449,7 @@ pub fn remove_dangling_nodes(target: &mut Target) {
 }
 
 pub fn destruct_names(target: &mut Target) {
-    for node in target.pb.node.iter_mut() {
-        node.name = node.name.replace('/', "__");
-        for input in node.input.iter_mut() {
-            *input = input.replace('/', "__");
-        }
-    }
+    crate::naming::rename_nodes(&mut target.pb.node, |x| x.replace('/', "__"))
 }
 
 pub fn fuse_mini_batch(nodes: &[NodeDef], times: usize) -> Vec<NodeDef> {
@@ -79,3 +471,71 @@ pub fn fuse_mini_batch(nodes: &[NodeDef], times: usize) -> Vec<NodeDef> {
 
     todo!()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn remove_dangling_nodes_keeps_init_ops() {
+        let nodes = testing::linear_model(8, 4, 2);
+        let strategy = testing::replicate_all(&nodes, &[0, 1], 3);
+        let options = [("local_sgd_period", "4"), ("broadcast_op", "True"), ("broadcast_on_init", "True")];
+        let mut target = testing::compile(&nodes, testing::fake_target(2, 1000), &strategy, &options);
+        let init_ops = target.init_ops();
+        for name in [Graph::INIT_OP, Graph::BROADCAST_OP, Graph::INIT_BROADCAST_OP, Target::AUX_INIT_OP] {
+            assert!(init_ops.iter().any(|x| x == name), "{} is missing in the compiled graph", name)
+        }
+        assert!(target.aux_variables.iter().all(|x| init_ops.contains(&x.initializer)));
+
+        remove_dangling_nodes(&mut target);
+        for name in init_ops {
+            assert!(target.pb.node.iter().any(|x| x.name == name), "{} is removed", name)
+        }
+    }
+
+    #[test]
+    fn coalesce_transfers_terminates_on_while_loops() {
+        let nodes = testing::linear_model(8, 4, 2);
+        let strategy = testing::replicate_all(&nodes, &[0, 1], 3);
+        let mut target = testing::compile(&nodes, testing::fake_target(2, 1000), &strategy, &[]);
+        for (name, op, inputs) in [("loop/Merge", "Merge", &["loop/Enter", "loop/NextIteration"][..]), ("loop/NextIteration", "NextIteration", &["loop/Merge", "^missing"][..])] {
+            let mut node = NodeDef::new();
+            node.name = name.into();
+            node.op = op.into();
+            node.device = target.devices[0].clone();
+            for input in inputs {
+                node.input.push(input.to_string())
+            }
+            target.pb.node.push(node)
+        }
+        coalesce_transfers(&mut target, 1 << 20);
+    }
+
+    #[test]
+    fn step_markers_skip_the_initializers() {
+        let mut nodes = testing::linear_model(8, 4, 2);
+        let mut init = NodeDef::new();
+        init.name = "init".into();
+        init.op = "NoOp".into();
+        init.input.push("^dense/kernel/Assign".into());
+        init.input.push("^dense/bias/Assign".into());
+        nodes.push(init);
+        let strategy = testing::replicate_all(&nodes, &[0, 1], 3);
+        let mut target = testing::compile(&nodes, testing::fake_target(2, 1000), &strategy, &[]);
+        insert_step_markers(&mut target);
+
+        let dict: BTreeMap<&str, &NodeDef> = target.pb.node.iter().map(|x| (&x.name[..], x)).collect();
+        let mut queue: Vec<&str> = target.pb.node.iter().filter(|x| x.name.starts_with(MARKER_PREFIX)).map(|x| &x.name[..]).collect();
+        assert!(!queue.is_empty());
+        let mut visited = BTreeSet::new();
+        while let Some(x) = queue.pop() {
+            if visited.insert(x) {
+                let node = dict[x];
+                assert!(node.op != "Assign" && crate::naming::owner_of(node) != "init", "a step marker waits for {}", node.name);
+                queue.extend(node.input.iter().map(|x| crate::naming::parse_input(x).0));
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use oh_my_rust::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use crate::graph::Graph;
+use crate::misc::Target;
+use crate::strategy::{self, Strategy};
+
+/// a named bundle of all compilation knobs, so a cluster can have one blessed configuration shared as a file.
+/// The text format is one `key = value` per line, `#` starts a comment, and lines after `[options]` are passed to `Graph::options` verbatim:
+///
+/// ```text
+/// name = v100x8
+/// strategy = data_parallel   # or selective:<coverage>, or single
+/// collective = nccl          # ps, collective, ring, nccl, sync, hierarchical, tree, double_tree, cpu or cpu_ring
+/// replicas = 1,1,1,1         # replicas on each device, defaults to one per device
+/// bucket_size = 0            # bytes, 0 disables bucketing
+/// compression = none         # or fp16, which sums float gradients in half precision, topk, which sends their largest values, int8 or powersgd
+/// control_deps = chain       # chain collective instances in order, or none
+/// output = remove_collocation_hint,remove_shape_hint,collective_streams
+/// [options]
+/// hub_device = 0
+/// ```
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub strategy: StrategyKind,
+    pub method: u8, // see `editor::edit`
+    pub replicas: Vec<usize>, // empty means one replica per device
+    pub bucket_size: u64,
+    pub compression: String,
+    pub control_deps: bool,
+    pub output: Vec<String>, // polishing passes to run after compilation, in order
+    pub options: BTreeMap<String, String>
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StrategyKind {
+    DataParallel,
+    Selective(f64), // coverage
+    Single // everything on the first device
+}
+
+pub const OUTPUT_PASSES: [&str; 7] = ["remove_collocation_hint", "remove_shape_hint", "destruct_names", "remove_dangling_nodes", "collective_streams", "place_input_pipelines", "step_markers"];
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            name: "default".into(), strategy: StrategyKind::DataParallel, method: 0, replicas: vec![], bucket_size: 0,
+            compression: "none".into(), control_deps: true, output: vec![], options: BTreeMap::new()
+        }
+    }
+}
+
+impl Profile {
+    pub fn parse(text: &str) -> Result<Profile, String> {
+        let mut profile = Profile::default();
+        let mut in_options = false;
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue
+            }
+            if line == "[options]" {
+                in_options = true;
+                continue
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(i) => (line[..i].trim(), line[i+1..].trim()),
+                None => return Err(format!("line {}: expect `key = value`, got `{}`", lineno + 1, line))
+            };
+            let bad_value = || format!("line {}: invalid value `{}` for {}", lineno + 1, value, key);
+
+            if in_options {
+                profile.options.insert(key.to_string(), value.to_string());
+                continue
+            }
+
+            match key {
+                "name" => profile.name = value.to_string(),
+                "strategy" => profile.strategy = match value {
+                    "data_parallel" => StrategyKind::DataParallel,
+                    "single" => StrategyKind::Single,
+                    _ if value.starts_with("selective:") => StrategyKind::Selective(value[10..].parse().map_err(|_| bad_value())?),
+                    _ => return Err(bad_value())
+                },
+                "collective" => profile.method = strategy::parse_method(value).ok_or_else(bad_value)?,
+                "replicas" => profile.replicas = value.split(',').map(|x| x.trim().parse()).collect::<Result<_, _>>().map_err(|_| bad_value())?,
+                "bucket_size" => profile.bucket_size = value.parse().map_err(|_| bad_value())?,
+                "compression" => profile.compression = match value {
+                    "none" | "fp16" | "topk" | "int8" | "powersgd" => value.to_string(),
+                    _ => return Err(bad_value())
+                },
+                "control_deps" => profile.control_deps = match value {
+                    "chain" => true,
+                    "none" => false,
+                    _ => return Err(bad_value())
+                },
+                "output" => {
+                    profile.output = value.split(',').map(|x| x.trim().to_string()).filter(|x| !x.is_empty()).collect();
+                    if let Some(pass) = profile.output.iter().find(|x| !OUTPUT_PASSES.contains(&&x[..])) {
+                        return Err(format!("line {}: unknown output pass {}", lineno + 1, pass))
+                    }
+                }
+                _ => return Err(format!("line {}: unknown key {}", lineno + 1, key))
+            }
+        }
+        Ok(profile)
+    }
+
+    /// write the options that the compiler reads into the graph
+    pub fn apply_to(&self, graph: &mut Graph) {
+        info!("using compilation profile {}", self.name);
+        if self.bucket_size > 0 {
+            graph.options.insert("fusion_bucket_size".into(), self.bucket_size.to_string());
+        }
+        graph.options.insert("compression".into(), self.compression.clone());
+        graph.options.insert("collective_control_deps".into(), (if self.control_deps { "chain" } else { "none" }).into());
+        for (k, v) in self.options.iter() {
+            graph.options.insert(k.clone(), v.clone());
+        }
+    }
+
+    pub fn strategy(&self, graph: &Graph, ndev: usize) -> Strategy {
+        let devices: Vec<usize> = if self.replicas.is_empty() {
+            (0..ndev).collect()
+        } else {
+            self.replicas.iter().enumerate().flat_map(|(device_id, &n)| std::iter::repeat(device_id).take(n)).collect()
+        };
+
+        match self.strategy {
+            StrategyKind::DataParallel => graph.nodes.iter().map(|node| (node.raw_node.name.clone(), (devices.clone(), self.method))).collect(),
+            StrategyKind::Single => graph.nodes.iter().map(|node| (node.raw_node.name.clone(), (vec![0], 0))).collect(),
+            StrategyKind::Selective(coverage) => strategy::selective_replication(graph, None, ndev, coverage, self.method).into_iter().map(|(name, (replicated, method))| {
+                if replicated.len() > 1 { (name, (devices.clone(), method)) } else { (name, (replicated, method)) }
+            }).collect()
+        }
+    }
+
+    pub fn run_output_passes(&self, target: &mut Target) {
+        for pass in self.output.iter() {
+            match &pass[..] {
+                "remove_collocation_hint" => crate::polishing::remove_collocation_hint(target),
+                "remove_shape_hint" => crate::polishing::remove_shape_hint(target),
+                "destruct_names" => crate::polishing::destruct_names(target),
+                "remove_dangling_nodes" => crate::polishing::remove_dangling_nodes(target),
+                "collective_streams" => crate::polishing::assign_collective_streams(target),
+                "place_input_pipelines" => crate::polishing::place_input_pipelines(target),
+                "step_markers" => crate::polishing::insert_step_markers(target),
+                _ => unreachable!()
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "name = {}", self.name)?;
+        match self.strategy {
+            StrategyKind::DataParallel => writeln!(f, "strategy = data_parallel")?,
+            StrategyKind::Selective(coverage) => writeln!(f, "strategy = selective:{}", coverage)?,
+            StrategyKind::Single => writeln!(f, "strategy = single")?
+        }
+        writeln!(f, "collective = {}", ["ps", "collective", "ring", "nccl", "sync"].get(self.method as usize).copied().unwrap_or("ps"))?;
+        if !self.replicas.is_empty() {
+            let mut replicas = String::new();
+            for (i, n) in self.replicas.iter().enumerate() {
+                write!(replicas, "{}{}", if i == 0 { "" } else { "," }, n)?;
+            }
+            writeln!(f, "replicas = {}", replicas)?;
+        }
+        writeln!(f, "bucket_size = {}", self.bucket_size)?;
+        writeln!(f, "compression = {}", self.compression)?;
+        writeln!(f, "control_deps = {}", if self.control_deps { "chain" } else { "none" })?;
+        writeln!(f, "output = {}", self.output.join(","))?;
+        if !self.options.is_empty() {
+            writeln!(f, "[options]")?;
+            for (k, v) in self.options.iter() {
+                writeln!(f, "{} = {}", k, v)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use oh_my_rust::*;
+use std::collections::BTreeMap;
+use crate::proto::types::DataType;
+
+/// user-declared semantics of an op that the compiler has no built-in knowledge about
+#[derive(Debug, Clone)]
+pub struct CustomOp {
+    pub dtypes: Vec<DataType>, // dtype of each output
+    pub shapes: Vec<ShapeFn>, // shape of each output, only used when the node carries no `_output_shapes`
+    pub stateful: bool, // stateful ops are never replicated
+    pub splittable: bool // whether the op can run on a batch slice and produce the corresponding slice of output
+}
+
+#[derive(Debug, Clone)]
+pub enum ShapeFn {
+    Unknown,
+    Fixed(Vec<Option<usize>>),
+    SameAsInput(usize)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OpRegistry {
+    pub custom: BTreeMap<String, CustomOp>
+}
+
+impl OpRegistry {
+    pub fn get(&self, op: &str) -> Option<&CustomOp> {
+        self.custom.get(op)
+    }
+
+    /// parse a declaration line like `MyOp dtypes=1,3 shapes=in0;?x128 stateful=0 splittable=1`.
+    /// dtypes are the numeric values of DataType; shapes use `in<i>` for "same as the i-th input", `?` for unknown dimensions and `-` for totally unknown.
+    pub fn register(&mut self, spec: &str) {
+        let mut segs = spec.split_ascii_whitespace();
+        let name = segs.next().expect("empty custom op declaration").to_string();
+        let mut op = CustomOp { dtypes: vec![], shapes: vec![], stateful: false, splittable: false };
+
+        for seg in segs {
+            let (key, value) = match seg.find('=') {
+                Some(i) => (&seg[..i], &seg[i+1..]),
+                None => panic!("malformed field {} in declaration of {}", seg, name)
+            };
+
+            match key {
+                "dtypes" => op.dtypes = value.split(',').filter(|x| !x.is_empty()).map(|x| {
+                    let code = x.parse().unwrap();
+                    protobuf::ProtobufEnum::from_i32(code).unwrap_or_else(|| panic!("unknown dtype {} in declaration of {}", code, name))
+                }).collect(),
+                "shapes" => op.shapes = value.split(';').filter(|x| !x.is_empty()).map(parse_shape_fn).collect(),
+                "stateful" => op.stateful = value == "1",
+                "splittable" => op.splittable = value == "1",
+                _ => warn!("unknown field {} in declaration of {}", key, name)
+            }
+        }
+
+        if self.custom.insert(name.clone(), op).is_some() {
+            warn!("custom op {} is declared more than once, the last one wins", name)
+        }
+    }
+}
+
+fn parse_shape_fn(x: &str) -> ShapeFn {
+    if x == "-" {
+        ShapeFn::Unknown
+    } else if let Some(i) = x.strip_prefix("in") {
+        ShapeFn::SameAsInput(i.parse().unwrap())
+    } else if x == "scalar" {
+        ShapeFn::Fixed(vec![])
+    } else {
+        ShapeFn::Fixed(x.split('x').map(|d| d.parse().ok()).collect())
+    }
+}
@@ -0,0 +1,78 @@
+//! Support for the reproduction bundles of tge.py (`TGE.write_repro` and `python tge.py repro`), which let users report compiler bugs on
+//! models they cannot share: `anonymize` strips the contents of tensors and optionally the names from the graph, and `install_panic_hook`
+//! saves the message and backtrace of a panic to a file, since release builds abort the process before Python can see it.
+
+use oh_my_rust::*;
+use std::collections::BTreeMap;
+use crate::naming::Input;
+use crate::proto::attr_value::AttrValue;
+use crate::proto::node_def::NodeDef;
+use crate::proto::tensor::TensorProto;
+use crate::proto::types::DataType;
+
+/// integer tensors up to this many elements keep their values, as they are usually the shapes and axes that the compiler reads
+pub const KEPT_ELEMENTS: i64 = 16;
+
+/// a copy of the nodes with the contents of tensor attrs (e.g. the value of Consts) removed, keeping their dtypes and shapes so TF
+/// fills them with zeros, except small integer tensors (see `KEPT_ELEMENTS`). With `rename`, the nodes are named `node_<i>` in order,
+/// and the inputs, the colocation groups in `_class` and the frame names of while loops follow. Other string attrs are kept.
+pub fn anonymize(nodes: &[NodeDef], rename: bool) -> Vec<NodeDef> {
+    let names: BTreeMap<&str, String> = nodes.iter().enumerate().map(|(i, x)| (&x.name[..], format!("node_{}", i))).collect();
+    let mut frames: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+    let rename_node = |name: &str| names.get(name).cloned().unwrap_or_else(|| name.to_string());
+
+    nodes.iter().map(|node| {
+        let mut node = node.clone();
+        for value in node.attr.values_mut() {
+            if value.has_tensor() && !kept(value.get_tensor()) {
+                let tensor = value.get_tensor();
+                value.set_tensor(TensorProto::new().apply(|x| {
+                    x.set_dtype(tensor.get_dtype());
+                    x.set_tensor_shape(tensor.get_tensor_shape().clone());
+                    x.set_version_number(tensor.get_version_number())
+                }))
+            }
+        }
+        if !rename {
+            return node
+        }
+
+        node.name = rename_node(&node.name);
+        for input in node.input.iter_mut() {
+            let parsed = Input::parse(input);
+            *input = parsed.with_name(&rename_node(parsed.name))
+        }
+        if let Some(class) = node.attr.get_mut("_class") {
+            for x in class.mut_list().s.iter_mut() {
+                if let Some(name) = x.strip_prefix(b"loc:@") {
+                    *x = format!("loc:@{}", rename_node(&String::from_utf8_lossy(name))).into_bytes()
+                }
+            }
+        }
+        if let Some(frame) = node.attr.get_mut("frame_name") {
+            let n = frames.len();
+            let renamed = frames.entry(frame.get_s().to_vec()).or_insert_with(|| format!("frame_{}", n).into_bytes()).clone();
+            *frame = AttrValue::new().apply(|x| x.set_s(renamed))
+        }
+        node
+    }).collect()
+}
+
+fn kept(tensor: &TensorProto) -> bool {
+    let elements: i64 = tensor.get_tensor_shape().dim.iter().map(|x| x.size).product();
+    matches!(tensor.get_dtype(), DataType::DT_INT32 | DataType::DT_INT64) && elements <= KEPT_ELEMENTS
+}
+
+/// write the message, the location and the backtrace of the first panic to `path`, then run the previous hook
+pub fn install_panic_hook(path: std::path::PathBuf) {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let report = format!("{}\n\n{}", info, std::backtrace::Backtrace::force_capture());
+        if !path.exists() {
+            if let Err(e) = std::fs::write(&path, report) {
+                warn!("cannot write the panic to {}: {}", path.display(), e)
+            }
+        }
+        previous(info)
+    }))
+}
@@ -0,0 +1,77 @@
+//! Seeded pseudo-random numbers for the stochastic parts of the compiler: the moves of the strategy searches, the initial factors of
+//! PowerSGD and the inputs of the smoke tests. All of them derive their streams from one `RngConfig` (the option `seed`), each component
+//! by its own name, so adding draws to one component does not shift the others. The generator is xorshift64 on integers only, so a seed
+//! gives the same numbers on every machine. An explicit seed is recorded in the `tge_metadata` node of the compiled graph, see `of_graph`.
+
+use std::collections::BTreeMap;
+use crate::proto::graph::GraphDef;
+
+pub const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RngConfig {
+    pub seed: u64
+}
+
+impl Default for RngConfig {
+    fn default() -> Self {
+        RngConfig { seed: DEFAULT_SEED }
+    }
+}
+
+impl RngConfig {
+    pub fn new(seed: u64) -> Self {
+        RngConfig { seed }
+    }
+
+    pub fn from_options(options: &BTreeMap<String, String>) -> Self {
+        match options.get("seed") {
+            Some(seed) => Self::new(seed.parse().unwrap_or_else(|_| panic!("seed should be an unsigned 64-bit integer, got {}", seed))),
+            None => Self::default()
+        }
+    }
+
+    /// the seed that a compiled graph was emitted with, the default one if none is recorded
+    pub fn of_graph(graph: &GraphDef) -> Self {
+        let seed = graph.node.iter().find(|x| x.name == crate::naming::ReplicaScheme::METADATA_NODE).and_then(|node| {
+            let record = String::from_utf8_lossy(node.attr.get("value")?.get_tensor().string_val.first()?).into_owned();
+            let start = record.find("\"seed\": ")? + "\"seed\": ".len();
+            record[start..].split(|c: char| !c.is_ascii_digit()).next()?.parse().ok()
+        });
+        seed.map(Self::new).unwrap_or_default()
+    }
+
+    /// the stream of the component `name`, e.g. `search` or the tensor of a PowerSGD factor
+    pub fn stream(&self, name: &str) -> Rng {
+        let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100_0000_01b3)); // FNV-1a
+        // splitmix64 finalizer, so similar seeds and names give unrelated streams
+        let mut state = (self.seed ^ hash).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state = (state ^ (state >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        state = (state ^ (state >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        state ^= state >> 31;
+        Rng { state: if state == 0 { DEFAULT_SEED } else { state } } // xorshift never leaves zero
+    }
+}
+
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn next_u64(&mut self) -> u64 { // xorshift
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// uniform in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// uniform in [0, 1) with 52 bits of precision
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 12) as f64 / (1u64 << 52) as f64
+    }
+}
@@ -0,0 +1,222 @@
+//! Run graphs with the TensorFlow C API. Only available with the `tensorflow` feature.
+//! This is a smoke test for the compiler: the compiled graph is executed on virtual CPU devices (one per target device)
+//! and the fetched values are compared with those of the original graph. Ops without CPU kernels (e.g. NcclAllReduce) cannot be tested this way.
+
+use oh_my_rust::*;
+use protobuf::Message;
+use std::collections::BTreeMap;
+use tensorflow::{Graph, ImportGraphDefOptions, Session, SessionOptions, SessionRunArgs, Tensor, Operation};
+use crate::proto::{graph::GraphDef, types::DataType};
+
+pub enum Feed {
+    Float(Tensor<f32>),
+    Int32(Tensor<i32>),
+    Int64(Tensor<i64>)
+}
+
+/// deterministic inputs for every Placeholder of the original graph. Unknown dimensions are filled with `batchsize`.
+/// Float inputs are pseudo-random in [0, 1) from the `feeds` stream of `rng`, integer inputs (usually labels) are zeros.
+pub fn make_feeds(original: &GraphDef, batchsize: usize, rng: &crate::rng::RngConfig) -> Result<BTreeMap<String, Feed>, String> {
+    let mut rng = rng.stream("feeds");
+    let mut next = move || rng.unit() as f32;
+
+    original.node.iter().filter(|x| x.op == "Placeholder").map(|node| {
+        let dims: Vec<u64> = node.attr.get("shape").ok_or_else(|| format!("placeholder {} has no shape", node.name))?
+            .get_shape().dim.iter().map(|x| if x.size < 0 { batchsize as u64 } else { x.size as u64 }).collect();
+        let n = dims.iter().product::<u64>() as usize;
+        let feed = match node.attr["dtype"].get_field_type() {
+            DataType::DT_FLOAT => Feed::Float(Tensor::new(&dims).with_values(&(0..n).map(|_| next()).collect::<Vec<_>>()).map_err(|e| e.to_string())?),
+            DataType::DT_INT32 => Feed::Int32(Tensor::new(&dims)),
+            DataType::DT_INT64 => Feed::Int64(Tensor::new(&dims)),
+            dtype => return Err(format!("unsupported dtype {:?} of placeholder {}", dtype, node.name))
+        };
+        Ok((node.name.clone(), feed))
+    }).collect()
+}
+
+/// ConfigProto { device_count: { "CPU": ncpu }, allow_soft_placement: false }, encoded by hand since we do not have the generated code of config.proto
+fn session_config(ncpu: usize) -> Vec<u8> {
+    let mut entry = vec![0x0a, 3, b'C', b'P', b'U', 0x10];
+    let mut n = ncpu as u64;
+    while n >= 0x80 {
+        entry.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    entry.push(n as u8);
+    vec![0x0a, entry.len() as u8].apply(|x| x.extend(entry))
+}
+
+/// import the graph and run `init` (if any) and then one step, returning the values of `fetches`
+pub fn run(pb: &GraphDef, ncpu: usize, init: Option<&str>, feeds: &BTreeMap<String, Feed>, fetches: &[String]) -> Result<Vec<Tensor<f32>>, String> {
+    let mut graph = Graph::new();
+    graph.import_graph_def(&pb.write_to_bytes().map_err(|e| e.to_string())?, &ImportGraphDefOptions::new()).map_err(|e| e.to_string())?;
+
+    let mut options = SessionOptions::new();
+    options.set_config(&session_config(ncpu)).map_err(|e| e.to_string())?;
+    let session = Session::new(&options, &graph).map_err(|e| e.to_string())?;
+
+    if let Some(init) = init {
+        let mut args = SessionRunArgs::new();
+        args.add_target(&graph.operation_by_name_required(init).map_err(|e| e.to_string())?);
+        session.run(&mut args).map_err(|e| e.to_string())?;
+    }
+
+    let ops: Vec<(Operation, &Feed)> = feeds.iter().filter_map(|(name, feed)| match graph.operation_by_name(name) {
+        Ok(Some(op)) => Some((op, feed)),
+        _ => {
+            warn!("{} is not found in the graph and is not fed", name);
+            None
+        }
+    }).collect();
+
+    let mut args = SessionRunArgs::new();
+    for (op, feed) in ops.iter() {
+        match feed {
+            Feed::Float(x) => args.add_feed(op, 0, x),
+            Feed::Int32(x) => args.add_feed(op, 0, x),
+            Feed::Int64(x) => args.add_feed(op, 0, x)
+        }
+    }
+    let tokens = fetches.iter().map(|name| {
+        let (name, index) = match name.rfind(':') {
+            Some(i) => (&name[..i], name[i+1..].parse().map_err(|_| format!("bad fetch {}", name))?),
+            None => (&name[..], 0)
+        };
+        let op = graph.operation_by_name_required(name).map_err(|e| e.to_string())?;
+        Ok(args.request_fetch(&op, index))
+    }).collect::<Result<Vec<_>, String>>()?;
+    session.run(&mut args).map_err(|e| e.to_string())?;
+
+    tokens.into_iter().map(|token| args.fetch::<f32>(token).map_err(|e| e.to_string())).collect()
+}
+
+/// map every device of the compiled graph to a virtual CPU. Returns the number of CPUs needed.
+pub fn to_cpu_devices(pb: &mut GraphDef) -> usize {
+    let mut devices: BTreeMap<String, usize> = BTreeMap::new();
+    for node in pb.node.iter_mut() {
+        let n = devices.len();
+        let id = *devices.entry(node.device.clone()).or_insert(n);
+        node.device = format!("/device:CPU:{}", id);
+    }
+    std::cmp::max(devices.len(), 1)
+}
+
+/// give every random op without seeds a fixed seed derived from its original name, so the replicas of an op generate the same values as the op in the original graph
+pub fn fix_seeds(pb: &mut GraphDef) {
+    for node in pb.node.iter_mut() {
+        if !node.attr.contains_key("seed2") || node.attr["seed"].get_i() != 0 || node.attr["seed2"].get_i() != 0 {
+            continue
+        }
+
+        let origin = node.attr.get("_tge_origin").map(|x| x.get_s().to_vec()).unwrap_or_else(|| node.name.as_bytes().to_vec());
+        let hash = origin.iter().fold(0x811c_9dc5u32, |h, b| (h ^ *b as u32).wrapping_mul(0x0100_0193)); // FNV-1a
+        node.attr.insert("seed".into(), crate::proto::attr_value::AttrValue::new().apply(|x| x.set_i(1)));
+        node.attr.insert("seed2".into(), crate::proto::attr_value::AttrValue::new().apply(|x| x.set_i((hash >> 1) as _)));
+    }
+}
+
+fn compare(expected: &[f32], actual: &[f32], tolerance: f32) -> Option<(usize, f32, f32)> {
+    expected.iter().zip(actual.iter()).enumerate().find(|(_, (x, y))| {
+        (*x - *y).abs() > tolerance * std::cmp::max_by(x.abs(), 1., |a, b| a.partial_cmp(b).unwrap()) || x.is_nan() != y.is_nan()
+    }).map(|(i, (x, y))| (i, *x, *y))
+}
+
+/// run one step of both graphs on the same inputs and compare the fetched values (e.g. losses) within a relative tolerance.
+/// The fetches are names in the original graph; they are looked up as replica 0 in the compiled graph.
+/// Random ops without seeds are given fixed seeds, so the initial values of variables are the same in the two graphs. The inputs are drawn
+/// with the seed recorded in the compiled graph.
+pub fn smoke_test(original: &GraphDef, compiled: &GraphDef, original_init: Option<&str>, fetches: &[String], batchsize: usize, tolerance: f32) -> Result<(), String> {
+    let feeds = make_feeds(original, batchsize, &crate::rng::RngConfig::of_graph(compiled))?;
+    let mut original = original.clone();
+    fix_seeds(&mut original);
+    let expected = run(&original, 1, original_init, &feeds, fetches)?;
+
+    let mut compiled = compiled.clone();
+    fix_seeds(&mut compiled);
+    let ncpu = to_cpu_devices(&mut compiled);
+    let scheme = crate::naming::ReplicaScheme::of_graph(&compiled);
+    let compiled_feeds = feeds.into_iter().map(|(name, feed)| (scheme.replica_name(&name, 0), feed)).collect();
+    let compiled_fetches: Vec<_> = fetches.iter().map(|x| crate::naming::rename_input(x, |name| scheme.replica_name(name, 0))).collect();
+    let init = if compiled.node.iter().any(|x| x.name == crate::graph::Graph::INIT_OP) { Some(crate::graph::Graph::INIT_OP) } else { None };
+    let actual = run(&compiled, ncpu, init, &compiled_feeds, &compiled_fetches)?;
+
+    for ((name, e), a) in fetches.iter().zip(expected).zip(actual) {
+        if e.len() != a.len() {
+            return Err(format!("{}: expect {} elements, got {}", name, e.len(), a.len()))
+        }
+        if let Some((i, x, y)) = compare(&e, &a, tolerance) {
+            return Err(format!("{}[{}]: expect {}, got {}", name, i, x, y))
+        }
+    }
+
+    Ok(())
+}
+
+/// the first tensor (in the order of the original graph) whose value in the compiled graph differs from the original one
+#[derive(Debug)]
+pub struct Divergence {
+    pub origin: String,
+    pub replica: String,
+    pub element: usize,
+    pub expected: f32,
+    pub actual: f32
+}
+
+/// the part of `tensor` that the first replica of a form split along `axis` holds, given the shape `part` of that replica. None if the
+/// shapes disagree on the other axes or the part is longer than the tensor.
+fn first_part(tensor: &Tensor<f32>, axis: usize, part: &[u64]) -> Option<Vec<f32>> {
+    let dims = tensor.dims();
+    if dims.len() != part.len() || axis >= dims.len() || part[axis] > dims[axis] || (0..dims.len()).any(|i| i != axis && dims[i] != part[i]) {
+        return None
+    }
+    let inner: u64 = dims[axis+1..].iter().product();
+    let outer: u64 = dims[..axis].iter().product();
+    Some((0..outer).flat_map(|i| {
+        let start = (i * dims[axis] * inner) as usize;
+        tensor[start..start + (part[axis] * inner) as usize].iter().copied()
+    }).collect())
+}
+
+/// run one step of both graphs and compare the first output of every float op that has a replica in the compiled graph.
+/// For ops in Part form, the first replica is compared with the corresponding slice of the original tensor along the split axis,
+/// whose length is that of the replica since the parts can be uneven (see `Tensor::uneven_slices`).
+/// Returns the first diverging tensor by `_tge_origin`, or an error message if the graphs cannot be run.
+pub fn check_equivalence(original: &GraphDef, compiled: &GraphDef, original_init: Option<&str>, batchsize: usize, tolerance: f32) -> Result<Option<Divergence>, String> {
+    let is_float = |node: &crate::proto::node_def::NodeDef| {
+        node.attr.get("T").or_else(|| node.attr.get("dtype")).map(|x| x.get_field_type() == DataType::DT_FLOAT).unwrap_or(false)
+    };
+
+    // (origin, replica name, form)
+    let scheme = crate::naming::ReplicaScheme::of_graph(compiled);
+    let candidates: Vec<(String, String, crate::graph::Form)> = original.node.iter().filter(|x| is_float(x) && x.op != "Placeholder").filter_map(|node| {
+        let replica = scheme.replica_name(&node.name, 0);
+        let compiled_node = compiled.node.iter().find(|x| x.name == replica)?;
+        let form = crate::graph::Form::from_code(std::str::from_utf8(compiled_node.attr.get("_tge_form")?.get_s()).ok()?);
+        Some((node.name.clone(), replica, form))
+    }).collect();
+
+    let feeds = make_feeds(original, batchsize, &crate::rng::RngConfig::of_graph(compiled))?;
+    let mut original = original.clone();
+    fix_seeds(&mut original);
+    let expected = run(&original, 1, original_init, &feeds, &candidates.iter().map(|x| x.0.clone()).collect::<Vec<_>>())?;
+
+    let mut compiled = compiled.clone();
+    fix_seeds(&mut compiled);
+    let ncpu = to_cpu_devices(&mut compiled);
+    let compiled_feeds = feeds.into_iter().map(|(name, feed)| (scheme.replica_name(&name, 0), feed)).collect();
+    let init = if compiled.node.iter().any(|x| x.name == crate::graph::Graph::INIT_OP) { Some(crate::graph::Graph::INIT_OP) } else { None };
+    let actual = run(&compiled, ncpu, init, &compiled_feeds, &candidates.iter().map(|x| x.1.clone()).collect::<Vec<_>>())?;
+
+    for ((origin, replica, form), (e, a)) in candidates.into_iter().zip(expected.into_iter().zip(actual)) {
+        let e = if form.is_part() { first_part(&e, form.axis, a.dims()) } else { Some(e.to_vec()) };
+        let e = match e {
+            Some(e) if e.len() == a.len() => e,
+            e => return Ok(Some(Divergence { origin, replica, element: std::cmp::min(e.map(|x| x.len()).unwrap_or(0), a.len()), expected: f32::NAN, actual: f32::NAN }))
+        };
+        if let Some((element, expected, actual)) = compare(&e, &a, tolerance) {
+            return Ok(Some(Divergence { origin, replica, element, expected, actual }))
+        }
+    }
+
+    Ok(None)
+}
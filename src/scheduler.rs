@@ -10,6 +10,9 @@ use crate::proto::attr_value::{AttrValue, AttrValue_oneof_value};
 use crate::proto::node_def::NodeDef;
 use crate::proto::tensor::TensorProto;
 use crate::simulator::{GRPC_LATENCY, FALLBACK_NCCL_MODEL};
+use crate::naming::parse_input;
+
+type Transfers = BTreeMap<(String, usize), (u64, Vec<(usize, usize)>)>; // (tensor, destination) => (size, consumers as (node id, input id))
 
 pub fn heft_control(target: &mut Target, profiler: &impl Profiler) {
     heft_rank(target, profiler, true);
@@ -34,7 +37,8 @@ pub fn heft_control(target: &mut Target, profiler: &impl Profiler) {
 
 pub fn heft_rank(target: &mut Target, profiler: &impl Profiler, break_tie: bool) {
     let name_dict: BTreeMap<String, usize> = target.pb.node.iter().enumerate().map(|(i, x)| (x.name.clone(), i)).collect();
-    let device_dict: BTreeMap<&String, usize> = target.devices.iter().enumerate().map(|(i, x)| (x, i)).collect();
+    let mut device_dict: BTreeMap<String, usize> = target.host_cpus(); // nodes on host CPUs are ranked with the GPUs they serve
+    device_dict.extend(target.devices.iter().enumerate().map(|(i, x)| (x.clone(), i)));
     let mut ranks = vec![Option::<u64>::None; name_dict.len()];
     let mut succs = vec![BTreeSet::<usize>::new(); name_dict.len()];
 
@@ -77,27 +81,14 @@ pub fn heft_rank(target: &mut Target, profiler: &impl Profiler, break_tie: bool)
     }
 }
 
-fn parse_input(x: &str) -> (&str, usize) {
-    match x.find(':') {
-        Some(i) => (&x[..i], x[i+1..].parse().unwrap()),
-        None => (x, 0)
-    }
-}
-
 pub fn mark_non_dangling_nodes(target: &Target) -> std::collections::HashSet<String> {
-    let sinks: Vec<_> = target.sinks.iter().map(|x| format!("{}/replica_0", x)).collect();
+    let scheme = crate::naming::ReplicaScheme::of_graph(&target.pb);
+    let mut sinks: Vec<_> = target.sinks.iter().map(|x| scheme.replica_name(x, 0)).collect();
+    sinks.extend(target.init_ops());
 
     // note: don't forget control dependency
     let dict: std::collections::HashMap<_, Vec<_>> = target.pb.node.iter().map(|node| {
-        (&node.name[..], node.input.iter().map(|x| {
-            if x.starts_with('^') {
-                return &x[1..]
-            }
-            match x.find(':') {
-                Some(i) => &x[..i],
-                None => x
-            }
-        }).collect())
+        (&node.name[..], node.input.iter().map(|x| parse_input(x).0).collect())
     }).collect();
     let mut keep = std::collections::HashSet::new();
     let mut queue: std::collections::VecDeque<_> = sinks.iter().map(|x| &x[..]).collect();
@@ -110,3 +101,121 @@ pub fn mark_non_dangling_nodes(target: &Target) -> std::collections::HashSet<Str
 
     keep
 }
+
+/// stagger the large cross-device transfers that share a bottleneck link, so they go one after another in the order of HEFT rank instead of
+/// bursting at once. Each transfer of at least `threshold` bytes is gated by an Identity on the source device that control-depends on the arrival
+/// of the transfer `max_inflight` positions earlier on the same link, and arrives at an Identity on the destination that its consumers read.
+/// The ranks are strictly decreasing along paths, so the gates never wait for their own descendants. The result is kept only if the simulator
+/// does not predict a slower step. Returns the number of gated transfers.
+pub fn smooth_transfers(target: &mut Target, profiler: &impl Profiler, threshold: u64, max_inflight: usize) -> usize {
+    assert!(max_inflight > 0, "max_inflight should be positive");
+    heft_rank(target, profiler, true);
+
+    let n = target.ndev();
+    let name_dict: HashMap<String, usize> = target.pb.node.iter().enumerate().map(|(i, x)| (x.name.clone(), i)).collect();
+    let device_dict: HashMap<String, usize> = target.devices.iter().enumerate().map(|(i, x)| (x.clone(), i)).collect();
+    let rank_of = |node: &NodeDef| node.attr.get("_priority").map(|x| x.get_i()).unwrap_or(0);
+
+    let mut transfers: Transfers = BTreeMap::new(); // then grouped into `links` by the bottleneck link of their paths
+    for (node_id, node) in target.pb.node.iter().enumerate() {
+        let to = match device_dict.get(&node.device) {
+            Some(x) => *x,
+            None => continue
+        };
+        for (input_id, input) in node.input.iter().enumerate().filter(|(_, x)| !x.starts_with('^')) {
+            let from = match device_dict.get(&target.pb.node[name_dict[parse_input(input).0]].device) {
+                Some(x) => *x,
+                None => continue
+            };
+            let size = node.attr.get("_tge_input_sizes").and_then(|x| x.get_list().i.get(input_id)).copied().unwrap_or(0) as u64;
+            if from == to || size < threshold {
+                continue
+            }
+            let entry = transfers.entry((input.clone(), to)).or_insert((size, vec![]));
+            entry.1.push((node_id, input_id))
+        }
+    }
+
+    let mut links: BTreeMap<usize, Vec<(String, usize)>> = BTreeMap::new();
+    for (tensor, to) in transfers.keys() {
+        let from = device_dict[&target.pb.node[name_dict[parse_input(tensor).0]].device];
+        if let Some(link) = target.paths[from * n + to].iter().copied().min_by_key(|link| target.links[*link]) {
+            links.entry(link).or_default().push((tensor.clone(), *to))
+        }
+    }
+
+    let mut smoothed = Target::new(target.pb.clone(), target.devices.clone(), target.links.clone(), target.paths.clone(), target.sinks.clone(), target.nccls.clone());
+    let mut ngated = 0;
+    for (_, mut queue) in links.into_iter().filter(|(_, x)| x.len() > max_inflight) {
+        queue.sort_by_key(|(tensor, _)| cmp::Reverse(rank_of(&target.pb.node[name_dict[parse_input(tensor).0]])));
+        let mut arrivals: Vec<String> = vec![];
+        for (k, (tensor, to)) in queue.iter().enumerate() {
+            let (size, consumers) = &transfers[&(tensor.clone(), *to)];
+            let (name, index) = parse_input(tensor);
+            let producer = &target.pb.node[name_dict[name]];
+            let dtype = crate::graph::dtype_of(producer, index).unwrap_or_else(|| panic!("cannot determine dtype for {}", producer.op));
+
+            let mut gate = NodeDef::new();
+            gate.name = format!("{}/aux_smooth_{}_{}/gate", name, index, to);
+            gate.op = "Identity".into();
+            gate.device = producer.device.clone();
+            gate.attr.insert("T".into(), dtype.clone());
+            gate.input.push(tensor.clone());
+            if k >= max_inflight {
+                gate.input.push(format!("^{}", arrivals[k - max_inflight]));
+                ngated += 1
+            }
+            gate.attr.insert("_tge_input_sizes".into(), AttrValue::new().apply(|x| x.mut_list().i.push(*size as _)));
+
+            let mut arrival = NodeDef::new();
+            arrival.name = format!("{}/aux_smooth_{}_{}/arrival", name, index, to);
+            arrival.op = "Identity".into();
+            arrival.device = target.devices[*to].clone();
+            arrival.attr.insert("T".into(), dtype);
+            arrival.input.push(gate.name.clone());
+            arrival.attr.insert("_tge_input_sizes".into(), AttrValue::new().apply(|x| x.mut_list().i.push(*size as _)));
+
+            for (node_id, input_id) in consumers {
+                smoothed.pb.node[*node_id].input[*input_id] = arrival.name.clone()
+            }
+            arrivals.push(arrival.name.clone());
+            smoothed.pb.node.push(gate);
+            smoothed.pb.node.push(arrival);
+        }
+    }
+
+    if ngated == 0 {
+        return 0
+    }
+
+    use crate::simulator::Simulator;
+    let copy = |t: &Target| Target::new(t.pb.clone(), t.devices.clone(), t.links.clone(), t.paths.clone(), t.sinks.clone(), t.nccls.clone()).apply(|x| x.cpus = target.cpus.clone());
+    let before = crate::simulator::SimpleSimulator.evaluate::<std::fs::File>(profiler, copy(target), None, &mut vec![0; n]);
+    let after = crate::simulator::SimpleSimulator.evaluate::<std::fs::File>(profiler, copy(&smoothed), None, &mut vec![0; n]);
+    if after > before {
+        info!("smoothing {} transfers would slow the step from {} to {}, skipped", ngated, before, after);
+        return 0
+    }
+
+    info!("gated {} transfers, simulated step {} -> {}", ngated, before, after);
+    target.pb = smoothed.pb;
+    ngated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn init_ops_are_not_dangling() {
+        let nodes = testing::linear_model(8, 4, 2);
+        let strategy = testing::replicate_all(&nodes, &[0, 1], 3);
+        let target = testing::compile(&nodes, testing::fake_target(2, 1000), &strategy, &[("local_sgd_period", "4")]);
+        let kept = mark_non_dangling_nodes(&target);
+        assert!(!target.init_ops().is_empty());
+        for name in target.init_ops() {
+            assert!(kept.contains(&name), "{} is marked dangling", name)
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use oh_my_rust::*;
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+use crate::graph::Graph;
+use crate::misc::{Target, Profiler};
+use crate::editor::{self, Strategy};
+use crate::analysis::{self, Estimate};
+use crate::plan;
+use crate::rng::{Rng, RngConfig};
+
+/// the best plan found by `anytime_search` and its predicted metrics
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub strategy: Strategy,
+    pub estimate: Estimate,
+    pub evaluated: usize, // number of plans estimated, including the initial one
+    pub elapsed: Duration
+}
+
+/// local search from `initial` that can be stopped at any time: it keeps improving the plan until the wall-clock `budget` runs out and then
+/// returns the best plan so far, so a larger budget trades compile time for plan quality. Plans are compared by `analysis::estimate`, and
+/// those whose predicted memory exceeds `memory_limit` on any device rank after all plans that fit. A move changes the decision of one group
+/// (or one ungrouped node), see `Moves::mutate`, drawn from the `search` stream of `rng`: runs with the same seed try the same moves, so
+/// they find the same plan as long as they evaluate the same number of plans. The option `search_max_evaluations` stops the search after that
+/// many plans even if the budget is not used up, which makes the result independent of the speed of the machine. The graph is left unedited.
+pub fn anytime_search(graph: &mut Graph, target: &Target, profiler: &impl Profiler, initial: Strategy, budget: Duration, memory_limit: Option<u64>, rng: &RngConfig) -> SearchResult {
+    let start = Instant::now();
+    let mut moves = Moves::new(graph, &initial, target.ndev(), rng.stream("search"));
+    let max_evaluations = max_evaluations(graph);
+
+    let score = |estimate: &Estimate| {
+        let overflow = memory_limit.map(|limit| estimate.memory.iter().map(|x| x.saturating_sub(limit)).sum::<u64>()).unwrap_or(0);
+        (overflow, estimate.time)
+    };
+
+    let mut best_estimate = evaluate(graph, target, profiler, &initial);
+    let mut best = initial;
+    let mut evaluated = 1;
+
+    while !moves.units.is_empty() && start.elapsed() < budget && evaluated < max_evaluations {
+        let candidate = match moves.mutate(graph, &best) {
+            Some(x) => x,
+            None => continue
+        };
+        let estimate = evaluate(graph, target, profiler, &candidate);
+        evaluated += 1;
+        if score(&estimate) < score(&best_estimate) {
+            debug!("search: {} -> {} after {} plans", best_estimate.time, estimate.time, evaluated);
+            best = candidate;
+            best_estimate = estimate;
+        }
+    }
+
+    editor::reset(graph);
+    info!("search evaluated {} plans in {:?}, the best one is predicted to take {}", evaluated, start.elapsed(), best_estimate.time);
+    SearchResult { strategy: best, estimate: best_estimate, evaluated, elapsed: start.elapsed() }
+}
+
+/// plans that no other plan found beats in all of step time, peak memory and network traffic, each with its predicted metrics
+#[derive(Debug, Clone)]
+pub struct ParetoFront {
+    pub plans: Vec<(Strategy, Estimate)>, // sorted by step time
+    pub evaluated: usize,
+    pub elapsed: Duration
+}
+
+impl ParetoFront {
+    pub fn fastest(&self) -> &(Strategy, Estimate) {
+        &self.plans[0]
+    }
+
+    pub fn least_memory(&self) -> &(Strategy, Estimate) {
+        self.plans.iter().min_by_key(|(_, estimate)| (peak(estimate), estimate.time)).unwrap()
+    }
+
+    pub fn least_traffic(&self) -> &(Strategy, Estimate) {
+        self.plans.iter().min_by_key(|(_, estimate)| (estimate.traffic, estimate.time)).unwrap()
+    }
+}
+
+/// like `anytime_search`, but keeps every plan that is not dominated in (step time, peak memory, network traffic) instead of only the fastest,
+/// for users to pick by constraints that the cost model cannot see. Each move starts from a random plan of the front. Plans above
+/// `memory_limit` are kept only until one that fits is found. `rng` and `search_max_evaluations` work as in `anytime_search`.
+pub fn pareto_search(graph: &mut Graph, target: &Target, profiler: &impl Profiler, initial: Strategy, budget: Duration, memory_limit: Option<u64>, rng: &RngConfig) -> ParetoFront {
+    let start = Instant::now();
+    let mut moves = Moves::new(graph, &initial, target.ndev(), rng.stream("search"));
+    let max_evaluations = max_evaluations(graph);
+    let fits = |estimate: &Estimate| memory_limit.map(|limit| peak(estimate) <= limit).unwrap_or(true);
+    let objectives = |estimate: &Estimate| [estimate.time, peak(estimate), estimate.traffic];
+    let dominates = |a: &Estimate, b: &Estimate| {
+        let (a, b) = (objectives(a), objectives(b));
+        a.iter().zip(b.iter()).all(|(x, y)| x <= y) && a != b
+    };
+
+    let estimate = evaluate(graph, target, profiler, &initial);
+    let mut plans = vec![(initial, estimate)];
+    let mut evaluated = 1;
+
+    while !moves.units.is_empty() && start.elapsed() < budget && evaluated < max_evaluations {
+        let base = moves.next(plans.len());
+        let candidate = match moves.mutate(graph, &plans[base].0) {
+            Some(x) => x,
+            None => continue
+        };
+        let estimate = evaluate(graph, target, profiler, &candidate);
+        evaluated += 1;
+
+        let feasible = plans.iter().any(|(_, x)| fits(x));
+        if feasible && !fits(&estimate) || plans.iter().any(|(_, x)| dominates(x, &estimate) || objectives(x) == objectives(&estimate)) {
+            continue
+        }
+        if !feasible && fits(&estimate) {
+            plans.clear()
+        }
+        plans.retain(|(_, x)| !dominates(&estimate, x));
+        debug!("search: new plan on the front with time {}, peak memory {}, traffic {}", estimate.time, peak(&estimate), estimate.traffic);
+        plans.push((candidate, estimate));
+    }
+
+    editor::reset(graph);
+    plans.sort_by_key(|(_, estimate)| estimate.time);
+    info!("search evaluated {} plans in {:?}, {} of them are on the pareto front", evaluated, start.elapsed(), plans.len());
+    ParetoFront { plans, evaluated, elapsed: start.elapsed() }
+}
+
+/// what happens to a plan if one device disappears
+#[derive(Debug, Clone)]
+pub struct Contingency {
+    pub device: usize,
+    pub orphaned: Vec<String>, // nodes placed only on the device, which must move
+    pub lost_replicas: usize, // replicas on the device of nodes that keep other replicas
+    pub fallback: Option<usize>, // the surviving device that the orphaned nodes best move to, if any
+    pub repair: Option<Estimate>, // the estimate of the repaired plan. None if no device survives
+    pub fits: bool // whether the repaired plan stays within the memory limit
+}
+
+/// for each device of the target, predict the best repair of `strategy` if the device disappears: the replicas on it are dropped and the nodes
+/// that were only on it move together to the surviving device that gives the fastest repaired plan (those above `memory_limit` rank last).
+/// Returns the estimate of the intact plan and one contingency per device. Devices in the repaired estimates are numbered without the lost one.
+pub fn contingency_analysis(graph: &mut Graph, target: &Target, profiler: &impl Profiler, strategy: &Strategy, memory_limit: Option<u64>) -> (Estimate, Vec<Contingency>) {
+    let n = target.ndev();
+    let baseline = evaluate(graph, target, profiler, strategy);
+    let score = |estimate: &Estimate| (memory_limit.map(|limit| estimate.memory.iter().map(|x| x.saturating_sub(limit)).sum::<u64>()).unwrap_or(0), estimate.time);
+
+    let contingencies = (0..n).map(|lost| {
+        let surviving: Vec<usize> = (0..n).filter(|x| *x != lost).collect();
+        let orphaned: Vec<String> = strategy.iter().filter(|(_, (devices, _))| !devices.is_empty() && devices.iter().all(|x| *x == lost)).map(|(name, _)| name.clone()).collect();
+        let lost_replicas = strategy.values().filter(|(devices, _)| devices.iter().any(|x| *x != lost)).map(|(devices, _)| devices.iter().filter(|x| **x == lost).count()).sum();
+        if surviving.is_empty() {
+            return Contingency { device: lost, orphaned, lost_replicas, fallback: None, repair: None, fits: false }
+        }
+
+        let reduced = Target::new(target.pb.clone(), surviving.iter().map(|x| target.devices[*x].clone()).collect(), target.links.clone(),
+            surviving.iter().flat_map(|i| surviving.iter().map(move |j| target.paths[i * n + j].clone())).collect(), target.sinks.clone(), target.nccls.clone());
+        let profiler = Renumbered { inner: profiler, devices: &surviving };
+        let renumber = |x: usize| if x > lost { x - 1 } else { x };
+
+        let candidates = if orphaned.is_empty() { 1 } else { surviving.len() };
+        let (fallback, estimate) = (0..candidates).map(|fallback| {
+            let repaired: Strategy = strategy.iter().map(|(name, (devices, method))| {
+                let devices: Vec<usize> = devices.iter().filter(|x| **x != lost).map(|x| renumber(*x)).collect();
+                (name.clone(), (if devices.is_empty() { vec![fallback] } else { devices }, *method))
+            }).collect();
+            (fallback, evaluate(graph, &reduced, &profiler, &repaired))
+        }).min_by_key(|(_, estimate)| score(estimate)).unwrap();
+
+        debug!("losing device {} orphans {} nodes, the best repair takes {} instead of {}", lost, orphaned.len(), estimate.time, baseline.time);
+        let fits = score(&estimate).0 == 0;
+        let fallback = if orphaned.is_empty() { None } else { Some(surviving[fallback]) };
+        Contingency { device: lost, orphaned, lost_replicas, fallback, repair: Some(estimate), fits }
+    }).collect();
+
+    editor::reset(graph);
+    (baseline, contingencies)
+}
+
+/// a profiler for a target with some devices removed: device i of the target is device `devices[i]` of the profile
+struct Renumbered<'a, P: Profiler> {
+    inner: &'a P,
+    devices: &'a [usize]
+}
+
+impl<P: Profiler> Profiler for Renumbered<'_, P> {
+    fn profile(&self, node: &crate::proto::node_def::NodeDef, device_id: usize) -> Option<u64> {
+        self.inner.profile(node, self.devices[device_id])
+    }
+}
+
+/// the decisions that the searches change together: a group, or a node without group. Only nodes decided by the initial strategy are included.
+struct Moves {
+    units: Vec<Vec<usize>>,
+    methods: Vec<u8>, // the aggregation methods used in the initial strategy and AddN
+    ndev: usize,
+    rng: Rng
+}
+
+impl Moves {
+    fn new(graph: &Graph, initial: &Strategy, ndev: usize, rng: Rng) -> Self {
+        let mut units: Vec<Vec<usize>> = vec![];
+        let mut seen = BTreeSet::new();
+        for (id, node) in graph.nodes.iter().enumerate() {
+            if !initial.contains_key(&node.raw_node.name) || seen.contains(&id) {
+                continue
+            }
+            let unit: Vec<usize> = match &node.group {
+                Some(group) => group.borrow().iter().copied().filter(|x| initial.contains_key(&graph.nodes[*x].raw_node.name)).collect(),
+                None => vec![id]
+            };
+            seen.extend(unit.iter().copied());
+            units.push(unit)
+        }
+        let methods = initial.values().map(|(_, method)| *method).chain(Some(0)).collect::<BTreeSet<_>>().into_iter().collect();
+        Moves { units, methods, ndev, rng }
+    }
+
+    fn next(&mut self, n: usize) -> usize {
+        self.rng.below(n)
+    }
+
+    /// change the decision of a random unit: move it to a single device, replicate it on all devices, add or remove one replica,
+    /// or switch the aggregation method. None if the picked move does not apply.
+    fn mutate(&mut self, graph: &Graph, base: &Strategy) -> Option<Strategy> {
+        let ndev = self.ndev;
+        let i = self.next(self.units.len());
+        let unit = self.units[i].clone();
+        let (devices, method) = base[&graph.nodes[unit[0]].raw_node.name].clone();
+        let decision = match self.next(5) {
+            0 => (vec![self.next(ndev)], method),
+            1 => ((0..ndev).collect(), method),
+            2 => (devices.clone().apply(|x| { x.push(self.next(ndev)); x.sort() }), method),
+            3 if devices.len() > 1 => (devices[..devices.len() - 1].to_vec(), method),
+            4 if devices.len() > 1 => {
+                let m = self.next(self.methods.len());
+                (devices.clone(), self.methods[m])
+            }
+            _ => return None
+        };
+        if decision == (devices, method) {
+            return None
+        }
+
+        let mut candidate = base.clone();
+        for id in unit.iter() {
+            candidate.insert(graph.nodes[*id].raw_node.name.clone(), decision.clone());
+        }
+        Some(candidate)
+    }
+}
+
+fn max_evaluations(graph: &Graph) -> usize {
+    graph.options.get("search_max_evaluations").map(|x| x.parse().expect("search_max_evaluations should be a number")).unwrap_or(usize::MAX)
+}
+
+fn peak(estimate: &Estimate) -> u64 {
+    estimate.memory.iter().copied().max().unwrap_or(0)
+}
+
+fn evaluate(graph: &mut Graph, target: &Target, profiler: &impl Profiler, strategy: &Strategy) -> Estimate {
+    editor::reset(graph);
+    let mut target = Target::new(target.pb.clone(), target.devices.clone(), target.links.clone(), target.paths.clone(), target.sinks.clone(), target.nccls.clone()).apply(|x| {
+        x.mesh = target.mesh.clone();
+        x.cpus = target.cpus.clone()
+    });
+    editor::edit(graph, &mut target, &strategy.iter().map(|(name, decision)| (&name[..], decision.clone())).collect());
+    let plan = plan::plan_transfers(graph);
+    analysis::estimate(graph, &plan, &target, profiler)
+}
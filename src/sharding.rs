@@ -0,0 +1,118 @@
+use oh_my_rust::*;
+use std::collections::BTreeMap;
+use protobuf::{CodedInputStream, ProtobufResult};
+use crate::graph::*;
+
+/// the subset of xla.OpSharding that we understand
+#[derive(Debug, Default)]
+pub struct OpSharding {
+    pub kind: i32, // 0: REPLICATED, 1: MAXIMAL, 2: TUPLE, 3: OTHER
+    pub tile_assignment_dimensions: Vec<i64>,
+    pub tile_assignment_devices: Vec<i64>,
+    pub replicate_on_last_tile_dim: bool
+}
+
+impl OpSharding {
+    pub const REPLICATED: i32 = 0;
+    pub const MAXIMAL: i32 = 1;
+    pub const TUPLE: i32 = 2;
+    pub const OTHER: i32 = 3;
+
+    /// we do not have the generated code for xla_data.proto, so the few fields we need are decoded by hand
+    pub fn parse(bytes: &[u8]) -> ProtobufResult<Self> {
+        let mut result = OpSharding::default();
+        let mut unknown_fields = protobuf::UnknownFields::new();
+        let mut is = CodedInputStream::from_bytes(bytes);
+        while !is.eof()? {
+            let (field_number, wire_type) = is.read_tag_unpack()?;
+            match field_number {
+                1 => result.kind = is.read_int32()?,
+                3 => protobuf::rt::read_repeated_int64_into(wire_type, &mut is, &mut result.tile_assignment_dimensions)?,
+                4 => protobuf::rt::read_repeated_int64_into(wire_type, &mut is, &mut result.tile_assignment_devices)?,
+                6 => result.replicate_on_last_tile_dim = is.read_bool()?,
+                _ => protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, &mut is, &mut unknown_fields)?
+            }
+        }
+        Ok(result)
+    }
+
+    /// translate into a flat form on devices 0..ndev. Tiling along a single tensor dimension becomes a Part form along that axis. Layouts
+    /// that a flat form cannot express, i.e. tiling along several dimensions, partial replication (`replicate_on_last_tile_dim`) and
+    /// tiles assigned to devices out of order, are rejected with a warning.
+    pub fn to_form(&self, ndev: usize) -> Option<Form> {
+        let devices: Vec<usize> = self.tile_assignment_devices.iter().map(|x| *x as usize).collect();
+        if devices.iter().any(|x| *x >= ndev) {
+            warn!("sharding refers to device {} but there are only {} devices", devices.iter().max().unwrap(), ndev);
+            return None
+        }
+
+        match self.kind {
+            Self::REPLICATED => Some(Form { kind: FormKind::Full, devices: (0..ndev).collect(), axis: 0 }),
+            Self::MAXIMAL => devices.first().map(|d| Form { kind: FormKind::Full, devices: vec![*d], axis: 0 }),
+            Self::OTHER => {
+                let shape: Vec<usize> = self.tile_assignment_dimensions.iter().map(|x| *x as usize).collect();
+                let ntensor_dims = shape.len().saturating_sub(self.replicate_on_last_tile_dim as usize);
+                if shape[ntensor_dims..].iter().any(|x| *x > 1) {
+                    warn!("partially replicated sharding {:?} is not supported", shape);
+                    return None
+                }
+                let tiled: Vec<usize> = (0..ntensor_dims).filter(|i| shape[*i] > 1).collect();
+                match tiled[..] {
+                    [] => Some(Form { kind: FormKind::Full, devices: devices.apply(|x| x.sort_unstable()), axis: 0 }),
+                    [axis] if devices.windows(2).all(|x| x[0] <= x[1]) => Some(Form { kind: FormKind::Part, devices, axis }),
+                    [_] => {
+                        warn!("sharding assigns the tiles to devices {:?} out of order, which is not supported", devices);
+                        None
+                    }
+                    _ => {
+                        warn!("tiling along several dimensions {:?} is not supported", tiled);
+                        None
+                    }
+                }
+            }
+            _ => {
+                warn!("tuple sharding is not supported");
+                None
+            }
+        }
+    }
+}
+
+/// read `_XlaSharding` annotations of the input graph and translate them into forms, keyed by node name
+pub fn import(graph: &Graph, ndev: usize) -> BTreeMap<String, Form> {
+    graph.nodes.iter().filter_map(|node| {
+        let attr = node.raw_node.attr.get("_XlaSharding")?;
+        let sharding = match OpSharding::parse(attr.get_s()) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("cannot parse sharding of {}: {}", node.raw_node.name, e);
+                return None
+            }
+        };
+        Some((node.raw_node.name.clone(), sharding.to_form(ndev)?))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiled(dimensions: &[i64], devices: &[i64], replicate_on_last_tile_dim: bool) -> OpSharding {
+        OpSharding { kind: OpSharding::OTHER, tile_assignment_dimensions: dimensions.to_vec(), tile_assignment_devices: devices.to_vec(), replicate_on_last_tile_dim }
+    }
+
+    #[test]
+    fn tiling_along_one_dimension_becomes_a_part_form() {
+        assert_eq!(tiled(&[2, 1], &[0, 1], false).to_form(2), Some(Form::from_code("part_0_1")));
+        assert_eq!(tiled(&[1, 4], &[0, 1, 2, 3], false).to_form(4), Some(Form::from_code("part1_0_1_2_3")));
+        assert_eq!(tiled(&[1, 1], &[1], false).to_form(2), Some(Form::from_code("full_1")));
+    }
+
+    #[test]
+    fn inexpressible_layouts_are_rejected() {
+        assert_eq!(tiled(&[2, 1, 2], &[0, 1, 2, 3], true).to_form(4), None); // partially replicated
+        assert_eq!(tiled(&[2, 2], &[0, 1, 2, 3], false).to_form(4), None); // tiled along two dimensions
+        assert_eq!(tiled(&[2], &[1, 0], false).to_form(2), None); // out of order
+        assert_eq!(tiled(&[2], &[0, 2], false).to_form(2), None); // unknown device
+    }
+}
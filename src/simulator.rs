@@ -9,6 +9,7 @@ use crate::proto::types::DataType;
 use crate::proto::attr_value::{AttrValue, AttrValue_oneof_value};
 use crate::proto::node_def::NodeDef;
 use crate::proto::tensor::TensorProto;
+use crate::naming::parse_input;
 
 pub const GRPC_LATENCY: u64 = 12;
 
@@ -28,9 +29,9 @@ struct CollectiveGroup {
 
 #[derive(Debug)]
 enum TaskType<'a> {
-    Computation { id: usize, gpu: usize },
+    Computation { id: usize, gpu: usize, stream: usize },
     Transfer { size: u64, path: &'a [usize] },
-    Collective { instance_key: usize, group_key: usize, size: u64 }
+    Collective { instance_key: usize, group_key: usize, size: u64, overlapped: bool }
 }
 
 #[derive(Debug)]
@@ -79,18 +80,37 @@ type TensorBuf = (usize, usize, usize); // id, index, gpu
 
 pub struct SimpleSimulator;
 
+/// the interval that a tensor buffer lives on a device: allocated when the producing transfer finishes, freed after its last consumer
+#[derive(Debug, Clone)]
+pub struct TensorLifetime {
+    pub name: String,
+    pub device: usize,
+    pub size: u64,
+    pub alloc: u64,
+    pub free: u64
+}
+
 impl Simulator for SimpleSimulator {
-    fn evaluate<W: std::io::Write>(&self, profiler: &impl Profiler, mut target: Target, mut tracer: Option<&mut W>, max_memory: &mut [u64]) -> u64 {
+    fn evaluate<W: std::io::Write>(&self, profiler: &impl Profiler, target: Target, tracer: Option<&mut W>, max_memory: &mut [u64]) -> u64 {
+        self.evaluate_with_lifetimes(profiler, target, tracer, max_memory, None)
+    }
+}
+
+impl SimpleSimulator {
+    /// same as `evaluate`, but additionally records the lifetime of every tensor buffer if `lifetimes` is provided
+    pub fn evaluate_with_lifetimes<W: std::io::Write>(&self, profiler: &impl Profiler, mut target: Target, mut tracer: Option<&mut W>, max_memory: &mut [u64], mut lifetimes: Option<&mut Vec<TensorLifetime>>) -> u64 {
         task!("evaluating graph of {} nodes...", target.pb.node.len());
 
         if let Some(tracer) = &mut tracer { // initialize tracing
             write!(tracer, "[").unwrap();
         }
 
-        let nodes = sort_nodes(std::mem::replace(&mut target.pb.node, vec![].into()).into_vec());
+        let nodes = sort_nodes(link_send_recv(std::mem::replace(&mut target.pb.node, vec![].into()).into_vec()));
         let node_dict: HashMap<_, _> = nodes.iter().enumerate().map(|(i, x)| (&x.name[..], i)).collect();
         let device_dict: BTreeMap<_, _> = target.devices.iter().enumerate().map(|(i, x)| (&x[..], i)).collect();
         let collective_groups = analyze_collective_groups(&nodes, &device_dict, &target.nccls);
+        let hosts = target.host_cpus(); // nodes placed on host CPUs run on the queues of the GPUs they serve
+        let device_of = |name: &str| device_dict.get(name).or_else(|| hosts.get(name)).copied().unwrap_or_else(|| panic!("unknown device {}", name));
 
         // build tasks
         let mut tasks: Vec<Task> = vec![];
@@ -105,8 +125,8 @@ impl Simulator for SimpleSimulator {
 
                 let (name, index) = parse_input(&input);
                 let input_id = node_dict[name];
-                let from = device_dict[&nodes[input_id].device[..]];
-                let to = device_dict[&node.device[..]];
+                let from = device_of(&nodes[input_id].device);
+                let to = device_of(&node.device);
                 let size = node.attr.get("_tge_input_sizes").and_then(|x| x.get_list().i.get(input_index_of_this_node)).copied().unwrap_or(0) as _;
 
                 // info!("{}:{} {}->{} {}", name, index, from, to, size);
@@ -127,9 +147,11 @@ impl Simulator for SimpleSimulator {
                 let instance_key = node.attr["instance_key"].get_i() as _;
                 let group_key = node.attr["group_key"].get_i() as _;
                 let size = node.attr.get("_tge_input_sizes").and_then(|x| x.get_list().i.get(0)).copied().unwrap_or(0) as _;
-                Task::create(&mut tasks, TaskType::Collective { instance_key, group_key, size }, &wait_for, in_tensors, vec![])
+                // collectives without a stream hint keep running on their own stream, as before `assign_collective_streams` existed
+                let overlapped = node.attr.get(crate::polishing::STREAM_ATTR).map(|x| x.get_i() != crate::polishing::COMPUTE_STREAM).unwrap_or(true);
+                Task::create(&mut tasks, TaskType::Collective { instance_key, group_key, size, overlapped }, &wait_for, in_tensors, vec![])
             } else {
-                Task::create(&mut tasks, TaskType::Computation { id: i, gpu: device_dict[&node.device[..]] }, &wait_for, in_tensors, vec![])
+                Task::create(&mut tasks, TaskType::Computation { id: i, gpu: device_of(&node.device), stream: stream_of(node) }, &wait_for, in_tensors, vec![])
             };
             task_dict.push(id);
         }
@@ -138,34 +160,41 @@ impl Simulator for SimpleSimulator {
         let mut ongoing_tasks = BinaryHeap::new();
         let mut ready_list: VecDeque<_> = tasks.iter().enumerate().filter(|(_, task)| task.wait_for.is_empty()).map(|(i, _)| i).collect();
         let mut gpu_available_time = vec![0; target.devices.len()];
+        let mut stream_available_time = vec![0; target.devices.len()]; // nodes on a non-compute stream share a second queue per device
         let mut link_available_time = vec![0; target.links.len()];
         let mut current_memory = max_memory.to_vec();
         let mut collective_state: BTreeMap<usize, Vec<usize>> = BTreeMap::new(); // instance_key => [ready task_id]
         let mut collective_available_time = 0;
+        let mut alloc_time: BTreeMap<TensorBuf, u64> = BTreeMap::new(); // only used when recording lifetimes
 
         loop {
             // schedule ready tasks. Note the scheduled task may or may not start immediately depending on the GPU/link queue. There may be other tasks become ready before some tasks schedualed earlier actually start.
             while let Some(task_id) = ready_list.pop_front() {
                 let task = &tasks[task_id];
                 match task.content {
-                    TaskType::Computation { id: node_id, gpu } => {
-                        debug!("{:?} {:?} {:?} {:?} {:?}", gpu, gpu_available_time[gpu], time, nodes[node_id].name, profiler.profile(&nodes[node_id], gpu).unwrap_or(0));
-                        let eft = cmp::max(gpu_available_time[gpu], time) + profiler.profile(&nodes[node_id], gpu).unwrap_or(0);
-                        gpu_available_time[gpu] = eft;
+                    TaskType::Computation { id: node_id, gpu, stream } => {
+                        let queue = if stream == 0 { &mut gpu_available_time } else { &mut stream_available_time };
+                        debug!("{:?} {:?} {:?} {:?} {:?}", gpu, queue[gpu], time, nodes[node_id].name, profiler.profile(&nodes[node_id], gpu).unwrap_or(0));
+                        let eft = cmp::max(queue[gpu], time) + profiler.profile(&nodes[node_id], gpu).unwrap_or(0);
+                        queue[gpu] = eft;
                         ongoing_tasks.push(OngoingTask { id: task_id, eft });
                     }
-                    TaskType::Collective { instance_key, group_key, size } => {
+                    TaskType::Collective { instance_key, group_key, size, overlapped } => {
                         let ready_list = collective_state.entry(instance_key).or_default();
                         let group = &collective_groups[&group_key];
                         ready_list.push(task_id);
                         if ready_list.len() == group.devices.len() { // all ready
                             debug!("all ready {}", instance_key);
-                            // let barrier = group.devices.iter().map(|gpu| gpu_available_time[*gpu]).max().expect("bug");
-                            // let eft = barrier + nccl_time(size, &collective_groups[&group_key].model);
-                            // for gpu in group.devices.iter() {
-                            //     gpu_available_time[*gpu] = eft;
-                            // }
-                            let eft = cmp::max(time, collective_available_time) + nccl_time(size, &collective_groups[&group_key].model);
+                            let eft = if overlapped { // on its own stream, only serialized with other collectives
+                                cmp::max(time, collective_available_time) + nccl_time(size, &group.model)
+                            } else { // shares the compute stream, so it blocks all participating GPUs
+                                let barrier = group.devices.iter().map(|gpu| gpu_available_time[*gpu]).fold(cmp::max(time, collective_available_time), cmp::max);
+                                let eft = barrier + nccl_time(size, &group.model);
+                                for gpu in group.devices.iter() {
+                                    gpu_available_time[*gpu] = eft;
+                                }
+                                eft
+                            };
                             collective_available_time = eft;
                             for task_id in ready_list {
                                 ongoing_tasks.push(OngoingTask { id: *task_id, eft })
@@ -194,14 +223,15 @@ impl Simulator for SimpleSimulator {
                 // print tracing information
                 if let Some(tracer) = &mut tracer {
                     match &tasks[id].content {
-                        TaskType::Computation { id: node_id, gpu } => {
+                        TaskType::Computation { id: node_id, gpu, stream } => {
                             let duration = profiler.profile(&nodes[*node_id], *gpu).unwrap_or(0);
+                            let tid = gpu + stream * target.devices.len(); // separate rows for non-compute streams
                             if duration != 0 {
-                                writeln!(tracer, "{{ \"name\": \"{}\", \"cat\": \"computation\", \"ph\": \"B\", \"ts\": {}, \"pid\": 0, \"tid\": {} }},", nodes[*node_id].name, eft - duration, gpu).expect("fail to write log");
-                                writeln!(tracer, "{{ \"name\": \"{}\", \"cat\": \"computation\", \"ph\": \"E\", \"ts\": {}, \"pid\": 0, \"tid\": {} }},", nodes[*node_id].name, eft, gpu).expect("fail to write log");
+                                writeln!(tracer, "{{ \"name\": \"{}\", \"cat\": \"computation\", \"ph\": \"B\", \"ts\": {}, \"pid\": 0, \"tid\": {} }},", nodes[*node_id].name, eft - duration, tid).expect("fail to write log");
+                                writeln!(tracer, "{{ \"name\": \"{}\", \"cat\": \"computation\", \"ph\": \"E\", \"ts\": {}, \"pid\": 0, \"tid\": {} }},", nodes[*node_id].name, eft, tid).expect("fail to write log");
                             }
                         }
-                        TaskType::Collective { instance_key, group_key, size } => {
+                        TaskType::Collective { instance_key, group_key, size, .. } => {
                             let duration = nccl_time(*size, &collective_groups[group_key].model);
                             let gpu = tasks[id].in_tensors[0].2; // hack
                             if duration != 0 {
@@ -230,6 +260,10 @@ impl Simulator for SimpleSimulator {
                     let (size, ref_count, _) = tensor_buf.unwrap();
                     if *ref_count == 1 { // free
                         current_memory[in_tensor.2] -= *size;
+                        if let Some(lifetimes) = &mut lifetimes {
+                            let alloc = alloc_time.remove(in_tensor).unwrap_or(eft);
+                            lifetimes.push(TensorLifetime { name: format!("{}:{}", nodes[in_tensor.0].name, in_tensor.1), device: in_tensor.2, size: *size, alloc, free: eft })
+                        }
                         debug!("memory of {}:{} {} {} -{} {}", nodes[in_tensor.0].name, in_tensor.1, in_tensor.2, time, *size, current_memory[in_tensor.2]);
                         tensorbufs.remove(in_tensor);
                     } else {
@@ -249,6 +283,9 @@ impl Simulator for SimpleSimulator {
                         *activated = true;
                         let gpu = out_tensor.2;
                         current_memory[gpu] += *size;
+                        if lifetimes.is_some() {
+                            alloc_time.insert(*out_tensor, eft);
+                        }
                         debug!("memory of {}:{} {} {} +{} {}", nodes[out_tensor.0].name, out_tensor.1, out_tensor.2, time, *size, current_memory[out_tensor.2]);
                         max_memory[gpu] = cmp::max(current_memory[gpu], max_memory[gpu]);
                     }
@@ -267,18 +304,129 @@ impl Simulator for SimpleSimulator {
             }
         }
 
+        if let Some(lifetimes) = &mut lifetimes { // tensors that are never freed live until the end
+            for ((node_id, index, device), alloc) in alloc_time {
+                let size = tensorbufs.get(&(node_id, index, device)).map(|x| x.0).unwrap_or(0);
+                lifetimes.push(TensorLifetime { name: format!("{}:{}", nodes[node_id].name, index), device, size, alloc, free: time })
+            }
+        }
+
         time
     }
 }
 
+/// how the simulated step time of a compiled target responds to the bandwidth of one link
+#[derive(Debug, Clone)]
+pub struct LinkSensitivity {
+    pub link: usize,
+    pub time: u64, // step time with the bandwidth of this link scaled by 1 + delta
+    pub derivative: f64, // change of step time per unit of bandwidth, negative if more bandwidth helps
+    pub elasticity: f64 // relative change of step time per relative change of bandwidth, comparable between links of different speeds
+}
+
+/// perturb the bandwidth of each link by the factor `1 + delta` in turn and simulate the step again, telling which interconnect upgrade
+/// would actually help. Links that no path uses are reported as insensitive without simulating. Transfers inside NCCL groups follow the
+/// NCCL models of the target instead of the links, so they are not affected. Returns the unperturbed step time and one entry per link.
+pub fn bandwidth_sensitivity(profiler: &impl Profiler, target: &Target, delta: f64) -> (u64, Vec<LinkSensitivity>) {
+    let simulate = |links: Box<[u64]>| {
+        let copy = Target::new(target.pb.clone(), target.devices.clone(), links, target.paths.clone(), target.sinks.clone(), target.nccls.clone()).apply(|x| x.cpus = target.cpus.clone());
+        SimpleSimulator.evaluate::<std::fs::File>(profiler, copy, None, &mut vec![0; target.ndev()])
+    };
+    let used: BTreeSet<usize> = target.paths.iter().flat_map(|x| x.iter().copied()).collect();
+
+    let baseline = simulate(target.links.clone());
+    let result = (0..target.links.len()).map(|link| {
+        let bandwidth = target.links[link];
+        let perturbed = cmp::max(bandwidth + 1, (bandwidth as f64 * (1. + delta)) as u64);
+        if !used.contains(&link) {
+            return LinkSensitivity { link, time: baseline, derivative: 0., elasticity: 0. }
+        }
+        let time = simulate(target.links.clone().apply(|x| x[link] = perturbed));
+        let derivative = (time as f64 - baseline as f64) / (perturbed - bandwidth) as f64;
+        let elasticity = derivative * bandwidth as f64 / cmp::max(baseline, 1) as f64;
+        debug!("link {} ({} -> {}): step time {} -> {}", link, bandwidth, perturbed, baseline, time);
+        LinkSensitivity { link, time, derivative, elasticity }
+    }).collect();
+    (baseline, result)
+}
+
+#[cfg(feature = "exporters")]
+pub fn write_lifetimes_json<W: std::io::Write>(lifetimes: &[TensorLifetime], devices: &[String], out: &mut W) -> std::io::Result<()> {
+    write!(out, "[")?;
+    for (i, x) in lifetimes.iter().enumerate() {
+        if i != 0 {
+            writeln!(out, ",")?;
+        }
+        write!(out, "{{ \"name\": \"{}\", \"device\": \"{}\", \"size\": {}, \"alloc\": {}, \"free\": {} }}", x.name, devices[x.device], x.size, x.alloc, x.free)?;
+    }
+    writeln!(out, "]")
+}
+
+/// one horizontal band per device; each tensor is a bar from allocation to free whose height is proportional to its size.
+/// Bars are stacked first-fit so the height of a band at any time equals the memory in use at that time.
+#[cfg(feature = "exporters")]
+pub fn write_lifetimes_svg<W: std::io::Write>(lifetimes: &[TensorLifetime], devices: &[String], out: &mut W) -> std::io::Result<()> {
+    const WIDTH: f64 = 1600.;
+    const BAND_HEIGHT: f64 = 200.;
+
+    let end = lifetimes.iter().map(|x| x.free).max().unwrap_or(0).max(1) as f64;
+    writeln!(out, "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">", WIDTH + 200., BAND_HEIGHT * devices.len() as f64)?;
+
+    for (device_id, device) in devices.iter().enumerate() {
+        let mut list: Vec<_> = lifetimes.iter().filter(|x| x.device == device_id && x.size > 0).collect();
+        list.sort_by_key(|x| (x.alloc, x.free));
+
+        // first-fit stacking: each slot is (offset, size, free time)
+        let mut slots: Vec<(u64, u64, u64)> = vec![];
+        let mut placed = vec![];
+        for x in list {
+            let offset = match slots.iter_mut().find(|slot| slot.2 <= x.alloc && slot.1 >= x.size) {
+                Some(slot) => { slot.2 = x.free; slot.0 }
+                None => {
+                    let offset = slots.last().map(|s| s.0 + s.1).unwrap_or(0);
+                    slots.push((offset, x.size, x.free));
+                    offset
+                }
+            };
+            placed.push((offset, x))
+        }
+
+        let peak = slots.last().map(|s| s.0 + s.1).unwrap_or(0).max(1) as f64;
+        let top = BAND_HEIGHT * device_id as f64;
+        writeln!(out, "<text x=\"{}\" y=\"{}\" font-size=\"12\">{}</text>", WIDTH + 10., top + 20., device)?;
+        writeln!(out, "<line x1=\"0\" y1=\"{0}\" x2=\"{1}\" y2=\"{0}\" stroke=\"black\"/>", top + BAND_HEIGHT, WIDTH)?;
+        for (offset, x) in placed {
+            let y = top + BAND_HEIGHT * (1. - (offset + x.size) as f64 / peak);
+            writeln!(out, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"steelblue\" stroke=\"white\" stroke-width=\"0.2\"><title>{} {} bytes [{}, {}]</title></rect>",
+                WIDTH * x.alloc as f64 / end, y, (WIDTH * (x.free - x.alloc) as f64 / end).max(0.5), BAND_HEIGHT * x.size as f64 / peak, x.name, x.size, x.alloc, x.free)?;
+        }
+    }
+
+    writeln!(out, "</svg>")
+}
+
 // use crossbeam_channel;
 // include!("../deprecated/multithreaded_simulator.rs");
 
-fn parse_input(x: &str) -> (&str, usize) {
-    match x.find(':') {
-        Some(i) => (&x[..i], x[i+1..].parse().unwrap()),
-        None => (x, 0)
+/// nodes without a stream hint run on the compute stream
+fn stream_of(node: &NodeDef) -> usize {
+    node.attr.get(crate::polishing::STREAM_ATTR).map(|x| x.get_i() as _).unwrap_or(0)
+}
+
+/// make each `_Recv` of a partitioned graph (see `partition`) take its `_Send` as an input with the size annotated on them, so the
+/// transfer between them is simulated like any edge between devices
+fn link_send_recv(mut nodes: Vec<NodeDef>) -> Vec<NodeDef> {
+    let sends: HashMap<Vec<u8>, String> = nodes.iter().filter(|x| crate::partition::is_send(x))
+        .map(|x| (x.attr["tensor_name"].get_s().to_vec(), x.name.clone())).collect();
+    if sends.is_empty() {
+        return nodes
+    }
+    for node in nodes.iter_mut().filter(|x| crate::partition::is_recv(x) && x.input.is_empty()) {
+        if let Some(send) = sends.get(node.attr["tensor_name"].get_s()) {
+            node.input.push(send.clone())
+        }
     }
+    nodes
 }
 
 fn sort_nodes(x: Vec<NodeDef>) -> Vec<NodeDef> {
@@ -0,0 +1,131 @@
+//! Out-of-core compilation for graphs whose node list does not fit in memory together with the compiled graph, e.g. recommendation
+//! models with giant embedding tables in Const nodes. With the option `spill_dir`, tensor attrs of the original nodes larger than
+//! `spill_threshold` bytes (1 MiB by default) are moved to a scratch file before compiling and replaced by a reference, so the replicas
+//! and the subgraph cache carry only the reference, and they are read back into the compiled graph at the end (see `Graph::compile`).
+//! The subgraph cache (see `cache`) also keeps its entries in the scratch file. Reads are memory-mapped with the `mmap` feature and use
+//! positioned reads otherwise. The scratch file is removed when it is dropped.
+
+use oh_my_rust::*;
+use protobuf::Message;
+use std::fs::File;
+use std::io::Write;
+use crate::proto::attr_value::{AttrValue, AttrValue_ListValue};
+use crate::proto::node_def::NodeDef;
+
+/// the attr that lists the spilled attrs of a node as `<attr>:<offset>:<length>`
+pub const SPILLED_ATTR: &str = "_tge_spilled";
+
+pub const DEFAULT_THRESHOLD: u64 = 1 << 20;
+
+/// smaller thresholds are raised to this, so the small Consts that the compiler reads (shapes, axes) stay in place
+pub const MIN_THRESHOLD: u64 = 64 << 10;
+
+/// a range of the scratch file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Spilled {
+    pub offset: u64,
+    pub len: u64
+}
+
+#[derive(Debug)]
+pub struct SpillFile {
+    path: std::path::PathBuf,
+    file: File,
+    len: u64,
+    #[cfg(feature = "mmap")]
+    map: Option<memmap2::Mmap> // covers a prefix of the file, remapped when a read goes beyond it
+}
+
+impl SpillFile {
+    /// create a scratch file in `dir`, named after the process so concurrent compilations do not collide
+    pub fn create(dir: &str) -> std::io::Result<Self> {
+        static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let path = std::path::Path::new(dir).join(format!("tge_spill_{}_{}", std::process::id(), n));
+        let file = std::fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+        info!("spilling to {}", path.display());
+        Ok(SpillFile { path, file, len: 0, #[cfg(feature = "mmap")] map: None })
+    }
+
+    /// the bytes written so far
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> std::io::Result<Spilled> {
+        self.file.write_all(bytes)?;
+        let spilled = Spilled { offset: self.len, len: bytes.len() as _ };
+        self.len += bytes.len() as u64;
+        Ok(spilled)
+    }
+
+    #[cfg(feature = "mmap")]
+    pub fn read(&mut self, at: Spilled) -> std::io::Result<Vec<u8>> {
+        let end = (at.offset + at.len) as usize;
+        if self.map.as_ref().map(|x| x.len() < end).unwrap_or(true) {
+            self.file.flush()?;
+            self.map = Some(unsafe { memmap2::Mmap::map(&self.file)? })
+        }
+        Ok(self.map.as_ref().unwrap()[at.offset as usize..end].to_vec())
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    pub fn read(&mut self, at: Spilled) -> std::io::Result<Vec<u8>> {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut bytes = vec![0; at.len as usize];
+        self.file.seek(SeekFrom::Start(at.offset))?;
+        self.file.read_exact(&mut bytes)?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(bytes)
+    }
+
+    /// move the tensor attrs (e.g. the value of a Const) of `node` whose serialized size is at least `threshold` (but not less than
+    /// `MIN_THRESHOLD`) into the file, see `SPILLED_ATTR`. Returns the number of bytes moved.
+    pub fn spill_attrs(&mut self, node: &mut NodeDef, threshold: u64) -> std::io::Result<u64> {
+        let threshold = threshold.max(MIN_THRESHOLD);
+        let large: Vec<String> = node.attr.iter().filter(|(_, value)| value.has_tensor() && value.compute_size() as u64 >= threshold).map(|(name, _)| name.clone()).collect();
+        let mut moved = 0;
+        for name in large {
+            let value = node.attr.remove(&name).unwrap();
+            let spilled = self.write(&value.write_to_bytes().unwrap())?;
+            moved += spilled.len;
+            let list = node.attr.entry(SPILLED_ATTR.to_string()).or_insert_with(|| AttrValue::new().apply(|x| x.set_list(AttrValue_ListValue::new())));
+            list.mut_list().s.push(format!("{}:{}:{}", name, spilled.offset, spilled.len).into_bytes());
+        }
+        Ok(moved)
+    }
+
+    /// put the spilled attrs of `node` back, the reverse of `spill_attrs`. The file keeps them, so other copies can be restored too.
+    pub fn restore_attrs(&mut self, node: &mut NodeDef) -> std::io::Result<()> {
+        let list = match node.attr.remove(SPILLED_ATTR) {
+            Some(x) => x,
+            None => return Ok(())
+        };
+        for record in list.get_list().s.iter() {
+            let record = String::from_utf8_lossy(record);
+            let mut fields = record.rsplitn(3, ':');
+            let (len, offset, name) = match (fields.next().and_then(|x| x.parse().ok()), fields.next().and_then(|x| x.parse().ok()), fields.next()) {
+                (Some(len), Some(offset), Some(name)) => (len, offset, name),
+                _ => panic!("BUG: malformed spill record {} in {}", record, node.name)
+            };
+            let bytes = self.read(Spilled { offset, len })?;
+            let value: AttrValue = protobuf::parse_from_bytes(&bytes).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            node.attr.insert(name.to_string(), value);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        #[cfg(feature = "mmap")]
+        drop(self.map.take());
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("cannot remove the scratch file {}: {}", self.path.display(), e)
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! Summaries of a GraphDef for sanity-checking what was ingested and what the compiler added: the number of nodes of each op, the
+//! parameters (elements of the variables, counting every replica in compiled graphs), the bytes of all tensors produced by the other
+//! nodes, and the depth (the longest chain of data and control edges, with the back edges of while loops ignored). Sizes come from the
+//! `_tge_input_sizes` annotated by the compiler where present and from `_output_shapes` otherwise, with the batch split of Part forms
+//! taken into account; tensors with neither a known size nor a fully known shape are counted in `unknown_shapes` instead.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
+use crate::graph::{Form, dtype_of, dtype_size};
+use crate::naming::parse_input;
+use crate::proto::node_def::NodeDef;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GraphStats {
+    pub nodes: usize,
+    pub ops: BTreeMap<String, usize>, // op => the number of nodes
+    pub parameters: u64,
+    pub activation_bytes: u64,
+    pub unknown_shapes: usize,
+    pub depth: usize
+}
+
+impl GraphStats {
+    pub fn of(nodes: &[NodeDef]) -> Self {
+        let mut stats = GraphStats { nodes: nodes.len(), ..Default::default() };
+        for node in nodes {
+            *stats.ops.entry(node.op.clone()).or_default() += 1
+        }
+
+        let mut sizes: HashMap<(&str, usize), u64> = HashMap::new(); // annotated by the consumers
+        let mut consumed: HashMap<&str, BTreeSet<usize>> = HashMap::new(); // the annotated outputs of each node
+        for node in nodes {
+            let annotated = match node.attr.get("_tge_input_sizes") {
+                Some(x) => &x.get_list().i,
+                None => continue
+            };
+            for (input, size) in node.input.iter().zip(annotated.iter()) {
+                if !input.starts_with('^') && *size >= 0 {
+                    let (name, index) = parse_input(input);
+                    sizes.insert((name, index), *size as u64);
+                    consumed.entry(name).or_default().insert(index);
+                }
+            }
+        }
+
+        for node in nodes {
+            if is_variable(node) {
+                stats.parameters += variable_elements(node).unwrap_or_else(|| {
+                    stats.unknown_shapes += 1;
+                    0
+                });
+                continue
+            }
+            let noutputs = node.attr.get("_output_shapes").map(|x| x.get_list().shape.len()).unwrap_or(0);
+            let consumed = consumed.get(&node.name[..]).map(|x| x.iter().copied()).into_iter().flatten();
+            let indices: BTreeSet<usize> = (0..noutputs).chain(consumed).collect();
+            for i in indices {
+                match sizes.get(&(&node.name[..], i)).copied().or_else(|| output_size(node, i)) {
+                    Some(size) => stats.activation_bytes += size,
+                    None => stats.unknown_shapes += 1
+                }
+            }
+        }
+
+        stats.depth = depth(nodes);
+        stats
+    }
+
+    /// one `<key>\t<value>` line for each field, with a line `op\t<op>\t<count>` for each op
+    pub fn report(&self) -> String {
+        let mut lines = vec![
+            format!("nodes\t{}", self.nodes),
+            format!("parameters\t{}", self.parameters),
+            format!("activation_bytes\t{}", self.activation_bytes),
+            format!("unknown_shapes\t{}", self.unknown_shapes),
+            format!("depth\t{}", self.depth)
+        ];
+        lines.extend(self.ops.iter().map(|(op, n)| format!("op\t{}\t{}", op, n)));
+        lines.join("\n")
+    }
+}
+
+fn is_variable(node: &NodeDef) -> bool {
+    matches!(&node.op[..], "VariableV2" | "Variable" | "VarHandleOp")
+}
+
+fn variable_elements(node: &NodeDef) -> Option<u64> {
+    let dims = match node.attr.get("shape") {
+        Some(shape) => shape.get_shape().dim.iter().map(|x| x.size).collect(),
+        None => static_shape(node, 0)?
+    };
+    elements(&dims)
+}
+
+/// None if some dimension is unknown
+fn elements(dims: &[i64]) -> Option<u64> {
+    dims.iter().map(|x| -> Option<u64> { (*x).try_into().ok() }).product()
+}
+
+/// the static shape of an output, with the split axis of Part forms divided among their devices
+fn static_shape(node: &NodeDef, index: usize) -> Option<Vec<i64>> {
+    let shape = node.attr.get("_output_shapes")?.get_list().shape.get(index)?;
+    if shape.unknown_rank {
+        return None
+    }
+    let mut dims: Vec<i64> = shape.dim.iter().map(|x| x.size).collect();
+    if let Some(form) = node.attr.get("_tge_form").and_then(|x| std::str::from_utf8(x.get_s()).ok()) {
+        let form = Form::from_code(form);
+        if form.is_part() && dims.len() > form.axis && dims[form.axis] >= 0 { // unknown dimensions (-1) stay unknown
+            dims[form.axis] /= form.ndev() as i64
+        }
+    }
+    Some(dims)
+}
+
+fn output_size(node: &NodeDef, index: usize) -> Option<u64> {
+    let element_size = dtype_of(node, index).and_then(|x| dtype_size(x.get_field_type())).unwrap_or(4);
+    Some(elements(&static_shape(node, index)?)? * element_size)
+}
+
+/// the number of edges on the longest path. Inputs from NextIteration are the back edges of while loops and ignored;
+/// inputs missing in the graph are ignored too, so partial graphs work.
+fn depth(nodes: &[NodeDef]) -> usize {
+    let ids: HashMap<&str, usize> = nodes.iter().enumerate().map(|(i, x)| (&x.name[..], i)).collect();
+    let inputs: Vec<Vec<usize>> = nodes.iter().map(|node| node.input.iter().filter_map(|input| {
+        let name = if let Some(name) = input.strip_prefix('^') { name } else { parse_input(input).0 };
+        ids.get(name).copied().filter(|id| nodes[*id].op != "NextIteration" && nodes[*id].op != "RefNextIteration")
+    }).collect()).collect();
+
+    let mut outputs = vec![vec![]; nodes.len()];
+    let mut pending: Vec<usize> = inputs.iter().map(|x| x.len()).collect();
+    for (id, x) in inputs.iter().enumerate() {
+        for input in x {
+            outputs[*input].push(id)
+        }
+    }
+
+    let mut depth = vec![0; nodes.len()];
+    let mut queue: Vec<usize> = (0..nodes.len()).filter(|id| pending[*id] == 0).collect();
+    while let Some(id) = queue.pop() {
+        for &output in outputs[id].iter() {
+            depth[output] = depth[output].max(depth[id] + 1);
+            pending[output] -= 1;
+            if pending[output] == 0 {
+                queue.push(output)
+            }
+        }
+    }
+    depth.into_iter().max().unwrap_or(0)
+}
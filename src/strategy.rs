@@ -0,0 +1,435 @@
+use oh_my_rust::*;
+use std::io::Write;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::graph::*;
+use crate::misc::{DataProfiler, Target};
+
+pub use crate::editor::Strategy;
+
+/// replicate only the expensive backbone and keep the cheap pre/post-processing on device 0.
+/// The "hot" ops are the most expensive ones that together cover `coverage` (0~1) of the total estimated cost.
+/// Everything that lies between hot ops is also replicated so the backbone stays in one piece, and the decision is expanded to whole groups so splitting stays consistent.
+pub fn selective_replication(graph: &Graph, profiler: Option<&DataProfiler>, ndev: usize, coverage: f64, method: u8) -> Strategy {
+    let costs: Vec<u64> = graph.nodes.iter().map(|node| estimate_cost(node, profiler)).collect();
+    let total: u64 = costs.iter().sum();
+
+    let mut order: Vec<_> = (0..graph.nodes.len()).collect();
+    order.sort_by_key(|i| std::cmp::Reverse(costs[*i]));
+    let mut seeds = BTreeSet::new();
+    let mut acc = 0;
+    for i in order {
+        if costs[i] == 0 || acc as f64 >= coverage * total as f64 {
+            break
+        }
+        acc += costs[i];
+        seeds.insert(i);
+    }
+
+    // nodes are partially ordered, so one forward pass and one backward pass give the descendants and ancestors of seeds
+    let mut descendants = seeds.clone();
+    for (id, node) in graph.nodes.iter().enumerate() {
+        if node.inputs.iter().any(|(input_id, _, _)| descendants.contains(input_id)) {
+            descendants.insert(id);
+        }
+    }
+    let mut ancestors = seeds.clone();
+    for (id, node) in graph.nodes.iter().enumerate().rev() {
+        if ancestors.contains(&id) {
+            ancestors.extend(node.inputs.iter().map(|(input_id, _, _)| *input_id));
+        }
+    }
+
+    let mut hot: BTreeSet<usize> = descendants.intersection(&ancestors).copied().collect();
+    for id in hot.clone() {
+        if let Some(group) = &graph.nodes[id].group {
+            hot.extend(group.borrow().iter().copied())
+        }
+    }
+
+    info!("selective replication: {} of {} nodes are replicated, covering {}/{} of estimated cost", hot.len(), graph.nodes.len(), acc, total);
+
+    if let Some(mut trace) = open_decision_trace(graph) {
+        for (id, node) in graph.nodes.iter().enumerate() {
+            let reason = match (seeds.contains(&id), hot.contains(&id), descendants.contains(&id) && ancestors.contains(&id)) {
+                (true, _, _) => "seed",
+                (_, true, true) => "between seeds",
+                (_, true, false) => "group",
+                _ => "cold"
+            };
+            writeln!(trace, "{{ \"strategy\": \"selective_replication\", \"node\": {}, \"op\": {}, \"source\": {}, \"cost\": {}, \"chosen\": \"{}\", \"reason\": \"{}\" }}",
+                json_string(&node.raw_node.name), json_string(&node.raw_node.op), json_string(graph.source_location(&node.raw_node.name).unwrap_or("")), costs[id], if hot.contains(&id) { "replicated" } else { "device_0" }, reason).unwrap()
+        }
+    }
+
+    graph.nodes.iter().enumerate().map(|(id, node)| {
+        let decision = if hot.contains(&id) {
+            ((0..ndev).collect(), method)
+        } else {
+            (vec![0], 0)
+        };
+        (node.raw_node.name.clone(), decision)
+    }).collect()
+}
+
+/// parameter-server placement: compute is replicated on every device and aggregated with AddN (method 0) on the variable's device.
+/// Each variable, together with its optimizer op and slots, goes to the device with the lowest load relative to its aggregate bandwidth
+/// to all other devices (from the paths of the target), largest variables first. The largest variables that make up `hot_fraction` of all
+/// variable bytes are replicated on the two best devices so their reads are spread.
+pub fn ps_placement(graph: &mut Graph, target: &Target, hot_fraction: f64) -> Strategy {
+    let n = target.ndev();
+    let bandwidth = |from: usize, to: usize| target.paths[from * n + to].iter().map(|link| target.links[*link]).min().unwrap_or(0);
+    let aggregate_bandwidth: Vec<u64> = (0..n).map(|d| (0..n).filter(|w| *w != d).map(|w| std::cmp::min(bandwidth(w, d), bandwidth(d, w))).sum::<u64>().max(1)).collect();
+    let (shard_of, shards) = variable_shards(graph);
+
+    let total: u64 = shards.iter().map(|x| x.1).sum();
+    let mut load = vec![0u64; n];
+    let mut placement: BTreeMap<usize, Vec<usize>> = BTreeMap::new(); // variable => devices
+    let mut acc = 0;
+    let mut trace = open_decision_trace(graph);
+    for (var, size) in shards {
+        let cost = |d: usize| (load[d] + size) as f64 / aggregate_bandwidth[d] as f64;
+        let mut candidates: Vec<usize> = (0..n).collect();
+        candidates.sort_by(|a, b| cost(*a).partial_cmp(&cost(*b)).unwrap().then(a.cmp(b)));
+        let hot = n > 1 && (acc as f64) < hot_fraction * total as f64;
+        acc += size;
+        if let Some(trace) = &mut trace {
+            let alternatives: Vec<String> = candidates.iter().map(|d| format!("{{ \"device\": {}, \"cost\": {} }}", d, json_number(cost(*d)))).collect();
+            writeln!(trace, "{{ \"strategy\": \"ps_placement\", \"node\": {}, \"source\": {}, \"size\": {}, \"chosen\": {:?}, \"alternatives\": [{}] }}",
+                json_string(&graph.nodes[var].raw_node.name), json_string(graph.source_location(&graph.nodes[var].raw_node.name).unwrap_or("")), size, &candidates[..if hot { 2 } else { 1 }], alternatives.join(", ")).unwrap()
+        }
+        let devices: Vec<usize> = candidates.into_iter().take(if hot { 2 } else { 1 }).collect::<Vec<_>>().apply(|x| x.sort_unstable());
+        for d in devices.iter() {
+            load[*d] += size
+        }
+        placement.insert(var, devices);
+    }
+
+    info!("parameter server load on each device: {:?}", load);
+
+    graph.nodes.iter().enumerate().map(|(id, node)| {
+        let decision = match shard_of.get(&id) {
+            Some(var) => (placement[var].clone(), 0),
+            None => ((0..n).collect(), 0)
+        };
+        (node.raw_node.name.clone(), decision)
+    }).collect()
+}
+
+/// classic parameter servers: variables live on the `servers` and compute is replicated on the `workers`, each gradient being summed with AddN
+/// (method 0) on the server of its variable and each update read back by the workers. Shards (see `variable_shards`) go to the server
+/// with the fewest bytes so far, largest first, so the servers hold and serve similar amounts. Variables are not partitioned: a variable
+/// larger than the others together still lands on a single server.
+pub fn parameter_server(graph: &mut Graph, servers: &[usize], workers: &[usize]) -> Strategy {
+    assert!(!servers.is_empty() && !workers.is_empty(), "parameter_server needs at least one server and one worker");
+    let (shard_of, shards) = variable_shards(graph);
+
+    let mut load: BTreeMap<usize, u64> = servers.iter().map(|d| (*d, 0)).collect();
+    let mut placement: BTreeMap<usize, usize> = BTreeMap::new(); // variable => server
+    let mut trace = open_decision_trace(graph);
+    for (var, size) in shards {
+        let server = *servers.iter().min_by_key(|d| (load[d], **d)).unwrap();
+        if let Some(trace) = &mut trace {
+            writeln!(trace, "{{ \"strategy\": \"parameter_server\", \"node\": {}, \"source\": {}, \"size\": {}, \"chosen\": {}, \"load\": {:?} }}",
+                json_string(&graph.nodes[var].raw_node.name), json_string(graph.source_location(&graph.nodes[var].raw_node.name).unwrap_or("")), size, server, load.values().collect::<Vec<_>>()).unwrap()
+        }
+        *load.get_mut(&server).unwrap() += size;
+        placement.insert(var, server);
+    }
+
+    info!("parameter servers hold {:?} bytes", load);
+
+    let workers = workers.to_vec().apply(|x| x.sort_unstable());
+    graph.nodes.iter().enumerate().map(|(id, node)| {
+        let decision = match shard_of.get(&id) {
+            Some(var) => (vec![placement[var]], 0),
+            None => (workers.clone(), 0)
+        };
+        (node.raw_node.name.clone(), decision)
+    }).collect()
+}
+
+/// local SGD: mirror every node on `devices` so each replica trains on its own slice of the batch and applies its own gradients, and
+/// average the replicated variables every `period` steps. `method` is used for the gradients that are still aggregated, e.g. of variables
+/// without an optimizer op. Variables that are assigned outside of an optimizer (moving averages, counters) are not averaged and drift
+/// apart between the averaging steps. Returns the strategy and the period, which the caller sets as the option `local_sgd_period` (see
+/// `Graph::add_periodic_averaging`) since strategies leave the options alone.
+pub fn local_sgd(graph: &Graph, devices: &[usize], period: usize, method: u8) -> (Strategy, usize) {
+    assert!(!devices.is_empty(), "local_sgd needs at least one device");
+    assert!(period >= 1, "the averaging period should be at least 1");
+
+    let updated: BTreeSet<usize> = graph.nodes.iter().filter_map(|node| optimizer_inputs(&node.raw_node.op).map(|(_, states)| node.inputs[states[0]].0)).collect();
+    let drifting = graph.nodes.iter().enumerate().filter(|(id, node)| node.is_variable() && !updated.contains(id)).count();
+    info!("local SGD on {} devices: {} variables are averaged every {} steps", devices.len(), updated.len(), period);
+    if drifting > 0 {
+        warn!("local SGD: {} variables are not updated by an optimizer op and are not averaged", drifting)
+    }
+
+    let devices = devices.to_vec().apply(|x| x.sort_unstable());
+    (graph.nodes.iter().map(|node| (node.raw_node.name.clone(), (devices.clone(), method))).collect(), period)
+}
+
+/// the shards of the parameter-server strategies: a shard is a variable, plus its optimizer op and optimizer slots. Returns the variable
+/// that leads the shard of each member, and the shards with their bytes (slots included), largest first.
+fn variable_shards(graph: &mut Graph) -> (BTreeMap<usize, usize>, Vec<(usize, u64)>) {
+    let mut shard_of: BTreeMap<usize, usize> = BTreeMap::new(); // node => the variable that leads the shard
+    for (id, node) in graph.nodes.iter().enumerate() {
+        if let Some((_, states)) = optimizer_inputs(&node.raw_node.op) {
+            let var = node.inputs[states[0]].0;
+            shard_of.insert(id, var);
+            for i in states.iter().skip(1) {
+                shard_of.insert(node.inputs[*i].0, var);
+            }
+        }
+    }
+    let mut shards: Vec<(usize, u64)> = graph.nodes.iter_mut().enumerate().filter(|(id, node)| node.is_variable() && !shard_of.contains_key(id))
+        .map(|(id, node)| (id, crate::analysis::variable_size(node))).collect();
+    for (id, _) in shards.iter() {
+        shard_of.insert(*id, *id);
+    }
+    // slots count towards the size of their shard
+    for (&member, &var) in shard_of.clone().iter() {
+        if member != var && graph.nodes[member].is_variable() {
+            let size = crate::analysis::variable_size(&mut graph.nodes[member]);
+            if let Some(shard) = shards.iter_mut().find(|x| x.0 == var) {
+                shard.1 += size
+            }
+        }
+    }
+    shards.sort_by_key(|x| std::cmp::Reverse(x.1));
+
+    (shard_of, shards)
+}
+
+/// the names of the aggregation methods, indexed by their numbers in strategies, see `editor::edit`
+pub const METHODS: [&str; 10] = ["ps", "collective", "ring", "nccl", "sync", "hierarchical", "tree", "double_tree", "cpu", "cpu_ring"];
+
+/// the aggregation method by name
+pub fn parse_method(name: &str) -> Option<u8> {
+    METHODS.iter().position(|x| *x == name).map(|x| x as u8)
+}
+
+/// a line of an overrides file: `<pattern> devices=<d0,d1,...> [method=<ps|collective|ring|nccl|sync|hierarchical|tree|double_tree|cpu|cpu_ring>]`.
+/// The pattern matches node names, `*` matches any sequence of characters and `?` matches one character.
+#[derive(Debug, Clone)]
+pub struct Override {
+    pub pattern: String,
+    pub devices: Vec<usize>, // repeated indexes mean multiple replicas on the same device
+    pub method: u8
+}
+
+pub fn parse_overrides(text: &str) -> Result<Vec<Override>, String> {
+    text.lines().enumerate().filter_map(|(lineno, line)| {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            return None
+        }
+
+        let mut segs = line.split_ascii_whitespace();
+        let pattern = segs.next().unwrap().to_string();
+        let (mut devices, mut method) = (None, 0);
+        for seg in segs {
+            let result = match seg.find('=').map(|i| (&seg[..i], &seg[i+1..])) {
+                Some(("devices", value)) => value.split(',').map(|x| x.parse()).collect::<Result<Vec<_>, _>>().map(|x| devices = Some(x)).map_err(|_| ()),
+                Some(("method", value)) => parse_method(value).map(|x| method = x).ok_or(()),
+                _ => Err(())
+            };
+            if result.is_err() {
+                return Some(Err(format!("line {}: invalid field {}", lineno + 1, seg)))
+            }
+        }
+
+        Some(match devices {
+            Some(mut devices) if !devices.is_empty() => {
+                devices.sort_unstable();
+                Ok(Override { pattern, devices, method })
+            }
+            _ => Err(format!("line {}: missing devices for {}", lineno + 1, pattern))
+        })
+    }).collect()
+}
+
+/// patch the strategy with overrides as a final pass. A node matched by several overrides that disagree is an error, as is an override
+/// referring to a device out of range. Overrides that match nothing or that break up a group (which prevents splitting) are warned.
+pub fn apply_overrides(graph: &Graph, strategy: &mut Strategy, overrides: &[Override], ndev: usize) -> Result<(), String> {
+    if let Some(x) = overrides.iter().find(|x| x.devices.iter().any(|d| *d >= ndev)) {
+        return Err(format!("override {} refers to a device out of range", x.pattern))
+    }
+
+    let mut matched: BTreeMap<usize, &Override> = BTreeMap::new();
+    let mut conflicts = vec![];
+    for x in overrides {
+        let mut count = 0;
+        for (id, node) in graph.nodes.iter().enumerate() {
+            if !glob_match(&x.pattern, &node.raw_node.name) {
+                continue
+            }
+            count += 1;
+            match matched.get(&id) {
+                Some(prev) if prev.devices != x.devices || prev.method != x.method => conflicts.push(format!("{} (by {} and {})", node.raw_node.name, prev.pattern, x.pattern)),
+                _ => { matched.insert(id, x); }
+            }
+        }
+        if count == 0 {
+            warn!("override {} matches no node", x.pattern)
+        }
+    }
+
+    if !conflicts.is_empty() {
+        return Err(format!("{} nodes are matched by conflicting overrides: {}", conflicts.len(), conflicts.join(", ")))
+    }
+
+    let mut warned_groups = BTreeSet::new();
+    for (id, x) in matched.iter() {
+        let node = &graph.nodes[*id];
+        if let Some(group) = &node.group {
+            let partial = group.borrow().iter().any(|member| matched.get(member).map(|y| y.devices != x.devices).unwrap_or(true));
+            if partial && warned_groups.insert(group.as_ptr()) {
+                warn!("override {} changes {} but not the rest of its group, so the group will not be split", x.pattern, node.raw_node.name)
+            }
+        }
+        strategy.insert(node.raw_node.name.clone(), (x.devices.clone(), x.method));
+    }
+
+    info!("{} nodes are overridden", matched.len());
+    Ok(())
+}
+
+/// extend a plan for the first `m` devices to `n` devices, as a starting point that is refined instead of searching from scratch
+/// after adding devices. Nodes on a single device stay there. For replicated nodes, each new device takes half of the replicas
+/// of the device with the most of them, or a new replica if every device holds one, which splits the batch into finer shards.
+/// Nodes with the same placement get the same new placement, so groups stay splittable.
+pub fn warm_start(strategy: &Strategy, m: usize, n: usize) -> Strategy {
+    assert!(m > 0 && m <= n, "can only warm start from a plan for fewer devices");
+    let mut nextended = 0;
+    let result = strategy.iter().map(|(name, (devices, method))| {
+        if devices.iter().any(|d| *d >= m) {
+            panic!("{} is placed on device {} which is not in the plan for {} devices", name, devices.iter().max().unwrap(), m)
+        }
+        if devices.len() <= 1 {
+            return (name.clone(), (devices.clone(), *method))
+        }
+        let mut counts = vec![0; n];
+        for d in devices {
+            counts[*d] += 1
+        }
+
+        for new in m..n {
+            let (largest, count) = counts.iter().copied().enumerate().max_by_key(|(d, c)| (*c, std::cmp::Reverse(*d))).unwrap();
+            if count >= 2 {
+                counts[largest] -= count / 2;
+                counts[new] = count / 2
+            } else {
+                counts[new] = 1
+            }
+        }
+        nextended += 1;
+        let devices = counts.iter().enumerate().flat_map(|(d, c)| std::iter::repeat(d).take(*c)).collect();
+        (name.clone(), (devices, *method))
+    }).collect();
+
+    info!("extended {} replicated nodes from {} to {} devices", nextended, m, n);
+    result
+}
+
+/// the file set by the option `decision_trace`, opened for appending. Each decision of the strategy functions is written as a line of JSON
+/// with the scored alternatives and their predicted costs, so they can be compared offline with the measured step.
+fn open_decision_trace(graph: &Graph) -> Option<std::fs::File> {
+    let path = graph.options.get("decision_trace")?;
+    Some(std::fs::OpenOptions::new().create(true).append(true).open(path).unwrap_or_else(|e| panic!("cannot open decision trace {}: {}", path, e)))
+}
+
+/// `text` as a JSON string literal
+fn json_string(text: &str) -> String {
+    let mut result = String::with_capacity(text.len() + 2);
+    result.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c)
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// `x` as a JSON number, or `null` for the infinite and NaN costs that JSON cannot represent
+fn json_number(x: f64) -> String {
+    if x.is_finite() { x.to_string() } else { "null".to_string() }
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (p, n) = (pattern.as_bytes(), name.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    let mut backtrack = None; // position after the last `*` in pattern and the position in name it currently matches up to
+    while j < n.len() {
+        if i < p.len() && (p[i] == b'?' || p[i] == n[j]) {
+            i += 1;
+            j += 1;
+        } else if i < p.len() && p[i] == b'*' {
+            backtrack = Some((i + 1, j));
+            i += 1;
+        } else if let Some((bi, bj)) = backtrack {
+            backtrack = Some((bi, bj + 1));
+            i = bi;
+            j = bj + 1;
+        } else {
+            return false
+        }
+    }
+    p[i..].iter().all(|x| *x == b'*')
+}
+
+/// the average profiled time of the node when not replicated, or a rough guess by op type if there is no profiling data
+fn estimate_cost(node: &Node, profiler: Option<&DataProfiler>) -> u64 {
+    if let Some(prof) = profiler.and_then(|p| p.data.get(&node.raw_node.name)) {
+        if let Some((_, times)) = prof.first() {
+            return times.iter().sum::<u64>() / std::cmp::max(times.len(), 1) as u64
+        }
+    }
+
+    match &node.raw_node.op[..] {
+        "Conv2D" | "Conv2DBackpropInput" | "Conv2DBackpropFilter" | "DepthwiseConv2dNative" |
+        "DepthwiseConv2dNativeBackpropInput" | "DepthwiseConv2dNativeBackpropFilter" => 100,
+        "MatMul" | "BatchMatMul" | "BatchMatMulV2" => 50,
+        "MaxPool" | "MaxPoolGrad" | "AvgPool" | "AvgPoolGrad" | "FusedBatchNorm" | "FusedBatchNormV3" |
+        "FusedBatchNormGrad" | "FusedBatchNormGradV3" => 10,
+        _ => 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_method_has_a_name() {
+        for (i, name) in METHODS.iter().enumerate() {
+            assert_eq!(parse_method(name), Some(i as u8))
+        }
+        assert_eq!(parse_method("cpu"), Some(8));
+        assert_eq!(parse_method("cpu_ring"), Some(9));
+        assert_eq!(parse_method("allreduce"), None);
+    }
+
+    #[test]
+    fn overrides_accept_the_cpu_methods() {
+        let overrides = parse_overrides("dense/* devices=0,1 method=cpu_ring\nloss/* devices=0 method=cpu").unwrap();
+        assert_eq!(overrides.iter().map(|x| x.method).collect::<Vec<_>>(), vec![9, 8]);
+        assert!(parse_overrides("dense/* devices=0,1 method=cpu_tree").is_err());
+    }
+
+    #[test]
+    fn decision_trace_values_are_valid_json() {
+        assert_eq!(json_string("dense/MatMul"), "\"dense/MatMul\"");
+        assert_eq!(json_string("C:\\model.py:3 \"x\"\t\u{1}"), "\"C:\\\\model.py:3 \\\"x\\\"\\t\\u0001\"");
+        assert_eq!(json_string("größe"), "\"größe\"");
+        assert_eq!(json_number(0.25), "0.25");
+        assert_eq!(json_number(f64::INFINITY), "null");
+        assert_eq!(json_number(f64::NAN), "null");
+    }
+}
@@ -0,0 +1,250 @@
+//! Tiny synthetic graphs and assertions for testing strategies and compiler passes without TensorFlow. `linear_model` builds a
+//! one-layer regression with its gradients and optimizer ops, `fake_target` a fully connected cluster of k GPUs, and `compile` runs the
+//! editor and the compiler on them, so a test reads like:
+//!
+//! ```text
+//! let nodes = testing::linear_model(8, 4, 2);
+//! let strategy = testing::replicate_all(&nodes, &[0, 1], 3); // nccl
+//! let target = testing::compile(&nodes, testing::fake_target(2, 1000), &strategy, &[]);
+//! testing::assert_devices(&target, "dense/MatMul", &[0, 1]);
+//! testing::assert_op_count(&target, "NcclAllReduce", 4);
+//! ```
+
+use oh_my_rust::*;
+use std::collections::BTreeMap;
+use crate::graph::Graph;
+use crate::misc::Target;
+use crate::editor::Strategy;
+use crate::naming::owner_of;
+use crate::kernels::{KernelTable, is_gpu};
+use crate::proto::attr_value::{AttrValue, AttrValue_ListValue};
+use crate::proto::node_def::NodeDef;
+use crate::proto::tensor_shape::{TensorShapeProto, TensorShapeProto_Dim};
+use crate::proto::types::DataType;
+
+/// the sink of `linear_model`, which waits for all updates
+pub const TRAIN_OP: &str = "GradientDescent";
+
+fn shape_of(dims: &[usize]) -> TensorShapeProto {
+    TensorShapeProto::new().apply(|x| for d in dims {
+        x.dim.push(TensorShapeProto_Dim::new().apply(|x| x.size = *d as _))
+    })
+}
+
+fn float() -> AttrValue {
+    AttrValue::new().apply(|x| x.set_field_type(DataType::DT_FLOAT))
+}
+
+fn flag(value: bool) -> AttrValue {
+    AttrValue::new().apply(|x| x.set_b(value))
+}
+
+/// a NodeDef with the `_output_shapes` that the analysis relies on and `T` set to float
+fn make(name: &str, op: &str, inputs: &[&str], shapes: &[&[usize]]) -> NodeDef {
+    let mut node = NodeDef::new();
+    node.name = name.into();
+    node.op = op.into();
+    node.input = inputs.iter().map(|x| x.to_string()).collect();
+    node.attr.insert("T".into(), float());
+    node.attr.insert("_output_shapes".into(), AttrValue::new().apply(|x| x.set_list(AttrValue_ListValue::new().apply(|list| for shape in shapes {
+        list.shape.push(shape_of(shape))
+    }))));
+    node
+}
+
+fn constant(name: &str, dtype: DataType, shape: &[usize], fill: impl FnOnce(&mut crate::proto::tensor::TensorProto)) -> NodeDef {
+    make(name, "Const", &[], &[shape]).apply(|node| {
+        node.attr.remove("T");
+        node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(dtype)));
+        let value = crate::proto::tensor::TensorProto::new().apply(|x| {
+            x.set_dtype(dtype);
+            x.set_tensor_shape(shape_of(shape));
+            fill(x)
+        });
+        node.attr.insert("value".into(), AttrValue::new().apply(|x| x.set_tensor(value)));
+    })
+}
+
+/// a float variable initialized to zeros by `<name>/Assign`
+fn variable(name: &str, shape: &[usize]) -> Vec<NodeDef> {
+    let var = make(name, "VariableV2", &[], &[shape]).apply(|node| {
+        node.attr.remove("T");
+        node.attr.insert("dtype".into(), float());
+        node.attr.insert("shape".into(), AttrValue::new().apply(|x| x.set_shape(shape_of(shape))));
+    });
+    let init = constant(&format!("{}/Initializer", name), DataType::DT_FLOAT, shape, |_| {});
+    let assign = make(&format!("{}/Assign", name), "Assign", &[name, &init.name], &[shape]).apply(|node| {
+        node.attr.insert("use_locking".into(), flag(true));
+        node.attr.insert("validate_shape".into(), flag(true));
+    });
+    vec![var, init, assign]
+}
+
+/// a linear regression `y = x * w + b` on a batch of `batch` examples with squared loss, trained by plain SGD:
+///
+/// ```text
+/// x, y                     Placeholder [batch, features], [batch, outputs]
+/// dense/kernel, dense/bias VariableV2 [features, outputs], [outputs] with zero initializers
+/// dense/MatMul, dense/BiasAdd, loss/Sub, loss/Square, loss/Sum
+/// gradients/kernel         MatMul(x, loss/Sub) with transpose_a, the gradient of 0.5 * loss
+/// gradients/bias           BiasAddGrad(loss/Sub)
+/// update/kernel, update/bias ApplyGradientDescent with the learning rate `update/lr`
+/// GradientDescent          NoOp that waits for both updates, see `TRAIN_OP`
+/// ```
+pub fn linear_model(batch: usize, features: usize, outputs: usize) -> Vec<NodeDef> {
+    let mut nodes = vec![];
+    for (name, width) in [("x", features), ("y", outputs)] {
+        nodes.push(make(name, "Placeholder", &[], &[&[batch, width]]).apply(|node| {
+            node.attr.remove("T");
+            node.attr.insert("dtype".into(), float());
+            node.attr.insert("shape".into(), AttrValue::new().apply(|x| x.set_shape(shape_of(&[batch, width]))));
+        }));
+    }
+    nodes.extend(variable("dense/kernel", &[features, outputs]));
+    nodes.extend(variable("dense/bias", &[outputs]));
+
+    let matmul = |name: &str, inputs: &[&str], shape: &[usize], transpose_a: bool| make(name, "MatMul", inputs, &[shape]).apply(|node| {
+        node.attr.insert("transpose_a".into(), flag(transpose_a));
+        node.attr.insert("transpose_b".into(), flag(false));
+    });
+    nodes.push(matmul("dense/MatMul", &["x", "dense/kernel"], &[batch, outputs], false));
+    nodes.push(make("dense/BiasAdd", "BiasAdd", &["dense/MatMul", "dense/bias"], &[&[batch, outputs]]));
+    nodes.push(make("loss/Sub", "Sub", &["dense/BiasAdd", "y"], &[&[batch, outputs]]));
+    nodes.push(make("loss/Square", "Square", &["loss/Sub"], &[&[batch, outputs]]));
+    nodes.push(constant("loss/axes", DataType::DT_INT32, &[2], |x| x.int_val.extend_from_slice(&[0, 1])));
+    nodes.push(make("loss/Sum", "Sum", &["loss/Square", "loss/axes"], &[&[]]).apply(|node| {
+        node.attr.insert("Tidx".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT32)));
+        node.attr.insert("keep_dims".into(), flag(false));
+    }));
+
+    nodes.push(matmul("gradients/kernel", &["x", "loss/Sub"], &[features, outputs], true));
+    nodes.push(make("gradients/bias", "BiasAddGrad", &["loss/Sub"], &[&[outputs]]));
+    nodes.push(constant("update/lr", DataType::DT_FLOAT, &[], |x| x.float_val.push(0.01)));
+    for (var, grad, shape) in [("kernel", "gradients/kernel", &[features, outputs][..]), ("bias", "gradients/bias", &[outputs][..])] {
+        nodes.push(make(&format!("update/{}", var), "ApplyGradientDescent", &[&format!("dense/{}", var), "update/lr", grad], &[shape]).apply(|node| {
+            node.attr.insert("use_locking".into(), flag(false));
+        }));
+    }
+    nodes.push(make(TRAIN_OP, "NoOp", &["^update/kernel", "^update/bias"], &[]).apply(|node| {
+        node.attr.remove("T");
+        node.attr.remove("_output_shapes");
+    }));
+    nodes
+}
+
+/// a sparse layer `y = x * w` on a batch of `batch` rows of `features` columns, fed as a SparseTensor with `nnz` values, without training:
+///
+/// ```text
+/// x/indices, x/values, x/shape Placeholder [nnz, 2] int64, [nnz] float, [2] int64
+/// dense/kernel                 VariableV2 [features, outputs] with a zero initializer
+/// dense/MatMul                 SparseTensorDenseMatMul(x/indices, x/values, x/shape, dense/kernel)
+/// GradientDescent              NoOp that waits for the product, see `TRAIN_OP`
+/// ```
+pub fn sparse_model(batch: usize, features: usize, outputs: usize, nnz: usize) -> Vec<NodeDef> {
+    let mut nodes = vec![];
+    for (name, dtype, shape) in [("x/indices", DataType::DT_INT64, &[nnz, 2][..]), ("x/values", DataType::DT_FLOAT, &[nnz][..]), ("x/shape", DataType::DT_INT64, &[2][..])] {
+        nodes.push(make(name, "Placeholder", &[], &[shape]).apply(|node| {
+            node.attr.remove("T");
+            node.attr.insert("dtype".into(), AttrValue::new().apply(|x| x.set_field_type(dtype)));
+            node.attr.insert("shape".into(), AttrValue::new().apply(|x| x.set_shape(shape_of(shape))));
+        }));
+    }
+    nodes.extend(variable("dense/kernel", &[features, outputs]));
+    nodes.push(make("dense/MatMul", "SparseTensorDenseMatMul", &["x/indices", "x/values", "x/shape", "dense/kernel"], &[&[batch, outputs]]).apply(|node| {
+        node.attr.insert("Tindices".into(), AttrValue::new().apply(|x| x.set_field_type(DataType::DT_INT64)));
+        node.attr.insert("adjoint_a".into(), flag(false));
+        node.attr.insert("adjoint_b".into(), flag(false));
+    }));
+    nodes.push(make(TRAIN_OP, "NoOp", &["^dense/MatMul"], &[]).apply(|node| {
+        node.attr.remove("T");
+        node.attr.remove("_output_shapes");
+    }));
+    nodes
+}
+
+/// `k` GPUs of one task, each pair connected by its own link of `bandwidth`
+pub fn fake_target(k: usize, bandwidth: u64) -> Target {
+    let devices = (0..k).map(|i| format!("/job:worker/replica:0/task:0/device:GPU:{}", i)).collect();
+    let mut links = vec![];
+    let paths = (0..k * k).map(|x| if x / k == x % k {
+        vec![].into_boxed_slice()
+    } else {
+        links.push(bandwidth);
+        vec![links.len() - 1].into_boxed_slice()
+    }).collect();
+    Target::new(Default::default(), devices, links.into_boxed_slice(), paths, vec![TRAIN_OP.to_string()].into_boxed_slice(), BTreeMap::new())
+}
+
+/// replicate every node on `devices` with the aggregation `method`, the strategy of plain data parallelism
+pub fn replicate_all(nodes: &[NodeDef], devices: &[usize], method: u8) -> Strategy {
+    nodes.iter().map(|x| (x.name.clone(), (devices.to_vec(), method))).collect()
+}
+
+/// edit and compile `nodes` on `target` with `strategy` and the graph `options`, panicking if the checks of `batch::compile_checked` fail
+pub fn compile(nodes: &[NodeDef], mut target: Target, strategy: &Strategy, options: &[(&str, &str)]) -> Target {
+    let mut graph = Graph::new(nodes);
+    for (name, value) in options {
+        graph.options.insert(name.to_string(), value.to_string());
+    }
+    crate::editor::edit(&mut graph, &mut target, &strategy.iter().map(|(name, decision)| (&name[..], decision.clone())).collect());
+    crate::batch::compile_checked(&mut graph, &mut target).unwrap_or_else(|e| panic!("compiling failed: {:?}", e));
+    target
+}
+
+/// the number of nodes of each op in the compiled graph
+pub fn op_counts(target: &Target) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for node in target.pb.node.iter() {
+        *counts.entry(node.op.clone()).or_default() += 1
+    }
+    counts
+}
+
+pub fn assert_op_count(target: &Target, op: &str, expected: usize) {
+    let actual = op_counts(target).get(op).copied().unwrap_or(0);
+    assert_eq!(actual, expected, "expected {} {} nodes in the compiled graph, found {}", expected, op, actual)
+}
+
+/// the device indices of the replicas of an original node, in the order of the replicas
+pub fn replica_devices(target: &Target, name: &str) -> Vec<usize> {
+    target.pb.node.iter().filter(|x| x.attr.contains_key("_tge_origin") && owner_of(x) == name).map(|x| {
+        target.devices.iter().position(|d| *d == x.device).unwrap_or_else(|| panic!("{} is on the unknown device {}", x.name, x.device))
+    }).collect()
+}
+
+pub fn assert_devices(target: &Target, name: &str, expected: &[usize]) {
+    let actual = replica_devices(target, name);
+    assert_eq!(actual, expected, "expected {} on devices {:?}, found {:?}", name, expected, actual)
+}
+
+/// the nodes of the compiled graph without GPU kernels (see `kernels::CPU_ONLY_OPS`) must not be placed on GPUs
+pub fn assert_cpu_only_ops_on_cpus(target: &Target) {
+    let table = KernelTable::from_options(&BTreeMap::new());
+    let misplaced: Vec<_> = target.pb.node.iter().filter(|x| is_gpu(&x.device) && !table.has_gpu_kernel(&x.op)).map(|x| format!("{} ({})", x.name, x.op)).collect();
+    assert!(misplaced.is_empty(), "nodes without GPU kernels are placed on GPUs: {}", misplaced.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_parallel_with_nccl() {
+        let nodes = linear_model(8, 4, 2);
+        let strategy = replicate_all(&nodes, &[0, 1], 3);
+        let target = compile(&nodes, fake_target(2, 1000), &strategy, &[]);
+        assert_devices(&target, "dense/MatMul", &[0, 1]);
+        assert_devices(&target, "update/kernel", &[0, 1]);
+        assert_op_count(&target, "NcclAllReduce", 4);
+    }
+
+    #[test]
+    fn single_device_needs_no_aggregation() {
+        let nodes = linear_model(8, 4, 2);
+        let strategy = replicate_all(&nodes, &[1], 3);
+        let target = compile(&nodes, fake_target(2, 1000), &strategy, &[]);
+        assert_devices(&target, "dense/MatMul", &[1]);
+        assert_op_count(&target, "NcclAllReduce", 0);
+        assert_eq!(op_counts(&target).get("MatMul"), Some(&2));
+    }
+}
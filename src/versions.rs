@@ -0,0 +1,57 @@
+use oh_my_rust::*;
+use crate::proto::{node_def::NodeDef, versions::VersionDef};
+
+/// the producer version stamped on emitted graphs when the input graph has no `versions`. A producer of 0 would make TF
+/// apply legacy interpretations (e.g. for Placeholder shapes), while the generated nodes follow the r1.14 conventions.
+pub const DEFAULT_PRODUCER: i32 = 27;
+
+/// producers before this version read an empty `shape` attr of Placeholder as unknown rather than scalar
+pub const PLACEHOLDER_SCALAR_SHAPE: i32 = 22;
+
+/// attrs that the compiler reads from input nodes of these ops. A missing one indicates the graph is produced by a TF whose op definitions differ from what is assumed.
+const ASSUMED_ATTRS: &[(&str, &[&str])] = &[
+    ("Placeholder", &["dtype", "shape"]),
+    ("VariableV2", &["dtype", "shape"]),
+    ("VarHandleOp", &["dtype", "shape"]),
+    ("ConcatV2", &["N", "T", "Tidx"]),
+    ("Split", &["num_split", "T"]),
+    ("Assign", &["T", "validate_shape"]),
+    ("AssignVariableOp", &["dtype"]),
+    ("ApplyGradientDescent", &["T"]),
+    ("ApplyAdam", &["T"])
+];
+
+/// warn about anything in the input graph that the producer version implies to be interpreted differently than the compiler assumes
+pub fn check(versions: &VersionDef, nodes: &[NodeDef]) {
+    let producer = versions.producer;
+    if producer == 0 && !nodes.is_empty() {
+        warn!("the graph has no producer version, assuming {}", DEFAULT_PRODUCER);
+        return
+    }
+
+    if producer < PLACEHOLDER_SCALAR_SHAPE {
+        for node in nodes.iter().filter(|x| x.op == "Placeholder") {
+            if node.attr.get("shape").map(|x| x.has_shape() && !x.get_shape().unknown_rank && x.get_shape().dim.is_empty()).unwrap_or(false) {
+                warn!("{} has an empty shape, which means unknown shape for producer {} but a scalar for the compiler", node.name, producer)
+            }
+        }
+    }
+
+    for node in nodes.iter() {
+        if let Some((_, attrs)) = ASSUMED_ATTRS.iter().find(|(op, _)| *op == node.op) {
+            for attr in attrs.iter().filter(|x| !node.attr.contains_key(**x)) {
+                warn!("{} ({}) has no attr {}, its definition at producer {} may differ from what the compiler assumes", node.name, node.op, attr, producer)
+            }
+        }
+    }
+}
+
+/// the versions of the emitted graph: the producer of the input graph (whose attrs are copied to the replicas) and at least
+/// the consumer requirements of the input graph. Falls back to `DEFAULT_PRODUCER` when the input graph has no versions.
+pub fn emitted(versions: &VersionDef) -> VersionDef {
+    VersionDef::new().apply(|x| {
+        x.producer = if versions.producer == 0 { DEFAULT_PRODUCER } else { versions.producer };
+        x.min_consumer = versions.min_consumer;
+        x.bad_consumers = versions.bad_consumers.clone();
+    })
+}
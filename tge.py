@@ -1,31 +1,108 @@
 import re
+import os
+import json
+import tempfile
 import ctypes
 
 PROFILER_T = ctypes.CFUNCTYPE(ctypes.c_uint64, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32)
+BATCH_CALLBACK_T = ctypes.CFUNCTYPE(None, ctypes.c_uint32, ctypes.c_uint32)
+PROGRESS_T = ctypes.CFUNCTYPE(None, ctypes.c_uint32, ctypes.c_uint32, ctypes.c_uint32, ctypes.c_double)
 
 libtge = ctypes.cdll.LoadLibrary("./libtge.so")
 
+_REPRO_DIR = os.environ.get('TGE_REPRO_DIR') # set by `python tge.py repro`, see TGE.write_repro()
+
 libtge.create_graph.argtypes = [ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
 libtge.create_graph.restype = ctypes.c_void_p
 
+libtge.create_graph_with_registry.argtypes = [ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.c_void_p]
+libtge.create_graph_with_registry.restype = ctypes.c_void_p
+
+libtge.create_registry.argtypes = []
+libtge.create_registry.restype = ctypes.c_void_p
+
+libtge.destroy_registry.argtypes = [ctypes.c_void_p]
+libtge.destroy_registry.restype = None
+
+libtge.register_custom_op.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.register_custom_op.restype = None
+
 libtge.destroy_graph.argtypes = [ctypes.c_void_p]
 libtge.destroy_graph.restype = None
 
 libtge.set_option.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
 libtge.set_option.restype = None
 
+libtge.get_options.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.get_options.restype = ctypes.c_uint32
+
+libtge.set_source_locations.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.set_source_locations.restype = None
+
 libtge.get_groups.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32)]
 libtge.get_groups.restype = None
 
+libtge.selective_replication.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_uint32, ctypes.c_double, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32)]
+libtge.selective_replication.restype = None
+
+libtge.ps_placement.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_double, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32)]
+libtge.ps_placement.restype = None
+
+libtge.parameter_server.argtypes = [ctypes.c_void_p, ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32), ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32)]
+libtge.parameter_server.restype = None
+libtge.local_sgd.argtypes = [ctypes.c_void_p, ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32), ctypes.c_uint32, ctypes.c_uint32, ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32)]
+libtge.local_sgd.restype = ctypes.c_uint32
+
+libtge.apply_overrides.argtypes = [ctypes.c_void_p, ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32)]
+libtge.apply_overrides.restype = ctypes.c_uint32
+
+libtge.warm_start.argtypes = [ctypes.c_uint32, ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32)]
+libtge.warm_start.restype = None
+
+libtge.load_profile.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.load_profile.restype = ctypes.c_void_p
+
+libtge.destroy_profile.argtypes = [ctypes.c_void_p]
+libtge.destroy_profile.restype = None
+
+libtge.profile_strategy.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32)]
+libtge.profile_strategy.restype = None
+
+libtge.run_output_passes.argtypes = [ctypes.c_void_p, ctypes.c_void_p]
+libtge.run_output_passes.restype = None
+
+libtge.import_sharding.argtypes = [ctypes.c_void_p, ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32)]
+libtge.import_sharding.restype = None
+
 libtge.edit_graph.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
 libtge.edit_graph.restype = None
 
+libtge.estimate.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_void_p, ctypes.POINTER(ctypes.c_uint64)]
+libtge.estimate.restype = ctypes.c_uint64
+libtge.anytime_search.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_void_p, ctypes.c_uint64, ctypes.c_uint64, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32), ctypes.POINTER(ctypes.c_uint64)]
+libtge.anytime_search.restype = None
+libtge.pareto_search.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_void_p, ctypes.c_uint64, ctypes.c_uint64, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint64)]
+libtge.pareto_search.restype = ctypes.c_uint32
+libtge.contingency_report.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_void_p, ctypes.c_uint64, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32), ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.contingency_report.restype = ctypes.c_uint32
+libtge.startup_cost.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_uint64)]
+libtge.startup_cost.restype = None
+
+libtge.variable_memory.argtypes = [ctypes.c_void_p, ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint64)]
+libtge.variable_memory.restype = None
+
 libtge.reset_graph.argtypes = [ctypes.c_void_p]
 libtge.reset_graph.restype = None
 
 libtge.create_target.argtypes = [ctypes.POINTER(ctypes.c_char), ctypes.c_uint32] * 5
 libtge.create_target.restype = ctypes.c_void_p
 
+libtge.set_mesh.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.set_mesh.restype = None
+
+libtge.set_cpus.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.set_cpus.restype = None
+
 libtge.destroy_target.argtypes = [ctypes.c_void_p]
 libtge.destroy_target.restype = None
 
@@ -35,8 +112,42 @@ libtge.compute_size.restype = ctypes.c_uint32
 libtge.read_protobuf.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char)]
 libtge.read_protobuf.restype = None
 
+libtge.get_aux_variables.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.get_aux_variables.restype = ctypes.c_uint32
+
+libtge.check_input_sizes.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.c_float, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.check_input_sizes.restype = ctypes.c_uint32
+
+libtge.soft_placement_report.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.soft_placement_report.restype = ctypes.c_uint32
+
+libtge.graph_stats.argtypes = [ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.graph_stats.restype = ctypes.c_uint32
+
+libtge.anonymize_graph.argtypes = [ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.anonymize_graph.restype = ctypes.c_uint32
+
+libtge.set_panic_file.argtypes = [ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.set_panic_file.restype = None
+
+libtge.tge_version.argtypes = []
+libtge.tge_version.restype = ctypes.c_char_p
+
 libtge.compile.argtypes = [ctypes.c_void_p, ctypes.c_void_p]
-libtge.compile.restype = None
+libtge.compile.restype = ctypes.c_uint32
+
+libtge.compile_batch.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_void_p), ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, BATCH_CALLBACK_T]
+libtge.compile_batch.restype = None
+
+libtge.write_feed_mapping.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.write_feed_mapping.restype = None
+libtge.save_target.argtypes = [ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.save_target.restype = ctypes.c_uint64
+libtge.export_partitions.argtypes = [ctypes.c_void_p, ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.export_partitions.restype = ctypes.c_uint32
+
+libtge.set_progress_callback.argtypes = [ctypes.c_void_p, PROGRESS_T]
+libtge.set_progress_callback.restype = None
 
 libtge.create_profiler.argtypes = [ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
 libtge.create_profiler.restype = ctypes.c_void_p
@@ -50,8 +161,15 @@ libtge.heft_rank.restype = None
 libtge.heft_control.argtypes = [ctypes.c_void_p, ctypes.c_void_p]
 libtge.heft_control.restype = None
 
-libtge.evaluate.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint64)]
+libtge.smooth_transfers.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_uint64, ctypes.c_uint32]
+libtge.smooth_transfers.restype = ctypes.c_uint32
+
+libtge.evaluate.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint64)]
 libtge.evaluate.restype = ctypes.c_uint64
+libtge.bandwidth_sensitivity.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_double, ctypes.POINTER(ctypes.c_double)]
+libtge.bandwidth_sensitivity.restype = ctypes.c_uint64
+libtge.placement_heatmap.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32]
+libtge.placement_heatmap.restype = None
 
 libtge.remove_collocation_hint.argtypes = [ctypes.c_void_p]
 libtge.remove_collocation_hint.restype = None
@@ -59,6 +177,18 @@ libtge.remove_collocation_hint.restype = None
 libtge.remove_shape_hint.argtypes = [ctypes.c_void_p]
 libtge.remove_shape_hint.restype = None
 
+libtge.coalesce_transfers.argtypes = [ctypes.c_void_p, ctypes.c_uint64]
+libtge.coalesce_transfers.restype = None
+
+libtge.place_input_pipelines.argtypes = [ctypes.c_void_p]
+libtge.place_input_pipelines.restype = None
+
+libtge.assign_collective_streams.argtypes = [ctypes.c_void_p]
+libtge.assign_collective_streams.restype = None
+
+libtge.insert_step_markers.argtypes = [ctypes.c_void_p]
+libtge.insert_step_markers.restype = None
+
 libtge.destruct_names.argtypes = [ctypes.c_void_p]
 libtge.destruct_names.restype = None
 
@@ -66,6 +196,20 @@ libtge.remove_dangling_nodes.argtypes = [ctypes.c_void_p]
 libtge.remove_dangling_nodes.restype = None
 
 
+if hasattr(libtge, 'plugin_strategy'): # only available when built with the `plugins` feature
+    libtge.plugin_strategy.argtypes = [ctypes.c_void_p, ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint32)]
+    libtge.plugin_strategy.restype = ctypes.c_uint32
+if hasattr(libtge, 'smoke_test'): # only available when built with the `tensorflow` feature
+    libtge.smoke_test.argtypes = [ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.c_uint32, ctypes.c_float]
+    libtge.smoke_test.restype = ctypes.c_uint32
+    libtge.check_equivalence.argtypes = [ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.c_void_p, ctypes.POINTER(ctypes.c_char), ctypes.c_uint32, ctypes.c_uint32, ctypes.c_float]
+    libtge.check_equivalence.restype = ctypes.c_uint32
+
+
+if _REPRO_DIR is not None:
+    _panic_raw = os.path.join(_REPRO_DIR, 'panic.txt').encode('utf-8')
+    libtge.set_panic_file(_panic_raw, len(_panic_raw))
+
 def chain(func):
     def chained(self, *args, **kwargs):
         func(self, *args, **kwargs)
@@ -73,23 +217,330 @@ def chain(func):
     return chained
 
 
+# naming conventions of the compiled graph, the same as src/naming.rs
+
+DEFAULT_REPLICA_FORMAT = '{name}/replica_{index}'
+
+
+def replica_name(name, index, fmt=DEFAULT_REPLICA_FORMAT):
+    return fmt.replace('{name}', name, 1).replace('{index}', str(index), 1)
+
+
+def original_name(replica, fmt=DEFAULT_REPLICA_FORMAT):
+    """(original node name, replica index), or None if `replica` is not a replica name under the replica format `fmt`"""
+    sep, suffix = fmt[len('{name}'):].split('{index}', 1)
+    if not replica.endswith(suffix):
+        return None
+    name, found, index = replica[:len(replica) - len(suffix)].rpartition(sep)
+    if not found or not index.isdigit() or (len(index) > 1 and index.startswith('0')):
+        return None
+    return name, int(index)
+
+
+def replica_format(graph_def):
+    """the replica format that a compiled graph was emitted with, recorded in its `tge_metadata` node if it is not the default"""
+    for node in graph_def.node:
+        if node.name == "tge_metadata" or node.name.endswith("/tge_metadata"):
+            return json.loads(node.attr["value"].tensor.string_val[0].decode())["replica_format"]
+    return DEFAULT_REPLICA_FORMAT
+
+
+def recorded_seed(graph_def):
+    """the seed that a compiled graph was emitted with (see TGE.set_seed), None if it was compiled without an explicit seed"""
+    for node in graph_def.node:
+        if node.name == "tge_metadata" or node.name.endswith("/tge_metadata"):
+            return json.loads(node.attr["value"].tensor.string_val[0].decode()).get("seed")
+    return None
+
+
+def parse_input(x):
+    """
+    (node name, output arg name, output index, is control dependency) of an entry of NodeDef.input.
+    The output arg name is only present in the `node:out:i` syntax of function bodies, where the index is relative to it.
+    """
+    if x.startswith('^'):
+        if not x[1:] or ':' in x:
+            raise ValueError("malformed control input `{}`".format(x))
+        return x[1:], None, 0, True
+    segs = x.split(':')
+    if not segs[0] or len(segs) > 3 or (len(segs) == 3 and not segs[1]) or (len(segs) > 1 and not segs[-1].isdigit()):
+        raise ValueError("malformed input `{}`".format(x))
+    if len(segs) == 1:
+        return x, None, 0, False
+    if len(segs) == 2:
+        return segs[0], None, int(segs[1]), False
+    return segs[0], segs[1], int(segs[2]), False
+
+
+def format_input(name, output=None, index=0, control=False):
+    """the inverse of parse_input"""
+    if control:
+        return '^' + name
+    if output is not None:
+        return '{}:{}:{}'.format(name, output, index)
+    return name if index == 0 else '{}:{}'.format(name, index)
+
+
+def rename_input(x, f):
+    """rename the node referred by an input string, keeping the control mark and the output index"""
+    name, output, index, control = parse_input(x)
+    return format_input(f(name), output, index, control)
+
+
+def add_name_scope(graph_def, scope):
+    """
+    nest every node of graph_def under `scope` in place, e.g. `tge/dense/MatMul/replica_0`, updating the inputs and the `loc:@`
+    entries of `_class` as src/naming.rs does. Function bodies in the library have their own namespace and are untouched.
+    """
+    prefix = scope.rstrip('/') + '/'
+    for node in graph_def.node:
+        node.name = prefix + node.name
+        node.input[:] = [rename_input(x, lambda name: prefix + name) for x in node.input]
+        if '_class' in node.attr:
+            node.attr['_class'].list.s[:] = [b'loc:@' + prefix.encode('ascii') + x[5:] if x.startswith(b'loc:@') else x for x in node.attr['_class'].list.s]
+    return graph_def
+
+
+def import_prefix(graph_def):
+    """the name scope shared by all nodes, as left by `tf.import_graph_def(name=...)`, or None"""
+    scopes = set(node.name.split('/', 1)[0] if '/' in node.name else None for node in graph_def.node)
+    if len(scopes) != 1 or None in scopes:
+        return None
+    return scopes.pop()
+
+
+def strip_name_scope(graph_def, scope):
+    """a copy of graph_def with `scope` removed from the names of all nodes under it, the inverse of add_name_scope"""
+    prefix = scope.rstrip('/') + '/'
+    strip = lambda name: name[len(prefix):] if name.startswith(prefix) else name
+    result = type(graph_def)()
+    result.CopyFrom(graph_def)
+    for node in result.node:
+        node.name = strip(node.name)
+        node.input[:] = [rename_input(x, strip) for x in node.input]
+        if '_class' in node.attr:
+            node.attr['_class'].list.s[:] = [b'loc:@' + strip(x[5:].decode('ascii')).encode('ascii') if x.startswith(b'loc:@') else x for x in node.attr['_class'].list.s]
+    return result
+
+
+_CONTROL_FLOW_OPS = ('Switch', 'RefSwitch', 'Merge', 'RefMerge', 'Enter', 'RefEnter', 'Exit', 'RefExit', 'NextIteration', 'RefNextIteration', 'LoopCond')
+_REF_VARIABLE_OPS = ('Variable', 'VariableV2', 'TemporaryVariable')
+
+def contract_trivial_chains(graph_def, preserve=()):
+    """
+    a copy of graph_def without the trivial Identity and NoOp nodes that frameworks emit in long chains for name scopes and device fences,
+    and the number of removed nodes. Consumers are rewired to whatever the removed nodes forwarded.
+    An Identity is trivial if it has one data input and no control inputs, and does not read a ref variable (which turns the ref into a value)
+    or a control flow op (cond and while loops use those Identities as pivots). A NoOp is trivial if it has at most one control input or at
+    most one consumer, so removing it never multiplies control edges. Nodes in `preserve` (fetches, feeds and sinks), nodes without
+    consumers (which are usually fetched) and nodes named by colocation constraints are kept.
+    """
+    nodes = { node.name: node for node in graph_def.node }
+    keep = set(preserve)
+    consumers = {}
+    for node in graph_def.node:
+        for x in node.input:
+            consumers[parse_input(x)[0]] = consumers.get(parse_input(x)[0], 0) + 1
+        if '_class' in node.attr:
+            keep.update(x[5:].decode('ascii') for x in node.attr['_class'].list.s if x.startswith(b'loc:@'))
+
+    def trivial(node):
+        if node.name in keep or node.name not in consumers:
+            return False
+        inputs = [parse_input(x) for x in node.input]
+        if node.op == 'Identity':
+            if len(inputs) != 1 or inputs[0][3] or inputs[0][1] is not None or inputs[0][0] not in nodes:
+                return False
+            return nodes[inputs[0][0]].op not in _CONTROL_FLOW_OPS + _REF_VARIABLE_OPS
+        if node.op == 'NoOp':
+            return all(x[3] for x in inputs) and (len(inputs) <= 1 or consumers.get(node.name, 0) <= 1)
+        return False
+
+    removed = set(name for name, node in nodes.items() if trivial(node))
+
+    def resolve(x): # the inputs that replace the input entry `x`, following chains of removed nodes
+        name, output, index, control = parse_input(x)
+        if name not in removed:
+            return [x]
+        node = nodes[name]
+        if node.op == 'NoOp':
+            return [y for input in node.input for y in resolve(input)]
+        forwarded = resolve(node.input[0])[0]
+        return [format_input(parse_input(forwarded)[0], control=True)] if control else [forwarded]
+
+    result = type(graph_def)()
+    result.CopyFrom(graph_def)
+    del result.node[:]
+    for node in graph_def.node:
+        if node.name in removed:
+            continue
+        copy = result.node.add()
+        copy.CopyFrom(node)
+        inputs = [y for x in node.input for y in resolve(x)]
+        producers = set(parse_input(x)[0] for x in inputs if not x.startswith('^'))
+        copy.input[:] = [x for i, x in enumerate(inputs) if not x.startswith('^') or (x not in inputs[:i] and x[1:] not in producers)]
+    return result, len(removed)
+
+
+def graph_stats(graph_def):
+    """
+    a dict summarizing graph_def: 'nodes', 'ops' (op => the number of nodes), 'parameters' (elements of the variables, every replica
+    counted), 'activation_bytes' (bytes of the tensors produced by the other nodes), 'unknown_shapes' (tensors left out of the sums as
+    their shapes are not fully known) and 'depth' (the longest chain of edges, ignoring the back edges of while loops)
+    """
+    graph_raw = graph_def.SerializeToString()
+    size = libtge.graph_stats(graph_raw, len(graph_raw), None, 0)
+    buf = ctypes.create_string_buffer(size)
+    libtge.graph_stats(graph_raw, len(graph_raw), buf, size)
+    result = { 'ops': {} }
+    for line in buf.raw.decode('utf-8').split('\n'):
+        fields = line.split('\t')
+        if fields[0] == 'op':
+            result['ops'][fields[1]] = int(fields[2])
+        else:
+            result[fields[0]] = int(fields[1])
+    return result
+
+def format_graph_stats(stats, compiled=None):
+    """graph_stats() as a table, side by side with the stats of the compiled graph and the difference if given"""
+    rows = [(key, stats[key], compiled and compiled[key]) for key in ('nodes', 'parameters', 'activation_bytes', 'unknown_shapes', 'depth')]
+    ops = sorted(set(stats['ops']) | set(compiled['ops'] if compiled else ()), key=lambda op: -max(stats['ops'].get(op, 0), compiled['ops'].get(op, 0) if compiled else 0))
+    rows.extend(('  ' + op, stats['ops'].get(op, 0), compiled and compiled['ops'].get(op, 0)) for op in ops)
+    width = max(len(row[0]) for row in rows)
+    lines = ['{:<{}} {:>14}'.format('', width, 'input') + (' {:>14} {:>14}'.format('compiled', 'added') if compiled else '')]
+    for name, x, y in rows:
+        lines.append('{:<{}} {:>14}'.format(name, width, x) + (' {:>14} {:>+14}'.format(y, y - x) if compiled else ''))
+    return '\n'.join(lines)
+
+def load_graph_def(path):
+    """read a GraphDef written by TGE.save(): text if the path ends with .pbtxt and binary otherwise, gzipped if it further ends with .gz"""
+    import gzip
+    from google.protobuf import text_format
+    from tensorflow.core.framework.graph_pb2 import GraphDef
+    with open(path, 'rb') as f:
+        data = f.read()
+    if path.endswith('.gz'):
+        data, path = gzip.decompress(data), path[:-3]
+    graph_def = GraphDef()
+    if path.endswith('.pbtxt'):
+        text_format.Merge(data.decode('utf-8'), graph_def)
+    else:
+        graph_def.ParseFromString(data)
+    return graph_def
+
+
+def _write_bundle(path, files):
+    """a .tar.gz of the dict from file names to bytes"""
+    import io
+    import tarfile
+    with tarfile.open(path, 'w:gz') as tar:
+        for name, data in sorted(files.items()):
+            info = tarfile.TarInfo(name)
+            info.size = len(data)
+            tar.addfile(info, io.BytesIO(data))
+
+def replay_repro(path):
+    """compile the graph of a bundle written by TGE.write_repro() with its config again. Returns the TGE object"""
+    import tarfile
+    from tensorflow.core.framework.graph_pb2 import GraphDef
+    with tarfile.open(path, 'r:gz') as tar:
+        graph_def = GraphDef()
+        graph_def.ParseFromString(tar.extractfile('graph.pb').read())
+        config = json.loads(tar.extractfile('config.json').read().decode('utf-8'))
+    result = TGE(graph_def, config['devices'], sinks=config['sinks'], custom_ops=config['custom_ops'])
+    result.set_topology(config['links'], config['paths']).set_nccl_model(config['nccls'])
+    if config['mesh'] is not None:
+        result.set_mesh(*config['mesh'])
+    result.set_cpus([(name, { int(g): b for g, b in bandwidth.items() }) for name, bandwidth in config['cpus']])
+    for name, value in config['options'].items():
+        result._set_option(name, value)
+    result.budget_policy = config['budget_policy']
+    return result.set_strategy(config['strategy']).compile()
+
+
+def _custom_op_spec(op, decl):
+    """
+    decl: a dict with keys
+        dtypes: list of DataType enum values (e.g. tf.float32.as_datatype_enum), one for each output
+        shapes: (optional) list of shapes, one for each output. Each shape is either a list of dims (None for unknown),
+                an int i meaning "same as the i-th input", or None for unknown
+        stateful: (optional) stateful ops are never replicated
+        splittable: (optional) whether the op can process a slice of batch
+    """
+    def shape_code(shape):
+        if shape is None:
+            return '-'
+        if type(shape) is int:
+            return 'in' + str(shape)
+        if len(shape) == 0:
+            return 'scalar'
+        return 'x'.join('?' if d is None else str(d) for d in shape)
+
+    spec = op + ' dtypes=' + ','.join(map(str, decl['dtypes']))
+    if 'shapes' in decl:
+        spec += ' shapes=' + ';'.join(map(shape_code, decl['shapes']))
+    spec += ' stateful=' + ('1' if decl.get('stateful', False) else '0')
+    spec += ' splittable=' + ('1' if decl.get('splittable', False) else '0')
+    return spec
+
+
+def _parse_duration(x):
+    """seconds from a number or a string like '90s', '5m' or '1h'"""
+    if isinstance(x, str) and x[-1:] in ('s', 'm', 'h'):
+        return float(x[:-1]) * { 's': 1, 'm': 60, 'h': 3600 }[x[-1]]
+    return float(x)
+
 class TGE:
-    def __init__(self, graph_def, device_list, sinks=["GradientDescent"]):
+    def __init__(self, graph_def, device_list, sinks=["GradientDescent"], custom_ops={}, strip_import_prefix=False, contract_chains=False, preserve=()):
+        """
+        strip_import_prefix: remove the name scope shared by all nodes (see import_prefix()) before analysis, so a graph exported after
+        `tf.import_graph_def(name="model")` does not end up with doubled prefixes. Strategies and sinks then use the stripped names
+        (sinks given with the prefix are stripped as well). Use set_name_scope(self.import_prefix) to put it back on the result.
+        contract_chains: remove trivial Identity and NoOp chains before analysis (see contract_trivial_chains()), which shrinks framework
+        graphs and their compile time. Strategies must not name the removed nodes. `preserve` lists the fetches and feeds to keep; the sinks
+        are always kept.
+        """
+        self.import_prefix = import_prefix(graph_def)
+        if self.import_prefix is not None:
+            if strip_import_prefix:
+                graph_def = strip_name_scope(graph_def, self.import_prefix)
+                sinks = [x[len(self.import_prefix) + 1:] if x.startswith(self.import_prefix + '/') else x for x in sinks]
+            else:
+                print('all nodes are under the scope {}, consider strip_import_prefix=True'.format(self.import_prefix))
+                self.import_prefix = None
+        if contract_chains:
+            graph_def, removed = contract_trivial_chains(graph_def, [*preserve, *sinks])
+            print('contracted {} trivial Identity and NoOp nodes'.format(removed))
+
         self.sinks = sinks
         self.devices = device_list
         self.graph_def = graph_def
+        self.custom_ops = dict(custom_ops)
 
         graph_raw = graph_def.SerializeToString()
-        self.graph = libtge.create_graph(graph_raw, len(graph_raw))
+        if custom_ops:
+            registry = libtge.create_registry()
+            spec_raw = '\n'.join(_custom_op_spec(op, decl) for op, decl in custom_ops.items()).encode('ascii')
+            libtge.register_custom_op(registry, spec_raw, len(spec_raw))
+            self.graph = libtge.create_graph_with_registry(graph_raw, len(graph_raw), registry)
+            libtge.destroy_registry(registry)
+        else:
+            self.graph = libtge.create_graph(graph_raw, len(graph_raw))
 
         # default topology
         self.links = [1000000]
         self.paths = [[] if i == j else [0] for i in range(len(device_list)) for j in range(len(device_list))]
         self.nccls = {}
+        self.mesh = None
+        self.cpus = []
+        self.budget_policy = 'fail'
+        self.progress_callback = None # keeps the ctypes wrapper alive while libtge holds it
 
         self.strategy = None
         self.target = None
         self.profiler = None
+        self.name_scope = None # applied when the compiled graph is read out, see set_name_scope()
+        self.compile_profile = None # a named bundle of knobs loaded by use_profile(), not to be confused with the op profiler
         self.compiled = False # if the target is compiled. Being True also implies that self.target is not None.
         self.edited = False # if the graph is edited. It must be reset before another editing.
 
@@ -102,6 +553,9 @@ class TGE:
         if self.profiler is not None:
             libtge.destroy_profiler(self.profiler)
 
+        if self.compile_profile is not None:
+            libtge.destroy_profile(self.compile_profile)
+
     def get_result(self):
         assert self.target is not None
         size = libtge.compute_size(self.target)
@@ -109,29 +563,494 @@ class TGE:
         libtge.read_protobuf(self.target, buf)
         result = type(self.graph_def)()
         result.ParseFromString(buf.raw)
+        if self.name_scope is not None:
+            add_name_scope(result, self.name_scope)
         return result
 
+    def save(self, path):
+        """
+        write the compiled graph to `path` as text if it ends with .pbtxt and binary otherwise, gzipped if it further ends with .gz.
+        The file is replaced atomically. Returns the number of bytes written.
+        """
+        assert self.compiled
+        if self.name_scope is None:
+            path_raw = path.encode('utf-8')
+            size = libtge.save_target(self.target, path_raw, len(path_raw))
+            if size == 0:
+                raise RuntimeError("cannot save the compiled graph to {}, see the log".format(path))
+            return size
+
+        # the name scope is added on the Python side, see get_result()
+        import gzip
+        from google.protobuf import text_format
+        graph_def = self.get_result()
+        base = path[:-3] if path.endswith('.gz') else path
+        data = text_format.MessageToString(graph_def).encode('utf-8') if base.endswith('.pbtxt') else graph_def.SerializeToString()
+        if path.endswith('.gz'):
+            data = gzip.compress(data)
+        temp = '{}.tmp{}'.format(path, os.getpid())
+        with open(temp, 'wb') as f:
+            f.write(data)
+            f.flush()
+            os.fsync(f.fileno())
+        os.replace(temp, path)
+        return len(data)
+
+    def export_partitions(self, directory, by='device', suffix='.pb', incarnations=None):
+        """
+        split the compiled graph into one GraphDef per device (`by`='device') or per task ('task') and write them to `directory`, for runtimes
+        where each worker imports only its own subgraph. The edges between partitions become _Send/_Recv pairs agreeing on `tensor_name`;
+        `incarnations` maps device names to the incarnations reported by the workers. `suffix` decides the format of the files as in save().
+        `directory`/manifest.json lists the files, the transfers between them and the partitions taking part in each collective.
+        Returns the number of partitions.
+        """
+        assert self.compiled and by in ('device', 'task')
+        assert self.name_scope is None, "exporting partitions does not support set_name_scope()"
+        dir_raw, suffix_raw = directory.encode('utf-8'), suffix.encode('utf-8')
+        incarnations_raw = ''.join('{} {}\n'.format(device, x) for device, x in (incarnations or {}).items()).encode('utf-8')
+        n = libtge.export_partitions(self.target, ('device', 'task').index(by), dir_raw, len(dir_raw), suffix_raw, len(suffix_raw), incarnations_raw, len(incarnations_raw))
+        if n == 0:
+            raise RuntimeError("cannot export the partitions to {}, see the log".format(directory))
+        return n
+
+    def stats(self):
+        """graph_stats() of the input graph and of the compiled graph (None before compile()), see format_graph_stats()"""
+        return graph_stats(self.graph_def), graph_stats(self.get_result()) if self.compiled else None
+
+    def write_repro(self, path, error=None, keep_names=False):
+        """
+        write a bundle for reporting a compiler bug to `path` (a .tar.gz) without sharing the model: graph.pb is the input graph with the
+        contents of tensors stripped (small integer tensors like shapes are kept) and, unless `keep_names`, the nodes renamed to node_<i>;
+        config.json has the devices, the topology, the strategy, the options and the sinks, renamed alike; version.json has the versions of
+        tge, Python and TensorFlow; backtrace.txt has the traceback of `error` (defaults to the exception being handled) and the backtrace of
+        the Rust panic if any. Release builds abort on panics before Python sees them: run the script with `python tge.py repro` for those.
+        """
+        import sys
+        import traceback
+        files = self._repro_files(keep_names)
+        error = error or sys.exc_info()[1]
+        backtrace = ''.join(traceback.format_exception(type(error), error, error.__traceback__)) if error is not None else ''
+        if _REPRO_DIR is not None and os.path.exists(os.path.join(_REPRO_DIR, 'panic.txt')):
+            with open(os.path.join(_REPRO_DIR, 'panic.txt')) as f:
+                backtrace += '\n' + f.read()
+        files['backtrace.txt'] = backtrace.encode('utf-8')
+        _write_bundle(path, files)
+
+    def _repro_files(self, keep_names):
+        """graph.pb, config.json and version.json of write_repro()"""
+        import sys
+        graph_raw = self.graph_def.SerializeToString()
+        size = libtge.anonymize_graph(graph_raw, len(graph_raw), 0 if keep_names else 1, None, 0)
+        buf = ctypes.create_string_buffer(size)
+        libtge.anonymize_graph(graph_raw, len(graph_raw), 0 if keep_names else 1, buf, size)
+
+        size = libtge.get_options(self.graph, None, 0)
+        options_buf = ctypes.create_string_buffer(size)
+        libtge.get_options(self.graph, options_buf, size)
+        options = dict(line.split('\t', 1) for line in options_buf.raw.decode('utf-8').split('\n') if line)
+
+        names = {} if keep_names else { node.name: 'node_{}'.format(i) for i, node in enumerate(self.graph_def.node) }
+        rename = lambda x: re.sub(r'[^\s,=;:^]+', lambda m: names.get(m.group(0), m.group(0)), x) # also node names in tensor names and lists
+        config = {
+            'devices': self.devices,
+            'sinks': [rename(x) for x in self.sinks],
+            'strategy': { rename(name): list(s) for name, s in (self.strategy or {}).items() },
+            'links': self.links,
+            'paths': self.paths,
+            'nccls': self.nccls,
+            'mesh': self.mesh,
+            'cpus': [[name, { str(g): b for g, b in bandwidth.items() }] for name, bandwidth in self.cpus],
+            'budget_policy': self.budget_policy,
+            'custom_ops': self.custom_ops,
+            'options': { name: rename(value) for name, value in options.items() }
+        }
+        tf = sys.modules.get('tensorflow')
+        version = { 'tge': libtge.tge_version().decode('ascii'), 'python': sys.version, 'tensorflow': getattr(tf, '__version__', None) }
+        return {
+            'graph.pb': buf.raw,
+            'config.json': json.dumps(config, indent=2).encode('utf-8'),
+            'version.json': json.dumps(version, indent=2).encode('utf-8')
+        }
+
+    def scoped(self, name):
+        """the name of a node of the compiled graph as it appears in get_result(), see set_name_scope()"""
+        return name if self.name_scope is None else '{}/{}'.format(self.name_scope.rstrip('/'), name)
+
+    init_op = "tge_init" # runs all variable initializers (and aux variable initializers) of the compiled graph once
+    broadcast_op = "tge_broadcast" # copies replica 0 of every replicated variable to the other replicas, e.g. after restoring only replica 0. See set_broadcast_op()
+
+    def get_aux_variables(self):
+        """
+        names of the persistent variables created by the compiler (all under `tge_aux/`). They are initialized by the `tge_aux/init` op
+        and should be excluded from checkpoints.
+        """
+        assert self.compiled
+        size = libtge.get_aux_variables(self.target, None, 0)
+        buf = ctypes.create_string_buffer(size)
+        libtge.get_aux_variables(self.target, buf, size)
+        return [self.scoped(x) for x in buf.raw.decode('ascii').split('\n') if x]
+
+    @staticmethod
+    def dump_runtime_shapes(run_metadata):
+        """
+        the output shapes recorded in the step stats of a traced run (`tf.RunOptions(trace_level=tf.RunOptions.FULL_TRACE)`)
+        in the format accepted by check_input_sizes: one `<node>:<output> <dtype> [<dim>,...]` line per tensor
+        """
+        lines = []
+        for dev_stats in run_metadata.step_stats.dev_stats:
+            for node_stats in dev_stats.node_stats:
+                for output in node_stats.output:
+                    dims = [d.size for d in output.tensor_description.shape.dim]
+                    if any(d < 0 for d in dims):
+                        continue
+                    lines.append("{}:{} {} [{}]".format(node_stats.node_name, output.slot, output.tensor_description.dtype, ','.join(map(str, dims))))
+        return '\n'.join(lines)
+
+    def check_input_sizes(self, shape_dump, tolerance=0.1):
+        """
+        compare the `_tge_input_sizes` that the compiler annotated with a runtime shape dump (see dump_runtime_shapes) of the compiled graph.
+        Returns (node, input, annotated bytes, actual bytes) for the inputs whose relative error exceeds `tolerance`, largest error first.
+        Stale `_output_shapes` show up here and usually explain bad placement decisions.
+        """
+        assert self.compiled
+        dump_raw = shape_dump.encode('ascii')
+        size = libtge.check_input_sizes(self.target, dump_raw, len(dump_raw), tolerance, None, 0)
+        buf = ctypes.create_string_buffer(size)
+        libtge.check_input_sizes(self.target, dump_raw, len(dump_raw), tolerance, buf, size)
+        result = []
+        for line in buf.raw.decode('ascii').split('\n'):
+            if line:
+                node, input, annotated, actual = line.split('\t')
+                result.append((node, input, int(annotated), int(actual)))
+        return result
+
+    @chain
+    def set_source_locations(self, locations):
+        """
+        where the nodes were defined in the model code, so warnings, errors, the decision trace and soft_placement_report() point at it.
+        `locations` maps node names to 'file:line', or is the tf.Graph that built the model, whose op tracebacks are used
+        """
+        if hasattr(locations, 'get_operations'):
+            locations = { op.name: '{}:{}'.format(op.traceback[-1][0], op.traceback[-1][1]) for op in locations.get_operations() if op.traceback }
+        if self.import_prefix is not None: # the names were stripped by strip_import_prefix
+            prefix = self.import_prefix + '/'
+            locations = { name[len(prefix):] if name.startswith(prefix) else name: location for name, location in locations.items() }
+        locations_raw = ''.join('{}\t{}\n'.format(name, location) for name, location in locations.items()).encode('utf-8')
+        libtge.set_source_locations(self.graph, locations_raw, len(locations_raw))
+
+    def soft_placement_report(self):
+        """
+        the compiled nodes that TF would move to the CPU of their task under `allow_soft_placement`, as (node, op, from, to, reason, source),
+        where source is the location of the op it comes from (see set_source_locations(), empty if unknown). Includes the aux nodes of the
+        compiler. Colocation groups dragged along are only found if `_class` hints are still present, which compile() removes by default.
+        """
+        assert self.compiled
+        size = libtge.soft_placement_report(self.graph, self.target, None, 0)
+        buf = ctypes.create_string_buffer(size)
+        libtge.soft_placement_report(self.graph, self.target, buf, size)
+        return [tuple(line.split('\t')) for line in buf.raw.decode('utf-8').split('\n') if line]
+
     def get_groups(self):
         names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
         result = (ctypes.c_uint32 * len(self.graph_def.node))(*(0 for x in self.graph_def.node))
         libtge.get_groups(self.graph, names_raw, len(names_raw), result)
         return list(result)
 
+    @chain
+    def selective_replication(self, profile_dict=None, coverage=0.9, method=1):
+        """
+        data-parallel only the expensive backbone (the ops that cover `coverage` of the total cost and everything between them)
+        and keep cheap pre/post-processing on the first device. Costs are estimated by op type if profile_dict is not given.
+        """
+        profiler = None
+        if profile_dict is not None:
+            self._create_profiler(profile_dict)
+            profiler = self.profiler
+        names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
+        result = (ctypes.c_uint32 * len(self.graph_def.node))(*(0 for x in self.graph_def.node))
+        libtge.selective_replication(self.graph, profiler, len(self.devices), coverage, names_raw, len(names_raw), result)
+        strategy = {}
+        for node, hot in zip(self.graph_def.node, result):
+            if hot:
+                strategy[node.name] = [method] + [1] * len(self.devices)
+            else:
+                strategy[node.name] = [0, 1] + [0] * (len(self.devices) - 1)
+        self.set_strategy(strategy)
+
+    @chain
+    def use_profile(self, name, profile_dir="profiles"):
+        """
+        load `<profile_dir>/<name>.profile` (see src/profile.rs for the format), apply its options and use its strategy.
+        The output passes listed in the profile run at the end of compile().
+        """
+        with open(os.path.join(profile_dir, name + ".profile")) as f:
+            text_raw = f.read().encode('ascii')
+        profile = libtge.load_profile(self.graph, text_raw, len(text_raw))
+        if not profile:
+            raise ValueError("invalid profile {}, see the log".format(name))
+        if self.compile_profile is not None:
+            libtge.destroy_profile(self.compile_profile)
+        self.compile_profile = profile
+
+        n, ndev = len(self.graph_def.node), len(self.devices)
+        names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
+        result = (ctypes.c_uint32 * (n * (ndev + 1)))(*(0 for x in range(n * (ndev + 1))))
+        libtge.profile_strategy(self.compile_profile, self.graph, ndev, names_raw, len(names_raw), result)
+        self.set_strategy({ node.name: list(result[i * (ndev + 1): (i + 1) * (ndev + 1)]) for i, node in enumerate(self.graph_def.node) })
+
+    @chain
+    def ps_placement(self, hot_fraction=0):
+        """
+        parameter-server strategy: replicate the computation on all devices and put each variable (with its optimizer slots) on the device
+        with the best aggregate bandwidth to the others given the current topology, balancing the load. The largest variables that make up
+        `hot_fraction` of all variable bytes are replicated on two devices.
+        """
+        self._create_target()
+        n, ndev = len(self.graph_def.node), len(self.devices)
+        names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
+        result = (ctypes.c_uint32 * (n * (ndev + 1)))(*(0 for x in range(n * (ndev + 1))))
+        libtge.ps_placement(self.graph, self.target, hot_fraction, names_raw, len(names_raw), result)
+        self.set_strategy({ node.name: list(result[i * (ndev + 1): (i + 1) * (ndev + 1)]) for i, node in enumerate(self.graph_def.node) })
+
+    @chain
+    def parameter_server(self, servers, workers=None):
+        """
+        parameter-server strategy: put the variables (with their optimizer ops and slots) on the devices in `servers`, balancing the bytes
+        each one holds, and replicate the computation on the devices in `workers` (by default all devices that are not servers, or all
+        devices if every device is a server). Gradients are summed on the server of their variable. Devices are indexes into the device list.
+        """
+        ndev = len(self.devices)
+        if workers is None:
+            workers = [i for i in range(ndev) if i not in servers] or list(range(ndev))
+        assert len(servers) > 0 and len(workers) > 0
+        n = len(self.graph_def.node)
+        names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
+        servers_raw = (ctypes.c_uint32 * len(servers))(*servers)
+        workers_raw = (ctypes.c_uint32 * len(workers))(*workers)
+        result = (ctypes.c_uint32 * (n * (ndev + 1)))(*(0 for x in range(n * (ndev + 1))))
+        libtge.parameter_server(self.graph, ndev, servers_raw, len(servers), workers_raw, len(workers), names_raw, len(names_raw), result)
+        self.set_strategy({ node.name: list(result[i * (ndev + 1): (i + 1) * (ndev + 1)]) for i, node in enumerate(self.graph_def.node) })
+
+    @chain
+    def local_sgd(self, period, devices=None, method=1):
+        """
+        local SGD strategy: replicate everything on `devices` (by default all devices), let each replica apply its own gradients and
+        average the variables across the replicas only every `period` steps, so slow links are used once per `period` steps. `method`
+        aggregates the gradients that are not applied locally. Same as setting the strategy by hand and calling set_local_sgd(period).
+        """
+        ndev = len(self.devices)
+        if devices is None:
+            devices = list(range(ndev))
+        assert period >= 1 and len(devices) > 0
+        n = len(self.graph_def.node)
+        names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
+        devices_raw = (ctypes.c_uint32 * len(devices))(*devices)
+        result = (ctypes.c_uint32 * (n * (ndev + 1)))(*(0 for x in range(n * (ndev + 1))))
+        period = libtge.local_sgd(self.graph, ndev, devices_raw, len(devices), period, method, names_raw, len(names_raw), result)
+        self.set_strategy({ node.name: list(result[i * (ndev + 1): (i + 1) * (ndev + 1)]) for i, node in enumerate(self.graph_def.node) })
+        self.set_local_sgd(period)
+
+    @chain
+    def plugin_strategy(self, path):
+        """
+        set the strategy chosen by a strategy plugin, a shared object implementing the versioned C ABI described in src/plugin.rs.
+        Nodes the plugin makes no decision for keep their current strategy. Raises ValueError if the plugin cannot be loaded or fails.
+        """
+        assert hasattr(libtge, 'plugin_strategy'), "libtge is not built with the plugins feature"
+        self._create_target()
+        n, ndev = len(self.graph_def.node), len(self.devices)
+        path_raw = path.encode('utf-8')
+        names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
+        result = (ctypes.c_uint32 * (n * (ndev + 1)))(*(0 for x in range(n * (ndev + 1))))
+        if libtge.plugin_strategy(self.graph, self.target, path_raw, len(path_raw), names_raw, len(names_raw), result) != 0:
+            raise ValueError("strategy plugin {} failed, see the log".format(path))
+        strategy = dict(self.strategy or {})
+        for i, node in enumerate(self.graph_def.node):
+            row = list(result[i * (ndev + 1): (i + 1) * (ndev + 1)])
+            if sum(row[1:]) > 0:
+                strategy[node.name] = row
+        self.set_strategy(strategy)
+
+    @chain
+    def apply_overrides(self, path):
+        """
+        patch the current strategy with an overrides file applied as a final pass. Each line is
+        `<pattern> devices=<d0,d1,...> [method=<ps|collective|ring|nccl|sync|hierarchical|tree|double_tree|cpu|cpu_ring>]`, e.g. `decoder/attention/* devices=1`.
+        Raises ValueError if a node is matched by overrides that disagree.
+        """
+        assert self.strategy is not None
+        with open(path) as f:
+            text_raw = f.read().encode('ascii')
+        n, ndev = len(self.graph_def.node), len(self.devices)
+        names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
+        rows = [self.strategy.get(node.name, [0] * (ndev + 1)) for node in self.graph_def.node]
+        result = (ctypes.c_uint32 * (n * (ndev + 1)))(*(x for row in rows for x in row))
+        if libtge.apply_overrides(self.graph, ndev, text_raw, len(text_raw), names_raw, len(names_raw), result) != 0:
+            raise ValueError("cannot apply overrides in {}, see the log".format(path))
+        strategy = dict(self.strategy)
+        for i, node in enumerate(self.graph_def.node):
+            row = list(result[i * (ndev + 1): (i + 1) * (ndev + 1)])
+            if sum(row[1:]) > 0:
+                strategy[node.name] = row
+        self.set_strategy(strategy)
+
+    @chain
+    def warm_start(self, strategy):
+        """
+        use a plan made for the first few of the current devices (e.g. a good 4-GPU plan after expanding to 8 GPUs) as the strategy,
+        extended to all devices by splitting the largest shards of replicated nodes. Refine it afterwards instead of searching from scratch.
+        """
+        n, ndev = len(self.graph_def.node), len(self.devices)
+        m = max(len(row) - 1 for row in strategy.values())
+        assert m <= ndev
+        names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
+        rows = [list(strategy.get(node.name, [0] * (m + 1))) + [0] * (ndev - m) for node in self.graph_def.node]
+        result = (ctypes.c_uint32 * (n * (ndev + 1)))(*(x for row in rows for x in row))
+        libtge.warm_start(m, ndev, names_raw, len(names_raw), result)
+        strategy = {}
+        for i, node in enumerate(self.graph_def.node):
+            row = list(result[i * (ndev + 1): (i + 1) * (ndev + 1)])
+            if sum(row[1:]) > 0:
+                strategy[node.name] = row
+        self.set_strategy(strategy)
+
+    @chain
+    def import_sharding(self, method=1):
+        """
+        use the XLA sharding annotations (_XlaSharding attrs) in the graph as the strategy of the annotated nodes.
+        Nodes without annotation keep the decisions of the current strategy (or are replicated on all devices if there is none).
+        Nodes annotated as replicated are not split even if their group is, see the `unsplit_nodes` option. Nodes tiled along another
+        dimension than the batch are split along it, see set_split_forms().
+        """
+        n, ndev = len(self.graph_def.node), len(self.devices)
+        names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
+        result = (ctypes.c_uint32 * (n * (ndev + 1)))(*(0 for x in range(n * (ndev + 1))))
+        libtge.import_sharding(self.graph, ndev, names_raw, len(names_raw), result)
+        strategy = dict(self.strategy or {})
+        unsplit, split_forms = [], {}
+        for i, node in enumerate(self.graph_def.node):
+            row = result[i * (ndev + 1): (i + 1) * (ndev + 1)]
+            if sum(row[1:]) > 0:
+                strategy[node.name] = [method if sum(row[1:]) > 1 else 0] + list(row[1:])
+                if not row[0]:
+                    unsplit.append(node.name)
+                elif row[0] > 1:
+                    split_forms[node.name] = 'part{}'.format(row[0] - 1) + ''.join('_{}'.format(d) for d in range(ndev) for _ in range(row[d + 1]))
+        self.set_strategy(strategy)
+        self._set_option("unsplit_nodes", ' '.join(unsplit))
+        self.set_split_forms(split_forms)
+
     @chain
     def compile(self):
         print('compile() is called.')
         assert self.strategy is not None
         self._create_target()
         self._edit()
+        if _REPRO_DIR is not None:
+            for name, data in self._repro_files(keep_names=os.environ.get('TGE_REPRO_KEEP_NAMES') == '1').items():
+                with open(os.path.join(_REPRO_DIR, name), 'wb') as f:
+                    f.write(data)
         print('libtge.compile() starts!')
-        libtge.compile(self.graph, self.target)
+        status = libtge.compile(self.graph, self.target)
         print('libtge.compile() finishes!')
+        if status == 3:
+            raise RuntimeError("some ops without GPU kernels are placed on GPUs, see the log. Use set_kernel_overrides() if the kernel table is wrong, or set_soft_placement() if the session allows soft placement")
+        if status == 2:
+            raise RuntimeError("some transfers have no path in the topology, see the log. Use set_hub_device() to relay them")
+        if status == 1:
+            if self.budget_policy != 'degrade' or all(s[0] == 0 for s in self.strategy.values()):
+                raise RuntimeError("the compiled graph exceeds the budget")
+            print('the compiled graph exceeds the budget, retry with the simplest aggregation for all nodes')
+            self.strategy = { name: [0, *s[1:]] for name, s in self.strategy.items() }
+            return self.compile()
         self.compiled = True
 
         # for backward compatibility
         self.remove_collocation_hint()
         self.remove_shape_hint()
 
+        if self.compile_profile is not None:
+            libtge.run_output_passes(self.compile_profile, self.target)
+
+    def compile_batch(self, jobs, on_result=None):
+        """
+        compile the graph once for each job, reusing the parsed graph. Each job is a dict with a `strategy` (same format as set_strategy)
+        and optionally `links`, `paths` and `nccls` overriding the current topology.
+        on_result(index, graph_def, status) is called as each job finishes; graph_def is None unless status is 0 (see compile()).
+        Returns the list of (graph_def, status) in the order of jobs. The current strategy and target are not affected.
+        """
+        targets = [self._new_target(job.get('links', self.links), job.get('paths', self.paths), job.get('nccls', self.nccls)) for job in jobs]
+        strategies_raw = '---\n'.join(self._strategy_raw(job['strategy']) for job in jobs).encode('ascii')
+        results = [None] * len(jobs)
+
+        def callback(index, status):
+            graph_def = None
+            if status == 0:
+                size = libtge.compute_size(targets[index])
+                buf = ctypes.create_string_buffer(size)
+                libtge.read_protobuf(targets[index], buf)
+                graph_def = type(self.graph_def)()
+                graph_def.ParseFromString(buf.raw)
+                if self.name_scope is not None:
+                    add_name_scope(graph_def, self.name_scope)
+            results[index] = (graph_def, status)
+            if on_result is not None:
+                on_result(index, graph_def, status)
+
+        try:
+            libtge.compile_batch(self.graph, (ctypes.c_void_p * len(targets))(*targets), len(targets), strategies_raw, len(strategies_raw), BATCH_CALLBACK_T(callback))
+        finally:
+            for target in targets:
+                libtge.destroy_target(target)
+            self.edited = True # the graph now holds the edits of the last job
+            self.compiled = False
+        return results
+
+    def variable_memory(self):
+        """the resident bytes of variables and optimizer slots on each device under the current strategy, regardless of activations"""
+        assert self.strategy is not None
+        if not self.edited:
+            self._create_target()
+            self._edit()
+        result = (ctypes.c_uint64 * len(self.devices))(*(0 for x in self.devices))
+        libtge.variable_memory(self.graph, len(self.devices), result)
+        return list(result)
+
+    def startup_cost(self):
+        """
+        the one-time cost of starting the compiled plan, to weigh against the per-step time for short jobs:
+        `broadcast_time` to copy replica 0 of the variables to all replicas with `broadcast_op` (0 without set_broadcast_op()), `constant_time` to ship the constants to remote workers,
+        and `constant_bytes` in total. Must be called before evaluate() since that prunes the broadcast op.
+        """
+        assert self.compiled
+        result = (ctypes.c_uint64 * 3)(0, 0, 0)
+        libtge.startup_cost(self.target, result)
+        return { 'broadcast_time': result[0], 'constant_time': result[1], 'constant_bytes': result[2] }
+
+    def smoke_test(self, fetches, batchsize, init_op="init", tolerance=1e-4):
+        """
+        run one step of both the original and the compiled graph on virtual CPUs and compare the values of `fetches` (e.g. the loss).
+        Requires libtge built with `--features tensorflow`, and the model must use fixed op-level seeds. Returns True if they match.
+        """
+        assert self.compiled
+        assert hasattr(libtge, 'smoke_test'), "libtge is not built with the tensorflow feature"
+        graph_raw = self.graph_def.SerializeToString()
+        init_raw = (init_op or "").encode('ascii')
+        fetches_raw = ' '.join(fetches).encode('ascii')
+        return libtge.smoke_test(graph_raw, len(graph_raw), self.target, init_raw, len(init_raw), fetches_raw, len(fetches_raw), batchsize, tolerance) == 0
+
+    def check_equivalence(self, batchsize, init_op="init", tolerance=1e-4):
+        """
+        run one step of both graphs with the same inputs and seeds, and compare every float tensor that has a replica in the compiled graph.
+        The first diverging tensor (by its original name) is reported in the log. Requires the `tensorflow` feature.
+        Returns True if the graphs are equivalent within the tolerance.
+        """
+        assert self.compiled
+        assert hasattr(libtge, 'check_equivalence'), "libtge is not built with the tensorflow feature"
+        graph_raw = self.graph_def.SerializeToString()
+        init_raw = (init_op or "").encode('ascii')
+        return libtge.check_equivalence(graph_raw, len(graph_raw), self.target, init_raw, len(init_raw), batchsize, tolerance) == 0
+
     @chain
     def heft(self, profile_dict, add_control_dependency=False):
         if not self.compiled:
@@ -143,7 +1062,137 @@ class TGE:
         else:
             libtge.heft_rank(self.target, self.profiler)
 
-    def evaluate(self, profile_dict, trace_path=""):
+    @chain
+    def smooth_transfers(self, profile_dict, threshold=1<<20, max_inflight=1):
+        """
+        let at most `max_inflight` transfers of at least `threshold` bytes share a bottleneck link at a time, ordered by HEFT rank,
+        instead of firing all gradient transfers at once. Kept only if the simulator does not predict a slower step.
+        """
+        if not self.compiled:
+            self.compile()
+
+        self._create_profiler(profile_dict)
+        ngated = libtge.smooth_transfers(self.target, self.profiler, threshold, max_inflight)
+        print('gated {} transfers'.format(ngated))
+
+    @chain
+    def enable_subgraph_cache(self):
+        """
+        reuse the replicas and conversions emitted by previous compile() or compile_batch() calls on this object when their forms are unchanged,
+        which speeds up strategy searches that change a few nodes at a time. The cache is dropped when options change.
+        """
+        self._set_option("subgraph_cache", True)
+
+    def estimate(self, profile_dict):
+        """
+        predict (time, memory per device) of the current strategy without compiling, for search loops that evaluate many candidates.
+        Coarser than evaluate(): the graph is scheduled in its own order instead of simulated. Aggregations are still built by the editor.
+        """
+        assert self.strategy is not None
+        self._create_target()
+        self._edit()
+        self._create_profiler(profile_dict)
+        memory = (ctypes.c_uint64 * len(self.devices))(*(0 for x in self.devices))
+        time = libtge.estimate(self.graph, self.target, self.profiler, memory)
+        return time, list(memory)
+
+    def search(self, profile_dict, budget=60, memory_limit=None):
+        """
+        improve the current strategy by local search until the wall-clock `budget` runs out, then set the best plan found so far.
+        `budget` is in seconds, or a string like '90s', '5m' or '1h'. Plans are compared with estimate(); those predicted to use more than
+        `memory_limit` bytes on a device rank after all plans that fit. Returns (time, memory per device) of the chosen plan as predicted by estimate().
+        """
+        assert self.strategy is not None
+        budget = _parse_duration(budget)
+        self._create_target()
+        self._create_profiler(profile_dict)
+        if self.edited:
+            libtge.reset_graph(self.graph)
+            self.edited = False
+        n, ndev = len(self.graph_def.node), len(self.devices)
+        names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
+        rows = [self.strategy.get(node.name, [0] * (ndev + 1)) for node in self.graph_def.node]
+        result = (ctypes.c_uint32 * (n * (ndev + 1)))(*(x for row in rows for x in row))
+        metrics = (ctypes.c_uint64 * (3 + ndev))(*(0 for x in range(3 + ndev)))
+        libtge.anytime_search(self.graph, self.target, self.profiler, int(budget * 1000), memory_limit or 0, names_raw, len(names_raw), result, metrics)
+        print("search evaluated {} plans in {:.1f}s".format(metrics[1], metrics[2] / 1000))
+        strategy = dict(self.strategy)
+        for i, node in enumerate(self.graph_def.node):
+            row = list(result[i * (ndev + 1): (i + 1) * (ndev + 1)])
+            if sum(row[1:]) > 0:
+                strategy[node.name] = row
+        self.set_strategy(strategy)
+        return metrics[0], list(metrics[3:])
+
+    def pareto_search(self, profile_dict, budget=60, memory_limit=None, max_plans=8):
+        """
+        like search(), but returns the plans that are not beaten in all of step time, peak memory and network traffic instead of setting the fastest.
+        Each plan is a dict with 'strategy', 'time', 'traffic' (bytes between devices) and 'memory' (per device). The fastest, the one with the
+        least peak memory and the one with the least traffic come first, then the others by time, up to `max_plans`. Pick one with set_strategy().
+        """
+        assert self.strategy is not None
+        budget = _parse_duration(budget)
+        self._create_target()
+        self._create_profiler(profile_dict)
+        if self.edited:
+            libtge.reset_graph(self.graph)
+            self.edited = False
+        n, ndev = len(self.graph_def.node), len(self.devices)
+        block = n * (ndev + 1)
+        names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
+        rows = [self.strategy.get(node.name, [0] * (ndev + 1)) for node in self.graph_def.node]
+        result = (ctypes.c_uint32 * (max_plans * block))(*(x for row in rows for x in row))
+        metrics = (ctypes.c_uint64 * (max_plans * (2 + ndev)))(*(0 for x in range(max_plans * (2 + ndev))))
+        count = libtge.pareto_search(self.graph, self.target, self.profiler, int(budget * 1000), memory_limit or 0, names_raw, len(names_raw), result, max_plans, metrics)
+        plans = []
+        for k in range(count):
+            strategy = dict(self.strategy)
+            for i, node in enumerate(self.graph_def.node):
+                row = list(result[k * block + i * (ndev + 1): k * block + (i + 1) * (ndev + 1)])
+                if sum(row[1:]) > 0:
+                    strategy[node.name] = row
+            m = metrics[k * (2 + ndev): (k + 1) * (2 + ndev)]
+            plans.append({ 'strategy': strategy, 'time': m[0], 'traffic': m[1], 'memory': list(m[2:]) })
+        return plans
+
+    def contingency_report(self, profile_dict, memory_limit=None):
+        """
+        what breaks if a device disappears, for each device of the current strategy: the nodes placed only on it move together to the
+        surviving device that gives the fastest repaired plan, and the replicas on it are dropped. Returns a list of dicts with 'device',
+        'slowdown' (repaired / intact predicted time, None if no device survives), 'fits' (within `memory_limit` bytes per device after repair),
+        'fallback' (where the orphaned nodes go, None if there are none), 'lost_replicas' and 'orphaned' (node names).
+        """
+        assert self.strategy is not None
+        self._create_target()
+        self._create_profiler(profile_dict)
+        if self.edited:
+            libtge.reset_graph(self.graph)
+            self.edited = False
+        n, ndev = len(self.graph_def.node), len(self.devices)
+        names_raw = ' '.join((node.name for node in self.graph_def.node)).encode('ascii')
+        rows = [self.strategy.get(node.name, [0] * (ndev + 1)) for node in self.graph_def.node]
+        result = (ctypes.c_uint32 * (n * (ndev + 1)))(*(x for row in rows for x in row))
+        args = (self.graph, self.target, self.profiler, memory_limit or 0, names_raw, len(names_raw), result)
+        size = libtge.contingency_report(*args, None, 0)
+        buf = ctypes.create_string_buffer(size)
+        libtge.contingency_report(*args, buf, size)
+        report = []
+        for line in buf.raw.decode('ascii').split('\n'):
+            if not line:
+                continue
+            device, baseline, repaired, fits, fallback, lost_replicas, orphaned = line.split('\t')
+            report.append({
+                'device': int(device),
+                'slowdown': int(repaired) / max(int(baseline), 1) if int(repaired) >= 0 else None,
+                'fits': fits == 'true',
+                'fallback': int(fallback) if int(fallback) >= 0 else None,
+                'lost_replicas': int(lost_replicas),
+                'orphaned': orphaned.split(',') if orphaned else []
+            })
+        return report
+
+    def evaluate(self, profile_dict, trace_path="", lifetime_path=""):
+        """lifetime_path: if set, export the lifetime of each tensor on each device. The format is svg if the path ends with .svg, otherwise json."""
         print('evaluate is called.')
         if not self.compiled: # for backward compatibility
             self.compile()
@@ -155,44 +1204,88 @@ class TGE:
         self.remove_dangling_nodes()
         print('remove_dangling_nodes() finishes!')
         trace_path = trace_path.encode('ascii')
+        lifetime_path = lifetime_path.encode('ascii')
         memory = (ctypes.c_uint64 * len(self.devices))(*(0 for x in self.devices))
         self._create_profiler(profile_dict)
         result = libtge.evaluate(self.target, self.profiler, trace_path,
-                                 len(trace_path), memory)
+                                 len(trace_path), lifetime_path, len(lifetime_path), memory)
         self.target = None # evaluator now takes the ownership of target
 
         return result, list(memory)
 
+    def bandwidth_sensitivity(self, profile_dict, delta=0.1):
+        """
+        simulate the compiled plan with the bandwidth of each link raised by `delta` (relative) in turn, to tell which interconnect upgrade
+        would actually help. Returns (step time, entries) where each entry is a dict with 'link', 'bandwidth', 'time' (the perturbed step time),
+        'derivative' (step time per unit of bandwidth) and 'elasticity' (relative change of step time per relative change of bandwidth),
+        most helpful links first. Unlike evaluate(), the target is kept as is: the dangling nodes are only removed from a copy.
+        """
+        if not self.compiled:
+            self.compile()
+        self._create_profiler(profile_dict)
+        result = (ctypes.c_double * (3 * len(self.links)))(*(0 for x in range(3 * len(self.links))))
+        time = libtge.bandwidth_sensitivity(self.target, self.profiler, delta, result)
+        entries = [{ 'link': i, 'bandwidth': self.links[i], 'time': int(result[3 * i]), 'derivative': result[3 * i + 1], 'elasticity': result[3 * i + 2] } for i in range(len(self.links))]
+        return time, sorted(entries, key=lambda x: x['elasticity'])
+
+    def placement_heatmap(self, profile_dict, path, scope_depth=None):
+        """
+        write which devices each op of the compiled graph runs on and for how long, including the aux nodes created for it, so plans can be
+        compared without reading GraphDefs. The format is html if `path` ends with .html, otherwise json with 'devices', 'totals' and 'rows'
+        (each with 'name', 'ops', 'time' and 'nodes' per device). `scope_depth` groups ops by the first components of their names, e.g. 2 for
+        one row per layer of `model/dense_1/...`. The target is kept as is: the dangling nodes are only removed from a copy.
+        """
+        if not self.compiled:
+            self.compile()
+        self._create_profiler(profile_dict)
+        path = path.encode('ascii')
+        libtge.placement_heatmap(self.target, self.profiler, scope_depth or 0, path, len(path))
+
     def _create_target(self):
+        if self.target is not None:
+            libtge.destroy_target(self.target)
+        self.target = self._new_target(self.links, self.paths, self.nccls)
+        self.compiled = False
+
+    def _new_target(self, links, paths, nccls):
         devices_raw = ' '.join(self.devices).encode('ascii')
         sinks_raw = ' '.join(self.sinks).encode('ascii')
-        links_raw = ' '.join(map(str, self.links)).encode('ascii')
-        paths_raw = '\n'.join((' '.join(map(str, path)) for path in self.paths))
+        links_raw = ' '.join(map(str, links)).encode('ascii')
+        paths_raw = '\n'.join((' '.join(map(str, path)) for path in paths))
         paths_raw = (paths_raw + '\n').encode('ascii')
-        nccls_raw = '\n'.join((' '.join([k, *map(str, v)]) for k, v in self.nccls.items()))
+        nccls_raw = '\n'.join((' '.join([k, *map(str, v)]) for k, v in nccls.items()))
         nccls_raw = (nccls_raw + '\n').encode('ascii')
 
-        if self.target is not None:
-            libtge.destroy_target(self.target)
-        self.target = libtge.create_target(
+        target = libtge.create_target(
             devices_raw, len(devices_raw),
             links_raw, len(links_raw),
             paths_raw, len(paths_raw),
             sinks_raw, len(sinks_raw),
             nccls_raw, len(nccls_raw)
         )
-        self.compiled = False
+        if self.mesh is not None:
+            shape, devices = self.mesh
+            mesh_raw = ('x'.join(map(str, shape)) + ':' + ','.join(map(str, devices))).encode('ascii')
+            libtge.set_mesh(target, mesh_raw, len(mesh_raw))
+        if self.cpus:
+            cpus_raw = '\n'.join(' '.join([name, *('{}:{}'.format(g, b) for g, b in bandwidth.items())]) for name, bandwidth in self.cpus)
+            cpus_raw = (cpus_raw + '\n').encode('ascii')
+            libtge.set_cpus(target, cpus_raw, len(cpus_raw))
+        return target
 
-    def _edit(self):
+    def _strategy_raw(self, strategy):
         strategy_raw = ''
-        for name, strategy in self.strategy.items():
-            strategy_raw += name + ' ' + str(strategy[0])
-            for i, j in enumerate(strategy[1:]):
+        for name, s in strategy.items():
+            strategy_raw += name + ' ' + str(s[0])
+            for i, j in enumerate(s[1:]):
                 while j > 0:
                     strategy_raw += ' ' + str(i)
                     j -= 1
             strategy_raw += '\n'
-        strategy_raw = strategy_raw.encode('ascii')
+        return strategy_raw
+
+    def _edit(self):
+        strategy_raw = self._strategy_raw(self.strategy).encode('ascii')
         if self.edited:
             libtge.reset_graph(self.graph)
         libtge.edit_graph(self.graph, self.target, strategy_raw, len(strategy_raw))
@@ -221,6 +1314,36 @@ class TGE:
         libtge.remove_shape_hint(self.target)
         print('libtge.remove_shape_hint finishes!')
 
+    @chain
+    def coalesce_transfers(self, threshold=65536):
+        """
+        pack tensors smaller than `threshold` bytes that move between the same pair of devices at the same graph depth into a single transfer,
+        to amortize the per-transfer latency. Only tensors with static shapes are packed.
+        """
+        assert self.compiled
+        libtge.coalesce_transfers(self.target, threshold)
+
+    @chain
+    def place_input_pipelines(self):
+        """move the dataset/iterator ops feeding each GPU to its NUMA-local CPU given by set_cpus()"""
+        assert self.compiled
+        libtge.place_input_pipelines(self.target)
+
+    @chain
+    def assign_collective_streams(self):
+        """tag all-reduce nodes with a dedicated `_tge_stream` so they can overlap with computation. The simulator models the separation as well."""
+        assert self.compiled
+        libtge.assign_collective_streams(self.target)
+
+    @chain
+    def insert_step_markers(self):
+        """
+        add NoOps named `tge_marker/device_{i}/{forward,backward,aggregation}_end` after each phase on each device,
+        so the step stats of a real run can be aligned with the simulated phases. The sinks depend on them.
+        """
+        assert self.compiled
+        libtge.insert_step_markers(self.target)
+
     @chain
     def destruct_names(self):
         assert self.compiled
@@ -266,6 +1389,244 @@ class TGE:
                     paths.append([0])
         self.set_topology(links, paths)
 
+    @chain
+    def set_mesh(self, shape, devices=None):
+        """
+        arrange the devices into an n-dimensional mesh, e.g. shape=(2, 4) for 2-way data parallel x 4-way model parallel.
+        devices: the indexes of devices in row-major order, defaults to all devices in order.
+        """
+        if devices is None:
+            devices = list(range(len(self.devices)))
+        n = 1
+        for x in shape:
+            n *= x
+        assert n == len(devices)
+        self.mesh = (list(shape), list(devices))
+
+    @chain
+    def set_mesh_forms(self, forms):
+        """
+        place nodes by their forms on the mesh (see set_mesh) instead of the strategy: a dict of node name => (mesh form, slice).
+        A mesh form gives 'part' or 'full' for each mesh axis, e.g. 'part/full' splits the batch along the first axis and replicates along
+        the second. A form with several replica groups (see mesh_slices) puts the node on the one with the given index, e.g.
+        ('part/full', 1) on a 2x4 mesh splits the node between devices 1 and 5. The aggregation methods still come from the strategy.
+        """
+        assert self.mesh is not None
+        self._set_option("mesh_forms", ' '.join('{}:{}:{}'.format(name, form, index) for name, (form, index) in forms.items()))
+
+    @chain
+    def set_split_forms(self, forms):
+        """
+        place nodes by their forms instead of the strategy: a dict of node name => form code, e.g. 'part1_0_1' splits the columns of a
+        MatMul between devices 0 and 1, and 'full_0_1' replicates a node on them without splitting it. The inputs of a node split along
+        another axis than the batch are split the same way if they span it and replicated otherwise.
+        """
+        self._set_option("split_forms", ' '.join('{}:{}'.format(name, code) for name, code in forms.items()))
+
+    @chain
+    def set_cpus(self, cpus):
+        """
+        describe the host CPUs, e.g. one per NUMA node: a list of (name, {gpu_index: bandwidth}).
+        GPUs of other hosts should be absent from the dict; GPUs on the other socket get the lower cross-socket bandwidth.
+        """
+        for name, bandwidth in cpus:
+            assert all(0 <= g < len(self.devices) for g in bandwidth), "cpu {} refers to unknown devices".format(name)
+        self.cpus = list(cpus)
+
+    def mesh_slices(self, axis):
+        """the groups of device indexes that differ only along the given mesh axis"""
+        assert self.mesh is not None
+        shape, devices = self.mesh
+        groups = {}
+        for i, d in enumerate(devices):
+            coords, rest = [], i
+            for length in reversed(shape):
+                coords.append(rest % length)
+                rest //= length
+            coords.reverse()
+            groups.setdefault(tuple(c for a, c in enumerate(coords) if a != axis), []).append(d)
+        return [groups[k] for k in sorted(groups)]
+
+    @chain
+    def set_progress_callback(self, callback=None, interval_ms=1000):
+        """`callback(compiled, total, aux_nodes, eta_seconds)` is called periodically during compile().
+        Without arguments, prints a progress line instead. Pass False to disable."""
+        if callback is False:
+            self.progress_callback = ctypes.cast(None, PROGRESS_T)
+        else:
+            if callback is None:
+                def callback(compiled, total, aux_nodes, eta):
+                    print('compiled {}/{} nodes, {} aux nodes, ETA {:.0f}s'.format(compiled, total, aux_nodes, eta))
+            self.progress_callback = PROGRESS_T(callback)
+        libtge.set_progress_callback(self.graph, self.progress_callback)
+        self._set_option("progress_interval_ms", interval_ms)
+
+    @chain
+    def set_collective_backend(self, backend):
+        """
+        'nccl' emits NcclAllReduce for method 3 and within the machines of method 5. 'collective' emits CollectiveReduce instead, and
+        CollectiveGather for the all-gathers of split tensors, so the graph runs on TF's collective executor across workers without NCCL.
+        'horovod' emits HorovodAllreduce and HorovodBroadcast for tensors replicated on every task (one Horovod rank per task, in the order
+        of the devices), so the Horovod ops must be loaded (e.g. by importing horovod.tensorflow) before importing the compiled graph
+        """
+        assert backend in ('nccl', 'collective', 'horovod')
+        self._set_option("collective_backend", backend)
+
+    @chain
+    def set_collective_timeout(self, seconds):
+        """
+        make CollectiveReduce and CollectiveGather fail with DeadlineExceeded after `seconds` instead of stalling the whole cluster when a
+        device hangs (needs TF 2.4 or later). NCCL and Horovod ops cannot time out in the graph, which the compiler warns about
+        """
+        assert seconds > 0
+        self._set_option("collective_timeout", float(seconds))
+
+    @chain
+    def set_broadcast_method(self, method):
+        """how `broadcast_op` distributes weights: 'tree' relays Identities along the widest links, 'nccl' uses NcclBroadcast"""
+        assert method in ('tree', 'nccl')
+        self._set_option("broadcast_method", method)
+
+    @chain
+    def set_conversion_reuse(self, enabled=True):
+        """
+        when a tensor produced in parts is needed in full on several device sets, aggregate it once and copy the full value to the others
+        (and split it for consumers of parts on a different number of devices) instead of aggregating the parts for each. On by default
+        """
+        self._set_option("reuse_conversions", bool(enabled))
+
+    @chain
+    def set_broadcast_op(self, enabled=True):
+        """add `broadcast_op` to the compiled graph, to be run after restoring a checkpoint into replica 0 only. Off by default"""
+        self._set_option("broadcast_op", bool(enabled))
+
+    @chain
+    def set_broadcast_on_init(self, enabled=True):
+        """
+        make `init_op` copy replica 0 of every replicated variable to the other replicas (as `broadcast_op` does, with the same method)
+        after running the initializers, so all replicas start from identical weights
+        """
+        self._set_option("broadcast_on_init", bool(enabled))
+
+    @chain
+    def set_backend_selection(self, mode, small_bytes=None, large_bytes=None, broadcast_fanout=None):
+        """
+        choose the aggregation method of each gradient instead of applying the method of the strategy to all of them (only methods 0 to 3
+        are replaced). 'thresholds' uses AddN below `small_bytes`, NCCL within a machine, and across machines the collective ops (with the
+        collective backend) below `large_bytes` and the ring above. 'cost' picks the fastest by a simple model of the links. 'none' disables it.
+        It also decides whether a replicated tensor is sent to the devices of a machine with a NcclBroadcast instead of being read by each of
+        them: 'thresholds' does so from `small_bytes` and `broadcast_fanout` (4 by default) devices, 'cost' when the model predicts it faster
+        """
+        assert mode in ('thresholds', 'cost', 'none')
+        self._set_option("backend_selection", mode)
+        if small_bytes is not None:
+            self._set_option("backend_small_bytes", int(small_bytes))
+        if large_bytes is not None:
+            self._set_option("backend_large_bytes", int(large_bytes))
+        if broadcast_fanout is not None:
+            self._set_option("backend_broadcast_fanout", int(broadcast_fanout))
+
+    @chain
+    def set_compression(self, method, ratio=None, overrides=None, rank=None):
+        """
+        'fp16' sums float gradients in half precision (casting around the aggregation), 'none' keeps their dtype.
+        'topk' sends only the `ratio` (0.01 by default) largest-magnitude values of each float gradient with their indices, and each replica
+        adds what it did not send to its next gradient. 'int8' quantizes each replica of float32 gradients to 8 bits with its own scale and
+        sums them after dequantizing; TF runs the (de)quantization on CPUs only, so it needs soft placement. 'powersgd' sends factors of
+        `rank` (4 by default) of float matrix gradients, keeping the previous factor and the error feedback of each replica in variables.
+        'topk', 'int8' and 'powersgd' replace the aggregation method of the gradients, except sync and backup workers.
+        `overrides` maps tensor names (e.g. 'gradients/kernel' or 'grads:1') to the method for that tensor, other tensors use `method`.
+        The annotated sizes and the simulator account for the casted, sparsified or quantized bytes.
+        """
+        methods = ('none', 'fp16', 'topk', 'int8', 'powersgd')
+        assert method in methods
+        self._set_option("compression", method)
+        if ratio is not None:
+            assert 0 < ratio <= 1
+            self._set_option("topk_ratio", float(ratio))
+        if rank is not None:
+            assert rank > 0
+            self._set_option("powersgd_rank", int(rank))
+        if overrides is not None:
+            assert all(x in methods for x in overrides.values())
+            self._set_option("compression_overrides", ','.join("{}={}".format(name, x) for name, x in overrides.items()))
+
+    @chain
+    def set_reduction(self, op):
+        """
+        how the replicas of each gradient are combined: 'sum' (the default), 'mean', 'max', 'min' or 'prod'. 'mean' divides the sum by the
+        number of replicas, so the learning rate tuned on one device needs no rescaling. It applies to every aggregation method; sync and
+        backup workers accept only 'sum' and 'mean', and 'topk', 'int8' and 'powersgd' compression is skipped for 'max', 'min' and 'prod'.
+        """
+        assert op in ('sum', 'mean', 'max', 'min', 'prod')
+        self._set_option("reduction", op)
+
+    @chain
+    def set_kernel_overrides(self, overrides):
+        """
+        correct the table of ops without GPU kernels, which is checked before compiling. `overrides` maps op names to 'cpu'
+        (e.g. a custom op built without CUDA) or 'any' (the op has a GPU kernel in your TF build)
+        """
+        assert all(x in ('cpu', 'any') for x in overrides.values())
+        self._set_option("kernel_overrides", ','.join("{}={}".format(op, x) for op, x in overrides.items()))
+
+    @chain
+    def set_soft_placement(self, enabled=True):
+        """the session runs with `allow_soft_placement`, so ops without GPU kernels on GPUs are only reported instead of rejected"""
+        self._set_option("soft_placement", bool(enabled))
+
+    @chain
+    def set_decision_trace(self, path):
+        """
+        append the decisions of selective_replication() and ps_placement() to `path`, one JSON object per line with the alternatives
+        that were scored and their predicted costs, for finding where the cost model disagrees with measurements
+        """
+        self._set_option("decision_trace", path)
+
+    @chain
+    def set_name_scope(self, scope):
+        """
+        nest the whole compiled graph under `scope` (e.g. 'tge') in get_result() and compile_batch(), so it can be imported alongside
+        other graphs without name collisions. Use scoped() for the names of `init_op`, `broadcast_op` and the sinks. The passes
+        on the target still see the unscoped names.
+        """
+        self.name_scope = scope
+
+    @chain
+    def set_ring_order(self, order):
+        """
+        'index' builds the rings of method 2 (and the inter-machine rings of methods 5 and 9) in device order, 'bandwidth' orders them to maximize the slowest hop according to
+        the links and paths of the topology, which matters on mixed interconnects (NVLink + PCIe + Ethernet)
+        """
+        assert order in ('index', 'bandwidth')
+        self._set_option("ring_order", order)
+
+    @chain
+    def set_chunked_all_reduce(self, threshold, chunks=4):
+        """
+        all-reduce gradients of at least `threshold` bytes in `chunks` pipelined pieces, so the communication of one piece overlaps the
+        reduction of the next instead of the whole gradient going at once. Applies to methods 1, 2, 3, 5, 6 and 7.
+        """
+        assert chunks >= 1
+        self._set_option("chunk_threshold", int(threshold))
+        self._set_option("chunk_count", chunks)
+
+    @chain
+    def set_tensor_fusion(self, bucket_bytes):
+        """
+        all-reduce gradients smaller than `bucket_bytes` together: gradients with the same devices, dtype and method are concatenated into
+        buckets of up to `bucket_bytes`, each bucket is all-reduced once and split back. Saves the per-tensor latency of many small all-reduces.
+        Gradients summed in another dtype (see `set_compression`) are casted per bucket. The sums that the compiler plans for other tensors split
+        on the same devices (e.g. the gradients of activations consumed in full elsewhere) are fused the same way when they are ready together.
+        """
+        assert bucket_bytes > 0
+        self._set_option("fusion_bucket_size", int(bucket_bytes))
+
+    @chain
+    def set_hub_device(self, device_index):
+        """relay transfers between devices that have no path in the topology through this device"""
+        self._set_option("hub_device", device_index)
+
     @chain
     def set_nccl_model(self, model):
         """use profiler.py to make a model"""
@@ -287,6 +1648,160 @@ class TGE:
     def fill_batchsize(self, batchsize):
         self._set_option("fill_batchsize", batchsize)
 
+    @chain
+    def set_placeholder_mode(self, mode):
+        """
+        how split Placeholders are fed:
+        'split': keep the original Placeholder on the first device and split it in graph, so the feeding code does not change.
+        'per_replica': one Placeholder per replica with the sliced batch dimension. See write_feed_mapping().
+        """
+        assert mode in ('split', 'per_replica')
+        self._set_option("placeholder_mode", mode)
+
+    @chain
+    def set_split_ratios(self, ratios):
+        """
+        give the devices slices proportional to their capability when splitting tensors (SplitV instead of Split), e.g. [3, 3, 1, 1] for
+        two fast and two slow GPUs. `ratios` has a positive weight for each device, or is a dict from device indexes to weights (1 for the
+        others). Lengths that are not known at compile time are still split evenly (see fill_batchsize()). With 'per_replica' placeholders,
+        feed each replica the number of rows in the shape of its Placeholder.
+        """
+        if isinstance(ratios, dict):
+            ratios = [ratios.get(i, 1) for i in range(len(self.devices))]
+        assert len(ratios) == len(self.devices) and all(x > 0 for x in ratios)
+        self._set_option("split_ratios", ','.join(map(str, ratios)))
+
+    @chain
+    def set_local_sgd(self, period):
+        """
+        apply gradients locally on each replica and average the replicated variables only every `period` steps.
+        Variables whose gradients are computed on the same devices are affected; others are aggregated as usual.
+        """
+        assert period >= 1
+        self._set_option("local_sgd_period", period)
+
+    @chain
+    def set_out_of_core(self, directory=None, threshold=None):
+        """
+        keep Const values larger than `threshold` bytes (1 MiB by default) and the subgraph cache in a scratch file under `directory`
+        (by default $TGE_SPILL_DIR or the temporary directory) while compiling, so graphs with giant embedding tables compile with bounded memory.
+        """
+        directory = directory or os.environ.get("TGE_SPILL_DIR") or tempfile.gettempdir()
+        assert os.path.isdir(directory)
+        self._set_option("spill_dir", directory)
+        if threshold is not None:
+            self._set_option("spill_threshold", threshold)
+
+    @chain
+    def set_gradient_accumulation(self, steps):
+        """
+        run each step on a micro-batch and accumulate the gradients on each replica, aggregating and applying their mean only every
+        `steps` steps, so devices with little memory train with a `steps` times larger effective batch. The train op is skipped in the
+        other steps, and so is everything that waits for the updates (e.g. the increment of the global step).
+        """
+        assert steps >= 1
+        self._set_option("accumulation_steps", steps)
+
+    @chain
+    def set_async_sgd(self, sync_period=0):
+        """
+        apply the gradient of each replica to the shared variable on its own instead of aggregating them, i.e. asynchronous SGD. Only variables
+        with a single copy are affected (e.g. under `parameter_server`); gradients of mirrored variables are aggregated as usual. With
+        `sync_period`, the replicas wait for each other every `sync_period` steps before applying, which bounds the staleness when the replicas
+        are fetched separately (e.g. one thread per device).
+        """
+        assert sync_period >= 0
+        self._set_option("async_sgd", True)
+        self._set_option("async_sync_period", sync_period)
+
+    @chain
+    def set_backup_workers(self, required):
+        """
+        gradients aggregated by ps (method 0) proceed once `required` replicas have delivered, through a ConditionalAccumulator.
+        Late gradients of a step are dropped. Each replica of the sinks still runs its own backward pass, so fetch them separately
+        (e.g. one thread per device) to keep the fast replicas from waiting on stragglers.
+        """
+        assert required >= 1
+        self._set_option("backup_required", required)
+
+    @chain
+    def set_replicas_to_aggregate(self, required):
+        """
+        for gradients aggregated with method 4 (sync, the SyncReplicasOptimizer machinery with a ConditionalAccumulator and a token queue),
+        the number of replicas whose gradients each update waits for. Defaults to all replicas.
+        """
+        assert required >= 1
+        self._set_option("replicas_to_aggregate", required)
+
+    @chain
+    def set_audit(self, strategy_name):
+        """
+        embed a Const node `tge_audit` in the compiled graph with a JSON record of the strategy name, a hash of the compiled plan and the compile time,
+        so a running job can be traced back to the compilation that produced it. See read_audit().
+        """
+        self._set_option("audit_name", strategy_name)
+
+    @chain
+    def set_replica_format(self, fmt):
+        """
+        name the replicas by `fmt` instead of '{name}/replica_{index}', e.g. '{name}/tge_r{index}' when the graph already uses `/replica_`.
+        `fmt` starts with {name}, and the separator before {index} must not end with a digit. The format is recorded in the compiled graph,
+        see replica_format().
+        """
+        assert fmt.startswith('{name}') and '{index}' in fmt
+        self._set_option("replica_format", fmt)
+
+    @chain
+    def set_seed(self, seed, max_evaluations=None):
+        """
+        seed the stochastic parts of the compiler: the moves of search() and pareto_search(), the initial PowerSGD factors and the inputs
+        of smoke_test(). The seed is recorded in the compiled graph, see recorded_seed(). The searches stop at the wall-clock budget, so
+        their results only repeat if they evaluate the same number of plans; `max_evaluations` caps it to make them reproducible across machines.
+        """
+        assert 0 <= seed < 1 << 64
+        self._set_option("seed", seed)
+        if max_evaluations is not None:
+            assert max_evaluations >= 1
+            self._set_option("search_max_evaluations", max_evaluations)
+
+    @staticmethod
+    def read_audit(graph_def):
+        """the audit record of a compiled graph as a dict, or None if it was compiled without set_audit()"""
+        for node in graph_def.node:
+            if node.name == "tge_audit" or node.name.endswith("/tge_audit"):
+                return json.loads(node.attr["value"].tensor.string_val[0].decode())
+        return None
+
+    def write_feed_mapping(self, path):
+        """
+        write a JSON file mapping each split Placeholder to its per-replica placeholders, in the order of batch slices.
+        The names are those of get_result(), i.e. under the name scope if one is set.
+        """
+        assert self.compiled
+        path_raw = path.encode('ascii')
+        libtge.write_feed_mapping(self.graph, path_raw, len(path_raw))
+        if self.name_scope is not None:
+            with open(path) as f:
+                mapping = json.load(f)
+            mapping = { self.scoped(name): [self.scoped(x) for x in replicas] for name, replicas in mapping.items() }
+            with open(path, 'w') as f:
+                json.dump(mapping, f, indent=2)
+
+    @chain
+    def set_budget(self, max_nodes=None, max_aux_nodes_per_tensor=None, max_control_edges=None, policy='fail'):
+        """
+        limit the size of the compiled graph. Aggregations that need more aux nodes than max_aux_nodes_per_tensor fall back to AddN.
+        If the whole graph is still over budget, compile() raises when policy is 'fail', or retries with AddN for every node when policy is 'degrade'.
+        """
+        assert policy in ('fail', 'degrade')
+        if max_nodes is not None:
+            self._set_option("max_nodes", max_nodes)
+        if max_aux_nodes_per_tensor is not None:
+            self._set_option("max_aux_nodes_per_tensor", max_aux_nodes_per_tensor)
+        if max_control_edges is not None:
+            self._set_option("max_control_edges", max_control_edges)
+        self.budget_policy = policy
+
     @chain
     def verbose(self):
         self._set_option("log_forms", True)
@@ -310,3 +1825,60 @@ class TGE:
     @chain
     def set_strategy(self, strategy): # each value is an array, where the first element is 0 or 1 indicating PS or all-reduce, followed by the devices
         self.strategy = strategy
+
+if __name__ == '__main__':
+    import argparse
+    parser = argparse.ArgumentParser(prog='tge')
+    commands = parser.add_subparsers(dest='command', required=True)
+    stats_parser = commands.add_parser('stats', help='op histogram, parameters, activation bytes and depth of a graph, and what the compiler added to it')
+    stats_parser.add_argument('graph', help='the input GraphDef (.pb, .pbtxt, optionally .gz)')
+    stats_parser.add_argument('compiled', nargs='?', help='the compiled GraphDef, e.g. written by TGE.save()')
+    stats_parser.add_argument('--json', action='store_true', help='print JSON instead of a table')
+    repro_parser = commands.add_parser('repro', help='run a script and, if it fails, bundle the graph and settings of its last compile() for a bug report (see TGE.write_repro)')
+    repro_parser.add_argument('--keep-names', action='store_true', help='do not rename the nodes')
+    repro_parser.add_argument('bundle', help='the .tar.gz to write')
+    repro_parser.add_argument('script')
+    repro_parser.add_argument('args', nargs=argparse.REMAINDER)
+    replay_parser = commands.add_parser('replay', help='compile the graph of a bundle again')
+    replay_parser.add_argument('bundle')
+    args = parser.parse_args()
+
+    if args.command == 'stats':
+        stats = graph_stats(load_graph_def(args.graph))
+        compiled = graph_stats(load_graph_def(args.compiled)) if args.compiled else None
+        if args.json:
+            print(json.dumps({ 'input': stats, 'compiled': compiled }, indent=2))
+        else:
+            print(format_graph_stats(stats, compiled))
+
+    if args.command == 'repro':
+        import sys
+        import subprocess
+        with tempfile.TemporaryDirectory() as directory:
+            env = dict(os.environ, TGE_REPRO_DIR=directory, TGE_REPRO_KEEP_NAMES='1' if args.keep_names else '0')
+            child = subprocess.Popen([sys.executable, args.script, *args.args], env=env, stderr=subprocess.PIPE)
+            stderr = []
+            for line in child.stderr: # shown as usual and kept for the bundle
+                sys.stderr.buffer.write(line)
+                sys.stderr.flush()
+                stderr.append(line)
+            status = child.wait()
+            if status == 0:
+                print('the script succeeded, no bundle is written')
+                sys.exit(0)
+            if not os.path.exists(os.path.join(directory, 'graph.pb')):
+                print('the script failed before compile(), nothing to bundle')
+                sys.exit(1)
+            files = {}
+            for name in ('graph.pb', 'config.json', 'version.json', 'panic.txt'):
+                if os.path.exists(os.path.join(directory, name)):
+                    with open(os.path.join(directory, name), 'rb') as f:
+                        files[name] = f.read()
+            files['backtrace.txt'] = 'exit status {}\n\n'.format(status).encode('utf-8') + files.pop('panic.txt', b'') + b'\n' + b''.join(stderr[-200:])
+            _write_bundle(args.bundle, files)
+            print('wrote {}'.format(args.bundle))
+            sys.exit(1)
+
+    if args.command == 'replay':
+        replay_repro(args.bundle)
+        print('compiled without errors')